@@ -0,0 +1,108 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// How far back send_rate looks
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+
+// Paces sends to a number of bytes per second. A packet may take us into debt, which whoever sends
+// next waits out, so one bigger than the bucket still goes.
+pub struct TokenBucket {
+    rate: f64,
+    // Tokens saved up while idle, so a short burst goes out at once. A tenth of a second's worth.
+    burst: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(bytes_per_sec: u64, now: Instant) -> TokenBucket {
+        let rate = bytes_per_sec.max(1) as f64;
+        TokenBucket {
+            rate,
+            burst: rate / 10.0,
+            tokens: rate / 10.0,
+            last: now,
+        }
+    }
+
+    // How long to wait before sending bytes. Either way they're paid for now.
+    pub fn take(&mut self, now: Instant, bytes: usize) -> Duration {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.last = now;
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+
+        let wait = if self.tokens < 0.0 {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        } else {
+            Duration::default()
+        };
+        self.tokens -= bytes as f64;
+        wait
+    }
+}
+
+// The bytes sent over the last RATE_WINDOW, by when they went.
+#[derive(Default)]
+pub struct SendMeter {
+    sent: Mutex<VecDeque<(Instant, usize)>>,
+}
+
+impl SendMeter {
+    pub fn record(&self, now: Instant, bytes: usize) {
+        let mut sent = self.sent.lock().unwrap();
+        sent.push_back((now, bytes));
+        SendMeter::expire(&mut sent, now);
+    }
+
+    // In bytes per second
+    pub fn rate(&self, now: Instant) -> f64 {
+        let mut sent = self.sent.lock().unwrap();
+        SendMeter::expire(&mut sent, now);
+        sent.iter().map(|(_, bytes)| *bytes).sum::<usize>() as f64 / RATE_WINDOW.as_secs_f64()
+    }
+
+    fn expire(sent: &mut VecDeque<(Instant, usize)>, now: Instant) {
+        while let Some((at, _)) = sent.front() {
+            if now.saturating_duration_since(*at) < RATE_WINDOW {
+                break;
+            }
+            sent.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn token_bucket() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(1000, start);
+
+        // The first 100 bytes were saved up, and go into debt for the next 100
+        assert_eq!(bucket.take(start, 100), Duration::default());
+        assert_eq!(bucket.take(start, 100), Duration::default());
+        let wait = bucket.take(start, 100);
+        assert!((wait.as_secs_f64() - 0.1).abs() < 1e-9, "{:?}", wait);
+
+        // Idling only ever saves up a burst's worth
+        let later = start + Duration::from_secs(60);
+        assert_eq!(bucket.take(later, 300), Duration::default());
+        let wait = bucket.take(later, 1);
+        assert!((wait.as_secs_f64() - 0.2).abs() < 1e-9, "{:?}", wait);
+    }
+
+    #[test]
+    fn send_meter() {
+        let start = Instant::now();
+        let meter = SendMeter::default();
+        assert_eq!(meter.rate(start), 0.0);
+        meter.record(start, 300);
+        meter.record(start + Duration::from_millis(500), 200);
+        assert_eq!(meter.rate(start + Duration::from_millis(900)), 500.0);
+        assert_eq!(meter.rate(start + Duration::from_millis(1200)), 200.0);
+        assert_eq!(meter.rate(start + Duration::from_secs(2)), 0.0);
+    }
+}
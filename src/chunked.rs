@@ -0,0 +1,234 @@
+// Values too big for one datagram, stored as a series of chunks plus a manifest under the key
+// itself that says how many chunks to fetch and what they should add up to.
+
+use sha2::{Digest, Sha256};
+
+use crate::{Dht, Error};
+
+// Leaves plenty of room for the rest of a Store packet within MAX_PACKET_SIZE
+const CHUNK_SIZE: usize = 32 * 1024;
+
+#[derive(Serialize, Deserialize)]
+struct Manifest {
+    chunks: u32,
+    len: u64,
+    hash: [u8; 32],
+}
+
+impl Manifest {
+    // Whether it describes a value put_large could have stored, no bigger than max. It comes from
+    // whoever answered, so it has to be checked before anything is allocated for it.
+    fn valid(&self, max: u64) -> bool {
+        self.len <= max && u64::from(self.chunks) == self.len.div_ceil(CHUNK_SIZE as u64)
+    }
+}
+
+// Marks a chunk's key as one, so no plain key can land on it
+const CHUNK_KEY_PREFIX: &[u8] = b"dht-chunk\0";
+
+// Chunk index of key is stored under hash(CHUNK_KEY_PREFIX || key || index)
+fn chunk_key(key: &[u8], index: u32) -> Vec<u8> {
+    let mut chunk_key = CHUNK_KEY_PREFIX.to_vec();
+    chunk_key.extend_from_slice(key);
+    chunk_key.extend_from_slice(&index.to_be_bytes());
+    chunk_key
+}
+
+impl Dht {
+    // Stores a value of any size under key, returning how many nodes took the least-stored piece.
+    pub fn put_large(&self, key: &[u8], value: &[u8]) -> Result<usize, Error> {
+        let manifest = Manifest {
+            chunks: value.chunks(CHUNK_SIZE).count() as u32,
+            len: value.len() as u64,
+            hash: Sha256::digest(value).into(),
+        };
+
+        let mut stored = usize::MAX;
+        for (i, chunk) in value.chunks(CHUNK_SIZE).enumerate() {
+            stored = stored.min(self.put(&chunk_key(key, i as u32), chunk.to_vec())?);
+        }
+        // The manifest goes last, so it's never found pointing at chunks that aren't there yet
+        let manifest = bincode::serialize(&manifest).unwrap();
+        Ok(stored.min(self.put(key, manifest)?))
+    }
+
+    // Fetches and reassembles a value stored with put_large.
+    pub fn get_large(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        let manifest = match self.get(key)? {
+            Some(manifest) => manifest,
+            None => return Ok(None),
+        };
+        let manifest: Manifest = bincode::deserialize(&manifest).map_err(|_| Error::Corrupt)?;
+        if !manifest.valid(self.max_large_value) {
+            return Err(Error::Corrupt);
+        }
+        reassemble(&manifest, |i| self.get(&chunk_key(key, i))).map(Some)
+    }
+}
+
+// Rounds of fetching whichever chunks are still missing before giving up on them
+const CHUNK_ROUNDS: usize = 3;
+
+// The chunks of one manifest's value fetched so far, in whatever order they turn up.
+struct Reassembly<'a> {
+    manifest: &'a Manifest,
+    chunks: Vec<Option<Vec<u8>>>,
+}
+
+impl<'a> Reassembly<'a> {
+    fn new(manifest: &'a Manifest) -> Reassembly<'a> {
+        Reassembly {
+            manifest,
+            chunks: vec![None; manifest.chunks as usize],
+        }
+    }
+
+    fn missing(&self) -> Vec<u32> {
+        (0..self.manifest.chunks)
+            .filter(|&i| self.chunks[i as usize].is_none())
+            .collect()
+    }
+
+    fn insert(&mut self, index: u32, chunk: Vec<u8>) {
+        self.chunks[index as usize] = Some(chunk);
+    }
+
+    // The whole value, once every chunk is in and they add up to what the manifest says.
+    fn finish(self) -> Result<Vec<u8>, Error> {
+        let mut value = Vec::with_capacity(self.manifest.len as usize);
+        for chunk in self.chunks {
+            value.extend_from_slice(&chunk.ok_or(Error::Corrupt)?);
+        }
+        if value.len() as u64 != self.manifest.len
+            || Sha256::digest(&value)[..] != self.manifest.hash
+        {
+            return Err(Error::Corrupt);
+        }
+        Ok(value)
+    }
+}
+
+// Fetches every chunk, then goes back for just the ones that didn't turn up, a few times over. A
+// chunk that's still missing after that means the value is incomplete.
+fn reassemble(
+    manifest: &Manifest,
+    mut fetch: impl FnMut(u32) -> Result<Option<Vec<u8>>, Error>,
+) -> Result<Vec<u8>, Error> {
+    let mut reassembly = Reassembly::new(manifest);
+    for _ in 0..CHUNK_ROUNDS {
+        let missing = reassembly.missing();
+        if missing.is_empty() {
+            break;
+        }
+        for i in missing {
+            match fetch(i) {
+                Ok(Some(chunk)) => reassembly.insert(i, chunk),
+                // Maybe it's somewhere we'll reach next time
+                Ok(None) | Err(Error::Timeout) => (),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+    reassembly.finish()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn put_then_get_large() {
+        let storer = Dht::start("127.0.0.1:0").unwrap();
+        let writer = Dht::start("127.0.0.1:0").unwrap();
+        let reader = Dht::start("127.0.0.1:0").unwrap();
+
+        // Several chunks, the last one partial
+        let value: Vec<u8> = (0..3 * CHUNK_SIZE + 100).map(|_| rand::random()).collect();
+        writer.ping(storer.local_addr()).unwrap();
+        assert_eq!(writer.put_large(b"key", &value).unwrap(), 1);
+
+        reader.ping(storer.local_addr()).unwrap();
+        assert_eq!(reader.get_large(b"key").unwrap(), Some(value));
+        assert_eq!(reader.get_large(b"missing").unwrap(), None);
+
+        // A plain value isn't a manifest
+        writer.put(b"plain", b"value".to_vec()).unwrap();
+        assert!(matches!(reader.get_large(b"plain"), Err(Error::Corrupt)));
+
+        // Nor is one claiming more than we'd take, or more chunks than its length needs
+        for (chunks, len) in [(1 << 16, 1 << 31), (u32::MAX, 1)] {
+            let manifest = Manifest {
+                chunks,
+                len,
+                hash: [0; 32],
+            };
+            let manifest = bincode::serialize(&manifest).unwrap();
+            writer.put(b"forged", manifest).unwrap();
+            assert!(matches!(reader.get_large(b"forged"), Err(Error::Corrupt)));
+        }
+    }
+
+    #[test]
+    fn chunk_keys() {
+        assert_eq!(chunk_key(b"key", 1), b"dht-chunk\0key\0\0\0\x01");
+        // The chunk of one key isn't the plain value, or the manifest, of another
+        let key = [b'k', 0, 0, 0, 0];
+        assert_ne!(chunk_key(b"k", 0), key);
+        assert_ne!(chunk_key(b"k", 0), chunk_key(&key, 0));
+    }
+
+    #[test]
+    fn manifest_bounds() {
+        let manifest = |chunks, len| Manifest {
+            chunks,
+            len,
+            hash: [0; 32],
+        };
+        let size = CHUNK_SIZE as u64;
+        assert!(manifest(0, 0).valid(0));
+        assert!(manifest(1, 1).valid(size));
+        assert!(manifest(1, size).valid(size));
+        assert!(manifest(2, size + 1).valid(size + 1));
+        assert!(!manifest(2, size).valid(size));
+        assert!(!manifest(1, size + 1).valid(size + 1));
+        assert!(!manifest(2, size + 1).valid(size));
+    }
+
+    #[test]
+    fn missing_chunks() {
+        let value: Vec<u8> = (0..5 * CHUNK_SIZE).map(|_| rand::random()).collect();
+        let manifest = Manifest {
+            chunks: 5,
+            len: value.len() as u64,
+            hash: Sha256::digest(&value).into(),
+        };
+        let chunk = |i: u32| value.chunks(CHUNK_SIZE).nth(i as usize).unwrap().to_vec();
+
+        // Chunk 1 times out once and chunk 3 isn't found twice, so only those get asked for again
+        let mut fetched = Vec::new();
+        let reassembled = reassemble(&manifest, |i| {
+            let tries = fetched.iter().filter(|&&f| f == i).count();
+            fetched.push(i);
+            match (i, tries) {
+                (1, 0) => Err(Error::Timeout),
+                (3, 0) | (3, 1) => Ok(None),
+                _ => Ok(Some(chunk(i))),
+            }
+        });
+        assert_eq!(reassembled.unwrap(), value);
+        assert_eq!(fetched, vec![0, 1, 2, 3, 4, 1, 3, 3]);
+
+        // One that never turns up leaves the value incomplete
+        let mut fetched = Vec::new();
+        let reassembled = reassemble(&manifest, |i| {
+            fetched.push(i);
+            Ok(if i == 2 { None } else { Some(chunk(i)) })
+        });
+        assert!(matches!(reassembled, Err(Error::Corrupt)));
+        assert_eq!(fetched, vec![0, 1, 2, 3, 4, 2, 2]);
+
+        // Shutting down isn't worth retrying through
+        let reassembled = reassemble(&manifest, |_| Err(Error::Shutdown));
+        assert!(matches!(reassembled, Err(Error::Shutdown)));
+    }
+}
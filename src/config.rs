@@ -0,0 +1,219 @@
+use std::time::Duration;
+
+use crate::error::Error;
+use crate::kad::{ALPHA, MAX_PACKET_SIZE, MAX_VALUE_SIZE, SIGNATURE_LEN};
+use crate::mac::MAC_LEN;
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    // How long get, put and ping wait when not given their own timeout
+    pub timeout: Duration,
+    // How often the worker runs its maintenance: timing out requests and operations, keepalives,
+    // republishing and refreshing the neighborhood. Timeouts are only noticed this often.
+    pub tick_interval: Duration,
+    // Largest datagram the receiver will read. Anything longer is truncated and dropped as malformed.
+    pub max_datagram_size: usize,
+    // Contacts we haven't sent anything to for this long get pinged, so NAT mappings for them
+    // don't expire. None turns keepalives off.
+    pub keepalive_interval: Option<Duration>,
+    // Stack size for the worker, sender and receiver threads. None uses the platform default.
+    pub stack_size: Option<usize>,
+    // Pass stores for keys we aren't among the closest to on towards a closer node, rather than
+    // rejecting them. Either way the sender is told we didn't keep it (Rejection::Forwarded).
+    pub forward_stores: bool,
+    // Lookups pass over a peer while it has this many of our requests outstanding, and come back
+    // to it once some are answered. Must be at least 1.
+    pub max_requests_per_peer: usize,
+    // How often we re-store every value we hold on whichever nodes are now closest to its key.
+    // None turns republishing off.
+    pub republish_interval: Option<Duration>,
+    // Values nobody has stored with us for this long are dropped. Republishing skips nodes that
+    // already hold a value, so it's up to whoever put it to put it again before then. A signed
+    // value is kept alive by putting it again unchanged, at the same sequence number. None keeps
+    // values forever.
+    pub value_ttl: Option<Duration>,
+    // Peers nobody has announced under a key for this long are dropped from it, so a swarm's
+    // members have to keep announcing. Set members (see Dht::add) go the same way. None keeps them
+    // forever.
+    pub peer_ttl: Option<Duration>,
+    // How often we look up our own ID to find out who our closest neighbors are. None turns this
+    // off, leaving the neighborhood empty.
+    pub neighborhood_interval: Option<Duration>,
+    // Sent in every packet. Packets with any other network ID are dropped, so private networks
+    // sharing infrastructure with others never hear from each other.
+    pub network_id: u32,
+    // Lookups don't ask nodes more than this many responses away from the ones they started
+    // with, so a network that keeps handing out ever closer nodes can't keep one going forever.
+    // None takes the lookup as far as it goes.
+    pub max_lookup_hops: Option<usize>,
+    // Sign every packet we send with a key made up when the node starts, and drop any packet that
+    // isn't validly signed. The node's ID is the hash of the key, and a signature only verifies
+    // for the ID its key hashes to, so nobody can sign as another node. Off by default, since
+    // nodes that don't sign can't talk to ones that do. Packets that are signed but don't verify
+    // are dropped either way.
+    pub sign_packets: bool,
+    // Keep packets whose payload is from a newer version of the protocol, for the sake of learning
+    // their sender, and ignore the payload itself. Off drops them as malformed. Either way they're
+    // dropped as unsigned while sign_packets is on, since their signature can't be found.
+    pub accept_unknown_payloads: bool,
+    // Drop every response that doesn't answer a request of ours, rather than still learning its
+    // sender from it, and ban IPs that send too many for a while (see Anomaly::Banned). Answers
+    // to the requests we don't wait on, which go out with sequence number 0, are let off. Bans
+    // need psk or sign_packets, since otherwise anyone could spoof a victim's IP to get it banned.
+    // Even then, a node holding the key can still do that.
+    pub strict_responses: bool,
+    // Take contacts and peers at private, loopback and link-local addresses from other nodes,
+    // and add nodes that reach us from one to the routing table. A LAN-only network needs them,
+    // but on the internet they're bogus at best, and at worst a way to have us flood someone's
+    // local network, so a public node should turn this off.
+    pub accept_private_addrs: bool,
+    // Compress big Nodes and Value payloads (see COMPRESS_THRESHOLD) when that saves bytes. Every
+    // node decompresses them, but ones from before compression can't, so leave this off until
+    // the whole network is up to date.
+    pub compress_payloads: bool,
+    // A key shared by every node of a private network. When set, each datagram we send ends in a
+    // MAC made with it, and any datagram without the right MAC is dropped, so only nodes holding
+    // the key can make packets the others will take. Nothing in the MAC is unique to one datagram,
+    // though, so a datagram recorded off the wire can be sent again, and is taken again.
+    pub psk: Option<[u8; 32]>,
+    // Who makes way when someone new turns up for a full bucket. Whoever it is gets pinged first,
+    // and is only replaced if it doesn't answer.
+    pub eviction: EvictionPolicy,
+    // How many received packets can wait for the worker at once. While they're waiting the
+    // receivers keep emptying the socket, so the worker stalling for a moment doesn't leave the
+    // kernel dropping datagrams. 0 hands each one straight to the worker.
+    pub recv_queue: usize,
+    // Add whoever we hear from to the routing table. Turned off, it only holds contacts we're
+    // given (seeds that answer, and contacts seeded by hand), and the nodes a lookup turns up are
+    // forgotten once it's done. That suits a client that only gets and puts and would rather not
+    // keep up a full table.
+    pub maintain_routing_table: bool,
+    // Most bytes per second we send, counting every datagram in full. Once there's a backlog,
+    // responses go ahead of our own requests, so we stay polite to others while held back. None
+    // sends as fast as the socket takes them.
+    pub max_send_rate: Option<u64>,
+    // Most queries a lookup keeps in flight at once. Lookups done for someone waiting on them
+    // (gets, puts, find_node, republishing) use lookup_alpha, so raising it gets answers faster.
+    // The ones that only keep the routing table fresh (bucket refreshes and finding our
+    // neighborhood) use refresh_alpha, and go easier on the network if it's lower.
+    // Both must be at least 1.
+    pub lookup_alpha: usize,
+    pub refresh_alpha: usize,
+    // Most lookups running at once, counting puts still waiting on their stores. What happens to
+    // any more is up to lookup_overflow. None runs as many as are asked for.
+    pub max_lookups: Option<usize>,
+    pub lookup_overflow: LookupOverflow,
+    // Sent in our pings and pongs, along with how long we've been up, for peers to note against
+    // our contact. Cut down to MAX_VERSION_LEN bytes. None tells them nothing.
+    pub software_version: Option<String>,
+    // Which of the nodes we know of go in our answers to FindNode, and to FindValue and GetPeers
+    // when we've nothing else to give
+    pub closest_nodes: ClosestNodes,
+    // get_large refuses values whose manifest says they're bigger than this, rather than fetching
+    // and holding that much on some other node's say-so
+    pub max_large_value: u64,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Default)]
+pub enum EvictionPolicy {
+    // The contact we've gone longest without hearing from, as in Kademlia
+    #[default]
+    LeastRecentlySeen,
+    // The contact slowest to answer us, counting ones that never have as slowest of all
+    QualityWeighted,
+    // Like LeastRecentlySeen, but only ever contacts we haven't heard from directly. A bucket full
+    // of verified contacts turns newcomers away.
+    KeepVerified,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Default)]
+pub enum ClosestNodes {
+    // The K nearest to the target, from however many buckets that takes, so the asker gets as
+    // close as it can in one hop
+    #[default]
+    Spillover,
+    // Only the bucket the target falls into, as in the original Kademlia, which may be fewer
+    Bucket,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Default)]
+pub enum LookupOverflow {
+    // Wait for a running lookup to finish, oldest first. Their deadlines still count while they
+    // wait.
+    #[default]
+    Queue,
+    // Fail straight away with Error::TooManyLookups. Lookups of our own, like refreshes, are
+    // skipped.
+    Fail,
+}
+
+impl Config {
+    // Checks for settings that would leave the node unable to work, naming the first it finds.
+    // Every way of starting a Dht checks this first.
+    pub fn validate(&self) -> Result<(), Error> {
+        // Lookups that can't send a query never finish
+        if self.lookup_alpha < 1 {
+            return Err(Error::BadConfig("lookup_alpha"));
+        }
+        if self.refresh_alpha < 1 {
+            return Err(Error::BadConfig("refresh_alpha"));
+        }
+        // Lookups would pass over everyone
+        if self.max_requests_per_peer < 1 {
+            return Err(Error::BadConfig("max_requests_per_peer"));
+        }
+        Ok(())
+    }
+
+    // The largest value a Store can carry, which is less than MAX_VALUE_SIZE once there's a MAC or
+    // a signature to leave room for. Bigger puts fail with Error::TooLarge.
+    pub fn max_value_size(&self) -> usize {
+        let signature = if self.sign_packets { SIGNATURE_LEN } else { 0 };
+        MAX_VALUE_SIZE - (MAX_PACKET_SIZE - self.max_packet_size()) as usize - signature
+    }
+
+    // The most we encode of a packet, leaving room for whatever goes on the end of the datagram
+    pub(crate) fn max_packet_size(&self) -> u64 {
+        match self.psk {
+            Some(_) => MAX_PACKET_SIZE - MAC_LEN as u64,
+            None => MAX_PACKET_SIZE,
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            timeout: Duration::from_secs(10),
+            tick_interval: Duration::from_millis(50),
+            max_datagram_size: 1 << 16, // Maximum size of a UDP datagram
+            keepalive_interval: Some(Duration::from_secs(25)), // NATs commonly forget UDP flows after 30s
+            stack_size: None,
+            forward_stores: false,
+            max_requests_per_peer: 2,
+            republish_interval: Some(Duration::from_secs(60 * 60)),
+            value_ttl: Some(Duration::from_secs(24 * 60 * 60)),
+            peer_ttl: Some(Duration::from_secs(30 * 60)),
+            neighborhood_interval: Some(Duration::from_secs(10 * 60)),
+            network_id: 0,
+            max_lookup_hops: Some(32),
+            sign_packets: false,
+            accept_unknown_payloads: true,
+            strict_responses: false,
+            accept_private_addrs: true,
+            compress_payloads: false,
+            psk: None,
+            eviction: EvictionPolicy::default(),
+            recv_queue: 256,
+            maintain_routing_table: true,
+            max_send_rate: None,
+            lookup_alpha: ALPHA,
+            refresh_alpha: ALPHA,
+            max_lookups: None,
+            lookup_overflow: LookupOverflow::default(),
+            software_version: None,
+            closest_nodes: ClosestNodes::default(),
+            max_large_value: 64 << 20,
+        }
+    }
+}
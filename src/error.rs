@@ -0,0 +1,61 @@
+use std::error;
+use std::fmt;
+use std::io;
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    // Couldn't bind the DHT's socket, e.g. because the address is in use or needs privileges
+    Bind(io::Error),
+    // The operation's deadline passed before it finished
+    Timeout,
+    // The worker thread is gone, so nothing will ever answer
+    Shutdown,
+    // A value was found but is missing pieces or doesn't match its hash
+    Corrupt,
+    // The given line (counting from 1) of a bootstrap file isn't of the form hexid@addr
+    BadDescriptor(usize),
+    // Config::max_lookups lookups were already running, and Config::lookup_overflow says not to
+    // wait for one to finish
+    TooManyLookups,
+    // The routing table was reset (see Dht::reset_and_bootstrap) while the operation was running
+    Reset,
+    // The value is bigger than a Store packet can carry (see Config::max_value_size)
+    TooLarge,
+    // The named Config field is set to something that can't work (see Config::validate)
+    BadConfig(&'static str),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "I/O error: {}", e),
+            Error::Bind(e) => write!(f, "couldn't bind socket: {}", e),
+            Error::Timeout => write!(f, "operation timed out"),
+            Error::Shutdown => write!(f, "the DHT has shut down"),
+            Error::Corrupt => write!(f, "value is incomplete or corrupt"),
+            Error::BadDescriptor(line) => {
+                write!(f, "line {} isn't a node descriptor (hexid@addr)", line)
+            }
+            Error::TooManyLookups => write!(f, "too many lookups running"),
+            Error::Reset => write!(f, "the routing table was reset"),
+            Error::TooLarge => write!(f, "value too large to store"),
+            Error::BadConfig(field) => write!(f, "Config::{} is out of range", field),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::Io(e) | Error::Bind(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
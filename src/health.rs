@@ -0,0 +1,21 @@
+use crate::recv::DropReason;
+
+// How a node is doing, all in one go, as returned by `Dht::health`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct HealthReport {
+    // Contacts in the routing table
+    pub peers: usize,
+    // How many of them share each number of leading bits with our ID, for every number some do,
+    // furthest first
+    pub buckets: Vec<(usize, usize)>,
+    // Values we're holding, plain and signed
+    pub values: usize,
+    pub signed_values: usize,
+    // Lookups running, and waiting to because of Config::max_lookups
+    pub lookups: usize,
+    pub queued_lookups: usize,
+    // How many datagrams the receivers have thrown away since we started, for each reason any
+    // have been
+    pub drops: Vec<(DropReason, u64)>,
+    pub estimated_size: Option<u64>,
+}
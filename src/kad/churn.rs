@@ -0,0 +1,84 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use super::kbucket::NodeID;
+
+// How long an evicted ID has to wait before it can take a place in the table again, unless it
+// answers one of our requests first
+pub const EVICTION_COOLDOWN: Duration = Duration::from_secs(2 * 60);
+
+// How long a contact keeps its place before anyone can challenge it for it
+pub const MIN_DWELL: Duration = Duration::from_secs(30);
+
+// Damps routing table churn, so a peer can't keep a bucket flapping by dropping out and offering
+// itself again, or by pushing in and out over and over.
+#[derive(Default)]
+pub struct Churn {
+    // When each was evicted
+    evicted: HashMap<NodeID, Instant>,
+    // When each was let in
+    admitted: HashMap<NodeID, Instant>,
+}
+
+impl Churn {
+    pub fn evicted(&mut self, id: NodeID, now: Instant) {
+        self.admitted.remove(&id);
+        self.evicted.insert(id, now);
+    }
+
+    pub fn admitted(&mut self, id: NodeID, now: Instant) {
+        self.evicted.remove(&id);
+        self.admitted.insert(id, now);
+    }
+
+    // Whether id was evicted too recently to be let back in on its own say-so.
+    pub fn cooling(&self, id: NodeID, now: Instant) -> bool {
+        self.evicted
+            .get(&id)
+            .is_some_and(|&at| now.saturating_duration_since(at) < EVICTION_COOLDOWN)
+    }
+
+    // Whether id has been in the table long enough to be evicted. Anyone we never saw let in has.
+    pub fn settled(&self, id: NodeID, now: Instant) -> bool {
+        self.admitted
+            .get(&id)
+            .is_none_or(|&at| now.saturating_duration_since(at) >= MIN_DWELL)
+    }
+
+    // Forgets evictions and admissions that no longer hold anything back.
+    pub fn expire(&mut self, now: Instant) {
+        self.evicted
+            .retain(|_, &mut at| now.saturating_duration_since(at) < EVICTION_COOLDOWN);
+        self.admitted
+            .retain(|_, &mut at| now.saturating_duration_since(at) < MIN_DWELL);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn hysteresis_lapses() {
+        let start = Instant::now();
+        let mut churn = Churn::default();
+        let (a, b) = (rand::random(), rand::random());
+        churn.admitted(a, start);
+        churn.evicted(b, start);
+        assert!(!churn.settled(a, start));
+        assert!(churn.settled(b, start));
+        assert!(churn.cooling(b, start));
+        assert!(!churn.cooling(a, start));
+
+        let later = start + MIN_DWELL;
+        assert!(churn.settled(a, later));
+        assert!(churn.cooling(b, later));
+        churn.expire(start + EVICTION_COOLDOWN);
+        assert!(churn.evicted.is_empty() && churn.admitted.is_empty());
+
+        // Getting let back in ends the cooldown
+        churn.evicted(b, later);
+        churn.admitted(b, later);
+        assert!(!churn.cooling(b, later));
+    }
+}
@@ -0,0 +1,51 @@
+use std::time::Instant;
+
+// Where everything time-dependent in Kad gets the time from, so tests can move it along by hand
+// instead of sleeping.
+pub trait Clock: Send {
+    fn now(&self) -> Instant;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+#[cfg(test)]
+pub use self::manual::ManualClock;
+
+#[cfg(test)]
+mod manual {
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+
+    use super::Clock;
+
+    // Stands still until advanced. Clones share the same time, so a test can keep one to advance
+    // while Kad owns another.
+    #[derive(Clone)]
+    pub struct ManualClock {
+        now: Arc<Mutex<Instant>>,
+    }
+
+    impl ManualClock {
+        pub fn new() -> ManualClock {
+            ManualClock {
+                now: Arc::new(Mutex::new(Instant::now())),
+            }
+        }
+
+        pub fn advance(&self, by: Duration) {
+            *self.now.lock().unwrap() += by;
+        }
+    }
+
+    impl Clock for ManualClock {
+        fn now(&self) -> Instant {
+            *self.now.lock().unwrap()
+        }
+    }
+}
@@ -1,19 +1,81 @@
-use std::collections::VecDeque;
-use std::net::SocketAddr;
+use std::cmp::Reverse;
+use std::collections::{HashMap, VecDeque};
+use std::marker::PhantomData;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
 
 use rand::distributions::Standard;
 use rand::prelude::*;
+use sha2::{Digest, Sha256};
+
+use super::proto::{Capabilities, Metadata};
+use crate::config::EvictionPolicy;
 
 pub const K: usize = 20;
 pub const KEY_BITS: usize = 256;
 pub const KEY_BYTES: usize = KEY_BITS / 8;
 
-#[derive(Copy, Clone, PartialEq, Debug, Serialize, Deserialize)]
+// The least time we'll give any peer to answer, however fast it has been
+pub const MIN_TIMEOUT: Duration = Duration::from_millis(250);
+
+// Derived ordering compares bytes lexicographically, which is the same as comparing the IDs as
+// 256-bit big-endian integers.
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Debug, Serialize, Deserialize)]
 pub struct NodeID {
     pub(crate) bytes: [u8; KEY_BYTES],
 }
 
 impl NodeID {
+    pub fn zero() -> NodeID {
+        NodeID {
+            bytes: [0x0; KEY_BYTES],
+        }
+    }
+
+    pub fn max() -> NodeID {
+        NodeID {
+            bytes: [0xFF; KEY_BYTES],
+        }
+    }
+
+    pub fn random<R: Rng + ?Sized>(rng: &mut R) -> NodeID {
+        rng.gen()
+    }
+
+    // Keys live in the same space as node IDs, so values are stored on the nodes nearest their hash.
+    pub fn from_key(key: &[u8]) -> NodeID {
+        let mut id = NodeID::zero();
+        id.bytes.copy_from_slice(&Sha256::digest(key));
+        id
+    }
+
+    // Bit i, counting from 0 at the most significant bit of the first byte.
+    pub fn bit(&self, i: usize) -> bool {
+        assert!(i < KEY_BITS);
+        self.bytes[i / 8] & (0x80 >> (i % 8)) != 0
+    }
+
+    // Every bit, most significant first.
+    pub fn bits(self) -> impl Iterator<Item = bool> {
+        (0..KEY_BITS).map(move |i| self.bit(i))
+    }
+
+    // Where this ID falls in the keyspace, from 0 up to (not quite) 1. Only the leading 64 bits
+    // count, which is as much as an f64 can hold anyway.
+    pub fn fraction(self) -> f64 {
+        let mut top = [0; 8];
+        top.copy_from_slice(&self.bytes[..8]);
+        u64::from_be_bytes(top) as f64 / 2f64.powi(64)
+    }
+
+    // How many leading bits the two IDs share.
+    pub fn common_prefix_len(self, other: NodeID) -> usize {
+        self.bits()
+            .zip(other.bits())
+            .take_while(|(a, b)| a == b)
+            .count()
+    }
+
     fn leading_zeros(self) -> u32 {
         let mut ret = 0;
         for x in self.bytes.iter().map(|x| x.leading_zeros()) {
@@ -24,15 +86,77 @@ impl NodeID {
         }
         ret
     }
+
+    // Returns the inclusive `(low, high)` bounds of the IDs that land in `bucket` relative to `me`,
+    // i.e. the IDs sharing exactly `bucket` leading bits with `me`.
+    pub fn bucket_range(me: NodeID, bucket: usize) -> (NodeID, NodeID) {
+        assert!(bucket < KEY_BITS);
+        let byte = bucket / 8;
+        let bit = 0x80u8 >> (bucket % 8);
+        let prefix = !((u16::from(bit) << 1) - 1) as u8;
+
+        // Keep the shared prefix, flip the first bit that differs and let everything after it vary.
+        let mut low = me;
+        low.bytes[byte] = (me.bytes[byte] & prefix) | (!me.bytes[byte] & bit);
+        let mut high = low;
+        high.bytes[byte] |= bit - 1;
+
+        for b in low.bytes[byte + 1..].iter_mut() {
+            *b = 0x00;
+        }
+        for b in high.bytes[byte + 1..].iter_mut() {
+            *b = 0xFF;
+        }
+
+        (low, high)
+    }
+
+    // The inclusive `(low, high)` bounds of the IDs sharing at least the first prefix_len bits with
+    // `me`, which is every ID when prefix_len is 0.
+    pub fn prefix_range(me: NodeID, prefix_len: usize) -> (NodeID, NodeID) {
+        assert!(prefix_len <= KEY_BITS);
+        let mut low = me;
+        let mut high = me;
+        for i in prefix_len..KEY_BITS {
+            let bit = 0x80u8 >> (i % 8);
+            low.bytes[i / 8] &= !bit;
+            high.bytes[i / 8] |= bit;
+        }
+        (low, high)
+    }
+
+    // Where the IDs sharing our first prefix_len bits divide in two: our prefix, a one, then
+    // zeros. Those below it have a zero next, and the rest a one.
+    pub fn split_point(self, prefix_len: usize) -> NodeID {
+        assert!(prefix_len < KEY_BITS);
+        let byte = prefix_len / 8;
+        let bit = 0x80u8 >> (prefix_len % 8);
+
+        let mut point = self;
+        point.bytes[byte] = (self.bytes[byte] & !((u16::from(bit) << 1) - 1) as u8) | bit;
+        for b in point.bytes[byte + 1..].iter_mut() {
+            *b = 0x00;
+        }
+        point
+    }
+
+    // A random ID sharing exactly `bucket` leading bits with `me`.
+    pub fn random_in_bucket<R: Rng + ?Sized>(rng: &mut R, me: NodeID, bucket: usize) -> NodeID {
+        let (low, high) = NodeID::bucket_range(me, bucket);
+        let noise = NodeID::random(rng);
+        let mut id = low;
+        for i in 0..KEY_BYTES {
+            id.bytes[i] |= noise.bytes[i] & (low.bytes[i] ^ high.bytes[i]);
+        }
+        id
+    }
 }
 
 impl std::ops::BitXor for NodeID {
     type Output = Self;
 
     fn bitxor(self, rhs: Self) -> Self {
-        let mut ret = NodeID {
-            bytes: [0; KEY_BYTES],
-        };
+        let mut ret = NodeID::zero();
         self.bytes
             .iter()
             .zip(rhs.bytes.iter())
@@ -44,11 +168,47 @@ impl std::ops::BitXor for NodeID {
     }
 }
 
+// Hex, most significant byte first
+impl std::fmt::Display for NodeID {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        for b in self.bytes.iter() {
+            write!(f, "{:02x}", b)?;
+        }
+        Ok(())
+    }
+}
+
+// The ID wasn't 64 hex digits
+#[derive(Debug, PartialEq)]
+pub struct ParseIdError;
+
+impl std::fmt::Display for ParseIdError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "node IDs are {} hex digits", KEY_BYTES * 2)
+    }
+}
+
+impl std::error::Error for ParseIdError {}
+
+// The inverse of Display
+impl std::str::FromStr for NodeID {
+    type Err = ParseIdError;
+
+    fn from_str(s: &str) -> Result<NodeID, ParseIdError> {
+        if s.len() != KEY_BYTES * 2 || !s.is_ascii() {
+            return Err(ParseIdError);
+        }
+        let mut id = NodeID::zero();
+        for (i, b) in id.bytes.iter_mut().enumerate() {
+            *b = u8::from_str_radix(&s[i * 2..i * 2 + 2], 16).map_err(|_| ParseIdError)?;
+        }
+        Ok(id)
+    }
+}
+
 impl Distribution<NodeID> for Standard {
     fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> NodeID {
-        let mut ret = NodeID {
-            bytes: [0; KEY_BYTES],
-        };
+        let mut ret = NodeID::zero();
 
         for b in ret.bytes.iter_mut() {
             *b = rng.gen();
@@ -57,10 +217,155 @@ impl Distribution<NodeID> for Standard {
     }
 }
 
-#[derive(Copy, Clone, Debug)]
+// Port 0 and the unspecified address only mean something to the OS when binding, so an address
+// with either can't be sent to. Broadcast and multicast addresses aren't any one node's, and the
+// OS refuses to send to them without being asked to.
+pub fn usable_addr(addr: SocketAddr) -> bool {
+    let ip = addr.ip().to_canonical();
+    let broadcast = matches!(ip, IpAddr::V4(ip) if ip.is_broadcast());
+    addr.port() != 0 && !ip.is_unspecified() && !ip.is_multicast() && !broadcast
+}
+
+// Whether addr only makes sense on a local network or host: loopback, link-local, and the private
+// ranges (RFC 1918 for IPv4, unique local for IPv6).
+pub fn private_addr(addr: SocketAddr) -> bool {
+    let v4 = match addr.ip() {
+        IpAddr::V4(ip) => ip,
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => ip,
+            None => return ip.is_loopback() || ip.is_unique_local() || ip.is_unicast_link_local(),
+        },
+    };
+    v4.is_loopback() || v4.is_private() || v4.is_link_local()
+}
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
 pub struct Contact {
-    pub id: NodeID,
-    pub addr: SocketAddr,
+    id: NodeID,
+    addr: SocketAddr,
+    // Local bookkeeping, never sent over the wire
+    #[serde(skip)]
+    last_sent: Option<Instant>,
+    // When we last heard from it directly
+    #[serde(skip)]
+    last_seen: Option<Instant>,
+    // It has answered a request we sent to its address, rather than us only being told about it,
+    // or hearing from someone who says they're it
+    #[serde(skip)]
+    verified: bool,
+    // We've asked it what it supports, with a PingExt
+    #[serde(skip)]
+    asked: bool,
+    // How long it has taken to answer us, once it has
+    #[serde(skip)]
+    rtt: Option<Rtt>,
+    // What its last ping or pong said it supports, if we've had one
+    #[serde(skip)]
+    capabilities: Option<Capabilities>,
+}
+
+// A smoothed round trip time and how much it varies, kept the way TCP does (RFC 6298). In
+// microseconds, which keeps contacts small and is plenty for anything a peer could take.
+#[derive(Copy, Clone, Debug)]
+struct Rtt {
+    smoothed: u32,
+    variation: u32,
+}
+
+impl Rtt {
+    fn new(sample: u32) -> Rtt {
+        Rtt {
+            smoothed: sample,
+            variation: sample / 2,
+        }
+    }
+
+    // Moves the averages an eighth and a quarter of the way towards the new sample.
+    fn update(&mut self, sample: u32) {
+        let error = u64::from(sample.abs_diff(self.smoothed));
+        self.variation = ((u64::from(self.variation) * 3 + error) / 4) as u32;
+        self.smoothed = ((u64::from(self.smoothed) * 7 + u64::from(sample)) / 8) as u32;
+    }
+}
+
+impl Contact {
+    pub fn new(id: NodeID, addr: SocketAddr) -> Contact {
+        Contact {
+            id,
+            addr,
+            last_sent: None,
+            last_seen: None,
+            verified: false,
+            asked: false,
+            rtt: None,
+            capabilities: None,
+        }
+    }
+
+    pub(crate) fn verified(mut self) -> Contact {
+        self.verified = true;
+        self
+    }
+
+    pub(crate) fn seen(mut self, now: Instant) -> Contact {
+        self.last_seen = Some(now);
+        self
+    }
+
+    pub fn is_verified(&self) -> bool {
+        self.verified
+    }
+
+    // Whether anything sent to its address could reach a node. Contacts we're told about or read
+    // back in are checked with this before we take them.
+    pub fn has_usable_addr(&self) -> bool {
+        usable_addr(self.addr)
+    }
+
+    // Whether it has said what it supports, which only nodes that can read extensions do
+    pub fn has_extensions(&self) -> bool {
+        self.capabilities.is_some()
+    }
+
+    // Whether it has everything in wanted, going by what it last told us. One that hasn't told us
+    // gets the benefit of the doubt.
+    pub fn supports(&self, wanted: Capabilities) -> bool {
+        self.capabilities.is_none_or(|c| c.contains(wanted))
+    }
+
+    // How long to wait on an answer from it: its smoothed RTT plus four times the variation, like
+    // TCP's retransmission timeout, but never under MIN_TIMEOUT. None until it has answered us.
+    pub fn timeout(&self) -> Option<Duration> {
+        self.rtt.map(|rtt| {
+            let micros = u64::from(rtt.smoothed) + u64::from(rtt.variation) * 4;
+            Duration::from_micros(micros).max(MIN_TIMEOUT)
+        })
+    }
+
+    pub(crate) fn observe_rtt(&mut self, sample: Duration) {
+        let sample = sample.as_micros().min(u128::from(u32::MAX)) as u32;
+        match &mut self.rtt {
+            Some(rtt) => rtt.update(sample),
+            None => self.rtt = Some(Rtt::new(sample)),
+        }
+    }
+
+    pub fn id(&self) -> NodeID {
+        self.id
+    }
+
+    // Folds what another sighting of the same node tells us into this one.
+    fn merge(&mut self, other: &Contact) {
+        self.verified |= other.verified;
+        self.asked |= other.asked;
+        self.last_seen = self.last_seen.max(other.last_seen);
+        self.rtt = self.rtt.or(other.rtt);
+        self.capabilities = other.capabilities.or(self.capabilities);
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
 }
 
 impl PartialEq for Contact {
@@ -69,29 +374,263 @@ impl PartialEq for Contact {
     }
 }
 
+// How far apart two IDs are, and which bucket that distance lands in. Buckets are numbered by how
+// close they are, from 0 (the most distant) to KEY_BITS - 1 (the nearest).
+pub trait Metric {
+    fn distance(a: NodeID, b: NodeID) -> NodeID;
+
+    fn bucket_index(me: NodeID, other: NodeID) -> usize {
+        Self::distance(me, other).leading_zeros() as usize
+    }
+}
+
+// The Kademlia metric
+pub struct Xor;
+
+impl Metric for Xor {
+    fn distance(a: NodeID, b: NodeID) -> NodeID {
+        a ^ b
+    }
+}
+
 struct KBucket {
     can_split: bool,
     contacts: VecDeque<Contact>,
+    // When we last started a lookup for an ID in this bucket
+    last_refresh: Option<Instant>,
+}
+
+// A copy of one k-bucket, for callers doing their own maintenance. The table itself stays private.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BucketView {
+    range: (NodeID, NodeID),
+    can_split: bool,
+    last_refresh: Option<Instant>,
+    contacts: Vec<Contact>,
+}
+
+impl BucketView {
+    // The inclusive `(low, high)` bounds of the IDs that land in this bucket.
+    pub fn range(&self) -> (NodeID, NodeID) {
+        self.range
+    }
+
+    // Whether this is the bucket nearest us, the only one that splits when it fills up.
+    pub fn can_split(&self) -> bool {
+        self.can_split
+    }
+
+    // When a lookup last went looking for an ID in this bucket, if one ever has.
+    pub fn last_refresh(&self) -> Option<Instant> {
+        self.last_refresh
+    }
+
+    // Least recently seen first, the order eviction goes in.
+    pub fn contacts(&self) -> &[Contact] {
+        &self.contacts
+    }
 }
 
-pub struct KBuckets {
+pub struct KBuckets<M = Xor> {
     indices: [u8; KEY_BITS],
     next_to_split: usize,
     k_buckets: Vec<KBucket>,
+    eviction: EvictionPolicy,
+    // What contacts' last pings or pongs said about them, for those that said anything. Kept out
+    // of Contact, which is copied all over and would more than double in size.
+    metadata: HashMap<NodeID, Metadata>,
+    // Which bucket holds each contact, by address, so finding one by address doesn't scan the
+    // whole table. Two contacts can share an address, so there's an entry for each.
+    addrs: HashMap<SocketAddr, Vec<usize>>,
+    metric: PhantomData<M>,
 }
 
 impl KBuckets {
     pub fn new() -> KBuckets {
+        KBuckets::with_metric()
+    }
+
+    // Every k-bucket, furthest first, ending with the splittable one that holds everything
+    // sharing at least as many bits with us as the others. Only under XOR does each bucket cover
+    // one contiguous range of IDs, so there's no such view of a table with some other metric.
+    pub fn buckets(&self, me: NodeID) -> impl Iterator<Item = BucketView> + '_ {
+        (0..=self.next_to_split).map(move |shared| {
+            let bucket = &self.k_buckets[self.indices[shared] as usize];
+            let range = if shared < self.next_to_split {
+                NodeID::bucket_range(me, shared)
+            } else {
+                NodeID::prefix_range(me, shared)
+            };
+            BucketView {
+                range,
+                can_split: bucket.can_split,
+                last_refresh: bucket.last_refresh,
+                contacts: bucket.contacts.iter().cloned().collect(),
+            }
+        })
+    }
+}
+
+impl<M: Metric> KBuckets<M> {
+    pub fn with_metric() -> KBuckets<M> {
         KBuckets {
             indices: [0; KEY_BITS],
             next_to_split: 0,
             k_buckets: vec![KBucket {
                 can_split: true,
                 contacts: VecDeque::with_capacity(K),
+                last_refresh: None,
             }],
+            eviction: EvictionPolicy::default(),
+            metadata: HashMap::new(),
+            addrs: HashMap::new(),
+            metric: PhantomData,
+        }
+    }
+
+    // The index of the k-bucket that id falls into, after splitting is taken into account.
+    pub fn bucket_of(&self, me: NodeID, id: NodeID) -> usize {
+        let bucket = M::bucket_index(me, id);
+        assert!(bucket < KEY_BITS);
+        self.indices[bucket] as usize
+    }
+
+    // The contact with the given ID, if we have it. We are never in our own table.
+    pub fn get(&self, me: NodeID, id: NodeID) -> Option<Contact> {
+        if id == me {
+            return None;
+        }
+        let bucket = self.bucket_of(me, id);
+        self.k_buckets[bucket]
+            .contacts
+            .iter()
+            .find(|c| c.id == id)
+            .cloned()
+    }
+
+    // Moves the contact with the given ID to a new address. Returns false if we don't know it.
+    pub fn set_addr(&mut self, me: NodeID, id: NodeID, addr: SocketAddr) -> bool {
+        if id == me {
+            return false;
+        }
+        let bucket = self.bucket_of(me, id);
+        match self.k_buckets[bucket]
+            .contacts
+            .iter_mut()
+            .find(|c| c.id == id)
+        {
+            Some(contact) => {
+                let old = std::mem::replace(&mut contact.addr, addr);
+                self.unindex(old, bucket);
+                self.index(addr, bucket);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn index(&mut self, addr: SocketAddr, bucket: usize) {
+        self.addrs.entry(addr).or_default().push(bucket);
+    }
+
+    fn unindex(&mut self, addr: SocketAddr, bucket: usize) {
+        if let Some(buckets) = self.addrs.get_mut(&addr) {
+            if let Some(i) = buckets.iter().position(|&b| b == bucket) {
+                buckets.swap_remove(i);
+            }
+            if buckets.is_empty() {
+                self.addrs.remove(&addr);
+            }
+        }
+    }
+
+    // Each bucket holding a contact at addr, once
+    fn buckets_at(&self, addr: SocketAddr) -> Vec<usize> {
+        let mut buckets = self.addrs.get(&addr).cloned().unwrap_or_default();
+        buckets.sort_unstable();
+        buckets.dedup();
+        buckets
+    }
+
+    // Records what the contact at addr with the given ID says it supports and about itself, from
+    // a ping or pong. Returns false if we don't know it there.
+    pub fn describe(
+        &mut self,
+        me: NodeID,
+        id: NodeID,
+        addr: SocketAddr,
+        capabilities: Capabilities,
+        metadata: Option<Metadata>,
+    ) -> bool {
+        if id == me {
+            return false;
+        }
+        let bucket = self.bucket_of(me, id);
+        match self.k_buckets[bucket]
+            .contacts
+            .iter_mut()
+            .find(|c| c.id == id && c.addr == addr)
+        {
+            Some(contact) => {
+                contact.capabilities = Some(capabilities);
+                match metadata {
+                    Some(metadata) => self.metadata.insert(id, metadata),
+                    None => self.metadata.remove(&id),
+                };
+                // Once more than the table's worth has piled up, some of it must be about
+                // contacts that have since gone
+                if self.metadata.len() > self.len() {
+                    let mut metadata = std::mem::take(&mut self.metadata);
+                    metadata.retain(|&id, _| self.get(me, id).is_some());
+                    self.metadata = metadata;
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Whether we should ask the contact at addr what it supports, because it hasn't said and we
+    // haven't asked yet. Only once, so a node older than extensions isn't asked every time.
+    pub fn ask(&mut self, me: NodeID, id: NodeID, addr: SocketAddr) -> bool {
+        if id == me {
+            return false;
+        }
+        let bucket = self.bucket_of(me, id);
+        match self.k_buckets[bucket]
+            .contacts
+            .iter_mut()
+            .find(|c| c.id == id && c.addr == addr)
+        {
+            Some(contact) if contact.capabilities.is_none() && !contact.asked => {
+                contact.asked = true;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // Whether contact has everything in wanted, going by our own entry for it if we have one, since
+    // a copy handed around elsewhere may be older.
+    pub fn supports(&self, me: NodeID, contact: &Contact, wanted: Capabilities) -> bool {
+        match self.get(me, contact.id) {
+            Some(known) if known.addr == contact.addr => known.supports(wanted),
+            _ => contact.supports(wanted),
         }
     }
 
+    // Whether inserting id would find space for it, rather than have someone make way.
+    pub fn has_room(&self, me: NodeID, id: NodeID) -> bool {
+        let bucket = &self.k_buckets[self.bucket_of(me, id)];
+        bucket.can_split || bucket.contacts.len() < K
+    }
+
+    pub fn set_eviction(&mut self, policy: EvictionPolicy) {
+        self.eviction = policy;
+    }
+
+    // Fails with whoever the eviction policy would make way for contact if its bucket is full. That
+    // may be contact itself, if the policy won't drop anyone for it.
     pub fn insert(&mut self, me: NodeID, contact: Contact) -> Result<(), Contact> {
         // If the network were full, Each K-bucket 0..256 would contain 20 nodes (except for degenerate buckets).
         // Nodes from the most distant half of the network (those whose msb differs from ours, i.e., no leading zeros),
@@ -102,11 +641,9 @@ impl KBuckets {
         // The nearest four buckets can /always/ be condensed into one K-bucket. There will never be more than 253 buckets.
         //
         // This approach also optimizes the query "what are the nodes I know of closest to this key". That can be looked up
-        // by sending the contents of the k-bucket containing that key.
+        // by sending the contents of the k-bucket containing that key (see bucket_contents).
 
-        let bucket = (me ^ contact.id).leading_zeros();
-        assert!(bucket < 256);
-        let bucket = self.indices[bucket as usize] as usize;
+        let bucket = self.bucket_of(me, contact.id);
 
         // Handle the case where contact is already in its bucket.
         if let Some((i, _)) = self.k_buckets[bucket]
@@ -115,143 +652,1247 @@ impl KBuckets {
             .enumerate()
             .find(|(_, c)| contact == **c)
         {
-            let contact = self.k_buckets[bucket].contacts.remove(i).unwrap();
-            self.k_buckets[bucket].contacts.push_back(contact);
+            let mut existing = self.k_buckets[bucket].contacts.remove(i).unwrap();
+            existing.merge(&contact);
+            self.k_buckets[bucket].contacts.push_back(existing);
             return Ok(());
         }
 
         // bucket is full
         if self.k_buckets[bucket].contacts.len() == K {
             if self.k_buckets[bucket].can_split {
-                self.k_buckets.push(KBucket {
-                    can_split: false,
-                    contacts: VecDeque::with_capacity(K),
-                });
-                self.k_buckets.push(KBucket {
-                    can_split: true,
-                    contacts: VecDeque::with_capacity(K),
-                });
-
-                // Zero is the only one to ever split
-                // Proof:
-                //   base case: 0 is the only one in the array => only one that can split
-                //   induction: When the 0th is split, it's replaced by a bucket that can (exclusively) split
-                let mut old_bucket = self.k_buckets.swap_remove(0);
-
-                // Next to split will always point at the first bucket-index that is 0.
-                // Therefore, indices[next_to_split] is the contiguous half of the splittable bucket.
-                self.indices[self.next_to_split] = (self.k_buckets.len() - 1) as u8;
-                self.next_to_split += 1;
-
-                for contact in old_bucket.contacts.drain(..) {
-                    self.insert_unchecked(me, contact);
-                }
+                self.split(me);
                 // Unlikely worst case, this could recur up to 253 times. Because this is a tail call,
                 // it can't blow that stack.
                 // If node ID's are distributed uniformly, that will almost never happen.
                 return self.insert(me, contact);
             } else {
-                return Err(self.k_buckets[bucket].contacts[0]); // Cannot panic
+                return Err(self.victim(bucket).unwrap_or(contact));
             }
         } else {
             self.k_buckets[bucket].contacts.push_back(contact);
+            self.index(contact.addr, bucket);
         }
+        // Checking means walking the whole table, which is too slow to do on every insert outside
+        // of tests
+        #[cfg(test)]
+        assert!(self.validate());
         Ok(())
     }
 
-    fn insert_unchecked(&mut self, me: NodeID, contact: Contact) {
-        let bucket = (me ^ contact.id).leading_zeros();
-        let bucket = self.indices[bucket as usize] as usize;
-        self.k_buckets[bucket].contacts.push_back(contact);
+    // Who in a full bucket should make way, if anyone.
+    fn victim(&self, bucket: usize) -> Option<Contact> {
+        // Re-inserting moves a contact to the back even if we only heard about it second hand, so
+        // queue order alone can't say who's gone quiet longest. Ties (including contacts never seen
+        // at all) go to the front of the queue.
+        let contacts = self.k_buckets[bucket].contacts.iter();
+        match self.eviction {
+            EvictionPolicy::LeastRecentlySeen => contacts.min_by_key(|c| c.last_seen),
+            EvictionPolicy::QualityWeighted => contacts
+                .max_by_key(|c| (c.timeout().unwrap_or(Duration::MAX), Reverse(c.last_seen))),
+            EvictionPolicy::KeepVerified => {
+                contacts.filter(|c| !c.verified).min_by_key(|c| c.last_seen)
+            }
+        }
+        .cloned()
     }
-}
 
-#[cfg(test)]
-mod test {
-    use super::*;
-    #[test]
-    fn full_distant_bucket() {
-        let sock = "[::]:6060".parse().unwrap();
-        let me = NodeID {
-            bytes: [0x0; KEY_BYTES],
-        };
+    // Splits the one splittable bucket in two: the half sharing exactly next_to_split bits with
+    // us gets a bucket of its own, and the nearer half stays splittable.
+    fn split(&mut self, me: NodeID) {
+        // A refresh of the whole was one of both halves
+        let last_refresh = self.k_buckets[0].last_refresh;
+        self.k_buckets.push(KBucket {
+            can_split: false,
+            contacts: VecDeque::with_capacity(K),
+            last_refresh,
+        });
+        self.k_buckets.push(KBucket {
+            can_split: true,
+            contacts: VecDeque::with_capacity(K),
+            last_refresh,
+        });
 
-        let mut peer = Contact {
-            id: NodeID {
-                bytes: [0xFF; KEY_BYTES],
-            },
-            addr: sock,
-        };
+        // Zero is the only one to ever split
+        // Proof:
+        //   base case: 0 is the only one in the array => only one that can split
+        //   induction: When the 0th is split, it's replaced by a bucket that can (exclusively) split
+        let mut old_bucket = self.k_buckets.swap_remove(0);
 
-        let mut buckets = KBuckets::new();
+        // Next to split will always point at the first bucket-index that is 0.
+        // Therefore, indices[next_to_split] is the contiguous half of the splittable bucket.
+        self.indices[self.next_to_split] = (self.k_buckets.len() - 1) as u8;
+        self.next_to_split += 1;
 
-        // insert 20 distinct peers
-        for _ in 0..K {
-            buckets.insert(me, peer).unwrap(); // Shouldn't panic
-            *peer.id.bytes.last_mut().unwrap() -= 1;
+        // Everything in the splittable bucket shares at least `shared` bits with us, as M counts
+        // them. Those sharing exactly that many go in the new bucket, and the rest stay.
+        let shared = self.next_to_split - 1;
+        let far = self.k_buckets.len() - 1;
+        for contact in old_bucket.contacts.drain(..) {
+            let bucket = if M::bucket_index(me, contact.id) == shared {
+                far
+            } else {
+                0
+            };
+            self.unindex(contact.addr, 0);
+            self.index(contact.addr, bucket);
+            self.k_buckets[bucket].contacts.push_back(contact);
         }
+    }
 
-        *peer.id.bytes.last_mut().unwrap() = 0;
-        assert_eq!(
-            buckets.insert(me, peer),
-            Err(Contact {
-                id: NodeID {
-                    bytes: [0xFF; KEY_BYTES]
-                },
-                addr: sock
-            })
-        );
-
-        assert_eq!(buckets.k_buckets.len(), 2);
-        assert_eq!(
-            buckets.k_buckets[buckets.indices[0] as usize]
-                .contacts
-                .len(),
-            K
-        );
-        for i in &buckets.indices[1..] {
-            assert_eq!(buckets.k_buckets[*i as usize].contacts.len(), 0);
+    // Splits as if the splittable bucket had just overflowed, for driving the split bookkeeping
+    // directly in tests. Returns false, without splitting, once the nearest buckets are all that's
+    // left, since those can never fill up.
+    #[cfg(test)]
+    pub(crate) fn force_split_next(&mut self, me: NodeID) -> bool {
+        if self.k_buckets.len() == KEY_BITS - 3 {
+            return false;
         }
+        self.split(me);
+        assert!(self.validate());
+        true
     }
 
-    #[test]
-    fn full_near_bucket() {
-        let sock = "[::]:6060".parse().unwrap();
-        let me = NodeID {
-            bytes: [0x0; KEY_BYTES],
-        };
+    // Checks the bookkeeping invariants, for tests. The prefix check assumes a metric that, like
+    // XOR, groups IDs by shared leading bits.
+    #[cfg(test)]
+    pub fn validate(&self) -> bool {
+        // Every split adds exactly one bucket, and the nearest buckets can never fill up enough
+        // to split, so there are at most KEY_BITS - 3 of them.
+        let count = self.k_buckets.len();
+        if count != self.next_to_split + 1 || count > KEY_BITS - 3 {
+            return false;
+        }
 
-        let mut peer = Contact {
-            id: NodeID {
-                bytes: [0x0; KEY_BYTES],
-            },
-            addr: sock,
-        };
+        // Indices up to next_to_split each point at their own bucket, the rest at the one bucket
+        // that can still split.
+        let splittable = self.indices[self.next_to_split] as usize;
+        if self.indices[self.next_to_split..]
+            .iter()
+            .any(|i| *i as usize != splittable)
+        {
+            return false;
+        }
+        let mut seen = vec![false; count];
+        for i in self.indices[..=self.next_to_split].iter() {
+            let i = *i as usize;
+            if i >= count || seen[i] {
+                return false;
+            }
+            seen[i] = true;
+        }
 
-        let mut buckets = KBuckets::new();
+        for (i, b) in self.k_buckets.iter().enumerate() {
+            if b.can_split != (i == splittable) || b.contacts.len() > K {
+                return false;
+            }
+        }
 
-        for _ in 0..K {
-            *peer.id.bytes.last_mut().unwrap() += 1;
-            buckets.insert(me, peer).unwrap(); // Shouldn't panic
+        // Contacts in a split-off bucket all share one more bit than the bucket's index
+        for (bucket, i) in self.indices[..=self.next_to_split].iter().enumerate() {
+            let shared = (bucket + 1).min(self.next_to_split) as u32;
+            let contacts = &self.k_buckets[*i as usize].contacts;
+            if let Some(first) = contacts.front() {
+                if contacts
+                    .iter()
+                    .any(|c| M::distance(first.id, c.id).leading_zeros() < shared)
+                {
+                    return false;
+                }
+            }
         }
 
-        peer.id.bytes = [0xFF; KEY_BYTES];
-        buckets.insert(me, peer).unwrap();
-        assert_eq!(
-            buckets.k_buckets[buckets.indices[0] as usize]
-                .contacts
-                .len(),
-            1
-        );
-        assert_eq!(
-            buckets.k_buckets[buckets.indices[KEY_BITS - 1] as usize]
-                .contacts
-                .len(),
-            K
-        );
+        // The address index has an entry for each contact, pointing at its bucket
+        let mut indexed: Vec<(SocketAddr, usize)> = self
+            .addrs
+            .iter()
+            .flat_map(|(&addr, buckets)| buckets.iter().map(move |&b| (addr, b)))
+            .collect();
+        let mut held: Vec<(SocketAddr, usize)> = self
+            .k_buckets
+            .iter()
+            .enumerate()
+            .flat_map(|(i, b)| b.contacts.iter().map(move |c| (c.addr, i)))
+            .collect();
+        indexed.sort();
+        held.sort();
+        if indexed != held {
+            return false;
+        }
 
-        peer.id.bytes = [0; KEY_BYTES];
-        *peer.id.bytes.last_mut().unwrap() = K as u8;
-        buckets.insert(me, peer).unwrap(); // Should end up splitting the nearest bucket
+        let mut ids: Vec<NodeID> = self.iter().map(|c| c.id).collect();
+        ids.sort();
+        !ids.windows(2).any(|w| w[0] == w[1])
+    }
+
+    fn iter(&self) -> impl Iterator<Item = &Contact> {
+        self.k_buckets.iter().flat_map(|b| b.contacts.iter())
+    }
+
+    // How many contacts we know of.
+    pub fn len(&self) -> usize {
+        self.k_buckets.iter().map(|b| b.contacts.len()).sum()
+    }
+
+    // How many known contacts are closer to target than id is.
+    pub fn closer_than(&self, target: NodeID, id: NodeID) -> usize {
+        let distance = M::distance(target, id);
+        self.iter()
+            .filter(|c| M::distance(target, c.id) < distance)
+            .count()
+    }
+
+    // Every known contact, ordered by ID.
+    pub fn contacts(&self) -> Vec<Contact> {
+        let mut contacts: Vec<Contact> = self.iter().cloned().collect();
+        contacts.sort_by_key(|c| c.id);
+        contacts
+    }
+
+    // Every known contact that has told us about itself, with what it said, ordered by ID.
+    pub fn described(&self) -> Vec<(Contact, Metadata)> {
+        self.contacts()
+            .into_iter()
+            .filter_map(|c| self.metadata.get(&c.id).map(|&m| (c, m)))
+            .collect()
+    }
+
+    // The least recently seen contact from each non-empty bucket.
+    pub fn oldest_per_bucket(&self) -> Vec<Contact> {
+        self.k_buckets
+            .iter()
+            .filter_map(|b| b.contacts.front().cloned())
+            .collect()
+    }
+
+    // Records that we just sent something to addr.
+    pub fn sent_to(&mut self, addr: SocketAddr, now: Instant) {
+        for bucket in self.buckets_at(addr) {
+            for c in self.k_buckets[bucket].contacts.iter_mut() {
+                if c.addr == addr {
+                    c.last_sent = Some(now);
+                }
+            }
+        }
+    }
+
+    // Feeds how long the contact at addr took to answer into its RTT estimate.
+    pub fn observe_rtt(&mut self, addr: SocketAddr, sample: Duration) {
+        for bucket in self.buckets_at(addr) {
+            for c in self.k_buckets[bucket].contacts.iter_mut() {
+                if c.addr == addr {
+                    c.observe_rtt(sample);
+                }
+            }
+        }
+    }
+
+    pub fn by_addr(&self, addr: SocketAddr) -> Option<Contact> {
+        let bucket = *self.addrs.get(&addr)?.first()?;
+        self.k_buckets[bucket]
+            .contacts
+            .iter()
+            .find(|c| c.addr == addr)
+            .cloned()
+    }
+
+    // Contacts we haven't sent anything to since `since`.
+    pub fn idle(&self, since: Instant) -> Vec<Contact> {
+        self.iter()
+            .filter(|c| c.last_sent.is_none_or(|t| t < since))
+            .cloned()
+            .collect()
+    }
+
+    // Contacts we haven't heard from directly within older_than of now, including any we never
+    // have.
+    pub fn stale_contacts(&self, now: Instant, older_than: Duration) -> Vec<Contact> {
+        let since = now.checked_sub(older_than);
+        self.iter()
+            .filter(|c| match (c.last_seen, since) {
+                (Some(seen), Some(since)) => seen < since,
+                (Some(_), None) => false,
+                (None, _) => true,
+            })
+            .cloned()
+            .collect()
+    }
+
+    // Every known contact, nearest to `target` first.
+    pub fn iter_by_distance(&self, target: NodeID) -> impl Iterator<Item = Contact> {
+        let mut contacts: Vec<Contact> = self.iter().cloned().collect();
+        contacts.sort_by_key(|c| M::distance(target, c.id));
+        contacts.into_iter()
+    }
+
+    // Which bucket id falls in, as the number of leading bits it shares with us.
+    pub fn bucket_index(&self, me: NodeID, id: NodeID) -> usize {
+        M::bucket_index(me, id)
+    }
+
+    // How many contacts share exactly `bucket` leading bits with us.
+    pub fn bucket_len(&self, me: NodeID, bucket: usize) -> usize {
+        self.k_buckets[self.indices[bucket] as usize]
+            .contacts
+            .iter()
+            .filter(|c| M::bucket_index(me, c.id) == bucket)
+            .count()
+    }
+
+    // Records that a lookup for target started at now, refreshing the bucket it falls in.
+    pub fn refreshed(&mut self, me: NodeID, target: NodeID, now: Instant) {
+        // Our own ID goes with the nearest bucket
+        let bucket = self.indices[M::bucket_index(me, target).min(KEY_BITS - 1)] as usize;
+        self.k_buckets[bucket].last_refresh = Some(now);
+    }
+
+    // Every bucket index some contact falls into, furthest first.
+    pub fn occupied_buckets(&self, me: NodeID) -> Vec<usize> {
+        let mut buckets: Vec<usize> = self.iter().map(|c| M::bucket_index(me, c.id)).collect();
+        buckets.sort();
+        buckets.dedup();
+        buckets
+    }
+
+    // Forgets the contact with the given ID, but only if we have it at addr.
+    pub fn remove(&mut self, me: NodeID, id: NodeID, addr: SocketAddr) -> Option<Contact> {
+        if id == me {
+            return None;
+        }
+        let bucket = self.bucket_of(me, id);
+        let contacts = &mut self.k_buckets[bucket].contacts;
+        let i = contacts.iter().position(|c| c.id == id && c.addr == addr)?;
+        let removed = contacts.remove(i);
+        self.unindex(addr, bucket);
+        removed
+    }
+
+    // Forgets whoever is at addr, e.g. once we learn nothing is listening there anymore.
+    pub fn remove_addr(&mut self, addr: SocketAddr) -> Option<Contact> {
+        let bucket = *self.addrs.get(&addr)?.first()?;
+        let contacts = &mut self.k_buckets[bucket].contacts;
+        let i = contacts.iter().position(|c| c.addr == addr)?;
+        let removed = contacts.remove(i);
+        self.unindex(addr, bucket);
+        removed
+    }
+
+    // Returns up to `count` known contacts, nearest to `target` first.
+    pub fn closest(&self, target: NodeID, count: usize) -> Vec<Contact> {
+        self.iter_by_distance(target).take(count).collect()
+    }
+
+    // Everyone in the one k-bucket target falls into, nearest to target first. Unlike closest it
+    // never spills over into neighboring buckets, so may come up short of K, but it only looks at
+    // a single bucket. Whoever it does return are the nearest we know of.
+    pub fn bucket_contents(&self, me: NodeID, target: NodeID) -> Vec<Contact> {
+        // Our own ID goes with the nearest bucket
+        let bucket = self.indices[M::bucket_index(me, target).min(KEY_BITS - 1)] as usize;
+        let mut contacts: Vec<Contact> = self.k_buckets[bucket].contacts.iter().cloned().collect();
+        contacts.sort_by_key(|c| M::distance(target, c.id));
+        contacts
+    }
+
+    // Guesses how many nodes there are in all from how tightly our K nearest contacts pack around
+    // us. With N nodes spread evenly, the i-th nearest is about i / (N + 1) of the keyspace away, so
+    // fit N to the distances by least squares. The table holds everyone near us, so this is only
+    // off by chance. None if we don't know anyone.
+    pub fn estimate_size(&self, me: NodeID) -> Option<u64> {
+        let nearest = self.closest(me, K);
+        if nearest.is_empty() {
+            return None;
+        }
+        let (mut ranks, mut weighted) = (0.0, 0.0);
+        for (i, contact) in nearest.iter().enumerate() {
+            let rank = (i + 1) as f64;
+            ranks += rank * rank;
+            weighted += rank * M::distance(me, contact.id).fraction();
+        }
+        let estimate = if weighted > 0.0 {
+            (ranks / weighted - 1.0).round() as u64
+        } else {
+            u64::MAX
+        };
+        // We can't be fewer than everyone we know of
+        Some(estimate.max(self.len() as u64))
+    }
+
+    // Inserts a batch of contacts, e.g. several packets' worth at once. The same node may turn up
+    // more than once, so copies are merged first and the batch only ever adds one entry for it.
+    // Returns the batch with its copies merged, in the order each node first appeared.
+    pub fn insert_all(&mut self, me: NodeID, contacts: Vec<Contact>) -> Vec<Contact> {
+        let mut batch: Vec<Contact> = Vec::with_capacity(contacts.len());
+        for contact in contacts {
+            match batch.iter_mut().find(|c| **c == contact) {
+                Some(c) => c.merge(&contact),
+                None => batch.push(contact),
+            }
+        }
+        for contact in batch.iter() {
+            self.insert(me, *contact).ok();
+        }
+        batch
+    }
+}
+
+// How a routing table's buckets stood at one moment, for tests to check an operation did to the
+// table what it should have. Two are equal if they split the keyspace the same way and hold the
+// same contacts at the same addresses, in the same order.
+#[cfg(test)]
+#[derive(Clone, Debug, PartialEq)]
+pub struct RoutingSnapshot {
+    indices: Vec<u8>,
+    // Each bucket's contacts, in the order the bucket keeps them
+    buckets: Vec<Vec<(NodeID, SocketAddr)>>,
+}
+
+// What changed between two snapshots. Each contact is given with the bucket it's in (or was in,
+// if removed).
+#[cfg(test)]
+#[derive(Debug, Default, PartialEq)]
+pub struct SnapshotDiff {
+    pub added: Vec<(usize, NodeID, SocketAddr)>,
+    pub removed: Vec<(usize, NodeID, SocketAddr)>,
+    // From one bucket to another, as when a bucket splits
+    pub moved: Vec<(NodeID, usize, usize)>,
+    // How many buckets there were, and are
+    pub buckets: (usize, usize),
+}
+
+#[cfg(test)]
+impl RoutingSnapshot {
+    fn locations(&self) -> HashMap<(NodeID, SocketAddr), usize> {
+        self.buckets
+            .iter()
+            .enumerate()
+            .flat_map(|(i, b)| b.iter().map(move |&c| (c, i)))
+            .collect()
+    }
+
+    // What it takes to get from this snapshot to later, in ID order.
+    pub fn diff(&self, later: &RoutingSnapshot) -> SnapshotDiff {
+        let (before, after) = (self.locations(), later.locations());
+        let mut diff = SnapshotDiff {
+            buckets: (self.buckets.len(), later.buckets.len()),
+            ..SnapshotDiff::default()
+        };
+        for (&(id, addr), &bucket) in after.iter() {
+            match before.get(&(id, addr)) {
+                None => diff.added.push((bucket, id, addr)),
+                Some(&old) if old != bucket => diff.moved.push((id, old, bucket)),
+                Some(_) => (),
+            }
+        }
+        for (&(id, addr), &bucket) in before.iter() {
+            if !after.contains_key(&(id, addr)) {
+                diff.removed.push((bucket, id, addr));
+            }
+        }
+        diff.added.sort_by_key(|&(_, id, _)| id);
+        diff.removed.sort_by_key(|&(_, id, _)| id);
+        diff.moved.sort_by_key(|&(id, _, _)| id);
+        diff
+    }
+}
+
+#[cfg(test)]
+impl SnapshotDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty()
+            && self.removed.is_empty()
+            && self.moved.is_empty()
+            && self.buckets.0 == self.buckets.1
+    }
+}
+
+#[cfg(test)]
+impl<M: Metric> KBuckets<M> {
+    pub fn snapshot(&self) -> RoutingSnapshot {
+        RoutingSnapshot {
+            indices: self.indices.to_vec(),
+            buckets: self
+                .k_buckets
+                .iter()
+                .map(|b| b.contacts.iter().map(|c| (c.id, c.addr)).collect())
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rand::rngs::StdRng;
+    #[test]
+    fn network_size() {
+        let sock: SocketAddr = "[::]:6060".parse().unwrap();
+        let mut rng = StdRng::seed_from_u64(155);
+        let me = NodeID::random(&mut rng);
+        assert_eq!(KBuckets::new().estimate_size(me), None);
+
+        for size in [100, 1000, 10000] {
+            let mut buckets = KBuckets::new();
+            for _ in 0..size {
+                buckets
+                    .insert(me, Contact::new(NodeID::random(&mut rng), sock))
+                    .ok();
+            }
+            let estimate = buckets.estimate_size(me).unwrap();
+            assert!(
+                estimate > size / 2 && estimate < size * 2,
+                "estimated {} nodes out of {}",
+                estimate,
+                size
+            );
+        }
+    }
+
+    #[test]
+    fn rtt_timeout() {
+        let addr = "[::]:6060".parse().unwrap();
+        let steady = |rtt| {
+            let mut contact = Contact::new(NodeID::zero(), addr);
+            for _ in 0..20 {
+                contact.observe_rtt(Duration::from_millis(rtt));
+            }
+            contact.timeout().unwrap()
+        };
+        assert_eq!(Contact::new(NodeID::zero(), addr).timeout(), None);
+
+        // A steady RTT leaves little variation, so the timeout settles just above it
+        let (quick, slow) = (steady(300), steady(1200));
+        assert!(quick >= Duration::from_millis(300) && quick < Duration::from_millis(350));
+        assert!(slow >= Duration::from_millis(1200) && slow < Duration::from_millis(1400));
+        assert!(slow > quick * 3);
+
+        // Very fast peers still get a sensible minimum
+        assert_eq!(steady(1), MIN_TIMEOUT);
+
+        // Jitter buys slack on top of the average
+        let mut jittery = Contact::new(NodeID::zero(), addr);
+        for i in 0..20 {
+            jittery.observe_rtt(Duration::from_millis(if i % 2 == 0 { 100 } else { 500 }));
+        }
+        assert!(jittery.timeout().unwrap() > steady(300) * 2);
+    }
+
+    #[test]
+    fn bucket_views() {
+        let sock = "[::]:6060".parse().unwrap();
+        let me = NodeID::zero();
+        let start = Instant::now();
+        let mut buckets = KBuckets::new();
+
+        // A few sharing 2 bits with us, then enough sharing none to split the table
+        let mut near = NodeID::zero();
+        near.bytes[0] = 0x20;
+        let mut far = NodeID::zero();
+        far.bytes[0] = 0x80;
+        for i in 0..3 {
+            near.bytes[KEY_BYTES - 1] = i;
+            buckets.insert(me, Contact::new(near, sock)).unwrap();
+        }
+        for i in 0..K as u8 {
+            far.bytes[KEY_BYTES - 1] = i;
+            buckets.insert(me, Contact::new(far, sock)).unwrap();
+        }
+        // Leaves an empty bucket for 1 bit and an empty splittable one after the near contacts
+        assert!(buckets.force_split_next(me));
+        assert!(buckets.force_split_next(me));
+        buckets.refreshed(me, near, start);
+
+        let views: Vec<BucketView> = buckets.buckets(me).collect();
+        assert_eq!(views.len(), 4);
+        let occupied: Vec<&BucketView> =
+            views.iter().filter(|v| !v.contacts().is_empty()).collect();
+        assert_eq!(occupied.len(), 2);
+        assert_eq!(occupied[0].contacts().len(), K);
+        assert_eq!(occupied[0].range(), NodeID::bucket_range(me, 0));
+        assert_eq!(occupied[1].contacts().len(), 3);
+        assert_eq!(occupied[1].range(), NodeID::bucket_range(me, 2));
+        assert_eq!(occupied[1].range().0.bytes[0], 0x20);
+        assert_eq!(occupied[1].range().1.bytes[0], 0x3F);
+        for view in &views {
+            let (low, high) = view.range();
+            assert!(view.contacts().iter().all(|c| low <= c.id && c.id <= high));
+        }
+
+        // Only the last can split, and it holds everything from 3 bits in
+        assert!(views.iter().rev().skip(1).all(|v| !v.can_split()));
+        assert!(views[3].can_split());
+        let mut high = NodeID::max();
+        high.bytes[0] = 0x1F;
+        assert_eq!(views[3].range(), (NodeID::zero(), high));
+        let refreshed: Vec<bool> = views.iter().map(|v| v.last_refresh().is_some()).collect();
+        assert_eq!(refreshed, vec![false, false, true, false]);
+    }
+
+    #[test]
+    fn full_distant_bucket() {
+        let sock = "[::]:6060".parse().unwrap();
+        let me = NodeID::zero();
+
+        let mut peer = Contact::new(NodeID::max(), sock);
+
+        let mut buckets = KBuckets::new();
+
+        // insert 20 distinct peers
+        for _ in 0..K {
+            buckets.insert(me, peer).unwrap(); // Shouldn't panic
+            *peer.id.bytes.last_mut().unwrap() -= 1;
+        }
+
+        *peer.id.bytes.last_mut().unwrap() = 0;
+        assert_eq!(
+            buckets.insert(me, peer),
+            Err(Contact::new(NodeID::max(), sock))
+        );
+
+        assert_eq!(buckets.k_buckets.len(), 2);
+        assert_eq!(
+            buckets.k_buckets[buckets.indices[0] as usize]
+                .contacts
+                .len(),
+            K
+        );
+        for i in &buckets.indices[1..] {
+            assert_eq!(buckets.k_buckets[*i as usize].contacts.len(), 0);
+        }
+    }
+
+    #[test]
+    fn evict_least_recently_seen() {
+        let sock = "[::]:6060".parse().unwrap();
+        let me = NodeID::zero();
+        let start = Instant::now();
+        let mut buckets = KBuckets::new();
+
+        let mut peer = Contact::new(NodeID::max(), sock);
+        for i in 0..K {
+            let seen = peer.seen(start + Duration::from_secs(i as u64));
+            buckets.insert(me, seen).unwrap();
+            *peer.id.bytes.last_mut().unwrap() -= 1;
+        }
+
+        // Hearing about the oldest again moves it to the back of the queue, but we still haven't
+        // heard from it since.
+        let oldest = Contact::new(NodeID::max(), sock);
+        buckets.insert(me, oldest).unwrap();
+        let bucket = buckets.bucket_of(me, oldest.id);
+        assert_ne!(buckets.k_buckets[bucket].contacts[0], oldest);
+
+        *peer.id.bytes.last_mut().unwrap() = 0;
+        assert_eq!(buckets.insert(me, peer), Err(oldest));
+    }
+
+    #[test]
+    fn eviction_policies() {
+        let sock = "[::]:6060".parse().unwrap();
+        let me = NodeID::zero();
+        let start = Instant::now();
+        let at = |secs| Some(start + Duration::from_secs(secs));
+
+        let mut peer = Contact::new(NodeID::max(), sock);
+        let mut full = Vec::new();
+        for i in 0..K {
+            let mut contact = peer
+                .seen(start + Duration::from_secs(10 + i as u64))
+                .verified();
+            contact.observe_rtt(Duration::from_millis(300));
+            full.push(contact);
+            *peer.id.bytes.last_mut().unwrap() -= 1;
+        }
+        // Quickest to answer, but we've not heard from it since the start
+        full[3].last_seen = at(0);
+        full[3].observe_rtt(Duration::from_millis(10));
+        // Heard from lately, but slow
+        full[7].observe_rtt(Duration::from_secs(2));
+        // Only heard about, though lately
+        full[11].verified = false;
+        full[11].last_seen = at(100);
+
+        let victim = |policy, full: &[Contact]| {
+            let mut buckets = KBuckets::new();
+            buckets.set_eviction(policy);
+            for contact in full.iter() {
+                buckets.insert(me, *contact).unwrap();
+            }
+            let newcomer = Contact::new(peer.id, sock);
+            buckets.insert(me, newcomer).unwrap_err()
+        };
+        assert_eq!(victim(EvictionPolicy::LeastRecentlySeen, &full), full[3]);
+        assert_eq!(victim(EvictionPolicy::QualityWeighted, &full), full[7]);
+        assert_eq!(victim(EvictionPolicy::KeepVerified, &full), full[11]);
+
+        // With everyone verified, the newcomer is the one turned away
+        full[11].verified = true;
+        assert_eq!(victim(EvictionPolicy::KeepVerified, &full).id, peer.id);
+    }
+
+    #[test]
+    fn duplicate_batch() {
+        let sock = "[::]:6060".parse().unwrap();
+        let me = NodeID::zero();
+        let mut buckets = KBuckets::new();
+
+        let id: NodeID = rand::random();
+        let first = Contact::new(id, sock);
+        let heard = first.seen(Instant::now()).verified();
+        let other = Contact::new(rand::random(), sock);
+        let batch = buckets.insert_all(me, vec![first, other, heard, first, first]);
+
+        assert_eq!(batch.len(), 2);
+        assert_eq!(batch[0], first);
+        assert_eq!(batch[1], other);
+        assert_eq!(buckets.len(), 2);
+        assert!(buckets.validate());
+        // Whatever any copy told us is kept
+        let stored = buckets.get(me, id).unwrap();
+        assert!(stored.is_verified());
+        assert_eq!(stored.last_seen, heard.last_seen);
+
+        // Again, into a bucket that fills up and splits part way through the batch
+        let mut buckets = KBuckets::new();
+        let mut peers: Vec<Contact> = (0..K as u8)
+            .map(|i| {
+                let mut id = NodeID::max();
+                id.bytes[KEY_BYTES - 1] = i;
+                Contact::new(id, sock)
+            })
+            .collect();
+        let near = Contact::new(NodeID::bucket_range(me, 10).0, sock);
+        peers.extend(peers.clone());
+        peers.push(near);
+        peers.push(near);
+        buckets.insert_all(me, peers);
+        assert!(buckets.validate());
+        assert_eq!(buckets.len(), K + 1);
+    }
+
+    #[test]
+    fn full_near_bucket() {
+        let sock = "[::]:6060".parse().unwrap();
+        let me = NodeID::zero();
+
+        let mut peer = Contact::new(NodeID::zero(), sock);
+
+        let mut buckets = KBuckets::new();
+
+        for _ in 0..K {
+            *peer.id.bytes.last_mut().unwrap() += 1;
+            buckets.insert(me, peer).unwrap(); // Shouldn't panic
+        }
+
+        peer.id.bytes = [0xFF; KEY_BYTES];
+        buckets.insert(me, peer).unwrap();
+        assert_eq!(
+            buckets.k_buckets[buckets.indices[0] as usize]
+                .contacts
+                .len(),
+            1
+        );
+        assert_eq!(
+            buckets.k_buckets[buckets.indices[KEY_BITS - 1] as usize]
+                .contacts
+                .len(),
+            K
+        );
+
+        peer.id.bytes = [0; KEY_BYTES];
+        *peer.id.bytes.last_mut().unwrap() = K as u8;
+        buckets.insert(me, peer).unwrap(); // Should end up splitting the nearest bucket
+    }
+
+    #[test]
+    fn distant_bucket_range() {
+        let me = NodeID::zero();
+        let (low, high) = NodeID::bucket_range(me, 0);
+
+        let mut expected = [0x0; KEY_BYTES];
+        expected[0] = 0x80;
+        assert_eq!(low.bytes, expected);
+        assert_eq!(high.bytes, [0xFF; KEY_BYTES]);
+
+        let me = NodeID::max();
+        let (low, high) = NodeID::bucket_range(me, 0);
+        assert_eq!(low.bytes, [0x0; KEY_BYTES]);
+        let mut expected = [0xFF; KEY_BYTES];
+        expected[0] = 0x7F;
+        assert_eq!(high.bytes, expected);
+    }
+
+    #[test]
+    fn nearest_bucket_range() {
+        let me: NodeID = rand::random();
+        let (low, high) = NodeID::bucket_range(me, KEY_BITS - 1);
+        assert_eq!(low, high);
+
+        let mut expected = me;
+        *expected.bytes.last_mut().unwrap() ^= 1;
+        assert_eq!(low, expected);
+    }
+
+    #[test]
+    fn bucket_range_bounds() {
+        let me: NodeID = rand::random();
+        for bucket in 0..KEY_BITS {
+            let (low, high) = NodeID::bucket_range(me, bucket);
+            assert!(low <= high);
+            assert_eq!((me ^ low).leading_zeros(), bucket as u32);
+            assert_eq!((me ^ high).leading_zeros(), bucket as u32);
+        }
+    }
+
+    #[test]
+    fn split_point() {
+        let me: NodeID = rand::random();
+        for prefix_len in [0, 1, 7, 8, 9, 100, KEY_BITS - 1].iter().cloned() {
+            let at = me.split_point(prefix_len);
+            assert!(at.bits().take(prefix_len).eq(me.bits().take(prefix_len)));
+            assert!(at.bit(prefix_len));
+            assert!(at.bits().skip(prefix_len + 1).all(|b| !b));
+
+            // Random IDs with our prefix fall either side of it by their next bit
+            let mut halves = [0, 0];
+            for _ in 0..100 {
+                let mut id: NodeID = rand::random();
+                for i in 0..prefix_len {
+                    let mask = 0x80 >> (i % 8);
+                    id.bytes[i / 8] = (id.bytes[i / 8] & !mask) | (me.bytes[i / 8] & mask);
+                }
+                assert_eq!(id >= at, id.bit(prefix_len));
+                halves[id.bit(prefix_len) as usize] += 1;
+            }
+            assert!(halves[0] > 0 && halves[1] > 0);
+        }
+    }
+
+    // Like Xor, but the last byte is the most significant
+    struct LowFirst;
+
+    impl Metric for LowFirst {
+        fn distance(a: NodeID, b: NodeID) -> NodeID {
+            let mut d = a ^ b;
+            d.bytes.reverse();
+            d
+        }
+    }
+
+    #[test]
+    fn alternate_metric() {
+        let sock = "[::]:6060".parse().unwrap();
+        let me = NodeID::zero();
+
+        // Under Xor these would spread over the nearest buckets, but under LowFirst they all land
+        // in the most distant one.
+        let mut buckets: KBuckets<LowFirst> = KBuckets::with_metric();
+        let mut peer = Contact::new(NodeID::zero(), sock);
+        *peer.id.bytes.last_mut().unwrap() = 0x80;
+        for i in 0..K {
+            peer.id.bytes[0] = i as u8;
+            buckets.insert(me, peer).unwrap();
+        }
+        assert_eq!(
+            buckets.k_buckets[buckets.indices[0] as usize]
+                .contacts
+                .len(),
+            K
+        );
+
+        peer.id.bytes[0] = K as u8;
+        assert!(buckets.insert(me, peer).is_err());
+
+        let mut xor = KBuckets::new();
+        for c in buckets.contacts() {
+            xor.insert(me, c).unwrap();
+        }
+        assert!(xor.insert(me, peer).is_ok());
+
+        // Closest is ordered by the metric too: the first byte only breaks ties
+        let target = NodeID::zero();
+        let closest: Vec<u8> = buckets
+            .closest(target, K)
+            .iter()
+            .map(|c| c.id.bytes[0])
+            .collect();
+        assert_eq!(closest, (0..K as u8).collect::<Vec<u8>>());
+    }
+
+    // Buckets two bits wide, so a bucket's index isn't how many leading bits its IDs share with us
+    struct Coarse;
+
+    impl Metric for Coarse {
+        fn distance(a: NodeID, b: NodeID) -> NodeID {
+            a ^ b
+        }
+
+        fn bucket_index(me: NodeID, other: NodeID) -> usize {
+            Self::distance(me, other).leading_zeros() as usize & !1
+        }
+    }
+
+    #[test]
+    fn split_by_metric() {
+        let me = NodeID::zero();
+        let mut buckets: KBuckets<Coarse> = KBuckets::with_metric();
+        let peers: Vec<Contact> = (0..4u8)
+            .map(|i| {
+                let mut id = NodeID::zero();
+                id.bytes[0] = 0x10 | i;
+                Contact::new(id, ([127, 0, 0, 1], u16::from(i) + 1).into())
+            })
+            .collect();
+        for peer in peers.iter() {
+            buckets.insert(me, *peer).unwrap();
+        }
+
+        // They share three bits with us, but the metric puts them in bucket 2, so that's where the
+        // split that gives bucket 2 its own has to move them
+        for _ in 0..3 {
+            assert!(buckets.force_split_next(me));
+        }
+        for peer in peers.iter() {
+            assert_eq!(buckets.get(me, peer.id), Some(*peer));
+        }
+    }
+
+    #[test]
+    fn contact_accessors() {
+        let id: NodeID = rand::random();
+        let addr = "127.0.0.1:6060".parse().unwrap();
+        let contact = Contact::new(id, addr);
+        assert_eq!(contact.id(), id);
+        assert_eq!(contact.addr(), addr);
+    }
+
+    #[test]
+    fn id_constructors() {
+        assert_eq!(NodeID::zero() ^ NodeID::max(), NodeID::max());
+        assert_eq!(NodeID::max() ^ NodeID::max(), NodeID::zero());
+
+        let a = NodeID::random(&mut StdRng::seed_from_u64(7));
+        let b = NodeID::random(&mut StdRng::seed_from_u64(7));
+        assert_eq!(a, b);
+        assert_ne!(a, NodeID::random(&mut StdRng::seed_from_u64(8)));
+    }
+
+    #[test]
+    fn bits() {
+        let mut id = NodeID::zero();
+        id.bytes[0] = 0x80;
+        id.bytes[KEY_BYTES - 1] = 0x01;
+        assert!(id.bit(0));
+        assert!(!id.bit(1));
+        assert!(id.bit(KEY_BITS - 1));
+        assert_eq!(id.bits().filter(|b| *b).count(), 2);
+
+        let id: NodeID = rand::random();
+        let bits: Vec<bool> = id.bits().collect();
+        assert_eq!(bits.len(), KEY_BITS);
+        for (i, byte) in id.bytes.iter().enumerate() {
+            let rebuilt = bits[i * 8..(i + 1) * 8]
+                .iter()
+                .fold(0u8, |acc, b| (acc << 1) | *b as u8);
+            assert_eq!(rebuilt, *byte);
+        }
+    }
+
+    #[test]
+    fn common_prefix() {
+        let me: NodeID = rand::random();
+        assert_eq!(me.common_prefix_len(me), KEY_BITS);
+        for _ in 0..100 {
+            let other: NodeID = rand::random();
+            let shared = me.common_prefix_len(other);
+            assert_eq!(shared, (me ^ other).leading_zeros() as usize);
+            assert_eq!(shared, Xor::bucket_index(me, other));
+        }
+        // Every ID in a bucket shares exactly that many bits with us
+        for bucket in 0..KEY_BITS {
+            let (low, high) = NodeID::bucket_range(me, bucket);
+            assert_eq!(me.common_prefix_len(low), bucket);
+            assert_eq!(me.common_prefix_len(high), bucket);
+        }
+    }
+
+    #[test]
+    fn parse_id() {
+        let id: NodeID = rand::random();
+        assert_eq!(id.to_string().parse(), Ok(id));
+        assert_eq!(id.to_string().to_uppercase().parse(), Ok(id));
+
+        let short = &id.to_string()[1..];
+        assert_eq!(short.parse::<NodeID>(), Err(ParseIdError));
+        assert_eq!(format!("{}0", id).parse::<NodeID>(), Err(ParseIdError));
+        assert_eq!("zz".repeat(KEY_BYTES).parse::<NodeID>(), Err(ParseIdError));
+        // Multibyte characters can't be split into pairs of digits
+        let wide = format!("é{}", &id.to_string()[2..]);
+        assert_eq!(wide.parse::<NodeID>(), Err(ParseIdError));
+    }
+
+    #[test]
+    fn bucket_of_after_split() {
+        let sock = "[::]:6060".parse().unwrap();
+        let me = NodeID::zero();
+        let mut buckets = KBuckets::new();
+
+        let mut near = NodeID::zero();
+        *near.bytes.last_mut().unwrap() = 1;
+        let far = NodeID::max();
+        // Before any split, everything shares the one bucket
+        assert_eq!(buckets.bucket_of(me, near), 0);
+        assert_eq!(buckets.bucket_of(me, far), 0);
+
+        let mut peer = Contact::new(NodeID::max(), sock);
+        for _ in 0..K {
+            buckets.insert(me, peer).unwrap();
+            *peer.id.bytes.last_mut().unwrap() -= 1;
+        }
+        buckets.insert(me, Contact::new(near, sock)).unwrap();
+
+        // The distant half split off into its own bucket, which was pushed on the end
+        assert_eq!(buckets.bucket_of(me, far), 1);
+        assert_eq!(buckets.bucket_of(me, near), 0);
+        for c in buckets.k_buckets[1].contacts.iter() {
+            assert_eq!(buckets.bucket_of(me, c.id), 1);
+        }
+    }
+
+    #[test]
+    fn get_after_split() {
+        let sock = "[::]:6060".parse().unwrap();
+        let me = NodeID::zero();
+        let mut buckets = KBuckets::new();
+
+        let mut peer = Contact::new(NodeID::max(), sock);
+        for _ in 0..K {
+            buckets.insert(me, peer).unwrap();
+            *peer.id.bytes.last_mut().unwrap() -= 1;
+        }
+        let mut near = NodeID::zero();
+        *near.bytes.last_mut().unwrap() = 1;
+        buckets.insert(me, Contact::new(near, sock)).unwrap();
+
+        // Found in whichever bucket it ended up in
+        assert_eq!(
+            buckets.get(me, NodeID::max()).map(|c| c.id),
+            Some(NodeID::max())
+        );
+        assert_eq!(buckets.get(me, near).map(|c| c.addr), Some(sock));
+
+        // Same buckets, but nobody by those IDs
+        *near.bytes.last_mut().unwrap() = 2;
+        assert!(buckets.get(me, near).is_none());
+        assert!(buckets.get(me, peer.id).is_none());
+        assert!(buckets.get(me, me).is_none());
+    }
+
+    #[test]
+    fn by_addr_after_split() {
+        let me = NodeID::zero();
+        let mut buckets = KBuckets::new();
+        let mut rng = StdRng::seed_from_u64(161);
+        let contacts: Vec<Contact> = (1..=200)
+            .map(|port| Contact::new(NodeID::random(&mut rng), ([127, 0, 0, 1], port).into()))
+            .collect();
+        for contact in contacts.iter() {
+            buckets.insert(me, *contact).ok();
+        }
+        assert!(buckets.k_buckets.len() > 1);
+        assert!(buckets.validate());
+        for contact in buckets.contacts() {
+            assert_eq!(
+                buckets.by_addr(contact.addr).map(|c| c.id),
+                Some(contact.id)
+            );
+        }
+
+        // Moving or removing a contact carries the index along
+        let moved = buckets.contacts()[0];
+        let to: SocketAddr = ([127, 0, 0, 2], 1).into();
+        assert!(buckets.set_addr(me, moved.id, to));
+        assert!(buckets.by_addr(moved.addr).is_none());
+        assert_eq!(buckets.by_addr(to).map(|c| c.id), Some(moved.id));
+        assert_eq!(buckets.remove_addr(to).map(|c| c.id), Some(moved.id));
+        assert!(buckets.by_addr(to).is_none());
+        assert!(buckets.validate());
+    }
+
+    #[test]
+    fn fill_near_buckets() {
+        let sock = "[::]:6060".parse().unwrap();
+        let me = NodeID::zero();
+        let mut buckets = KBuckets::new();
+        assert!(buckets.validate());
+
+        // IDs that only differ from ours in the last couple of bytes pile into the nearest buckets
+        for i in 1..=2048u16 {
+            let mut id = NodeID::zero();
+            id.bytes[KEY_BYTES - 2..].copy_from_slice(&i.to_be_bytes());
+            buckets.insert(me, Contact::new(id, sock)).ok();
+            assert!(buckets.validate());
+        }
+        // Re-inserting known contacts only reorders them
+        for i in (1..=64u16).rev() {
+            let mut id = NodeID::zero();
+            id.bytes[KEY_BYTES - 2..].copy_from_slice(&i.to_be_bytes());
+            buckets.insert(me, Contact::new(id, sock)).ok();
+            assert!(buckets.validate());
+        }
+
+        assert!(buckets.k_buckets.len() <= KEY_BITS - 3);
+        // The four nearest buckets (15 IDs total) were never split apart
+        let nearest = buckets.indices[KEY_BITS - 1];
+        assert_eq!(buckets.indices[KEY_BITS - 4], nearest);
+        assert!(buckets.k_buckets[nearest as usize].can_split);
+    }
+
+    #[test]
+    fn forced_splits() {
+        let sock = "[::]:6060".parse().unwrap();
+        let me: NodeID = rand::random();
+        let mut buckets = KBuckets::new();
+
+        // A full bucket's worth, spread out over every distance from us
+        let distances: Vec<usize> = (0..KEY_BITS).step_by(13).collect();
+        assert_eq!(distances.len(), K);
+        let ids: Vec<NodeID> = distances
+            .iter()
+            .map(|d| NodeID::bucket_range(me, *d).0)
+            .collect();
+        for id in ids.iter() {
+            buckets.insert(me, Contact::new(*id, sock)).unwrap();
+        }
+        assert_eq!(buckets.k_buckets.len(), 1);
+
+        let mut splits = 0;
+        while buckets.force_split_next(me) {
+            splits += 1;
+            assert_eq!(buckets.next_to_split, splits);
+            assert_eq!(buckets.k_buckets.len(), splits + 1);
+            assert!(buckets.validate());
+
+            // Every contact is stored in the bucket its index says it's in
+            for (distance, id) in distances.iter().zip(ids.iter()) {
+                let bucket = buckets.bucket_of(me, *id);
+                assert_eq!(bucket, buckets.indices[*distance] as usize);
+                assert!(buckets.k_buckets[bucket]
+                    .contacts
+                    .contains(&Contact::new(*id, sock)));
+            }
+            // Split-off buckets hold the one contact at their distance, if there is one
+            for (distance, i) in buckets.indices[..splits].iter().enumerate() {
+                let expected = distances.contains(&distance) as usize;
+                assert_eq!(buckets.k_buckets[*i as usize].contacts.len(), expected);
+            }
+        }
+
+        assert_eq!(splits, KEY_BITS - 4);
+        assert_eq!(buckets.contacts().len(), K);
+        // Only the nearest four distances still share the splittable bucket
+        let nearest = buckets.indices[KEY_BITS - 1];
+        assert_eq!(buckets.indices[KEY_BITS - 4], nearest);
+        assert!(buckets.k_buckets[nearest as usize].can_split);
+    }
+
+    #[test]
+    fn snapshot_diff() {
+        let sock: SocketAddr = "[::]:6060".parse().unwrap();
+        let mut rng = StdRng::seed_from_u64(193);
+        let me = NodeID::random(&mut rng);
+        let mut buckets = KBuckets::new();
+
+        let before = buckets.snapshot();
+        let contact = Contact::new(NodeID::random_in_bucket(&mut rng, me, 3), sock);
+        buckets.insert(me, contact).unwrap();
+        let after = buckets.snapshot();
+        assert_ne!(before, after);
+        assert_eq!(
+            before.diff(&after),
+            SnapshotDiff {
+                added: vec![(0, contact.id, sock)],
+                buckets: (1, 1),
+                ..SnapshotDiff::default()
+            }
+        );
+
+        // Seeing it again changes nothing
+        buckets.insert(me, contact).unwrap();
+        assert_eq!(buckets.snapshot(), after);
+        assert!(after.diff(&buckets.snapshot()).is_empty());
+
+        // Filling the bucket up splits it. The far half goes off to a bucket of its own, and the
+        // contact that did it joins the near half.
+        let mut far: Vec<NodeID> = (1..K)
+            .map(|_| NodeID::random_in_bucket(&mut rng, me, 0))
+            .collect();
+        for &id in far.iter() {
+            buckets.insert(me, Contact::new(id, sock)).unwrap();
+        }
+        let before = buckets.snapshot();
+        let near = Contact::new(NodeID::random_in_bucket(&mut rng, me, 5), sock);
+        buckets.insert(me, near).unwrap();
+        let diff = before.diff(&buckets.snapshot());
+        assert_eq!(diff.added, vec![(0, near.id, sock)]);
+        assert!(diff.removed.is_empty());
+        far.sort();
+        let moved: Vec<(NodeID, usize, usize)> = far.into_iter().map(|id| (id, 0, 1)).collect();
+        assert_eq!(diff.moved, moved);
+        assert_eq!(diff.buckets, (1, 2));
+    }
+
+    #[test]
+    fn bucket_contents() {
+        let sock = "[::]:6060".parse().unwrap();
+        let mut rng = StdRng::seed_from_u64(172);
+        let me = NodeID::random(&mut rng);
+        let mut buckets = KBuckets::new();
+        for _ in 0..100 {
+            buckets
+                .insert(me, Contact::new(NodeID::random(&mut rng), sock))
+                .ok();
+        }
+
+        let mut short = 0;
+        for bucket in 0..KEY_BITS {
+            let target = NodeID::random_in_bucket(&mut rng, me, bucket);
+            let contents = buckets.bucket_contents(me, target);
+            let closest = buckets.closest(target, K);
+            assert_eq!(closest.len(), K);
+            assert!(contents.len() <= K);
+            assert!(contents
+                .iter()
+                .all(|c| buckets.bucket_of(me, c.id) == buckets.bucket_of(me, target)));
+
+            // The bucket's contacts are the nearest there are, but closest tops them up from
+            // elsewhere
+            assert_eq!(contents[..], closest[..contents.len()]);
+            if contents.len() < K {
+                short += 1;
+            }
+        }
+        assert!(short > 0);
+        assert_eq!(
+            buckets.bucket_contents(me, me),
+            buckets.bucket_contents(me, NodeID::random_in_bucket(&mut rng, me, KEY_BITS - 1))
+        );
+    }
+
+    #[test]
+    fn iter_by_distance_is_sorted() {
+        let sock = "[::]:6060".parse().unwrap();
+        let me: NodeID = rand::random();
+        let mut buckets = KBuckets::new();
+        for _ in 0..200 {
+            buckets.insert(me, Contact::new(rand::random(), sock)).ok();
+        }
+
+        let target: NodeID = rand::random();
+        let distances: Vec<NodeID> = buckets
+            .iter_by_distance(target)
+            .map(|c| target ^ c.id)
+            .collect();
+        assert_eq!(distances.len(), buckets.contacts().len());
+        assert!(distances.windows(2).all(|w| w[0] <= w[1]));
     }
 }
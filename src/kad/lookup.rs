@@ -0,0 +1,387 @@
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crossbeam::channel;
+
+use super::kbucket::*;
+
+// Number of queries a lookup keeps in flight at once, unless configured otherwise.
+pub const ALPHA: usize = 3;
+
+// Most candidates a lookup keeps. Past this the farthest unqueried ones are dropped, so one peer
+// naming thousands of nodes can't make every insert slow or hold on to them all lookup long.
+const MAX_CANDIDATES: usize = 4 * K;
+
+#[derive(Debug, PartialEq)]
+pub enum LookupStatus {
+    Completed(Vec<Contact>),
+    // Stopped at Config::max_lookup_hops with closer nodes still left to ask. Holds the closest
+    // found so far.
+    TruncatedByHopLimit(Vec<Contact>),
+    Cancelled,
+}
+
+// Progress of a lookup, for anyone watching through `Dht::watch_lookups`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum LookupEvent {
+    Started,
+    Queried(Contact),
+    // The contact answered, telling us about this many contacts the lookup hadn't seen and kept
+    Responded(Contact, usize),
+    Completed(Vec<Contact>),
+    // Timed out or cancelled
+    Failed,
+}
+
+// How a get or put's lookup went, for keeping an eye on the network's health.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct LookupStats {
+    // How many responses away from our own contacts the furthest node that answered was, counting
+    // our contacts as one hop
+    pub hops: usize,
+    pub queried: usize,
+    pub responded: usize,
+    pub elapsed: Duration,
+}
+
+// The caller's side of an iterative lookup running on the worker.
+pub struct LookupHandle {
+    cancelled: Arc<AtomicBool>,
+    status: channel::Receiver<LookupStatus>,
+}
+
+impl LookupHandle {
+    pub(crate) fn new() -> (LookupHandle, Waiter) {
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let (reply, status) = channel::bounded(1);
+
+        (
+            LookupHandle {
+                cancelled: cancelled.clone(),
+                status,
+            },
+            Waiter { cancelled, reply },
+        )
+    }
+
+    // Asks the worker to stop the lookup. It stops issuing queries the next time it looks at it.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    // Blocks until the lookup finishes. A lookup whose worker went away counts as cancelled.
+    pub fn wait(self) -> LookupStatus {
+        self.status.recv().unwrap_or(LookupStatus::Cancelled)
+    }
+}
+
+// The worker's side of a `LookupHandle`.
+#[derive(Debug)]
+pub struct Waiter {
+    cancelled: Arc<AtomicBool>,
+    reply: channel::Sender<LookupStatus>,
+}
+
+impl Waiter {
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    pub fn resolve(self, status: LookupStatus) {
+        // The caller may have dropped its handle, which is fine.
+        self.reply.send(status).ok();
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum State {
+    Fresh,
+    Waiting,
+    Responded,
+    // Didn't answer in time, so it no longer counts towards the closest
+    Failed,
+}
+
+struct Candidate {
+    contact: Contact,
+    state: State,
+    // How many responses away from the seeds we learned of it
+    hops: usize,
+}
+
+pub struct Lookup {
+    target: NodeID,
+    // Kept sorted by distance to target
+    candidates: Vec<Candidate>,
+    // Candidates this many hops out are never asked
+    max_hops: Option<usize>,
+    // Most queries in flight at once
+    alpha: usize,
+}
+
+impl Lookup {
+    pub fn new(
+        target: NodeID,
+        seeds: Vec<Contact>,
+        max_hops: Option<usize>,
+        alpha: usize,
+    ) -> Lookup {
+        let mut lookup = Lookup {
+            target,
+            candidates: Vec::with_capacity(K),
+            max_hops,
+            alpha,
+        };
+        lookup.add_contacts(seeds);
+        lookup
+    }
+
+    pub fn target(&self) -> NodeID {
+        self.target
+    }
+
+    // Where contact sorts: by distance, then verified ahead of unverified
+    fn rank(&self, contact: &Contact) -> (NodeID, bool) {
+        (self.target ^ contact.id(), !contact.is_verified())
+    }
+
+    // Adds contacts to start from. Returns how many of them were new to the lookup, and kept.
+    pub fn add_contacts(&mut self, contacts: Vec<Contact>) -> usize {
+        self.insert(contacts, 0)
+    }
+
+    // Adds contacts that peer told us about, one hop further out than peer itself.
+    pub fn add_found(&mut self, peer: SocketAddr, contacts: Vec<Contact>) -> usize {
+        let hops = self
+            .candidates
+            .iter()
+            .find(|c| c.state == State::Responded && c.contact.addr() == peer)
+            .map_or(0, |c| c.hops + 1);
+        self.insert(contacts, hops)
+    }
+
+    fn insert(&mut self, contacts: Vec<Contact>, hops: usize) -> usize {
+        let mut added = Vec::new();
+        for contact in contacts {
+            if let Some(c) = self.candidates.iter_mut().find(|c| c.contact == contact) {
+                // Someone vouching for a node we've heard from directly doesn't replace it, but it
+                // works the other way round so long as we haven't asked it anything yet
+                if contact.is_verified() && !c.contact.is_verified() && c.state == State::Fresh {
+                    c.contact = contact;
+                }
+                continue;
+            }
+
+            let rank = self.rank(&contact);
+            let i = self
+                .candidates
+                .iter()
+                .position(|c| self.rank(&c.contact) > rank)
+                .unwrap_or(self.candidates.len());
+            self.candidates.insert(
+                i,
+                Candidate {
+                    contact,
+                    state: State::Fresh,
+                    hops,
+                },
+            );
+            added.push(contact.id());
+
+            // Anyone we're waiting on has to stay, or there'd be nothing to match its answer to
+            if self.candidates.len() > MAX_CANDIDATES {
+                if let Some(farthest) = self
+                    .candidates
+                    .iter()
+                    .rposition(|c| c.state == State::Fresh)
+                {
+                    self.candidates.remove(farthest);
+                }
+            }
+        }
+        // Some may have made way for closer ones since
+        added
+            .iter()
+            .filter(|&&id| self.candidates.iter().any(|c| c.contact.id() == id))
+            .count()
+    }
+
+    // Candidates are matched by the address we queried, since that's all a response is tied to.
+    fn set_state(&mut self, peer: SocketAddr, state: State) -> Option<Contact> {
+        let c = self
+            .candidates
+            .iter_mut()
+            .find(|c| c.state == State::Waiting && c.contact.addr() == peer)?;
+        c.state = state;
+        if state == State::Responded {
+            c.contact = c.contact.verified();
+        }
+        Some(c.contact)
+    }
+
+    // Returns the candidate that responded, if we were waiting on it.
+    pub fn responded(&mut self, peer: SocketAddr) -> Option<Contact> {
+        self.set_state(peer, State::Responded)
+    }
+
+    pub fn failed(&mut self, peer: SocketAddr) {
+        self.set_state(peer, State::Failed);
+    }
+
+    fn beyond_limit(&self, c: &Candidate) -> bool {
+        self.max_hops.is_some_and(|max| c.hops >= max)
+    }
+
+    // The K closest candidates that haven't failed
+    fn live(&self) -> impl Iterator<Item = &Candidate> {
+        self.candidates
+            .iter()
+            .filter(|c| c.state != State::Failed)
+            .take(K)
+    }
+
+    // Gives up on the unqueried candidates we can't ask at all, so they don't hold the lookup up.
+    pub fn rule_out(&mut self, mut unusable: impl FnMut(&Contact) -> bool) {
+        for c in self.candidates.iter_mut() {
+            if c.state == State::Fresh && unusable(&c.contact) {
+                c.state = State::Failed;
+            }
+        }
+    }
+
+    // Picks the closest unqueried candidates, up to alpha in flight, and marks them as waiting.
+    // Candidates can_query turns down are passed over for now and left to a later round.
+    pub fn next_queries(&mut self, mut can_query: impl FnMut(&Contact) -> bool) -> Vec<Contact> {
+        let mut in_flight = self
+            .candidates
+            .iter()
+            .filter(|c| c.state == State::Waiting)
+            .count();
+
+        let mut picked = Vec::new();
+        let mut live = 0;
+        for (i, c) in self.candidates.iter().enumerate() {
+            if live == K || in_flight >= self.alpha {
+                break;
+            }
+            if c.state == State::Failed {
+                continue;
+            }
+            live += 1;
+            if c.state == State::Fresh && !self.beyond_limit(c) && can_query(&c.contact) {
+                in_flight += 1;
+                picked.push(i);
+            }
+        }
+        picked
+            .into_iter()
+            .map(|i| {
+                let c = &mut self.candidates[i];
+                c.state = State::Waiting;
+                c.contact
+            })
+            .collect()
+    }
+
+    // A lookup is done once nothing is in flight and the K closest have all been asked, or are
+    // too many hops out to ask.
+    pub fn is_done(&self) -> bool {
+        !self.candidates.iter().any(|c| c.state == State::Waiting)
+            && !self
+                .live()
+                .any(|c| c.state == State::Fresh && !self.beyond_limit(c))
+    }
+
+    // Whether the hop limit left any of the K closest unasked
+    pub fn is_truncated(&self) -> bool {
+        self.live()
+            .any(|c| c.state == State::Fresh && self.beyond_limit(c))
+    }
+
+    // Everything but elapsed, since lookups don't know the time
+    pub fn stats(&self) -> LookupStats {
+        let responded = self
+            .candidates
+            .iter()
+            .filter(|c| c.state == State::Responded);
+        LookupStats {
+            hops: responded.clone().map(|c| c.hops + 1).max().unwrap_or(0),
+            queried: self
+                .candidates
+                .iter()
+                .filter(|c| c.state != State::Fresh)
+                .count(),
+            responded: responded.count(),
+            elapsed: Duration::default(),
+        }
+    }
+
+    // The closest contacts that answered us
+    pub fn closest(&self) -> Vec<Contact> {
+        self.candidates
+            .iter()
+            .filter(|c| c.state == State::Responded)
+            .take(K)
+            .map(|c| c.contact)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn prefer_verified() {
+        let target: NodeID = rand::random();
+        let id: NodeID = rand::random();
+        let told = Contact::new(id, ([127, 0, 0, 1], 1).into());
+        let heard = Contact::new(id, ([127, 0, 0, 1], 2).into()).verified();
+
+        // Whichever order we learn them in, the one we heard from directly is the one we ask
+        for contacts in [vec![told, heard], vec![heard, told]] {
+            let mut lookup = Lookup::new(target, Vec::new(), None, ALPHA);
+            for contact in contacts {
+                lookup.add_contacts(vec![contact]);
+            }
+            let queries = lookup.next_queries(|_| true);
+            assert_eq!(queries.len(), 1);
+            assert_eq!(queries[0].addr(), heard.addr());
+            assert!(queries[0].is_verified());
+        }
+    }
+
+    #[test]
+    fn candidate_cap() {
+        let target = NodeID::zero();
+        let far = Contact::new(NodeID::max(), ([127, 0, 0, 1], 1).into());
+        let mut lookup = Lookup::new(target, vec![far], None, ALPHA);
+        assert_eq!(lookup.next_queries(|_| true), vec![far]);
+
+        // Only the closest are kept, along with the farthest of all since it's being asked
+        let mut found: Vec<Contact> = (0..10 * K)
+            .map(|i| Contact::new(rand::random(), ([127, 0, 0, 2], i as u16 + 1).into()))
+            .collect();
+        assert_eq!(lookup.add_contacts(found.clone()), MAX_CANDIDATES - 1);
+        found.sort_by_key(|c| target ^ c.id());
+        found.truncate(MAX_CANDIDATES - 1);
+        found.push(far);
+        let kept: Vec<Contact> = lookup.candidates.iter().map(|c| c.contact).collect();
+        assert_eq!(kept, found);
+        assert_eq!(lookup.responded(far.addr()), Some(far));
+    }
+
+    #[test]
+    fn responded_is_verified() {
+        let target: NodeID = rand::random();
+        let contact = Contact::new(rand::random(), ([127, 0, 0, 1], 1).into());
+        let mut lookup = Lookup::new(target, vec![contact], None, ALPHA);
+        assert!(!lookup.next_queries(|_| true)[0].is_verified());
+
+        lookup.responded(contact.addr());
+        assert!(lookup.is_done());
+        assert!(lookup.closest()[0].is_verified());
+    }
+}
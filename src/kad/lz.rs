@@ -0,0 +1,121 @@
+// A small LZ77 compressor for packet payloads. The output is a series of ops, each starting with
+// a byte b:
+//
+//   b < 0x80    the next b + 1 bytes are literals
+//   b >= 0x80   copy (b & 0x7F) + MIN_MATCH bytes from a 2 byte little-endian offset back
+//
+// Matches are found greedily through a hash of the next MIN_MATCH bytes, so it only catches
+// repeats, but that's what a list of contacts has: the same address prefixes and ports over and
+// over among the random IDs.
+
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 0x7F + MIN_MATCH;
+const MAX_LITERALS: usize = 0x80;
+const MAX_OFFSET: usize = u16::MAX as usize;
+const HASH_BITS: u32 = 12;
+
+fn hash(bytes: &[u8]) -> usize {
+    let n = u32::from(bytes[0]) | u32::from(bytes[1]) << 8 | u32::from(bytes[2]) << 16;
+    (n.wrapping_mul(2_654_435_761) >> (32 - HASH_BITS)) as usize
+}
+
+fn flush_literals(out: &mut Vec<u8>, literals: &[u8]) {
+    for run in literals.chunks(MAX_LITERALS) {
+        out.push(run.len() as u8 - 1);
+        out.extend_from_slice(run);
+    }
+}
+
+pub fn compress(input: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(input.len());
+    // Where each hash was last seen, plus one so 0 can mean never
+    let mut table = vec![0usize; 1 << HASH_BITS];
+    let mut literals = 0;
+    let mut i = 0;
+    while i + MIN_MATCH <= input.len() {
+        let h = hash(&input[i..]);
+        let candidate = table[h];
+        table[h] = i + 1;
+        if candidate > 0 && i - (candidate - 1) <= MAX_OFFSET {
+            let start = candidate - 1;
+            let len = input[start..]
+                .iter()
+                .zip(&input[i..])
+                .take(MAX_MATCH)
+                .take_while(|(a, b)| a == b)
+                .count();
+            // A match of only MIN_MATCH takes as many bytes as the literals, and breaks up the
+            // run they're in
+            if len > MIN_MATCH {
+                flush_literals(&mut out, &input[literals..i]);
+                out.push(0x80 | (len - MIN_MATCH) as u8);
+                out.extend_from_slice(&((i - start) as u16).to_le_bytes());
+                i += len;
+                literals = i;
+                continue;
+            }
+        }
+        i += 1;
+    }
+    flush_literals(&mut out, &input[literals..]);
+    out
+}
+
+// None if input isn't something compress could have made, or would come to more than limit bytes.
+pub fn decompress(input: &[u8], limit: usize) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(limit.min(input.len() * 4));
+    let mut i = 0;
+    while i < input.len() {
+        let op = input[i] as usize;
+        i += 1;
+        if op < 0x80 {
+            let literals = input.get(i..i + op + 1)?;
+            if out.len() + literals.len() > limit {
+                return None;
+            }
+            out.extend_from_slice(literals);
+            i += op + 1;
+        } else {
+            let len = (op & 0x7F) + MIN_MATCH;
+            let offset = u16::from_le_bytes([*input.get(i)?, *input.get(i + 1)?]) as usize;
+            i += 2;
+            if offset == 0 || offset > out.len() || out.len() + len > limit {
+                return None;
+            }
+            // The copy may run into what it's writing, so it has to go a byte at a time
+            let start = out.len() - offset;
+            for j in 0..len {
+                out.push(out[start + j]);
+            }
+        }
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let repetitive: Vec<u8> = (0..5000).map(|i| (i % 7) as u8).collect();
+        let random: Vec<u8> = (0..5000).map(|_| rand::random()).collect();
+        for input in [&[][..], &[1, 2][..], &repetitive[..], &random[..]] {
+            let packed = compress(input);
+            assert_eq!(decompress(&packed, input.len()).unwrap(), input);
+        }
+        assert!(compress(&repetitive).len() < repetitive.len() / 10);
+        // Random bytes hardly grow
+        assert!(compress(&random).len() <= random.len() + random.len() / 64);
+    }
+
+    #[test]
+    fn bad_input() {
+        let packed = compress(&[5; 1000]);
+        assert!(decompress(&packed, 999).is_none());
+        assert!(decompress(&packed[..packed.len() - 1], 1000).is_none());
+        // A match reaching back before the start
+        assert!(decompress(&[0x80, 1, 0], 100).is_none());
+        assert!(decompress(&[0, 9, 0x80, 2, 0], 100).is_none());
+    }
+}
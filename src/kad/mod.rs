@@ -3,98 +3,3989 @@ extern crate crossbeam;
 extern crate rand;
 
 use crossbeam::channel;
-use std::net::SocketAddr;
+use ed25519_dalek::SigningKey;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::{Duration, Instant};
+
+use crate::config::{ClosestNodes, Config, LookupOverflow};
+use crate::error::Error;
+use crate::health::HealthReport;
+
+mod churn;
+use churn::*;
+
+mod clock;
+use clock::*;
 
 mod kbucket;
 use kbucket::*;
+pub use kbucket::{BucketView, Contact, NodeID, ParseIdError};
+
+mod lookup;
+pub(crate) use lookup::ALPHA;
+use lookup::*;
+pub use lookup::{LookupEvent, LookupHandle, LookupStats, LookupStatus};
+
+#[cfg(test)]
+mod network;
+
+mod lz;
+
+mod proto;
+pub use proto::{
+    Capabilities, Extensions, Metadata, Packet, PacketSignature, Payload, Rejection,
+    COMPRESS_THRESHOLD, MAX_PACKET_SIZE, MAX_VALUE_SIZE, MAX_VERSION_LEN, SIGNATURE_LEN,
+};
+
+mod signed;
+pub use signed::SignedValue;
+
+mod store;
+use store::*;
+pub use store::{MemoryStore, Store, StoreError, StoredValue, MAX_MEMBER_LEN};
+
+mod suspicion;
+use suspicion::*;
+
+mod token;
+pub use token::Token;
+use token::*;
+
+// How long we wait on any one peer before giving up on it
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(2);
+
+// Republish lookups started per tick, so a node holding many values doesn't send them all at once
+const REPUBLISH_PER_TICK: usize = 1;
+
+// Values handed off per tick while leaving, each to up to K nodes
+const HANDOFF_PER_TICK: usize = 16;
+
+// Most contacts we've only been told about that we ping at once, to see if they're worth adding
+const MAX_UNVERIFIED: usize = K;
+
+// Most of those we ping off any one Nodes or Peers answer, so a peer can't have us ping addresses
+// by the dozen for every packet it sends
+const MAX_VERIFY_PER_ANSWER: usize = 3;
 
-#[derive(Serialize, Deserialize, Debug)]
-enum Payload {
-    Ping,
-    Pong,
+// A value a get found, and the contact that supplied it. None if we hold it ourselves.
+pub type Found = (Vec<u8>, Option<Contact>);
+
+// Something that went wrong without stopping the worker, for anyone watching through
+// `Dht::watch_anomalies`. A supervisor might take one as its cue to restart the node.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Anomaly {
+    // A datagram couldn't be sent, with this error. The sender only stops if it was the socket
+    // that failed rather than the one send.
+    SendFailed(io::ErrorKind),
+    // The sender thread is gone, so nothing we send goes anywhere. Only reported the first time.
+    SenderGone,
+    // A packet for this peer couldn't be signed (see Config::sign_packets), so wasn't sent
+    SignFailed(SocketAddr),
+    // This IP sent too many authenticated responses we never asked for (see
+    // Config::strict_responses), so everything from it is being dropped for a while
+    Banned(IpAddr),
+}
+
+// Where this node ranks among the nodes it knows of for a key, as returned by
+// `Dht::responsibility`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Responsibility {
+    // How many known nodes are closer to the key than we are. 0 means we're the closest.
+    pub rank: usize,
+    // Whether we're among the K closest, and so keep values stored under the key
+    pub responsible: bool,
+}
+
+#[derive(Debug)]
+pub enum Command {
+    Shutdown,
+    // Replaces any bootstrap still in progress, so only these seeds are probed from now on
+    Bootstrap(Vec<SocketAddr>),
+    // Forgets every contact, failing everything in flight with Error::Reset, then bootstraps from
+    // these seeds and looks up our own ID once one answers
+    Reset(Vec<SocketAddr>),
+    // The latest bootstrap's seeds that haven't answered or timed out yet
+    BootstrapSeeds(channel::Sender<Vec<SocketAddr>>),
+    PingWait {
+        peer: SocketAddr,
+        deadline: Instant,
+        // Given the round trip time, by our clock
+        reply: channel::Sender<Result<Duration, Error>>,
+    },
+    FindNode(NodeID, Waiter),
+    Get {
+        key: NodeID,
+        deadline: Instant,
+        reply: channel::Sender<Result<(Option<Found>, LookupStats), Error>>,
+    },
+    Put {
+        key: NodeID,
+        value: Vec<u8>,
+        version: u64,
+        deadline: Instant,
+        reply: channel::Sender<Result<(usize, LookupStats), Error>>,
+    },
+    GetSigned {
+        key: NodeID,
+        deadline: Instant,
+        reply: channel::Sender<Result<Option<SignedValue>, Error>>,
+    },
+    PutSigned {
+        value: Box<SignedValue>,
+        deadline: Instant,
+        reply: channel::Sender<Result<(usize, LookupStats), Error>>,
+    },
+    // Every peer announced under the key that the lookup came across
+    GetPeers {
+        key: NodeID,
+        deadline: Instant,
+        reply: channel::Sender<Result<Vec<SocketAddr>, Error>>,
+    },
+    // Announces us at port to the nodes closest to key, answering with how many took it
+    Announce {
+        key: NodeID,
+        port: u16,
+        deadline: Instant,
+        reply: channel::Sender<Result<usize, Error>>,
+    },
+    // Adds value to the set under key on the nodes closest to it, answering with how many took it
+    Add {
+        key: NodeID,
+        value: Vec<u8>,
+        deadline: Instant,
+        reply: channel::Sender<Result<(usize, LookupStats), Error>>,
+    },
+    // Every member of the set under key that the lookup came across
+    GetAll {
+        key: NodeID,
+        deadline: Instant,
+        reply: channel::Sender<Result<Vec<Vec<u8>>, Error>>,
+    },
+    KnownPeers(channel::Sender<Vec<Contact>>),
+    PeerMetadata(channel::Sender<Vec<(Contact, Metadata)>>),
+    Buckets(channel::Sender<Vec<BucketView>>),
+    // Drops are left for the caller to fill in
+    Health(channel::Sender<HealthReport>),
+    // Events are tagged with the lookup's target
+    WatchLookups(channel::Sender<(NodeID, LookupEvent)>),
+    WatchAnomalies(channel::Sender<Anomaly>),
+    // How many lookups are running, and how many are queued
+    Lookups(channel::Sender<(usize, usize)>),
+    // Stops the worker taking packets until Resume, answering once it has
+    Pause(channel::Sender<()>),
+    Resume,
+    // Something one of the node's other threads ran into
+    Report(Anomaly),
+    Contains(NodeID, channel::Sender<Option<Contact>>),
+    StaleContacts(Duration, channel::Sender<Vec<Contact>>),
+    // Sent exactly as given, rather than from us
+    SendRaw(Box<Packet>, SocketAddr),
+    Neighborhood(channel::Sender<Vec<Contact>>),
+    Responsibility(NodeID, channel::Sender<Responsibility>),
+    EstimateSize(channel::Sender<Option<u64>>),
+    // Every plain value we hold, by key
+    ExportStore(channel::Sender<Vec<(NodeID, Vec<u8>)>>),
+    // Hands every value we hold to the nodes closest to its key and tells our neighbors we're
+    // going, answering with how many values were handed off
+    Leave(channel::Sender<usize>),
+    // Stores each pair as if it had just arrived, answering with how many were taken
+    ImportStore(Vec<(NodeID, Vec<u8>)>, channel::Sender<usize>),
+    // The OS told us nothing is listening at this address
+    Unreachable(SocketAddr),
+    // Looks up a random ID in the bucket sharing this many leading bits with us, if it has contacts
+    RefreshBucket(usize),
+    // Refreshes every bucket with contacts
+    RefreshAll,
+    // Pings one contact from every non-empty bucket
+    WarmUp,
+    // Contacts from somewhere other than the network, e.g. a saved list. Added unverified and
+    // pinged, so they're verified if they answer.
+    Seed(Vec<Contact>),
+    // Answered once the routing table holds at least min contacts, with how many it holds
+    WaitForPeers {
+        min: usize,
+        deadline: Instant,
+        reply: channel::Sender<Result<usize, Error>>,
+    },
+    // Puts a contact straight into the routing table, as is, for tests that need a particular
+    // table without pinging anyone into it. A full bucket just turns it away.
+    #[cfg(test)]
+    DebugInsert(Contact),
 }
 
-impl Payload {
-    fn is_response(&self) -> bool {
+// A leave still handing off values: the keys left to go, each with whether it's the signed value
+// under it, and how many have gone
+struct Leave {
+    keys: VecDeque<(NodeID, bool)>,
+    handed_off: usize,
+    reply: channel::Sender<usize>,
+}
+
+// Someone waiting for the routing table to grow to min contacts
+struct PeerWaiter {
+    min: usize,
+    deadline: Instant,
+    reply: channel::Sender<Result<usize, Error>>,
+}
+
+// What a put stores
+enum Stored {
+    // With its version
+    Plain(Vec<u8>, u64),
+    Signed(Box<SignedValue>),
+    // Added to the key's set
+    Member(Vec<u8>),
+}
+
+impl Stored {
+    fn payload(&self, key: NodeID) -> Payload {
         match self {
-            Payload::Pong => true,
-            _ => false,
+            Stored::Plain(value, version) => Payload::Store(key, value.clone(), *version),
+            Stored::Signed(value) => Payload::StoreSigned((**value).clone()),
+            Stored::Member(value) => Payload::Add(key, value.clone()),
+        }
+    }
+
+    // What a node has to support to take it
+    fn needs(&self) -> Capabilities {
+        match self {
+            Stored::Plain(..) => Capabilities::STORE,
+            Stored::Signed(_) => Capabilities::SIGNED,
+            Stored::Member(_) => Capabilities::SETS,
         }
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct Packet {
-    id: NodeID,
-    seq_num: u64,
-    payload: Payload,
+// Who is waiting on an operation, and for what
+enum Reply {
+    FindNode(Waiter),
+    Get(channel::Sender<Result<(Option<Found>, LookupStats), Error>>),
+    GetSigned(channel::Sender<Result<Option<SignedValue>, Error>>),
+    Put(Stored, channel::Sender<Result<(usize, LookupStats), Error>>),
+    // Collects the peers the lookup is told about as it goes
+    GetPeers(
+        Vec<SocketAddr>,
+        channel::Sender<Result<Vec<SocketAddr>, Error>>,
+    ),
+    // Collects the set's members the lookup is told about as it goes
+    GetAll(Vec<Vec<u8>>, channel::Sender<Result<Vec<Vec<u8>>, Error>>),
+    // Announcing at the port. Collects the token each node that answered gave us.
+    Announce(
+        u16,
+        Vec<(SocketAddr, Token)>,
+        channel::Sender<Result<usize, Error>>,
+    ),
+    // Re-storing a value we hold. Collects the nodes that already have it as the lookup goes.
+    Republish(Stored, Vec<SocketAddr>),
+    // A lookup for our own ID, whose result becomes the neighborhood
+    Neighborhood,
+    // A lookup for a random ID in one bucket, done for the contacts it turns up along the way
+    Refresh,
 }
 
-#[derive(Debug)]
-pub enum Command {
-    Shutdown,
-    Ping(SocketAddr),
+impl Reply {
+    // What a node has to support for the lookup to ask it anything
+    fn query_needs(&self) -> Option<Capabilities> {
+        match self {
+            Reply::Get(_) => Some(Capabilities::STORE),
+            Reply::GetSigned(_) => Some(Capabilities::SIGNED),
+            Reply::Republish(value, _) => Some(value.needs()),
+            Reply::GetPeers(..) | Reply::Announce(..) => Some(Capabilities::PEERS),
+            Reply::GetAll(..) => Some(Capabilities::SETS),
+            Reply::FindNode(_) | Reply::Put(..) | Reply::Neighborhood | Reply::Refresh => None,
+        }
+    }
+
+    fn fail(self, err: Error) {
+        // Whoever asked may have stopped waiting, which is fine.
+        match self {
+            Reply::FindNode(waiter) => waiter.resolve(LookupStatus::Cancelled),
+            Reply::Get(reply) => {
+                reply.send(Err(err)).ok();
+            }
+            Reply::GetSigned(reply) => {
+                reply.send(Err(err)).ok();
+            }
+            Reply::Put(_, reply) => {
+                reply.send(Err(err)).ok();
+            }
+            Reply::GetPeers(_, reply) => {
+                reply.send(Err(err)).ok();
+            }
+            Reply::GetAll(_, reply) => {
+                reply.send(Err(err)).ok();
+            }
+            Reply::Announce(_, _, reply) => {
+                reply.send(Err(err)).ok();
+            }
+            Reply::Republish(..) | Reply::Neighborhood | Reply::Refresh => (),
+        }
+    }
+}
+
+// An operation waiting for a lookup to finish before it can start its own
+struct QueuedOp {
+    target: NodeID,
+    deadline: Option<Instant>,
+    reply: Reply,
 }
 
-pub struct Kad {
+// A lookup plus whatever is done with its result
+struct Op {
+    lookup: Lookup,
+    started: Instant,
+    deadline: Option<Instant>,
+    reply: Reply,
+    // Once a put or announce's lookup is done: stores still awaiting an ack, and how many were
+    // accepted
+    storing: Option<(usize, usize)>,
+}
+
+// Two addresses claiming the same ID: the one we have it at, and one that has just turned up,
+// which might be the node having moved or someone else using its ID. Each side is None until it
+// answers our check or the check times out, then whether it answered as that ID.
+struct Conflict {
+    incumbent: SocketAddr,
+    challenger: SocketAddr,
+    incumbent_answered: Option<bool>,
+    challenger_answered: Option<bool>,
+}
+
+enum Request {
+    Lookup(u64),
+    Store(u64),
+    // Answered with how long the pong took to come back
+    Ping(channel::Sender<Result<Duration, Error>>),
+    // Checking one side of a conflict over the node's ID (see Kad::conflicts)
+    VerifyAddr(NodeID),
+    // Pinging a node some other node told us about, or we were seeded with. Its answer adds it to
+    // the routing table, or marks it verified if it's already there.
+    Verify,
+    // Probing one of the current bootstrap's seeds
+    Seed,
+    // Checking on a contact in a full bucket. If it doesn't answer, this one takes its place.
+    Evict(Contact),
+}
+
+// A request we sent and expect a response to
+struct Pending {
+    peer: SocketAddr,
+    sent: Instant,
+    expires: Instant,
+    request: Request,
+}
+
+pub struct Kad<S: Store = MemoryStore> {
     send: channel::Sender<(Packet, SocketAddr)>,
+    config: Config,
+    clock: Box<dyn Clock>,
 
     id: NodeID,
     known_peers: KBuckets,
+    values: ValueStore<S>,
+    signed: SignedStore,
+    announced: PeerStore,
+    sets: ValueSets,
+    tokens: Tokens,
+    suspicion: Suspicion,
+    churn: Churn,
+
+    // Sequence number 0 is for packets nobody answers or that answer nothing in particular, so no
+    // request is ever sent with it
+    next_seq: u64,
+    pending: HashMap<u64, Pending>,
+    // How many of the pending requests went to each peer
+    outstanding: HashMap<SocketAddr, usize>,
+    // Sequence numbers of the Verify requests still pending
+    verifying: HashSet<u64>,
+    next_op: u64,
+    ops: HashMap<u64, Op>,
+    // Oldest first
+    queued_ops: VecDeque<QueuedOp>,
+
+    next_republish: Instant,
+    // Keys waiting for their republish lookup to start
+    republish: VecDeque<NodeID>,
+    leaving: Option<Leave>,
+
+    // Dropped once they hang up
+    lookup_watchers: Vec<channel::Sender<(NodeID, LookupEvent)>>,
+    anomaly_watchers: Vec<channel::Sender<Anomaly>>,
+    sender_gone: bool,
+
+    next_neighborhood: Instant,
+    // The closest nodes to us found by the last lookup for our own ID
+    neighborhood: Vec<Contact>,
+
+    // Woken whenever we hear from someone, and timed out on ticks
+    peer_waiters: Vec<PeerWaiter>,
+
+    // Signs everything we send, if Config::sign_packets is set
+    signing_key: Option<SigningKey>,
+
+    // Seeds of the latest bootstrap we're still waiting on
+    seeds: Vec<SocketAddr>,
+    // Set by a reset until one of its seeds answers, when we look up our own ID
+    reseeding: bool,
+
+    // IDs we've heard from at an address other than the one we know them at, while we check both
+    conflicts: HashMap<NodeID, Conflict>,
+
+    // Where our own sockets are bound, so we never end up talking to ourselves
+    own_addrs: Vec<SocketAddr>,
+    // Packets wait in the receive queue while set, though commands and ticks go on
+    paused: bool,
+    // When we came up, for the uptime in our pings and pongs
+    started: Instant,
 }
 
-impl Kad {
-    pub fn new(send: channel::Sender<(Packet, SocketAddr)>) -> Kad {
+impl<S: Store> Kad<S> {
+    // Keeps plain values in store rather than in memory
+    pub fn with_store(
+        send: channel::Sender<(Packet, SocketAddr)>,
+        config: Config,
+        store: S,
+    ) -> Kad<S> {
+        Kad::with_clock(send, config, Box::new(SystemClock), store)
+    }
+
+    fn with_clock(
+        send: channel::Sender<(Packet, SocketAddr)>,
+        config: Config,
+        clock: Box<dyn Clock>,
+        store: S,
+    ) -> Kad<S> {
+        let now = clock.now();
+        let next_republish = now + config.republish_interval.unwrap_or_default();
+        let next_neighborhood = now + config.neighborhood_interval.unwrap_or_default();
+        let signing_key = if config.sign_packets {
+            Some(SigningKey::from_bytes(&rand::random()))
+        } else {
+            None
+        };
+        // Signed packets are only believed from the ID the key hashes to
+        let id = match &signing_key {
+            Some(key) => NodeID::from_key(key.verifying_key().as_bytes()),
+            None => rand::random(),
+        };
+        let mut known_peers = KBuckets::new();
+        known_peers.set_eviction(config.eviction);
         Kad {
-            id: rand::random(),
-            send: send,
-            known_peers: KBuckets::new(),
+            id,
+            send,
+            config,
+            clock,
+            known_peers,
+            values: ValueStore::new(store),
+            signed: SignedStore::new(),
+            announced: PeerStore::peers(),
+            sets: ValueSets::members(),
+            tokens: Tokens::new(now),
+            suspicion: Suspicion::default(),
+            churn: Churn::default(),
+
+            next_seq: 1,
+            pending: HashMap::new(),
+            outstanding: HashMap::new(),
+            verifying: HashSet::new(),
+            next_op: 0,
+            ops: HashMap::new(),
+            queued_ops: VecDeque::new(),
+
+            next_republish,
+            republish: VecDeque::new(),
+            leaving: None,
+
+            lookup_watchers: Vec::new(),
+            anomaly_watchers: Vec::new(),
+            sender_gone: false,
+
+            next_neighborhood,
+            neighborhood: Vec::new(),
+
+            peer_waiters: Vec::new(),
+
+            signing_key,
+
+            seeds: Vec::new(),
+            reseeding: false,
+
+            conflicts: HashMap::new(),
+
+            own_addrs: Vec::new(),
+            paused: false,
+            started: now,
+        }
+    }
+
+    pub fn id(&self) -> NodeID {
+        self.id
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    fn metadata(&self) -> Option<Metadata> {
+        let version = self.config.software_version.as_ref()?;
+        Some(Metadata::new(version, self.clock.now() - self.started))
+    }
+
+    fn extensions(&self) -> Extensions {
+        Extensions::new(Capabilities::ALL, self.metadata())
+    }
+
+    // A ping saying what we support for peers that have shown they can read one, and a plain one
+    // for everyone else
+    fn ping(&self, peer: SocketAddr) -> Payload {
+        match self.known_peers.by_addr(peer) {
+            Some(contact) if contact.has_extensions() => Payload::PingExt(self.extensions()),
+            _ => Payload::Ping,
         }
     }
 
+    pub fn set_own_addrs(&mut self, addrs: Vec<SocketAddr>) {
+        self.own_addrs = addrs;
+    }
+
+    // Whether we'd take a contact or peer at addr from another node. We'd have no way to reach
+    // one of a family we have no socket for.
+    fn acceptable_addr(&self, addr: SocketAddr) -> bool {
+        let reachable = self.own_addrs.is_empty()
+            || self
+                .own_addrs
+                .iter()
+                .any(|own| own.is_ipv4() == addr.is_ipv4());
+        usable_addr(addr) && reachable && (self.config.accept_private_addrs || !private_addr(addr))
+    }
+
+    // Whether addr reaches one of our own sockets. A socket bound to every interface can be reached
+    // at any of our IPs, but loopback is the only one we can be sure of.
+    fn is_own_addr(&self, addr: SocketAddr) -> bool {
+        self.own_addrs.iter().any(|own| {
+            own.port() == addr.port()
+                && (own.ip() == addr.ip() || (own.ip().is_unspecified() && addr.ip().is_loopback()))
+        })
+    }
+
     pub fn handle_packet(&mut self, pack: Packet, peer: SocketAddr) {
-        self.known_peers
-            .insert(
-                self.id,
-                Contact {
-                    id: pack.id,
-                    addr: peer,
-                },
-            )
-            .ok();
+        // Nobody else has our ID, so this is one of our own packets come back, e.g. off a NAT
+        // that hairpins. Answering it would answer ourselves, and taking it as a response would
+        // settle a request nobody answered.
+        if pack.id == self.id {
+            return;
+        }
+        if self.suspicion.is_banned(peer.ip(), self.clock.now()) {
+            return;
+        }
+
+        // Only accept a response from the peer we actually asked
+        let request = if pack.payload.is_response() {
+            match self.pending.get(&pack.seq_num) {
+                Some(p) if p.peer == peer => self.take_pending(pack.seq_num),
+                _ => None,
+            }
+        } else {
+            None
+        };
+        if self.config.strict_responses
+            && request.is_none()
+            && pack.payload.is_response()
+            && pack.seq_num != 0
+        {
+            // Anyone can put someone else's IP on a packet, so it only counts against the IP when
+            // the packet proves it's from one of us
+            if self.config.psk.is_some() || self.config.sign_packets {
+                self.suspect(peer);
+            }
+            return;
+        }
+
+        // The answer to a check in a conflict over an ID only counts towards settling it. The
+        // losing side answering shouldn't start the conflict over.
+        let checked = if let Some(Pending {
+            request: Request::VerifyAddr(id),
+            ..
+        }) = request
+        {
+            self.addr_checked(id, peer, id == pack.id);
+            true
+        } else {
+            false
+        };
+        // A seed answering is as good as being given its contact
+        let seed = matches!(
+            request,
+            Some(Pending {
+                request: Request::Seed,
+                ..
+            })
+        );
+        if (self.config.maintain_routing_table || seed) && !checked {
+            self.note_sender(pack.id, peer, request.is_some());
+        }
+        // Ask a contact that pings without saying what it supports, in case it can. Nodes older
+        // than extensions ignore the question.
+        let ask = match &pack.payload {
+            Payload::PingExt(extensions) | Payload::PongExt(extensions) => {
+                self.known_peers.describe(
+                    self.id,
+                    pack.id,
+                    peer,
+                    extensions.capabilities(),
+                    extensions.metadata(),
+                );
+                false
+            }
+            Payload::Ping | Payload::Pong => self.known_peers.ask(self.id, pack.id, peer),
+            _ => false,
+        };
+        if let Some(pending) = &request {
+            let rtt = self.clock.now() - pending.sent;
+            self.known_peers.observe_rtt(peer, rtt);
+        }
 
         match pack.payload {
-            Payload::Ping => self
-                .send
-                .send((
-                    Packet {
-                        id: self.id,
-                        seq_num: pack.seq_num,
-                        payload: Payload::Pong,
+            Payload::Ping => self.send_packet(peer, pack.seq_num, Payload::Pong),
+            Payload::PingExt(_) => {
+                let pong = Payload::PongExt(self.extensions());
+                self.send_packet(peer, pack.seq_num, pong);
+            }
+            Payload::Leaving => {
+                // Only the contact it claims to be, and only at the address it's leaving from, so
+                // nobody can have someone else dropped. Then look for someone to fill the gap.
+                if let Some(gone) = self.known_peers.remove(self.id, pack.id, peer) {
+                    self.churn.evicted(gone.id(), self.clock.now());
+                    self.refresh(self.known_peers.bucket_index(self.id, pack.id));
+                }
+            }
+            Payload::FindNode(target) => {
+                let closest = self.closest_nodes(target);
+                self.send_packet(peer, pack.seq_num, Payload::Nodes(closest));
+            }
+            Payload::Announce(key, port, token) => {
+                let now = self.clock.now();
+                let ack = if !self.tokens.check(peer.ip(), key, token) {
+                    Err(Rejection::BadToken)
+                } else if !self
+                    .announced
+                    .announce(key, SocketAddr::new(peer.ip(), port), now)
+                {
+                    Err(Rejection::TooMany)
+                } else {
+                    Ok(())
+                };
+                self.send_packet(peer, pack.seq_num, Payload::StoreAck(ack));
+            }
+            Payload::GetPeers(key) => {
+                let peers = self.announced.get(key, MAX_PEERS);
+                let nodes = if peers.is_empty() {
+                    self.closest_nodes(key)
+                } else {
+                    Vec::new()
+                };
+                let token = self.tokens.issue(peer.ip(), key);
+                self.send_packet(peer, pack.seq_num, Payload::Peers(peers, nodes, token));
+            }
+            Payload::Store(key, value, version) => {
+                let ack = self.store(key, Stored::Plain(value, version), peer.ip());
+                self.send_packet(peer, pack.seq_num, Payload::StoreAck(ack));
+            }
+            Payload::Add(key, value) => {
+                let ack = if value.len() > MAX_MEMBER_LEN {
+                    Err(Rejection::TooLarge)
+                } else {
+                    self.store(key, Stored::Member(value), peer.ip())
+                };
+                self.send_packet(peer, pack.seq_num, Payload::StoreAck(ack));
+            }
+            Payload::FindValues(key) => {
+                let members = self.sets.get(key, MAX_MEMBERS);
+                let nodes = if members.is_empty() {
+                    self.closest_nodes(key)
+                } else {
+                    Vec::new()
+                };
+                self.send_packet(peer, pack.seq_num, Payload::Values(members, nodes));
+            }
+            Payload::StoreSigned(value) => {
+                let ack = if value.verify() {
+                    self.store(value.key(), Stored::Signed(Box::new(value)), peer.ip())
+                } else {
+                    Err(Rejection::BadSignature)
+                };
+                self.send_packet(peer, pack.seq_num, Payload::StoreAck(ack));
+            }
+            Payload::FindValue(key) => {
+                let payload = match (self.signed.get(key), self.values.get(key)) {
+                    (Some(value), _) => Payload::Signed(value.clone()),
+                    (None, Some(value)) => Payload::Value(value),
+                    (None, None) => Payload::Nodes(self.closest_nodes(key)),
+                };
+                self.send_packet(peer, pack.seq_num, payload);
+            }
+            // Whatever a newer node wanted, we've already learned of it, which is all we can do
+            Payload::Unknown(_) => (),
+            response => {
+                if let Some(request) = request {
+                    self.handle_response(request, response);
+                }
+            }
+        }
+        if ask {
+            self.send_packet(peer, 0, Payload::PingExt(self.extensions()));
+        }
+
+        self.wake_peer_waiters();
+        self.reap_cancelled();
+        self.start_queued();
+    }
+
+    fn health(&self) -> HealthReport {
+        let buckets = self
+            .known_peers
+            .occupied_buckets(self.id)
+            .into_iter()
+            .map(|b| (b, self.known_peers.bucket_len(self.id, b)))
+            .collect();
+        HealthReport {
+            peers: self.known_peers.len(),
+            buckets,
+            values: self.values.len(),
+            signed_values: self.signed.len(),
+            lookups: self.ops.len(),
+            queued_lookups: self.queued_ops.len(),
+            drops: Vec::new(),
+            estimated_size: self.known_peers.estimate_size(self.id),
+        }
+    }
+
+    // The nodes we answer with when asked who's closest to target
+    fn closest_nodes(&self, target: NodeID) -> Vec<Contact> {
+        match self.config.closest_nodes {
+            ClosestNodes::Spillover => self.known_peers.closest(target, K),
+            ClosestNodes::Bucket => self.known_peers.bucket_contents(self.id, target),
+        }
+    }
+
+    // Counts an unsolicited response against peer's IP. If that gets it banned, we stop routing
+    // anything through it, too.
+    fn suspect(&mut self, peer: SocketAddr) {
+        if self.suspicion.offend(peer.ip(), self.clock.now()) {
+            self.evict_addr(peer);
+            self.report(Anomaly::Banned(peer.ip()));
+        }
+    }
+
+    // Whether we're among the K closest nodes we know of to key
+    fn is_responsible(&self, key: NodeID) -> bool {
+        self.responsibility(key).responsible
+    }
+
+    fn responsibility(&self, key: NodeID) -> Responsibility {
+        let rank = self.known_peers.closer_than(key, self.id);
+        Responsibility {
+            rank,
+            responsible: rank < K,
+        }
+    }
+
+    // from is whoever sent it to us, which set members count against
+    fn store(&mut self, key: NodeID, value: Stored, from: IpAddr) -> Result<(), Rejection> {
+        if self.is_responsible(key) {
+            return self.store_local(key, value, from);
+        }
+        if !self.config.forward_stores {
+            return Err(Rejection::NotResponsible);
+        }
+
+        // Hand it on to the closest node we know of. Each hop only ever forwards to a closer node,
+        // so this ends once it reaches a node that is responsible. We won't hear how that went, so
+        // the sender mustn't count this as stored.
+        let closest = self
+            .known_peers
+            .closest(key, K)
+            .into_iter()
+            .find(|c| c.supports(value.needs()))
+            .ok_or(Rejection::NotResponsible)?;
+        self.send_packet(closest.addr(), 0, value.payload(key));
+        Err(Rejection::Forwarded)
+    }
+
+    fn store_local(&mut self, key: NodeID, value: Stored, from: IpAddr) -> Result<(), Rejection> {
+        match value {
+            // Nobody but the key's holder gets to put anything under a signed value's key
+            Stored::Plain(..) if self.signed.get(key).is_some() => {
+                return Err(Rejection::SignedKey);
+            }
+            Stored::Plain(value, version) => {
+                match self.values.put(key, value, version, self.clock.now()) {
+                    Ok(true) => (),
+                    Ok(false) => return Err(Rejection::StaleVersion),
+                    Err(_) => return Err(Rejection::StoreFailed),
+                }
+            }
+            Stored::Signed(value) => {
+                if !self.signed.put(*value, self.clock.now()) {
+                    return Err(Rejection::StaleSequence);
+                }
+            }
+            Stored::Member(value) => {
+                if !self.sets.add(key, value, from, self.clock.now()) {
+                    return Err(Rejection::TooMany);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_response(&mut self, request: Pending, response: Payload) {
+        match (request.request, response) {
+            (Request::Lookup(id), Payload::Nodes(contacts)) => {
+                self.found_nodes(id, request.peer, contacts)
+            }
+            (Request::Lookup(id), Payload::Peers(peers, contacts, token)) => {
+                let peers: Vec<SocketAddr> = peers
+                    .into_iter()
+                    .filter(|&p| self.acceptable_addr(p))
+                    .collect();
+                match self.ops.get_mut(&id).map(|op| &mut op.reply) {
+                    Some(Reply::GetPeers(found, _)) => {
+                        for peer in peers {
+                            if !found.contains(&peer) {
+                                found.push(peer);
+                            }
+                        }
+                    }
+                    Some(Reply::Announce(_, tokens, _)) => tokens.push((request.peer, token)),
+                    _ => (),
+                }
+                self.found_nodes(id, request.peer, contacts)
+            }
+            (Request::Lookup(id), Payload::Values(values, contacts)) => {
+                if let Some(Reply::GetAll(found, _)) = self.ops.get_mut(&id).map(|op| &mut op.reply)
+                {
+                    for value in values {
+                        if value.len() <= MAX_MEMBER_LEN && !found.contains(&value) {
+                            found.push(value);
+                        }
+                    }
+                }
+                self.found_nodes(id, request.peer, contacts)
+            }
+            // A plain value is no answer to a lookup for a signed one, so that carries on as if the
+            // peer had nothing
+            (Request::Lookup(id), Payload::Value(value)) => {
+                let is_get = matches!(
+                    self.ops.get(&id),
+                    Some(Op {
+                        reply: Reply::Get(_),
+                        ..
+                    })
+                );
+                if is_get {
+                    if let (Reply::Get(reply), source, stats) = self.finish_early(id, request.peer)
+                    {
+                        reply.send(Ok((Some((value, source)), stats))).ok();
+                    }
+                } else {
+                    self.already_holds(id, request.peer);
+                }
+            }
+            (Request::Lookup(id), Payload::Signed(value)) => {
+                let wanted = match self.ops.get(&id) {
+                    Some(op) => match op.reply {
+                        Reply::GetSigned(_) => value.key() == op.lookup.target() && value.verify(),
+                        _ => false,
                     },
-                    peer,
-                ))
-                .unwrap(),
+                    None => false,
+                };
+                if wanted {
+                    if let (Reply::GetSigned(reply), ..) = self.finish_early(id, request.peer) {
+                        reply.send(Ok(Some(value))).ok();
+                    }
+                } else {
+                    self.already_holds(id, request.peer);
+                }
+            }
+            (Request::Store(id), Payload::StoreAck(ack)) => self.store_acked(id, ack.is_ok()),
+            (Request::Ping(reply), Payload::Pong | Payload::PongExt(_)) => {
+                reply.send(Ok(self.clock.now() - request.sent)).ok();
+            }
+            (Request::Seed, Payload::Pong | Payload::PongExt(_)) => {
+                let peer = request.peer;
+                self.seeds.retain(|&seed| seed != peer);
+                // The seed is in the table by now, so there's someone to start from
+                if self.reseeding {
+                    self.reseeding = false;
+                    self.start_op(self.id, None, Reply::Neighborhood);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    // Carries lookup id on with the contacts peer answered it with.
+    fn found_nodes(&mut self, id: u64, peer: SocketAddr, contacts: Vec<Contact>) {
+        let contacts: Vec<Contact> = contacts
+            .into_iter()
+            .filter(|c| {
+                c.id() != self.id && self.acceptable_addr(c.addr()) && !self.is_own_addr(c.addr())
+            })
+            .collect();
+        if let Some(op) = self.ops.get_mut(&id) {
+            let target = op.lookup.target();
+            let contact = op.lookup.responded(peer);
+            let added = op.lookup.add_found(peer, contacts.clone());
+            if let Some(contact) = contact {
+                self.lookup_event(target, LookupEvent::Responded(contact, added));
+            }
+        }
+        self.advance(id);
+        self.verify(contacts);
+    }
+
+    // Adds whoever sent us a packet to the routing table. Anyone can claim a known node's ID, so
+    // if it arrives from somewhere new we only move the contact once the new address answers a
+    // ping of its own.
+    // An ID evicted lately only gets back in by answering us, not by a packet of its own.
+    fn note_sender(&mut self, id: NodeID, peer: SocketAddr, answered: bool) {
+        if self.is_own_addr(peer) || !self.acceptable_addr(peer) {
+            return;
+        }
+        match self.known_peers.get(self.id, id) {
+            // Only one challenger at a time. Any others claiming the ID meanwhile are ignored.
+            Some(known) if known.addr() != peer && !self.conflicts.contains_key(&id) => {
+                self.conflicts.insert(
+                    id,
+                    Conflict {
+                        incumbent: known.addr(),
+                        challenger: peer,
+                        incumbent_answered: None,
+                        challenger_answered: None,
+                    },
+                );
+                for addr in [known.addr(), peer] {
+                    let expires = self.expires(addr);
+                    self.send_request(addr, expires, Request::VerifyAddr(id), self.ping(addr));
+                }
+            }
+            Some(known) if known.addr() != peer => (),
+            known => {
+                let now = self.clock.now();
+                if known.is_none() && !answered && self.churn.cooling(id, now) {
+                    return;
+                }
+                let mut contact = Contact::new(id, peer).seen(now);
+                if answered {
+                    contact = contact.verified();
+                }
+                match self.known_peers.insert(self.id, contact) {
+                    Ok(()) if known.is_none() => self.churn.admitted(id, now),
+                    Ok(()) => (),
+                    Err(victim) => self.challenge(victim, contact),
+                }
+            }
+        }
+    }
+
+    // Settles a conflict over id once we know enough about addr, one side of it. The address we
+    // had it at keeps it so long as it answers, so a node that's been in our table longer can't
+    // be pushed out by someone else taking its ID. The new address only takes over once the old
+    // one has stopped answering and the new one has answered as the node. Either way, it comes out
+    // the same whichever side answers first.
+    fn addr_checked(&mut self, id: NodeID, addr: SocketAddr, answered: bool) {
+        let conflict = match self.conflicts.get_mut(&id) {
+            Some(conflict) => conflict,
+            None => return,
+        };
+        if addr == conflict.incumbent {
+            conflict.incumbent_answered = Some(answered);
+        } else if addr == conflict.challenger {
+            conflict.challenger_answered = Some(answered);
+        } else {
+            return;
+        }
+        match (conflict.incumbent_answered, conflict.challenger_answered) {
+            (Some(true), _) | (Some(false), Some(false)) => {
+                self.conflicts.remove(&id);
+            }
+            (Some(false), Some(true)) => {
+                let challenger = conflict.challenger;
+                self.conflicts.remove(&id);
+                self.known_peers.set_addr(self.id, id, challenger);
+            }
             _ => (),
         }
     }
 
+    // Pings contacts we've been told about, so the ones that answer make it into the routing table
+    // without us taking anyone's word for them. Contacts we're already asking something, or who
+    // would have to push someone out of a full bucket, are left alone.
+    fn verify(&mut self, contacts: Vec<Contact>) {
+        if !self.config.maintain_routing_table {
+            return;
+        }
+        let mut pinged = 0;
+        for contact in contacts {
+            if self.verifying.len() >= MAX_UNVERIFIED || pinged >= MAX_VERIFY_PER_ANSWER {
+                return;
+            }
+            if self.known_peers.get(self.id, contact.id()).is_some()
+                || self.outstanding.contains_key(&contact.addr())
+                || !self.known_peers.has_room(self.id, contact.id())
+            {
+                continue;
+            }
+            let expires = self.expires(contact.addr());
+            self.send_request(
+                contact.addr(),
+                expires,
+                Request::Verify,
+                self.ping(contact.addr()),
+            );
+            pinged += 1;
+        }
+    }
+
+    // A peer answered a lookup with a value rather than nodes. That counts as an answer without any
+    // new nodes, and during a republish means the peer needn't be sent the value again.
+    fn already_holds(&mut self, id: u64, peer: SocketAddr) {
+        if let Some(op) = self.ops.get_mut(&id) {
+            let target = op.lookup.target();
+            let contact = op.lookup.responded(peer);
+            if let Reply::Republish(_, holders) = &mut op.reply {
+                holders.push(peer);
+            }
+            if let Some(contact) = contact {
+                self.lookup_event(target, LookupEvent::Responded(contact, 0));
+            }
+            self.advance(id);
+        }
+    }
+
+    // Ends a lookup as soon as peer gives us what it was looking for. Returns who was waiting, the
+    // lookup's contact for peer, and how the lookup went.
+    fn finish_early(&mut self, id: u64, peer: SocketAddr) -> (Reply, Option<Contact>, LookupStats) {
+        let mut op = self.remove_op(id);
+        let target = op.lookup.target();
+        let contact = op.lookup.responded(peer);
+        if let Some(contact) = contact {
+            self.lookup_event(target, LookupEvent::Responded(contact, 0));
+        }
+        self.lookup_event(target, LookupEvent::Completed(op.lookup.closest()));
+        let stats = self.stats(&op);
+        (op.reply, contact, stats)
+    }
+
+    fn lookup_event(&mut self, target: NodeID, event: LookupEvent) {
+        self.lookup_watchers
+            .retain(|w| w.send((target, event.clone())).is_ok());
+    }
+
+    // Only watchers hear about it. Peers can set some off at will, so they mustn't flood stderr.
+    fn report(&mut self, anomaly: Anomaly) {
+        self.anomaly_watchers
+            .retain(|w| w.send(anomaly.clone()).is_ok());
+    }
+
     pub fn handle_command(&mut self, command: Command) -> bool {
         match command {
             Command::Shutdown => return false,
-            Command::Ping(peer) => self
-                .send
-                .send((
-                    Packet {
-                        id: self.id,
-                        seq_num: 0,
-                        payload: Payload::Ping,
-                    },
-                    peer,
-                ))
-                .unwrap(),
+            Command::Bootstrap(seeds) => self.bootstrap(seeds),
+            Command::Reset(seeds) => self.reset(seeds),
+            Command::BootstrapSeeds(reply) => {
+                reply.send(self.seeds.clone()).ok();
+            }
+            Command::PingWait {
+                peer,
+                deadline,
+                reply,
+            } => {
+                // Whoever asked chose how long to wait, so unlike our own requests this isn't cut
+                // short to fit how fast the peer has been
+                self.send_request(peer, deadline, Request::Ping(reply), self.ping(peer));
+            }
+            Command::FindNode(target, waiter) => {
+                self.start_op(target, None, Reply::FindNode(waiter));
+            }
+            Command::Get {
+                key,
+                deadline,
+                reply,
+            } => match self.values.get(key) {
+                // Found without asking anyone
+                Some(value) => {
+                    let found = Some((value, None));
+                    reply.send(Ok((found, LookupStats::default()))).ok();
+                }
+                None => self.start_op(key, Some(deadline), Reply::Get(reply)),
+            },
+            Command::Put {
+                key,
+                value,
+                version,
+                deadline,
+                reply,
+            } => {
+                if value.len() > self.config.max_value_size() {
+                    reply.send(Err(Error::TooLarge)).ok();
+                } else {
+                    let reply = Reply::Put(Stored::Plain(value, version), reply);
+                    self.start_op(key, Some(deadline), reply);
+                }
+            }
+            Command::GetSigned {
+                key,
+                deadline,
+                reply,
+            } => match self.signed.get(key) {
+                Some(value) => {
+                    reply.send(Ok(Some(value.clone()))).ok();
+                }
+                None => self.start_op(key, Some(deadline), Reply::GetSigned(reply)),
+            },
+            Command::GetPeers {
+                key,
+                deadline,
+                reply,
+            } => {
+                let reply = Reply::GetPeers(self.announced.get(key, MAX_PEERS), reply);
+                self.start_op(key, Some(deadline), reply);
+            }
+            Command::Announce {
+                key,
+                port,
+                deadline,
+                reply,
+            } => self.start_op(
+                key,
+                Some(deadline),
+                Reply::Announce(port, Vec::new(), reply),
+            ),
+            Command::Add {
+                key,
+                value,
+                deadline,
+                reply,
+            } => {
+                if value.len() > MAX_MEMBER_LEN {
+                    reply.send(Err(Error::TooLarge)).ok();
+                } else {
+                    let reply = Reply::Put(Stored::Member(value), reply);
+                    self.start_op(key, Some(deadline), reply);
+                }
+            }
+            Command::GetAll {
+                key,
+                deadline,
+                reply,
+            } => {
+                let reply = Reply::GetAll(self.sets.get(key, MAX_MEMBERS), reply);
+                self.start_op(key, Some(deadline), reply);
+            }
+            Command::PutSigned {
+                value,
+                deadline,
+                reply,
+            } => {
+                let key = value.key();
+                self.start_op(
+                    key,
+                    Some(deadline),
+                    Reply::Put(Stored::Signed(value), reply),
+                );
+            }
+            Command::SendRaw(packet, peer) => self.send_raw(*packet, peer),
+            Command::WatchLookups(watcher) => self.lookup_watchers.push(watcher),
+            Command::WatchAnomalies(watcher) => self.anomaly_watchers.push(watcher),
+            Command::Lookups(reply) => {
+                reply.send((self.ops.len(), self.queued_ops.len())).ok();
+            }
+            Command::Pause(reply) => {
+                self.paused = true;
+                reply.send(()).ok();
+            }
+            Command::Resume => self.paused = false,
+            Command::Report(anomaly) => self.report(anomaly),
+            Command::KnownPeers(reply) => {
+                reply.send(self.known_peers.contacts()).ok();
+            }
+            Command::PeerMetadata(reply) => {
+                reply.send(self.known_peers.described()).ok();
+            }
+            Command::Buckets(reply) => {
+                reply.send(self.known_peers.buckets(self.id).collect()).ok();
+            }
+            Command::Health(reply) => {
+                reply.send(self.health()).ok();
+            }
+            Command::Contains(id, reply) => {
+                reply.send(self.known_peers.get(self.id, id)).ok();
+            }
+            Command::StaleContacts(older_than, reply) => {
+                let now = self.clock.now();
+                reply
+                    .send(self.known_peers.stale_contacts(now, older_than))
+                    .ok();
+            }
+            Command::Neighborhood(reply) => {
+                reply.send(self.neighborhood.clone()).ok();
+            }
+            Command::Responsibility(key, reply) => {
+                reply.send(self.responsibility(key)).ok();
+            }
+            Command::EstimateSize(reply) => {
+                reply.send(self.known_peers.estimate_size(self.id)).ok();
+            }
+            Command::Unreachable(peer) => self.unreachable(peer),
+            Command::Leave(reply) => self.leave(reply),
+            Command::ExportStore(reply) => {
+                let mut pairs: Vec<(NodeID, Vec<u8>)> = self.values.iter().collect();
+                pairs.sort_by_key(|(key, _)| *key);
+                reply.send(pairs).ok();
+            }
+            Command::ImportStore(pairs, reply) => {
+                let now = self.clock.now();
+                let mut imported = 0;
+                // Too big for a Store packet, so we could never hand it on
+                let max = self.config.max_value_size();
+                for (key, value) in pairs.into_iter().filter(|(_, v)| v.len() <= max) {
+                    if let Ok(true) = self.values.put(key, value, 0, now) {
+                        imported += 1;
+                    }
+                }
+                reply.send(imported).ok();
+            }
+            Command::RefreshBucket(bucket) => self.refresh(bucket),
+            Command::RefreshAll => {
+                for bucket in self.known_peers.occupied_buckets(self.id) {
+                    self.refresh(bucket);
+                }
+            }
+            Command::WaitForPeers {
+                min,
+                deadline,
+                reply,
+            } => self.peer_waiters.push(PeerWaiter {
+                min,
+                deadline,
+                reply,
+            }),
+            Command::Seed(mut contacts) => {
+                contacts.retain(|c| !self.is_own_addr(c.addr()));
+                let new: HashSet<NodeID> = contacts
+                    .iter()
+                    .map(|c| c.id())
+                    .filter(|&id| self.known_peers.get(self.id, id).is_none())
+                    .collect();
+                let now = self.clock.now();
+                for contact in self.known_peers.insert_all(self.id, contacts) {
+                    let id = contact.id();
+                    if new.contains(&id) && self.known_peers.get(self.id, id).is_some() {
+                        self.churn.admitted(id, now);
+                    }
+                    let expires = self.expires(contact.addr());
+                    let ping = self.ping(contact.addr());
+                    self.send_request(contact.addr(), expires, Request::Verify, ping);
+                }
+            }
+            Command::WarmUp => {
+                for contact in self.known_peers.oldest_per_bucket() {
+                    self.send_packet(contact.addr(), 0, self.ping(contact.addr()));
+                }
+            }
+            #[cfg(test)]
+            Command::DebugInsert(contact) => {
+                self.known_peers.insert(self.id, contact).ok();
+            }
         };
 
+        self.wake_peer_waiters();
+        self.reap_cancelled();
+        self.start_queued();
         true
     }
+
+    // Expires requests and operations whose time is up.
+    pub fn handle_tick(&mut self) {
+        let now = self.clock.now();
+        let expired: Vec<u64> = self
+            .pending
+            .iter()
+            .filter(|(_, p)| p.expires <= now)
+            .map(|(seq_num, _)| *seq_num)
+            .collect();
+        for seq_num in expired {
+            // Finishing an earlier one can remove its operation, and the rest of its requests
+            let Some(pending) = self.take_pending(seq_num) else {
+                continue;
+            };
+            match pending.request {
+                Request::Lookup(id) => {
+                    if let Some(op) = self.ops.get_mut(&id) {
+                        op.lookup.failed(pending.peer);
+                    }
+                    self.advance(id);
+                }
+                Request::Store(id) => self.store_acked(id, false),
+                Request::Ping(reply) => {
+                    reply.send(Err(Error::Timeout)).ok();
+                }
+                Request::VerifyAddr(id) => self.addr_checked(id, pending.peer, false),
+                Request::Verify => (),
+                Request::Seed => self.seeds.retain(|&seed| seed != pending.peer),
+                Request::Evict(replacement) => {
+                    self.evict_addr(pending.peer);
+                    if self.known_peers.insert(self.id, replacement).is_ok() {
+                        self.churn.admitted(replacement.id(), now);
+                    }
+                }
+            }
+        }
+
+        // Lookups held back by the per-peer cap may be able to go on now
+        let ids: Vec<u64> = self.ops.keys().cloned().collect();
+        for id in ids {
+            self.advance(id);
+        }
+
+        let late: Vec<u64> = self
+            .ops
+            .iter()
+            .filter(|(_, op)| op.deadline.is_some_and(|d| d <= now))
+            .map(|(id, _)| *id)
+            .collect();
+        for id in late {
+            let op = self.remove_op(id);
+            // A put that got as far as storing has already reported its lookup completed
+            if op.storing.is_none() {
+                self.lookup_event(op.lookup.target(), LookupEvent::Failed);
+            }
+            op.reply.fail(Error::Timeout);
+        }
+
+        let (late, queued): (VecDeque<_>, _) = self
+            .queued_ops
+            .drain(..)
+            .partition(|op| op.deadline.is_some_and(|d| d <= now));
+        self.queued_ops = queued;
+        for op in late {
+            op.reply.fail(Error::Timeout);
+        }
+
+        if let Some(since) = self
+            .config
+            .keepalive_interval
+            .and_then(|i| now.checked_sub(i))
+        {
+            for contact in self.known_peers.idle(since) {
+                self.send_packet(contact.addr(), 0, self.ping(contact.addr()));
+            }
+        }
+
+        let (late, waiting) = self.peer_waiters.drain(..).partition(|w| w.deadline <= now);
+        self.peer_waiters = waiting;
+        for waiter in late {
+            waiter.reply.send(Err(Error::Timeout)).ok();
+        }
+
+        if let Some(before) = self.config.value_ttl.and_then(|ttl| now.checked_sub(ttl)) {
+            self.values.expire(before);
+            self.signed.expire(before);
+        }
+        if let Some(before) = self.config.peer_ttl.and_then(|ttl| now.checked_sub(ttl)) {
+            self.announced.expire(before);
+            self.sets.expire(before);
+        }
+        self.hand_off();
+        self.tokens.rotate(now);
+        self.suspicion.expire(now);
+        self.churn.expire(now);
+
+        if let Some(interval) = self.config.republish_interval {
+            if now >= self.next_republish {
+                self.next_republish = now + interval;
+                for key in self.values.keys().chain(self.signed.keys()) {
+                    if !self.republish.contains(&key) {
+                        self.republish.push_back(key);
+                    }
+                }
+            }
+            for _ in 0..REPUBLISH_PER_TICK {
+                match self.republish.pop_front() {
+                    Some(key) => self.start_republish(key),
+                    None => break,
+                }
+            }
+        }
+
+        if let Some(interval) = self.config.neighborhood_interval {
+            if now >= self.next_neighborhood {
+                self.next_neighborhood = now + interval;
+                let in_progress = self
+                    .ops
+                    .values()
+                    .any(|op| matches!(op.reply, Reply::Neighborhood));
+                let queued = self
+                    .queued_ops
+                    .iter()
+                    .any(|op| matches!(op.reply, Reply::Neighborhood));
+                if !in_progress && !queued {
+                    self.start_op(self.id, None, Reply::Neighborhood);
+                }
+            }
+        }
+
+        self.reap_cancelled();
+        self.start_queued();
+    }
+
+    fn start_republish(&mut self, key: NodeID) {
+        let value = match (self.signed.get(key), self.values.get_versioned(key)) {
+            (Some(value), _) => Stored::Signed(Box::new(value.clone())),
+            (None, Some((value, version))) => Stored::Plain(value, version),
+            // No longer stored
+            (None, None) => return,
+        };
+        self.start_op(key, None, Reply::Republish(value, Vec::new()));
+    }
+
+    fn send_packet(&mut self, peer: SocketAddr, seq_num: u64, payload: Payload) {
+        let mut packet = Packet {
+            network_id: self.config.network_id,
+            id: self.id,
+            seq_num,
+            payload,
+            signature: None,
+        };
+        if let Some(signing_key) = &self.signing_key {
+            if let Err(e) = packet.sign(signing_key) {
+                eprintln!("Couldn't sign packet for {}: {}", peer, e);
+                self.report(Anomaly::SignFailed(peer));
+                return;
+            }
+        }
+        self.send_raw(packet, peer);
+    }
+
+    // Goes the same way as our own packets, so it's subject to whatever limits they are
+    fn send_raw(&mut self, packet: Packet, peer: SocketAddr) {
+        // Whatever we'd be asking, we'd only be asking ourselves. Anyone waiting on an answer times out.
+        if self.is_own_addr(peer) {
+            eprintln!("Not sending {:?} to our own address {}", packet, peer);
+            return;
+        }
+        self.known_peers.sent_to(peer, self.clock.now());
+        if self.send.send((packet, peer)).is_err() && !self.sender_gone {
+            self.sender_gone = true;
+            self.report(Anomaly::SenderGone);
+        }
+    }
+
+    // When a request sent to peer now should be given up on. Peers that have answered before get
+    // a timeout fitted to how fast they've been, up to the REQUEST_TIMEOUT everyone else gets.
+    fn expires(&self, peer: SocketAddr) -> Instant {
+        let timeout = self
+            .known_peers
+            .by_addr(peer)
+            .and_then(|c| c.timeout())
+            .map_or(REQUEST_TIMEOUT, |t| t.min(REQUEST_TIMEOUT));
+        self.clock.now() + timeout
+    }
+
+    fn send_request(
+        &mut self,
+        peer: SocketAddr,
+        expires: Instant,
+        request: Request,
+        payload: Payload,
+    ) {
+        let seq_num = self.next_seq_num();
+        *self.outstanding.entry(peer).or_default() += 1;
+        if let Request::Verify = request {
+            self.verifying.insert(seq_num);
+        }
+        self.pending.insert(
+            seq_num,
+            Pending {
+                peer,
+                sent: self.clock.now(),
+                expires,
+                request,
+            },
+        );
+        self.send_packet(peer, seq_num, payload);
+    }
+
+    // After wrapping around, skips 0 and anything still waiting on an answer.
+    fn next_seq_num(&mut self) -> u64 {
+        loop {
+            let seq_num = self.next_seq;
+            self.next_seq = self.next_seq.wrapping_add(1);
+            if seq_num != 0 && !self.pending.contains_key(&seq_num) {
+                return seq_num;
+            }
+        }
+    }
+
+    fn start_op(&mut self, target: NodeID, deadline: Option<Instant>, reply: Reply) {
+        let full = self
+            .config
+            .max_lookups
+            .is_some_and(|max| self.ops.len() >= max);
+        if !full {
+            self.run_op(target, deadline, reply);
+            return;
+        }
+        match self.config.lookup_overflow {
+            LookupOverflow::Queue => self.queued_ops.push_back(QueuedOp {
+                target,
+                deadline,
+                reply,
+            }),
+            LookupOverflow::Fail => reply.fail(Error::TooManyLookups),
+        }
+    }
+
+    // Starts queued operations for as many slots as are free
+    fn start_queued(&mut self) {
+        while self
+            .config
+            .max_lookups
+            .is_none_or(|max| self.ops.len() < max)
+        {
+            match self.queued_ops.pop_front() {
+                Some(op) => self.run_op(op.target, op.deadline, op.reply),
+                None => break,
+            }
+        }
+    }
+
+    fn run_op(&mut self, target: NodeID, deadline: Option<Instant>, reply: Reply) {
+        let id = self.next_op;
+        self.next_op += 1;
+        let alpha = match reply {
+            Reply::Refresh | Reply::Neighborhood => self.config.refresh_alpha,
+            _ => self.config.lookup_alpha,
+        };
+        let op = Op {
+            lookup: Lookup::new(
+                target,
+                self.known_peers.closest(target, K),
+                self.config.max_lookup_hops,
+                alpha,
+            ),
+            started: self.clock.now(),
+            deadline,
+            reply,
+            storing: None,
+        };
+        self.known_peers.refreshed(self.id, target, op.started);
+        self.ops.insert(id, op);
+        self.lookup_event(target, LookupEvent::Started);
+        self.advance(id);
+    }
+
+    fn stats(&self, op: &Op) -> LookupStats {
+        LookupStats {
+            elapsed: self.clock.now() - op.started,
+            ..op.lookup.stats()
+        }
+    }
+
+    // Starts handing every value we hold to the nodes closest to its key, a batch per tick so a
+    // big store doesn't go out in one burst. Starting again goes back to the beginning.
+    fn leave(&mut self, reply: channel::Sender<usize>) {
+        let plain = self.values.keys().map(|key| (key, false));
+        let signed = self.signed.keys().map(|key| (key, true));
+        self.leaving = Some(Leave {
+            keys: plain.chain(signed).collect(),
+            handed_off: 0,
+            reply,
+        });
+        self.hand_off();
+    }
+
+    // Hands off the next batch of a leave in progress. Once there's nothing left, tells our
+    // neighbors we're going and answers whoever asked. Nobody waits on the stores' acks, since we
+    // won't be around to hear them.
+    fn hand_off(&mut self) {
+        let mut leave = match self.leaving.take() {
+            Some(leave) => leave,
+            None => return,
+        };
+        for _ in 0..HANDOFF_PER_TICK {
+            let (key, signed) = match leave.keys.pop_front() {
+                Some(next) => next,
+                None => break,
+            };
+            let value = match (signed, self.signed.get(key), self.values.get_versioned(key)) {
+                (true, Some(value), _) => Stored::Signed(Box::new(value.clone())),
+                (false, _, Some((value, version))) => Stored::Plain(value, version),
+                // Gone since the leave started
+                _ => continue,
+            };
+            for contact in self.known_peers.closest(key, K) {
+                if contact.supports(value.needs()) {
+                    self.send_packet(contact.addr(), 0, value.payload(key));
+                }
+            }
+            leave.handed_off += 1;
+        }
+        if !leave.keys.is_empty() {
+            self.leaving = Some(leave);
+            return;
+        }
+
+        for contact in self.known_peers.closest(self.id, K) {
+            self.send_packet(contact.addr(), 0, Payload::Leaving);
+        }
+        leave.reply.send(leave.handed_off).ok();
+    }
+
+    // Pings victim, to find out if it should make way for replacement. Anyone already being checked
+    // keeps their first challenger, and anyone let in lately keeps their place for now.
+    fn challenge(&mut self, victim: Contact, replacement: Contact) {
+        let challenged = self
+            .pending
+            .values()
+            .any(|p| p.peer == victim.addr() && matches!(p.request, Request::Evict(_)));
+        let settled = self.churn.settled(victim.id(), self.clock.now());
+        if victim == replacement || challenged || !settled {
+            return;
+        }
+        let expires = self.expires(victim.addr());
+        self.send_request(
+            victim.addr(),
+            expires,
+            Request::Evict(replacement),
+            self.ping(victim.addr()),
+        );
+    }
+
+    // Out of range and empty buckets are ignored, since there's nobody to ask about them.
+    fn refresh(&mut self, bucket: usize) {
+        if bucket >= KEY_BITS || self.known_peers.bucket_len(self.id, bucket) == 0 {
+            return;
+        }
+        let target = NodeID::random_in_bucket(&mut rand::thread_rng(), self.id, bucket);
+        self.start_op(target, None, Reply::Refresh);
+    }
+
+    // There's no point waiting out the timeouts on a peer that's gone, so drop its contact and let
+    // the next tick fail whatever we're still waiting on it for.
+    fn unreachable(&mut self, peer: SocketAddr) {
+        self.evict_addr(peer);
+        let now = self.clock.now();
+        for pending in self.pending.values_mut().filter(|p| p.peer == peer) {
+            pending.expires = pending.expires.min(now);
+        }
+    }
+
+    // Drops whoever we know at addr, and holds them out of the table for a while so they can't
+    // push straight back in.
+    fn evict_addr(&mut self, addr: SocketAddr) {
+        if let Some(victim) = self.known_peers.remove_addr(addr) {
+            self.churn.evicted(victim.id(), self.clock.now());
+        }
+    }
+
+    // Forgets the pings of any earlier bootstrap, so their answers are taken like any other packet,
+    // and probes each of the new seeds instead.
+    fn bootstrap(&mut self, mut seeds: Vec<SocketAddr>) {
+        let superseded: Vec<u64> = self
+            .pending
+            .iter()
+            .filter(|(_, p)| matches!(p.request, Request::Seed))
+            .map(|(seq_num, _)| *seq_num)
+            .collect();
+        for seq_num in superseded {
+            self.take_pending(seq_num);
+        }
+
+        seeds.sort();
+        seeds.dedup();
+        for &seed in seeds.iter() {
+            let expires = self.expires(seed);
+            self.send_request(seed, expires, Request::Seed, self.ping(seed));
+        }
+        self.seeds = seeds;
+    }
+
+    // Starts over with an empty routing table, keeping our ID and whatever we store.
+    fn reset(&mut self, seeds: Vec<SocketAddr>) {
+        let ops: Vec<u64> = self.ops.keys().copied().collect();
+        for id in ops {
+            self.remove_op(id).reply.fail(Error::Reset);
+        }
+        for op in self.queued_ops.drain(..) {
+            op.reply.fail(Error::Reset);
+        }
+        let pending: Vec<u64> = self.pending.keys().copied().collect();
+        for seq_num in pending {
+            if let Some(Pending {
+                request: Request::Ping(reply),
+                ..
+            }) = self.take_pending(seq_num)
+            {
+                reply.send(Err(Error::Reset)).ok();
+            }
+        }
+        self.conflicts.clear();
+        self.neighborhood.clear();
+        self.churn = Churn::default();
+
+        self.known_peers = KBuckets::new();
+        self.known_peers.set_eviction(self.config.eviction);
+        self.bootstrap(seeds);
+        self.reseeding = true;
+    }
+
+    // Removes an operation along with any of its requests still in flight.
+    fn remove_op(&mut self, id: u64) -> Op {
+        let requests: Vec<u64> = self
+            .pending
+            .iter()
+            .filter(|(_, p)| match p.request {
+                Request::Lookup(op) | Request::Store(op) => op == id,
+                Request::Ping(_)
+                | Request::VerifyAddr(_)
+                | Request::Verify
+                | Request::Seed
+                | Request::Evict(_) => false,
+            })
+            .map(|(seq_num, _)| *seq_num)
+            .collect();
+        for seq_num in requests {
+            self.take_pending(seq_num);
+        }
+        self.ops.remove(&id).unwrap()
+    }
+
+    fn take_pending(&mut self, seq_num: u64) -> Option<Pending> {
+        let pending = self.pending.remove(&seq_num)?;
+        self.verifying.remove(&seq_num);
+        if let Some(count) = self.outstanding.get_mut(&pending.peer) {
+            *count -= 1;
+            if *count == 0 {
+                self.outstanding.remove(&pending.peer);
+            }
+        }
+        Some(pending)
+    }
+
+    // Sends the lookup's next round of queries, or finishes it if it's done or cancelled.
+    fn advance(&mut self, id: u64) {
+        let op = match self.ops.get_mut(&id) {
+            Some(op) => op,
+            None => return,
+        };
+        if let Reply::FindNode(waiter) = &op.reply {
+            if waiter.is_cancelled() {
+                let op = self.remove_op(id);
+                self.lookup_event(op.lookup.target(), LookupEvent::Failed);
+                if let Reply::FindNode(waiter) = op.reply {
+                    waiter.resolve(LookupStatus::Cancelled);
+                }
+                return;
+            }
+        }
+        if op.storing.is_some() {
+            return;
+        }
+
+        if let Some(needs) = op.reply.query_needs() {
+            let known_peers = &self.known_peers;
+            let me = self.id;
+            op.lookup
+                .rule_out(|contact| !known_peers.supports(me, contact, needs));
+        }
+
+        // Leave peers already at their cap for later, counting the ones picked in this round too
+        let outstanding = &self.outstanding;
+        let cap = self.config.max_requests_per_peer;
+        let mut picked: HashMap<SocketAddr, usize> = HashMap::new();
+        let target = op.lookup.target();
+        let queries = op.lookup.next_queries(|contact| {
+            let picked = picked.entry(contact.addr()).or_default();
+            if outstanding.get(&contact.addr()).unwrap_or(&0) + *picked < cap {
+                *picked += 1;
+                true
+            } else {
+                false
+            }
+        });
+        if queries.is_empty() {
+            if op.lookup.is_done() {
+                self.finish_lookup(id);
+            }
+            return;
+        }
+
+        let query = match op.reply {
+            Reply::Get(_) | Reply::GetSigned(_) | Reply::Republish(..) => Payload::FindValue,
+            Reply::GetPeers(..) | Reply::Announce(..) => Payload::GetPeers,
+            Reply::GetAll(..) => Payload::FindValues,
+            _ => Payload::FindNode,
+        };
+        for contact in queries {
+            let expires = self.expires(contact.addr());
+            self.send_request(contact.addr(), expires, Request::Lookup(id), query(target));
+            self.lookup_event(target, LookupEvent::Queried(contact));
+        }
+    }
+
+    fn finish_lookup(&mut self, id: u64) {
+        let mut op = self.remove_op(id);
+        let closest = op.lookup.closest();
+        let stats = self.stats(&op);
+        self.lookup_event(op.lookup.target(), LookupEvent::Completed(closest.clone()));
+        match op.reply {
+            Reply::FindNode(waiter) if op.lookup.is_truncated() => {
+                waiter.resolve(LookupStatus::TruncatedByHopLimit(closest))
+            }
+            Reply::FindNode(waiter) => waiter.resolve(LookupStatus::Completed(closest)),
+            Reply::Get(reply) => {
+                // Anyone holding the value would have answered with it
+                reply.send(Ok((None, stats))).ok();
+            }
+            Reply::GetSigned(reply) => {
+                reply.send(Ok(None)).ok();
+            }
+            Reply::Put(value, reply) => {
+                if closest.is_empty() {
+                    // We don't know anyone, so we're the closest node there is
+                    let me = Ipv4Addr::LOCALHOST.into();
+                    let stored = match self.store_local(op.lookup.target(), value, me) {
+                        Ok(()) => 1,
+                        Err(_) => 0,
+                    };
+                    reply.send(Ok((stored, stats))).ok();
+                    return;
+                }
+
+                let targets: Vec<&Contact> = closest
+                    .iter()
+                    .filter(|c| self.known_peers.supports(self.id, c, value.needs()))
+                    .collect();
+                if targets.is_empty() {
+                    reply.send(Ok((0, stats))).ok();
+                    return;
+                }
+
+                for contact in targets.iter() {
+                    let expires = self.expires(contact.addr());
+                    let store = value.payload(op.lookup.target());
+                    self.send_request(contact.addr(), expires, Request::Store(id), store);
+                }
+                op.storing = Some((targets.len(), 0));
+                op.reply = Reply::Put(value, reply);
+                self.ops.insert(id, op);
+            }
+            Reply::GetPeers(peers, reply) => {
+                reply.send(Ok(peers)).ok();
+            }
+            Reply::GetAll(values, reply) => {
+                reply.send(Ok(values)).ok();
+            }
+            Reply::Announce(port, tokens, reply) => {
+                // Only the closest that answered, and so gave us a token
+                let tokens: Vec<(SocketAddr, Token)> = tokens
+                    .into_iter()
+                    .filter(|(addr, _)| closest.iter().any(|c| c.addr() == *addr))
+                    .collect();
+                if tokens.is_empty() {
+                    reply.send(Ok(0)).ok();
+                    return;
+                }
+
+                let key = op.lookup.target();
+                for (addr, token) in tokens.iter() {
+                    let expires = self.expires(*addr);
+                    let announce = Payload::Announce(key, port, *token);
+                    self.send_request(*addr, expires, Request::Store(id), announce);
+                }
+                op.storing = Some((tokens.len(), 0));
+                op.reply = Reply::Announce(port, tokens, reply);
+                self.ops.insert(id, op);
+            }
+            Reply::Neighborhood => self.neighborhood = closest,
+            Reply::Refresh => (),
+            Reply::Republish(value, holders) => {
+                let key = op.lookup.target();
+                let targets: Vec<&Contact> = closest
+                    .iter()
+                    .filter(|c| !holders.contains(&c.addr()))
+                    .filter(|c| self.known_peers.supports(self.id, c, value.needs()))
+                    .collect();
+                for contact in targets {
+                    self.send_packet(contact.addr(), 0, value.payload(key));
+                }
+            }
+        }
+    }
+
+    fn store_acked(&mut self, id: u64, accepted: bool) {
+        let done = match self.ops.get_mut(&id).and_then(|op| op.storing.as_mut()) {
+            Some((remaining, stored)) => {
+                *remaining -= 1;
+                if accepted {
+                    *stored += 1;
+                }
+                *remaining == 0
+            }
+            None => false,
+        };
+
+        if done {
+            let op = self.remove_op(id);
+            let stats = self.stats(&op);
+            match (op.reply, op.storing) {
+                (Reply::Put(_, reply), Some((_, stored))) => {
+                    reply.send(Ok((stored, stats))).ok();
+                }
+                (Reply::Announce(_, _, reply), Some((_, stored))) => {
+                    reply.send(Ok(stored)).ok();
+                }
+                _ => (),
+            }
+        }
+    }
+
+    // Answers everyone waiting for no more contacts than we now have.
+    fn wake_peer_waiters(&mut self) {
+        if self.peer_waiters.is_empty() {
+            return;
+        }
+        let count = self.known_peers.len();
+        let (ready, waiting) = self.peer_waiters.drain(..).partition(|w| w.min <= count);
+        self.peer_waiters = waiting;
+        for waiter in ready {
+            waiter.reply.send(Ok(count)).ok();
+        }
+    }
+
+    fn reap_cancelled(&mut self) {
+        let cancelled: Vec<u64> = self
+            .ops
+            .iter()
+            .filter(|(_, op)| match &op.reply {
+                Reply::FindNode(waiter) => waiter.is_cancelled(),
+                _ => false,
+            })
+            .map(|(id, _)| *id)
+            .collect();
+        for id in cancelled {
+            self.advance(id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ed25519_dalek::SigningKey;
+    use std::cell::{Cell, RefCell};
+    use std::rc::Rc;
+
+    fn new_kad() -> (Kad, channel::Receiver<(Packet, SocketAddr)>) {
+        let (kad, sent, _) = timed_kad(Config::default());
+        (kad, sent)
+    }
+
+    // A node whose clock only moves when the test moves it
+    fn timed_kad(config: Config) -> (Kad, channel::Receiver<(Packet, SocketAddr)>, ManualClock) {
+        let (send_tx, send_rx) = channel::unbounded();
+        let clock = ManualClock::new();
+        let kad = Kad::with_clock(
+            send_tx,
+            config,
+            Box::new(clock.clone()),
+            MemoryStore::default(),
+        );
+        (kad, send_rx, clock)
+    }
+
+    // A packet as a peer on the default network that doesn't sign would send it
+    fn packet(from: NodeID, seq_num: u64, payload: Payload) -> Packet {
+        Packet {
+            network_id: 0,
+            id: from,
+            seq_num,
+            payload,
+            signature: None,
+        }
+    }
+
+    // An unsolicited ping, as a peer supporting everything would send it
+    fn ping(from: NodeID) -> Packet {
+        packet(
+            from,
+            0,
+            Payload::PingExt(Extensions::new(Capabilities::ALL, None)),
+        )
+    }
+
+    fn pong(from: NodeID, seq_num: u64) -> Packet {
+        packet(
+            from,
+            seq_num,
+            Payload::PongExt(Extensions::new(Capabilities::ALL, None)),
+        )
+    }
+
+    #[test]
+    fn verify_learned_contacts() {
+        let config = Config {
+            lookup_alpha: 1,
+            ..Config::default()
+        };
+        let (mut kad, sent, _) = timed_kad(config);
+        let known = Contact::new(rand::random(), ([127, 0, 0, 1], 1).into());
+        kad.known_peers.insert(kad.id, known).unwrap();
+        let (_handle, waiter) = LookupHandle::new();
+        kad.handle_command(Command::FindNode(rand::random(), waiter));
+        let (query, _) = sent.try_recv().unwrap();
+
+        let learned: Vec<Contact> = (2..=3)
+            .map(|port| Contact::new(rand::random(), ([127, 0, 0, 1], port).into()))
+            .collect();
+        let response = packet(known.id(), query.seq_num, Payload::Nodes(learned.clone()));
+        kad.handle_packet(response, known.addr());
+
+        // The lookup asks one of them, and the other is sent a ping of its own
+        let sent: Vec<(Packet, SocketAddr)> = sent.try_iter().collect();
+        assert_eq!(sent.len(), 2);
+        let (ping, pinged) = sent
+            .iter()
+            .find(|(p, _)| matches!(p.payload, Payload::Ping | Payload::PingExt(_)))
+            .unwrap();
+        let pinged = learned.iter().find(|c| c.addr() == *pinged).unwrap();
+        assert!(learned
+            .iter()
+            .all(|c| kad.known_peers.get(kad.id, c.id()).is_none()));
+
+        let pong = pong(pinged.id(), ping.seq_num);
+        kad.handle_packet(pong, pinged.addr());
+        assert!(kad.known_peers.get(kad.id, pinged.id()).is_some());
+    }
+
+    #[test]
+    fn spoofed_leaving() {
+        let (mut kad, _sent) = new_kad();
+        let contact = Contact::new(rand::random(), ([127, 0, 0, 1], 1).into());
+        kad.known_peers.insert(kad.id, contact).unwrap();
+
+        // Someone else's ID from its address, or its ID from somewhere else, doesn't count
+        kad.handle_packet(packet(rand::random(), 0, Payload::Leaving), contact.addr());
+        let elsewhere = ([127, 0, 0, 2], 1).into();
+        kad.handle_packet(packet(contact.id(), 0, Payload::Leaving), elsewhere);
+        assert!(kad.known_peers.get(kad.id, contact.id()).is_some());
+
+        kad.handle_packet(packet(contact.id(), 0, Payload::Leaving), contact.addr());
+        assert!(kad.known_peers.get(kad.id, contact.id()).is_none());
+    }
+
+    #[test]
+    fn paced_leave() {
+        let (mut kad, sent, clock) = timed_kad(Config::default());
+        let contact = Contact::new(rand::random(), ([127, 0, 0, 1], 1).into());
+        kad.known_peers.insert(kad.id, contact).unwrap();
+        let held = HANDOFF_PER_TICK + 3;
+        for _ in 0..held {
+            kad.values
+                .put(rand::random(), vec![1], 0, clock.now())
+                .unwrap();
+        }
+
+        let (reply, handed_off) = channel::bounded(1);
+        kad.handle_command(Command::Leave(reply));
+        let stores = |sent: &channel::Receiver<(Packet, SocketAddr)>| {
+            let sent: Vec<Packet> = sent.try_iter().map(|(p, _)| p).collect();
+            let leaving = sent.iter().any(|p| matches!(p.payload, Payload::Leaving));
+            let stores = sent
+                .iter()
+                .filter(|p| matches!(p.payload, Payload::Store(..)));
+            (stores.count(), leaving)
+        };
+        assert_eq!(stores(&sent), (HANDOFF_PER_TICK, false));
+        assert!(handed_off.try_recv().is_err());
+
+        kad.handle_tick();
+        assert_eq!(stores(&sent), (held - HANDOFF_PER_TICK, true));
+        assert_eq!(handed_off.try_recv(), Ok(held));
+    }
+
+    #[test]
+    fn verify_per_answer() {
+        let config = Config {
+            lookup_alpha: 1,
+            ..Config::default()
+        };
+        let (mut kad, sent, _) = timed_kad(config);
+        let known = Contact::new(rand::random(), ([127, 0, 0, 1], 1).into());
+        kad.known_peers.insert(kad.id, known).unwrap();
+        let (_handle, waiter) = LookupHandle::new();
+        kad.handle_command(Command::FindNode(rand::random(), waiter));
+        let (query, _) = sent.try_recv().unwrap();
+
+        // However many an answer holds, only a few of them are pinged
+        let learned: Vec<Contact> = (2..=12)
+            .map(|port| Contact::new(rand::random(), ([127, 0, 0, 1], port).into()))
+            .collect();
+        let response = packet(known.id(), query.seq_num, Payload::Nodes(learned));
+        kad.handle_packet(response, known.addr());
+        let pings: Vec<(Packet, SocketAddr)> = sent
+            .try_iter()
+            .filter(|(p, _)| matches!(p.payload, Payload::Ping | Payload::PingExt(_)))
+            .collect();
+        assert_eq!(pings.len(), MAX_VERIFY_PER_ANSWER);
+        assert_eq!(kad.verifying.len(), MAX_VERIFY_PER_ANSWER);
+
+        // And each stops counting once it's answered
+        let (ping, to) = &pings[0];
+        kad.handle_packet(pong(rand::random(), ping.seq_num), *to);
+        assert_eq!(kad.verifying.len(), MAX_VERIFY_PER_ANSWER - 1);
+    }
+
+    #[test]
+    fn alpha_per_operation() {
+        let config = Config {
+            lookup_alpha: 5,
+            refresh_alpha: 1,
+            ..Config::default()
+        };
+        let (mut kad, sent, clock) = timed_kad(config);
+        for port in 1..=10 {
+            let id = NodeID::random_in_bucket(&mut rand::thread_rng(), kad.id, 0);
+            let contact = Contact::new(id, ([127, 0, 0, 1], port).into());
+            kad.known_peers.insert(kad.id, contact).unwrap();
+        }
+
+        let (reply, _result) = channel::bounded(1);
+        kad.handle_command(Command::Get {
+            key: rand::random(),
+            deadline: clock.now() + Duration::from_secs(10),
+            reply,
+        });
+        assert_eq!(sent.try_iter().count(), 5);
+
+        kad.handle_command(Command::RefreshBucket(0));
+        let queries: Vec<(Packet, SocketAddr)> = sent.try_iter().collect();
+        assert_eq!(queries.len(), 1);
+        assert!(matches!(queries[0].0.payload, Payload::FindNode(_)));
+    }
+
+    #[test]
+    fn responsibility() {
+        let (mut kad, _) = new_kad();
+        let mut rng = rand::thread_rng();
+        for port in 1..=K as u16 {
+            let id = NodeID::random_in_bucket(&mut rng, kad.id, 0);
+            let contact = Contact::new(id, ([127, 0, 0, 1], port).into());
+            kad.known_peers.insert(kad.id, contact).unwrap();
+        }
+
+        let (reply, responsibility) = channel::bounded(1);
+        kad.handle_command(Command::Responsibility(kad.id, reply));
+        assert_eq!(
+            responsibility.recv().unwrap(),
+            Responsibility {
+                rank: 0,
+                responsible: true
+            }
+        );
+
+        // The key is in the other half of the space, so every contact is closer to it than we
+        // are, and the rank counts all of them rather than stopping at K
+        let id = NodeID::random_in_bucket(&mut rng, kad.id, 1);
+        let contact = Contact::new(id, ([127, 0, 0, 1], K as u16 + 1).into());
+        kad.known_peers.insert(kad.id, contact).unwrap();
+        let far = kad.id ^ NodeID::max();
+        let (reply, responsibility) = channel::bounded(1);
+        kad.handle_command(Command::Responsibility(far, reply));
+        assert_eq!(
+            responsibility.recv().unwrap(),
+            Responsibility {
+                rank: K + 1,
+                responsible: false
+            }
+        );
+    }
+
+    #[test]
+    fn max_lookups() {
+        for overflow in [LookupOverflow::Queue, LookupOverflow::Fail] {
+            let config = Config {
+                max_lookups: Some(1),
+                lookup_overflow: overflow,
+                ..Config::default()
+            };
+            let (mut kad, sent, clock) = timed_kad(config);
+            for port in 1..=10 {
+                let contact = Contact::new(rand::random(), ([127, 0, 0, 1], port).into());
+                kad.known_peers.insert(kad.id, contact).unwrap();
+            }
+
+            let (first, first_result) = channel::bounded(1);
+            kad.handle_command(Command::Get {
+                key: rand::random(),
+                deadline: clock.now() + Duration::from_secs(1),
+                reply: first,
+            });
+            assert_eq!(sent.try_iter().count(), ALPHA);
+
+            let (second, second_result) = channel::bounded(1);
+            kad.handle_command(Command::Get {
+                key: rand::random(),
+                deadline: clock.now() + Duration::from_secs(10),
+                reply: second,
+            });
+            assert_eq!(sent.try_iter().count(), 0);
+
+            let (reply, counts) = channel::bounded(1);
+            kad.handle_command(Command::Lookups(reply));
+            match overflow {
+                LookupOverflow::Queue => {
+                    assert_eq!(counts.recv().unwrap(), (1, 1));
+                    assert!(second_result.try_recv().is_err());
+                }
+                LookupOverflow::Fail => {
+                    assert_eq!(counts.recv().unwrap(), (1, 0));
+                    assert!(matches!(
+                        second_result.try_recv(),
+                        Ok(Err(Error::TooManyLookups))
+                    ));
+                }
+            }
+
+            // Once the first times out, a queued lookup takes its slot
+            clock.advance(Duration::from_secs(1));
+            kad.handle_tick();
+            assert!(matches!(first_result.try_recv(), Ok(Err(Error::Timeout))));
+            let started = sent
+                .try_iter()
+                .filter(|(p, _)| matches!(p.payload, Payload::FindValue(_)))
+                .count();
+            match overflow {
+                LookupOverflow::Queue => assert_eq!(started, ALPHA),
+                LookupOverflow::Fail => assert_eq!(started, 0),
+            }
+        }
+    }
+
+    #[test]
+    fn unusable_addrs() {
+        let (mut kad, sent) = new_kad();
+        let responder = Contact::new(rand::random(), ([127, 0, 0, 1], 1).into());
+        kad.known_peers.insert(kad.id, responder).unwrap();
+        let (_handle, waiter) = LookupHandle::new();
+        kad.handle_command(Command::FindNode(rand::random(), waiter));
+        let (query, _) = sent.try_recv().unwrap();
+
+        let good = Contact::new(rand::random(), ([127, 0, 0, 1], 2).into());
+        let nodes = vec![
+            Contact::new(rand::random(), ([0, 0, 0, 0], 3).into()),
+            Contact::new(rand::random(), ([127, 0, 0, 1], 0).into()),
+            Contact::new(rand::random(), "[::]:3".parse().unwrap()),
+            Contact::new(rand::random(), ([255, 255, 255, 255], 3).into()),
+            Contact::new(rand::random(), ([224, 0, 0, 1], 3).into()),
+            Contact::new(rand::random(), "[ff02::1]:3".parse().unwrap()),
+            good,
+        ];
+        kad.handle_packet(
+            packet(responder.id(), query.seq_num, Payload::Nodes(nodes)),
+            responder.addr(),
+        );
+        let sent: Vec<(Packet, SocketAddr)> = sent.try_iter().collect();
+        assert!(!sent.is_empty());
+        assert!(sent.iter().all(|(_, to)| *to == good.addr()));
+    }
+
+    #[test]
+    fn private_addrs() {
+        let public: SocketAddr = ([203, 0, 113, 1], 1).into();
+        let private = [
+            SocketAddr::from(([10, 1, 2, 3], 2)),
+            ([192, 168, 0, 1], 2).into(),
+            ([169, 254, 0, 1], 2).into(),
+            ([127, 0, 0, 1], 2).into(),
+            "[fd00::1]:2".parse().unwrap(),
+            "[fe80::1]:2".parse().unwrap(),
+            "[::ffff:10.0.0.1]:2".parse().unwrap(),
+        ];
+        assert!(private.iter().all(|&addr| private_addr(addr)));
+        assert!(!private_addr(public));
+
+        for accept_private_addrs in [false, true] {
+            let config = Config {
+                accept_private_addrs,
+                ..Config::default()
+            };
+            let (mut kad, sent, _) = timed_kad(config);
+            let responder = Contact::new(rand::random(), public);
+            kad.known_peers.insert(kad.id, responder).unwrap();
+            let (_handle, waiter) = LookupHandle::new();
+            kad.handle_command(Command::FindNode(rand::random(), waiter));
+            let (query, _) = sent.try_recv().unwrap();
+
+            // Told about a node at a private address, and hearing from one directly
+            let told = Contact::new(rand::random(), private[0]);
+            kad.handle_packet(
+                packet(responder.id(), query.seq_num, Payload::Nodes(vec![told])),
+                responder.addr(),
+            );
+            let asked = sent.try_iter().any(|(_, to)| to == told.addr());
+            let sender: NodeID = rand::random();
+            kad.handle_packet(packet(sender, 1, Payload::Ping), private[1]);
+            let added = kad.known_peers.get(kad.id, sender).is_some();
+            assert_eq!(asked, accept_private_addrs);
+            assert_eq!(added, accept_private_addrs);
+            // It's answered either way
+            assert!(sent.try_iter().any(|(_, to)| to == private[1]));
+        }
+    }
+
+    #[test]
+    fn cancel_lookup() {
+        let (mut kad, sent) = new_kad();
+        for port in 1..=10 {
+            let contact = Contact::new(rand::random(), ([127, 0, 0, 1], port).into());
+            kad.known_peers.insert(kad.id, contact).unwrap();
+        }
+
+        let (handle, waiter) = LookupHandle::new();
+        kad.handle_command(Command::FindNode(rand::random(), waiter));
+
+        let queries: Vec<(Packet, SocketAddr)> = sent.try_iter().collect();
+        assert_eq!(queries.len(), ALPHA);
+
+        handle.cancel();
+
+        // A response naming new nodes would normally trigger more queries
+        let (query, peer) = &queries[0];
+        let nodes = (0..K)
+            .map(|i| Contact::new(rand::random(), ([127, 0, 0, 2], i as u16 + 1).into()))
+            .collect();
+        let responder = kad
+            .known_peers
+            .closest(kad.id, K)
+            .into_iter()
+            .find(|c| c.addr() == *peer)
+            .unwrap();
+        kad.handle_packet(
+            packet(responder.id(), query.seq_num, Payload::Nodes(nodes)),
+            *peer,
+        );
+
+        assert!(sent
+            .try_iter()
+            .all(|(p, _)| !matches!(p.payload, Payload::FindNode(_))));
+        assert_eq!(handle.wait(), LookupStatus::Cancelled);
+        assert!(kad.ops.is_empty());
+        // All that's left is checking up on the nodes it was told about
+        assert!(kad
+            .pending
+            .values()
+            .all(|p| matches!(p.request, Request::Verify)));
+    }
+
+    #[test]
+    fn cancel_then_expire() {
+        let (mut kad, sent, clock) = timed_kad(Config::default());
+        for port in 1..=3 {
+            let contact = Contact::new(rand::random(), ([127, 0, 0, 1], port).into());
+            kad.known_peers.insert(kad.id, contact).unwrap();
+        }
+        let (handle, waiter) = LookupHandle::new();
+        kad.handle_command(Command::FindNode(rand::random(), waiter));
+        assert_eq!(sent.try_iter().count(), 3);
+        handle.cancel();
+
+        // The first query to time out ends the cancelled lookup, taking the others with it
+        clock.advance(REQUEST_TIMEOUT * 2);
+        kad.handle_tick();
+        assert_eq!(handle.wait(), LookupStatus::Cancelled);
+        assert!(kad.ops.is_empty() && kad.pending.is_empty());
+    }
+
+    #[test]
+    fn announce_many_peers() {
+        let (mut kad, sent) = new_kad();
+        let infohash: NodeID = rand::random();
+
+        let peers: Vec<SocketAddr> = (1..=5)
+            .map(|i| ([10, 0, 0, i], 7000 + u16::from(i)).into())
+            .collect();
+        for (seq_num, peer) in peers.iter().enumerate() {
+            kad.handle_packet(
+                packet(
+                    rand::random(),
+                    seq_num as u64,
+                    Payload::Announce(infohash, 6881, kad.tokens.issue(peer.ip(), infohash)),
+                ),
+                *peer,
+            );
+        }
+        let acks: Vec<(Packet, SocketAddr)> = sent.try_iter().collect();
+        assert_eq!(acks.len(), peers.len());
+        assert!(acks
+            .iter()
+            .all(|(p, _)| matches!(p.payload, Payload::StoreAck(Ok(())))));
+
+        // A token given to someone else doesn't count
+        let impostor: SocketAddr = ([10, 0, 2, 1], 7000).into();
+        kad.handle_packet(
+            packet(
+                rand::random(),
+                99,
+                Payload::Announce(infohash, 6881, kad.tokens.issue(peers[0].ip(), infohash)),
+            ),
+            impostor,
+        );
+        let (ack, _) = sent.try_recv().unwrap();
+        assert!(matches!(
+            ack.payload,
+            Payload::StoreAck(Err(Rejection::BadToken))
+        ));
+        // Nor does one given for another key
+        let other = kad.tokens.issue(peers[0].ip(), rand::random());
+        kad.handle_packet(
+            packet(rand::random(), 98, Payload::Announce(infohash, 6882, other)),
+            peers[0],
+        );
+        let (ack, _) = sent.try_recv().unwrap();
+        assert!(matches!(
+            ack.payload,
+            Payload::StoreAck(Err(Rejection::BadToken))
+        ));
+
+        let asker = ([10, 0, 1, 1], 7000).into();
+        kad.handle_packet(
+            packet(rand::random(), 100, Payload::GetPeers(infohash)),
+            asker,
+        );
+
+        let (reply, to) = sent.try_recv().unwrap();
+        assert_eq!(to, asker);
+        assert_eq!(reply.seq_num, 100);
+        match reply.payload {
+            Payload::Peers(mut got, nodes, token) => {
+                assert!(nodes.is_empty());
+                assert!(kad.tokens.check(asker.ip(), infohash, token));
+                let mut expected: Vec<SocketAddr> = peers
+                    .iter()
+                    .map(|p| SocketAddr::new(p.ip(), 6881))
+                    .collect();
+                got.sort();
+                expected.sort();
+                assert_eq!(got, expected);
+            }
+            p => panic!("expected Peers, got {:?}", p),
+        }
+    }
+
+    #[test]
+    fn warm_up_pings_each_bucket() {
+        let (mut kad, sent) = new_kad();
+        kad.id = NodeID::zero();
+
+        // Two full buckets (sharing 0 and 1 bits with us), which forces a third to split off
+        let mut port = 0;
+        for (shared, count) in [(0, K), (1, K), (2, 1)].iter() {
+            for _ in 0..*count {
+                port += 1;
+                let mut id = NodeID::zero();
+                id.bytes[0] = 0x80 >> shared;
+                id.bytes[KEY_BYTES - 1] = port as u8;
+                let contact = Contact::new(id, ([127, 0, 0, 1], port).into());
+                kad.known_peers.insert(kad.id, contact).unwrap();
+            }
+        }
+
+        kad.handle_command(Command::WarmUp);
+
+        let mut pinged: Vec<u16> = sent
+            .try_iter()
+            .map(|(p, addr)| {
+                assert!(matches!(p.payload, Payload::Ping | Payload::PingExt(_)));
+                addr.port()
+            })
+            .collect();
+        pinged.sort();
+        // The first contact inserted into each bucket is the least recently seen
+        assert_eq!(pinged, vec![1, K as u16 + 1, 2 * K as u16 + 1]);
+    }
+
+    #[test]
+    fn get_deadline() {
+        let (mut kad, sent, clock) = timed_kad(Config::default());
+        let silent = Contact::new(rand::random(), ([127, 0, 0, 1], 1).into());
+        kad.known_peers.insert(kad.id, silent).unwrap();
+
+        let start = clock.now();
+        let (reply, result) = channel::bounded(1);
+        kad.handle_command(Command::Get {
+            key: rand::random(),
+            deadline: start + Duration::from_millis(1),
+            reply,
+        });
+        assert!(matches!(
+            sent.try_recv().unwrap().0.payload,
+            Payload::FindValue(_)
+        ));
+
+        // The peer never answers, but the deadline passes well before its request would time out
+        clock.advance(Duration::from_millis(2));
+        kad.handle_tick();
+        assert!(matches!(result.try_recv(), Ok(Err(Error::Timeout))));
+        assert!(kad.ops.is_empty());
+        assert!(kad.pending.is_empty());
+    }
+
+    #[test]
+    fn interleaved_puts() {
+        let (mut kad, sent) = new_kad();
+        kad.config.max_requests_per_peer = 8;
+        let storer = Contact::new(rand::random(), ([127, 0, 0, 1], 1).into());
+        kad.known_peers.insert(kad.id, storer).unwrap();
+
+        let start = kad.clock.now();
+        let results: Vec<channel::Receiver<Result<(usize, LookupStats), Error>>> = (0..8)
+            .map(|_| {
+                let (reply, result) = channel::bounded(1);
+                kad.handle_command(Command::Put {
+                    key: rand::random(),
+                    value: b"value".to_vec(),
+                    version: 0,
+                    deadline: start + Duration::from_secs(10),
+                    reply,
+                });
+                result
+            })
+            .collect();
+
+        // Every put has a query out before anything answers. Answering them newest first finishes
+        // the newest lookups first, so their stores go out first too.
+        let queries: Vec<(Packet, SocketAddr)> = sent.try_iter().collect();
+        assert_eq!(queries.len(), results.len());
+        for (query, _) in queries.into_iter().rev() {
+            let response = packet(storer.id(), query.seq_num, Payload::Nodes(Vec::new()));
+            kad.handle_packet(response, storer.addr());
+        }
+        assert!(results.iter().all(|r| r.is_empty()));
+
+        let stores: Vec<(Packet, SocketAddr)> = sent.try_iter().collect();
+        assert_eq!(stores.len(), results.len());
+        for (i, (store, _)) in stores.into_iter().enumerate() {
+            assert!(matches!(store.payload, Payload::Store(..)));
+            let ack = packet(storer.id(), store.seq_num, Payload::StoreAck(Ok(())));
+            kad.handle_packet(ack, storer.addr());
+
+            // None of them waited on the ones started before
+            let newest = results.len() - 1 - i;
+            assert!(matches!(results[newest].try_recv(), Ok(Ok((1, _)))));
+            assert!(results[..newest].iter().all(|r| r.is_empty()));
+        }
+        assert!(kad.ops.is_empty());
+    }
+
+    #[test]
+    fn per_peer_cap() {
+        let (mut kad, sent) = new_kad();
+        let cap = kad.config.max_requests_per_peer;
+        let peer = Contact::new(rand::random(), ([127, 0, 0, 1], 1).into());
+        kad.known_peers.insert(kad.id, peer).unwrap();
+
+        let handles: Vec<LookupHandle> = (0..cap + 2)
+            .map(|_| {
+                let (handle, waiter) = LookupHandle::new();
+                kad.handle_command(Command::FindNode(rand::random(), waiter));
+                handle
+            })
+            .collect();
+        let at_peer = |kad: &Kad| {
+            kad.pending
+                .values()
+                .filter(|p| p.peer == peer.addr())
+                .count()
+        };
+        assert_eq!(at_peer(&kad), cap);
+        assert_eq!(kad.outstanding[&peer.addr()], cap);
+
+        // Each answer frees a slot, which a waiting lookup takes on the next tick
+        let mut queries = VecDeque::new();
+        for _ in 0..handles.len() {
+            queries.extend(sent.try_iter());
+            let (query, _) = queries.pop_front().unwrap();
+            let response = packet(peer.id(), query.seq_num, Payload::Nodes(Vec::new()));
+            kad.handle_packet(response, peer.addr());
+            kad.handle_tick();
+            assert!(at_peer(&kad) <= cap);
+        }
+        assert!(kad.ops.is_empty());
+        assert!(kad.outstanding.is_empty());
+        for handle in handles {
+            assert_eq!(handle.wait(), LookupStatus::Completed(vec![peer]));
+        }
+    }
+
+    #[test]
+    fn clock_expiry() {
+        let (mut kad, _sent, clock) = timed_kad(Config::default());
+        let ttl = kad.config.value_ttl.unwrap();
+
+        let key: NodeID = rand::random();
+        let store = packet(rand::random(), 1, Payload::Store(key, b"value".to_vec(), 0));
+        kad.handle_packet(store, ([127, 0, 0, 1], 1).into());
+
+        let (reply, result) = channel::bounded(1);
+        kad.handle_command(Command::PingWait {
+            peer: ([127, 0, 0, 1], 2).into(),
+            deadline: clock.now() + REQUEST_TIMEOUT * 2,
+            reply,
+        });
+
+        // Nothing is due until the clock says so, however long the test takes
+        kad.handle_tick();
+        assert!(result.is_empty());
+        assert!(kad.values.get(key).is_some());
+
+        // A ping waits as long as it was asked to, not just as long as we'd wait ourselves
+        clock.advance(REQUEST_TIMEOUT);
+        kad.handle_tick();
+        assert!(result.is_empty());
+        clock.advance(REQUEST_TIMEOUT);
+        kad.handle_tick();
+        assert!(matches!(result.try_recv(), Ok(Err(Error::Timeout))));
+        assert!(kad.values.get(key).is_some());
+
+        clock.advance(ttl);
+        kad.handle_tick();
+        assert!(kad.values.get(key).is_none());
+    }
+
+    #[test]
+    fn signed_ttl_refresh() {
+        let (mut kad, sent, clock) = timed_kad(Config::default());
+        let ttl = kad.config.value_ttl.unwrap();
+        let value = SignedValue::sign(&SigningKey::from_bytes(&rand::random()), 3, Vec::new());
+        assert_eq!(store_signed(&mut kad, &sent, value.clone()), Ok(()));
+
+        // Storing it again unchanged starts its time over, without needing a new sequence number
+        clock.advance(ttl / 2);
+        assert_eq!(store_signed(&mut kad, &sent, value.clone()), Ok(()));
+        clock.advance(ttl / 2 + Duration::from_secs(1));
+        kad.handle_tick();
+        assert_eq!(kad.signed.get(value.key()), Some(&value));
+
+        clock.advance(ttl);
+        kad.handle_tick();
+        assert_eq!(kad.signed.get(value.key()), None);
+    }
+
+    // Keeps values where the test can see them, along with which of its methods were called
+    #[derive(Clone, Default)]
+    struct SharedStore {
+        values: Rc<RefCell<HashMap<NodeID, StoredValue>>>,
+        calls: Rc<RefCell<Vec<&'static str>>>,
+        // Turns every put away
+        full: Rc<Cell<bool>>,
+    }
+
+    impl Store for SharedStore {
+        fn put(&mut self, key: NodeID, value: StoredValue) -> Result<(), StoreError> {
+            self.calls.borrow_mut().push("put");
+            if self.full.get() {
+                return Err(StoreError::Full);
+            }
+            self.values.borrow_mut().insert(key, value);
+            Ok(())
+        }
+
+        fn get(&self, key: NodeID) -> Option<StoredValue> {
+            self.calls.borrow_mut().push("get");
+            self.values.borrow().get(&key).cloned()
+        }
+
+        fn remove(&mut self, key: NodeID) -> Option<StoredValue> {
+            self.calls.borrow_mut().push("remove");
+            self.values.borrow_mut().remove(&key)
+        }
+
+        fn iter(&self) -> Box<dyn Iterator<Item = (NodeID, StoredValue)> + '_> {
+            self.calls.borrow_mut().push("iter");
+            let values: Vec<_> = self.values.borrow().clone().into_iter().collect();
+            Box::new(values.into_iter())
+        }
+
+        fn keys(&self) -> Box<dyn Iterator<Item = NodeID> + '_> {
+            self.calls.borrow_mut().push("keys");
+            let keys: Vec<_> = self.values.borrow().keys().copied().collect();
+            Box::new(keys.into_iter())
+        }
+
+        fn retain(&mut self, keep: &mut dyn FnMut(NodeID, &StoredValue) -> bool) {
+            self.calls.borrow_mut().push("retain");
+            self.values
+                .borrow_mut()
+                .retain(|key, value| keep(*key, value));
+        }
+
+        fn len(&self) -> usize {
+            self.values.borrow().len()
+        }
+    }
+
+    #[test]
+    fn skip_incapable_peers() {
+        let (mut kad, sent, clock) = timed_kad(Config::default());
+        let capable = Contact::new(rand::random(), ([127, 0, 0, 1], 1).into());
+        let incapable = Contact::new(rand::random(), ([127, 0, 0, 1], 2).into());
+        for contact in [capable, incapable] {
+            kad.known_peers.insert(kad.id, contact).unwrap();
+        }
+        let pong = Payload::PongExt(Extensions::new(Capabilities::PEERS, None));
+        kad.handle_packet(packet(incapable.id(), 0, pong), incapable.addr());
+
+        let (reply, result) = channel::bounded(1);
+        kad.handle_command(Command::Put {
+            key: rand::random(),
+            value: b"value".to_vec(),
+            version: 0,
+            deadline: clock.now() + Duration::from_secs(10),
+            reply,
+        });
+        // Anyone can be asked to find nodes
+        let queries: Vec<(Packet, SocketAddr)> = sent.try_iter().collect();
+        assert_eq!(queries.len(), 2);
+        for (query, to) in queries {
+            let id = if to == capable.addr() {
+                capable.id()
+            } else {
+                incapable.id()
+            };
+            kad.handle_packet(packet(id, query.seq_num, Payload::Nodes(Vec::new())), to);
+        }
+
+        let stores: Vec<(Packet, SocketAddr)> = sent.try_iter().collect();
+        assert_eq!(stores.len(), 1);
+        let (store, to) = &stores[0];
+        assert_eq!(*to, capable.addr());
+        assert!(matches!(store.payload, Payload::Store(..)));
+
+        let ack = Payload::StoreAck(Ok(()));
+        kad.handle_packet(packet(capable.id(), store.seq_num, ack), capable.addr());
+        assert!(matches!(result.try_recv(), Ok(Ok((1, _)))));
+
+        // Nor is it asked to find a value
+        let (reply, _result) = channel::bounded(1);
+        kad.handle_command(Command::Get {
+            key: rand::random(),
+            deadline: clock.now() + Duration::from_secs(10),
+            reply,
+        });
+        let queries: Vec<(Packet, SocketAddr)> = sent.try_iter().collect();
+        assert_eq!(queries.len(), 1);
+        assert_eq!(queries[0].1, capable.addr());
+    }
+
+    #[test]
+    fn verified_by_answer() {
+        let (mut kad, sent, clock) = timed_kad(Config::default());
+        let contact = Contact::new(rand::random(), ([127, 0, 0, 1], 1).into());
+
+        // Anyone can send a ping as it, so that only gets it in the table
+        kad.handle_packet(ping(contact.id()), contact.addr());
+        assert!(!kad
+            .known_peers
+            .get(kad.id, contact.id())
+            .unwrap()
+            .is_verified());
+        sent.try_iter().for_each(drop);
+
+        // Answering a ping of ours, sent to its address, shows it's really there
+        let (reply, _result) = channel::bounded(1);
+        kad.handle_command(Command::PingWait {
+            peer: contact.addr(),
+            deadline: clock.now() + Duration::from_secs(1),
+            reply,
+        });
+        let (request, _) = sent.try_recv().unwrap();
+        kad.handle_packet(pong(contact.id(), request.seq_num + 1), contact.addr());
+        assert!(!kad
+            .known_peers
+            .get(kad.id, contact.id())
+            .unwrap()
+            .is_verified());
+        kad.handle_packet(pong(contact.id(), request.seq_num), contact.addr());
+        assert!(kad
+            .known_peers
+            .get(kad.id, contact.id())
+            .unwrap()
+            .is_verified());
+    }
+
+    #[test]
+    fn old_node_ping() {
+        let (mut kad, sent) = new_kad();
+        let old = Contact::new(rand::random(), ([127, 0, 0, 1], 1).into());
+        kad.handle_packet(packet(old.id(), 3, Payload::Ping), old.addr());
+
+        // Answered the way it asked, then asked once what it supports
+        let (pong, to) = sent.try_recv().unwrap();
+        assert!(matches!(pong.payload, Payload::Pong) && pong.seq_num == 3);
+        assert_eq!(to, old.addr());
+        let (ask, _) = sent.try_recv().unwrap();
+        assert!(matches!(ask.payload, Payload::PingExt(_)));
+        kad.handle_packet(packet(old.id(), 4, Payload::Ping), old.addr());
+        assert!(matches!(sent.try_recv().unwrap().0.payload, Payload::Pong));
+        assert!(sent.try_recv().is_err());
+
+        // It never answers, so it's still pinged the old way
+        assert!(matches!(kad.ping(old.addr()), Payload::Ping));
+        let new = Contact::new(rand::random(), ([127, 0, 0, 1], 2).into());
+        kad.handle_packet(ping(new.id()), new.addr());
+        assert!(matches!(kad.ping(new.addr()), Payload::PingExt(_)));
+    }
+
+    #[test]
+    fn unknown_payload() {
+        let (mut kad, sent) = new_kad();
+        let id: NodeID = rand::random();
+        let peer: SocketAddr = ([127, 0, 0, 1], 1).into();
+        let pack = packet(id, 1, Payload::Unknown(99));
+        kad.handle_packet(pack, peer);
+        assert_eq!(
+            kad.known_peers.get(kad.id, id).map(|c| c.addr()),
+            Some(peer)
+        );
+        assert!(sent.try_recv().is_err());
+    }
+
+    #[test]
+    fn strict_responses() {
+        let peer: SocketAddr = ([10, 0, 0, 1], 1).into();
+        let id = NodeID::from_key(b"peer");
+
+        // Left alone, unsolicited responses still tell us of their sender
+        let (mut kad, sent, _) = timed_kad(Config::default());
+        for seq_num in 1..=u64::from(SUSPICION_LIMIT) {
+            kad.handle_packet(pong(id, seq_num), peer);
+        }
+        assert_eq!(kad.known_peers.len(), 1);
+        kad.handle_packet(ping(id), peer);
+        assert!(sent.try_recv().is_ok());
+
+        // Unauthenticated packets could have come from anywhere, so they're dropped but never
+        // held against their IP
+        let config = Config {
+            strict_responses: true,
+            ..Config::default()
+        };
+        let (mut kad, sent, _) = timed_kad(config.clone());
+        for seq_num in 1..=u64::from(SUSPICION_LIMIT) {
+            kad.handle_packet(pong(id, seq_num), peer);
+        }
+        assert_eq!(kad.known_peers.len(), 0);
+        kad.handle_packet(ping(id), peer);
+        assert!(sent.try_recv().is_ok());
+
+        let config = Config {
+            psk: Some([1; 32]),
+            ..config
+        };
+        let (mut kad, sent, clock) = timed_kad(config);
+        let (watch, anomalies) = channel::unbounded();
+        kad.handle_command(Command::WatchAnomalies(watch));
+        for seq_num in 1..u64::from(SUSPICION_LIMIT) {
+            kad.handle_packet(pong(id, seq_num), peer);
+        }
+        assert_eq!(kad.known_peers.len(), 0);
+        // Answers to requests we don't wait on never count
+        for _ in 0..SUSPICION_LIMIT {
+            kad.handle_packet(pong(id, 0), peer);
+        }
+        kad.handle_packet(ping(id), peer);
+        assert!(sent.try_recv().is_ok());
+        assert!(anomalies.try_recv().is_err());
+
+        // One more and it's banned, ping or not, though other IPs aren't
+        kad.handle_packet(pong(id, 100), peer);
+        assert_eq!(anomalies.try_recv(), Ok(Anomaly::Banned(peer.ip())));
+        kad.handle_packet(ping(id), peer);
+        assert!(sent.try_recv().is_err());
+        kad.handle_packet(ping(id), ([10, 0, 0, 2], 1).into());
+        assert!(sent.try_recv().is_ok());
+
+        // Until the ban runs out
+        clock.advance(BAN_DURATION);
+        kad.handle_tick();
+        kad.handle_packet(ping(id), peer);
+        assert!(sent.try_iter().any(|(_, to)| to == peer));
+    }
+
+    #[test]
+    fn reflected_packet() {
+        let (mut kad, sent, clock) = timed_kad(Config::default());
+        let peer: SocketAddr = ([127, 0, 0, 1], 1).into();
+        let (reply, result) = channel::bounded(1);
+        kad.handle_command(Command::PingWait {
+            peer,
+            deadline: clock.now() + Duration::from_secs(10),
+            reply,
+        });
+        let (ping, _) = sent.try_recv().unwrap();
+
+        // The ping comes back to us, and so would our answer to it
+        for payload in [Payload::Ping, Payload::Pong] {
+            let reflected = packet(kad.id, ping.seq_num, payload);
+            kad.handle_packet(reflected, peer);
+        }
+        assert!(sent.try_recv().is_err());
+        assert!(result.try_recv().is_err());
+        assert!(kad.pending.contains_key(&ping.seq_num));
+        assert_eq!(kad.known_peers.len(), 0);
+    }
+
+    #[test]
+    fn custom_store() {
+        let (send_tx, sent) = channel::unbounded();
+        let clock = ManualClock::new();
+        let store = SharedStore::default();
+        let mut kad = Kad::with_clock(
+            send_tx,
+            Config::default(),
+            Box::new(clock.clone()),
+            store.clone(),
+        );
+        let ttl = kad.config.value_ttl.unwrap();
+        let peer: SocketAddr = ([127, 0, 0, 1], 1).into();
+        let key: NodeID = rand::random();
+
+        kad.handle_packet(
+            packet(rand::random(), 1, Payload::Store(key, b"value".to_vec(), 0)),
+            peer,
+        );
+        assert_eq!(store.len(), 1);
+        assert_eq!(store.values.borrow()[&key].value, b"value".to_vec());
+
+        store.calls.borrow_mut().clear();
+        kad.handle_packet(packet(rand::random(), 2, Payload::FindValue(key)), peer);
+        assert_eq!(*store.calls.borrow(), ["get"]);
+        assert!(sent
+            .try_iter()
+            .any(|(p, _)| matches!(p.payload, Payload::Value(ref v) if v == b"value")));
+
+        store.calls.borrow_mut().clear();
+        let (reply, pairs) = channel::bounded(1);
+        kad.handle_command(Command::ExportStore(reply));
+        assert_eq!(pairs.recv().unwrap(), vec![(key, b"value".to_vec())]);
+        assert_eq!(*store.calls.borrow(), ["iter"]);
+
+        store.calls.borrow_mut().clear();
+        clock.advance(ttl + Duration::from_secs(1));
+        kad.handle_tick();
+        // Without reading every value out to do it
+        assert!(store.calls.borrow().contains(&"retain"));
+        assert!(!store.calls.borrow().contains(&"iter"));
+        assert!(store.is_empty());
+
+        // The sender hears about a store that couldn't keep its value
+        store.full.set(true);
+        sent.try_iter().for_each(drop);
+        kad.handle_packet(
+            packet(rand::random(), 3, Payload::Store(key, b"value".to_vec(), 0)),
+            peer,
+        );
+        assert!(store.is_empty());
+        let (ack, _) = sent.try_recv().unwrap();
+        assert!(matches!(
+            ack.payload,
+            Payload::StoreAck(Err(Rejection::StoreFailed))
+        ));
+    }
+
+    #[test]
+    fn adaptive_timeout() {
+        let (mut kad, sent, clock) = timed_kad(Config::default());
+        let fast: SocketAddr = ([127, 0, 0, 1], 1).into();
+        let slow: SocketAddr = ([127, 0, 0, 1], 2).into();
+        let unknown: SocketAddr = ([127, 0, 0, 1], 3).into();
+        for &(peer, rtt) in [(fast, 100), (slow, 1000)].iter() {
+            let id = rand::random();
+            kad.known_peers
+                .insert(kad.id, Contact::new(id, peer))
+                .unwrap();
+            for _ in 0..10 {
+                let (reply, _result) = channel::bounded(1);
+                kad.handle_command(Command::PingWait {
+                    peer,
+                    deadline: clock.now() + Duration::from_secs(60),
+                    reply,
+                });
+                let (ping, _) = sent.try_recv().unwrap();
+                clock.advance(Duration::from_millis(rtt));
+                let pong = pong(id, ping.seq_num);
+                kad.handle_packet(pong, peer);
+            }
+        }
+
+        let timeout = |peer| kad.expires(peer) - clock.now();
+        let fast_timeout = kad.known_peers.by_addr(fast).unwrap().timeout().unwrap();
+        let slow_timeout = kad.known_peers.by_addr(slow).unwrap().timeout().unwrap();
+        assert!(slow_timeout > fast_timeout * 4);
+        assert_eq!(timeout(fast), fast_timeout);
+        assert!(timeout(fast) < REQUEST_TIMEOUT / 2);
+        assert!(timeout(slow) > timeout(fast) * 3);
+        assert_eq!(timeout(unknown), REQUEST_TIMEOUT);
+    }
+
+    #[test]
+    fn ping_before_evicting() {
+        let (mut kad, sent, clock) = timed_kad(Config::default());
+        let me = kad.id;
+        let far = |port| {
+            let id = NodeID::random_in_bucket(&mut rand::thread_rng(), me, 0);
+            Contact::new(id, ([127, 0, 0, 1], port).into())
+        };
+        let full: Vec<Contact> = (1..=K as u16).map(far).collect();
+        for contact in full.iter() {
+            kad.known_peers.insert(kad.id, *contact).unwrap();
+        }
+
+        // Nobody in the full bucket has been heard from, so the first is the one to go
+        let newcomer = far(100);
+        let ping = ping(newcomer.id());
+        kad.handle_packet(ping, newcomer.addr());
+        let challenged: Vec<SocketAddr> = sent
+            .try_iter()
+            .filter(|(pack, _)| matches!(pack.payload, Payload::Ping | Payload::PingExt(_)))
+            .map(|(_, to)| to)
+            .collect();
+        assert_eq!(challenged, vec![full[0].addr()]);
+        assert!(kad.known_peers.get(kad.id, newcomer.id()).is_none());
+
+        clock.advance(REQUEST_TIMEOUT);
+        kad.handle_tick();
+        assert!(kad.known_peers.get(kad.id, full[0].id()).is_none());
+        assert!(kad.known_peers.get(kad.id, newcomer.id()).is_some());
+        assert_eq!(kad.known_peers.len(), K);
+    }
+
+    #[test]
+    fn eviction_cooldown() {
+        let (mut kad, sent, clock) = timed_kad(Config::default());
+        let me = kad.id;
+        let far = |port| {
+            let id = NodeID::random_in_bucket(&mut rand::thread_rng(), me, 0);
+            Contact::new(id, ([127, 0, 0, 1], port).into())
+        };
+        let full: Vec<Contact> = (1..=K as u16).map(far).collect();
+        for contact in full.iter() {
+            kad.known_peers.insert(kad.id, *contact).unwrap();
+        }
+        let challenged = || -> Vec<SocketAddr> {
+            sent.try_iter()
+                .filter(|(pack, _)| matches!(pack.payload, Payload::Ping | Payload::PingExt(_)))
+                .map(|(_, to)| to)
+                .collect()
+        };
+
+        let newcomer = far(100);
+        kad.handle_packet(ping(newcomer.id()), newcomer.addr());
+        assert_eq!(challenged(), vec![full[0].addr()]);
+        clock.advance(REQUEST_TIMEOUT);
+        kad.handle_tick();
+        assert!(kad.known_peers.get(kad.id, full[0].id()).is_none());
+        sent.try_iter().count();
+
+        // Offering itself straight back gets it nowhere, not even a challenge
+        kad.handle_packet(ping(full[0].id()), full[0].addr());
+        assert!(kad.known_peers.get(kad.id, full[0].id()).is_none());
+        assert!(challenged().is_empty());
+
+        // Nor does the newcomer get pushed straight back out
+        assert!(kad.churn.cooling(full[0].id(), clock.now()));
+        assert!(!kad.churn.settled(newcomer.id(), clock.now()));
+
+        // Once the cooldown is over it can challenge for a place again
+        clock.advance(EVICTION_COOLDOWN);
+        kad.handle_tick();
+        sent.try_iter().count();
+        kad.handle_packet(ping(full[0].id()), full[0].addr());
+        assert_eq!(challenged(), vec![full[1].addr()]);
+    }
+
+    #[test]
+    fn own_addr() {
+        let (mut kad, sent) = new_kad();
+        kad.set_own_addrs(vec![([0, 0, 0, 0], 4000).into()]);
+        let me: SocketAddr = ([127, 0, 0, 1], 4000).into();
+
+        let (reply, result) = channel::bounded(1);
+        kad.handle_command(Command::PingWait {
+            peer: me,
+            deadline: Instant::now() + Duration::from_secs(60),
+            reply,
+        });
+        assert_eq!(sent.try_iter().count(), 0);
+
+        // Nor do we take ourselves in under some other ID, however we hear about it
+        kad.handle_command(Command::Seed(vec![Contact::new(rand::random(), me)]));
+        let ping = ping(rand::random());
+        kad.handle_packet(ping, me);
+        assert_eq!(sent.try_iter().count(), 0);
+        assert!(kad.known_peers.contacts().is_empty());
+
+        // Same port on another interface is someone else
+        let other: SocketAddr = ([10, 0, 0, 1], 4000).into();
+        kad.handle_command(Command::Seed(vec![Contact::new(rand::random(), other)]));
+        assert_eq!(
+            sent.try_iter().map(|(_, to)| to).collect::<Vec<_>>(),
+            vec![other]
+        );
+        assert!(result.try_recv().is_err());
+    }
+
+    #[test]
+    fn refresh_bucket() {
+        let (mut kad, sent) = new_kad();
+        for port in 1..=3 {
+            let id = NodeID::random_in_bucket(&mut rand::thread_rng(), kad.id, 0);
+            let contact = Contact::new(id, ([127, 0, 0, 1], port).into());
+            kad.known_peers.insert(kad.id, contact).unwrap();
+        }
+
+        kad.handle_command(Command::RefreshBucket(0));
+        let queries: Vec<(Packet, SocketAddr)> = sent.try_iter().collect();
+        assert_eq!(queries.len(), 3);
+        for (pack, _) in queries {
+            match pack.payload {
+                Payload::FindNode(target) => assert_eq!(target.common_prefix_len(kad.id), 0),
+                payload => panic!("expected a FindNode, got {:?}", payload),
+            }
+        }
+
+        // Nobody in these
+        kad.handle_command(Command::RefreshBucket(KEY_BITS));
+        assert_eq!(sent.try_iter().count(), 0);
+        kad.handle_command(Command::RefreshBucket(1));
+        assert_eq!(sent.try_iter().count(), 0);
+        // Only bucket 0 has contacts, so refreshing them all starts one more lookup
+        assert_eq!(kad.ops.len(), 1);
+        kad.handle_command(Command::RefreshAll);
+        assert_eq!(kad.ops.len(), 2);
+    }
+
+    #[test]
+    fn unreachable_peer() {
+        let (mut kad, _sent) = new_kad();
+        let gone: SocketAddr = ([127, 0, 0, 1], 1).into();
+        let alive: SocketAddr = ([127, 0, 0, 1], 2).into();
+        for &addr in [gone, alive].iter() {
+            kad.known_peers
+                .insert(kad.id, Contact::new(rand::random(), addr))
+                .unwrap();
+        }
+        let (reply, result) = channel::bounded(1);
+        kad.handle_command(Command::PingWait {
+            peer: gone,
+            deadline: Instant::now() + Duration::from_secs(60),
+            reply,
+        });
+
+        kad.handle_command(Command::Unreachable(gone));
+        let left: Vec<SocketAddr> = kad
+            .known_peers
+            .contacts()
+            .iter()
+            .map(|c| c.addr())
+            .collect();
+        assert_eq!(left, vec![alive]);
+
+        // Failed on the next tick, rather than after the usual timeout
+        kad.handle_tick();
+        assert!(matches!(result.try_recv(), Ok(Err(Error::Timeout))));
+    }
+
+    #[test]
+    fn churn_sees_every_change() {
+        let (mut kad, _sent, clock) = timed_kad(Config::default());
+        let seeded = Contact::new(rand::random(), ([127, 0, 0, 1], 1).into());
+        let leaving = Contact::new(rand::random(), ([127, 0, 0, 1], 2).into());
+        kad.handle_command(Command::Seed(vec![seeded, leaving]));
+        assert!(!kad.churn.settled(seeded.id(), clock.now()));
+
+        kad.handle_command(Command::Unreachable(seeded.addr()));
+        kad.handle_packet(packet(leaving.id(), 0, Payload::Leaving), leaving.addr());
+        for gone in [seeded, leaving].iter() {
+            assert!(kad.churn.cooling(gone.id(), clock.now()));
+            // So neither can just ping its way back in
+            kad.handle_packet(ping(gone.id()), gone.addr());
+            assert!(kad.known_peers.get(kad.id, gone.id()).is_none());
+        }
+    }
+
+    #[test]
+    fn superseded_bootstrap() {
+        let (mut kad, sent, clock) = timed_kad(Config::default());
+        let seed = |port| SocketAddr::from(([127, 0, 0, 1], port));
+        kad.handle_command(Command::Bootstrap(vec![seed(1), seed(2)]));
+        let first: Vec<(Packet, SocketAddr)> = sent.try_iter().collect();
+        assert_eq!(first.len(), 2);
+
+        // A seed given twice is only probed once, wherever the repeat is
+        kad.handle_command(Command::Bootstrap(vec![seed(3), seed(4), seed(3)]));
+        let second: Vec<SocketAddr> = sent.try_iter().map(|(_, to)| to).collect();
+        assert_eq!(second, vec![seed(3), seed(4)]);
+        assert_eq!(kad.seeds, vec![seed(3), seed(4)]);
+        let mut probed: Vec<SocketAddr> = kad.pending.values().map(|p| p.peer).collect();
+        probed.sort();
+        assert_eq!(probed, vec![seed(3), seed(4)]);
+
+        // A late answer to the first bootstrap still gets its sender known, but changes nothing else
+        let (ping, to) = &first[0];
+        let late = pong(rand::random(), ping.seq_num);
+        kad.handle_packet(late, *to);
+        assert_eq!(kad.known_peers.len(), 1);
+        assert_eq!(kad.seeds, vec![seed(3), seed(4)]);
+
+        let seq_num = kad
+            .pending
+            .iter()
+            .find(|(_, p)| p.peer == seed(3))
+            .map(|(seq_num, _)| *seq_num)
+            .unwrap();
+        let pong = pong(rand::random(), seq_num);
+        kad.handle_packet(pong, seed(3));
+        assert_eq!(kad.seeds, vec![seed(4)]);
+
+        clock.advance(REQUEST_TIMEOUT);
+        kad.handle_tick();
+        assert!(kad.seeds.is_empty());
+        assert!(kad.pending.is_empty());
+    }
+
+    // A node we know at old says it's at new, and has the checks on both addresses sent. Returns
+    // the check on each.
+    fn id_conflict(
+        kad: &mut Kad,
+        sent: &channel::Receiver<(Packet, SocketAddr)>,
+        id: NodeID,
+        old: SocketAddr,
+        new: SocketAddr,
+    ) -> (u64, u64) {
+        kad.known_peers
+            .insert(kad.id, Contact::new(id, old))
+            .unwrap();
+        let ping = ping(id);
+        kad.handle_packet(ping, new);
+        assert!(kad.conflicts.contains_key(&id));
+
+        // Answered as usual, but not believed until both addresses have been checked
+        let (mut old_check, mut new_check) = (None, None);
+        for (pack, to) in sent.try_iter() {
+            if let Payload::Ping | Payload::PingExt(_) = pack.payload {
+                match to {
+                    to if to == old => old_check = Some(pack.seq_num),
+                    to if to == new => new_check = Some(pack.seq_num),
+                    to => panic!("{}", to),
+                }
+            }
+        }
+        assert_eq!(kad.known_peers.get(kad.id, id).unwrap().addr(), old);
+        (old_check.unwrap(), new_check.unwrap())
+    }
+
+    #[test]
+    fn address_change() {
+        let (mut kad, sent, clock) = timed_kad(Config::default());
+        let id: NodeID = rand::random();
+        let old: SocketAddr = ([127, 0, 0, 1], 1).into();
+        let new: SocketAddr = ([127, 0, 0, 1], 2).into();
+        let (_, check) = id_conflict(&mut kad, &sent, id, old, new);
+
+        // Someone else at that address, claiming to be it, isn't enough
+        kad.handle_packet(pong(id, check), ([127, 0, 0, 1], 3).into());
+        assert_eq!(kad.known_peers.get(kad.id, id).unwrap().addr(), old);
+
+        // Nor is the new address answering, while the old one might still
+        kad.handle_packet(pong(id, check), new);
+        assert_eq!(kad.known_peers.get(kad.id, id).unwrap().addr(), old);
+
+        // Once the old one has had its chance, the node must have moved
+        clock.advance(REQUEST_TIMEOUT);
+        kad.handle_tick();
+        assert_eq!(kad.known_peers.get(kad.id, id).unwrap().addr(), new);
+        assert_eq!(kad.known_peers.contacts().len(), 1);
+        assert!(kad.conflicts.is_empty());
+        assert!(kad.pending.is_empty());
+    }
+
+    #[test]
+    fn duplicate_id() {
+        let id: NodeID = rand::random();
+        let old: SocketAddr = ([127, 0, 0, 1], 1).into();
+        let new: SocketAddr = ([127, 0, 0, 1], 2).into();
+
+        // Both answer as the node, in either order. The address we had it at keeps it.
+        for old_first in [false, true] {
+            let (mut kad, sent, _) = timed_kad(Config::default());
+            let (old_check, new_check) = id_conflict(&mut kad, &sent, id, old, new);
+            let mut answers = [(pong(id, old_check), old), (pong(id, new_check), new)];
+            if !old_first {
+                answers.reverse();
+            }
+            for (pong, from) in answers {
+                kad.handle_packet(pong, from);
+            }
+            assert_eq!(kad.known_peers.get(kad.id, id).unwrap().addr(), old);
+            assert!(kad.conflicts.is_empty());
+            assert!(kad.pending.is_empty());
+
+            // It's only the one challenger at a time
+            let (_, other) = id_conflict(&mut kad, &sent, id, old, new);
+            kad.handle_packet(pong(id, other), ([127, 0, 0, 1], 3).into());
+            assert!(sent
+                .try_iter()
+                .all(|(_, to)| to != ([127, 0, 0, 1], 3).into()));
+        }
+
+        // Neither answers, so nothing changes
+        let (mut kad, sent, clock) = timed_kad(Config::default());
+        id_conflict(&mut kad, &sent, id, old, new);
+        clock.advance(REQUEST_TIMEOUT);
+        kad.handle_tick();
+        assert_eq!(kad.known_peers.get(kad.id, id).unwrap().addr(), old);
+        assert!(kad.conflicts.is_empty());
+    }
+
+    #[test]
+    fn hop_limit() {
+        let config = Config {
+            max_lookup_hops: Some(3),
+            ..Config::default()
+        };
+        let (mut kad, sent, _) = timed_kad(config);
+        let target: NodeID = rand::random();
+
+        // Nodes that always know of K more, each batch closer to the target than the last
+        let mut port = 0;
+        let mut nodes = |hops: usize| -> Vec<Contact> {
+            let (low, _) = NodeID::bucket_range(target, hops * 8);
+            (0..K)
+                .map(|i| {
+                    let mut id = low;
+                    id.bytes[KEY_BYTES - 1] = i as u8;
+                    port += 1;
+                    Contact::new(id, ([127, 0, 0, 1], port).into())
+                })
+                .collect()
+        };
+        let mut hops: HashMap<SocketAddr, (NodeID, usize)> = HashMap::new();
+        for contact in nodes(1) {
+            hops.insert(contact.addr(), (contact.id(), 1));
+            kad.known_peers.insert(kad.id, contact).ok();
+        }
+
+        let (handle, waiter) = LookupHandle::new();
+        kad.handle_command(Command::FindNode(target, waiter));
+
+        let mut deepest = 0;
+        while let Ok((query, peer)) = sent.try_recv() {
+            let (id, depth) = hops[&peer];
+            deepest = deepest.max(depth);
+            let found = nodes(depth + 1);
+            for contact in found.iter() {
+                hops.insert(contact.addr(), (contact.id(), depth + 1));
+            }
+            let response = packet(id, query.seq_num, Payload::Nodes(found));
+            kad.handle_packet(response, peer);
+        }
+
+        assert_eq!(deepest, 3);
+        assert!(kad.ops.is_empty());
+        match handle.wait() {
+            LookupStatus::TruncatedByHopLimit(closest) => {
+                // The best we got is as far as we were allowed to go
+                assert_eq!(hops[&closest[0].addr()].1, 3);
+            }
+            status => panic!("expected TruncatedByHopLimit, got {:?}", status),
+        }
+    }
+
+    #[test]
+    fn lookup_stats() {
+        let (mut kad, sent, clock) = timed_kad(Config::default());
+        let key: NodeID = rand::random();
+
+        // A chain of nodes, each closer to the key and known only to the one before it. The last
+        // one holds the value.
+        let chain: Vec<Contact> = (1..=3)
+            .map(|i| {
+                let (id, _) = NodeID::bucket_range(key, i * 8);
+                Contact::new(id, ([127, 0, 0, 1], i as u16).into())
+            })
+            .collect();
+        kad.known_peers.insert(kad.id, chain[0]).unwrap();
+
+        let (reply, result) = channel::bounded(1);
+        kad.handle_command(Command::Get {
+            key,
+            deadline: clock.now() + Duration::from_secs(10),
+            reply,
+        });
+        for (i, node) in chain.iter().enumerate() {
+            let (query, peer) = sent.try_recv().unwrap();
+            assert_eq!(peer, node.addr());
+            assert!(matches!(query.payload, Payload::FindValue(k) if k == key));
+            clock.advance(Duration::from_millis(10));
+
+            let payload = match chain.get(i + 1) {
+                Some(next) => Payload::Nodes(vec![*next]),
+                None => Payload::Value(b"value".to_vec()),
+            };
+            let response = packet(node.id(), query.seq_num, payload);
+            kad.handle_packet(response, peer);
+        }
+
+        let (found, stats) = result.try_recv().unwrap().unwrap();
+        assert_eq!(found.unwrap().0, b"value");
+        assert_eq!(
+            stats,
+            LookupStats {
+                hops: 3,
+                queried: 3,
+                responded: 3,
+                elapsed: Duration::from_millis(30),
+            }
+        );
+    }
+
+    #[test]
+    fn reset() {
+        let (mut kad, sent, clock) = timed_kad(Config::default());
+        for port in 1..=5 {
+            let contact = Contact::new(rand::random(), ([127, 0, 0, 1], port).into());
+            kad.known_peers.insert(kad.id, contact).unwrap();
+        }
+        let deadline = clock.now() + Duration::from_secs(10);
+        let (get, get_result) = channel::bounded(1);
+        kad.handle_command(Command::Get {
+            key: rand::random(),
+            deadline,
+            reply: get,
+        });
+        let (ping, ping_result) = channel::bounded(1);
+        kad.handle_command(Command::PingWait {
+            peer: ([127, 0, 0, 1], 1).into(),
+            deadline,
+            reply: ping,
+        });
+        sent.try_iter().for_each(drop);
+
+        let seed = Contact::new(rand::random(), ([127, 0, 0, 1], 100).into());
+        kad.handle_command(Command::Reset(vec![seed.addr()]));
+        assert!(matches!(get_result.try_recv(), Ok(Err(Error::Reset))));
+        assert!(matches!(ping_result.try_recv(), Ok(Err(Error::Reset))));
+        assert_eq!(kad.known_peers.len(), 0);
+        assert!(kad.ops.is_empty());
+        assert_eq!(kad.pending.len(), 1);
+        let (probe, to) = sent.try_recv().unwrap();
+        assert_eq!(to, seed.addr());
+
+        // The seed answers, so we find our neighborhood through it
+        let answer = |seq_num, payload| packet(seed.id(), seq_num, payload);
+        kad.handle_packet(pong(seed.id(), probe.seq_num), seed.addr());
+        assert_eq!(kad.known_peers.contacts(), vec![seed]);
+        let (query, to) = sent.try_recv().unwrap();
+        assert_eq!(to, seed.addr());
+        assert!(matches!(query.payload, Payload::FindNode(target) if target == kad.id));
+
+        // And the nodes it knows of make it in once they answer us
+        let nodes: Vec<Contact> = (1..=3)
+            .map(|port| Contact::new(rand::random(), ([127, 0, 0, 2], port).into()))
+            .collect();
+        kad.handle_packet(
+            answer(query.seq_num, Payload::Nodes(nodes.clone())),
+            seed.addr(),
+        );
+        let queries: Vec<(Packet, SocketAddr)> = sent.try_iter().collect();
+        assert_eq!(queries.len(), nodes.len());
+        for (query, to) in queries {
+            let node = nodes.iter().find(|n| n.addr() == to).unwrap();
+            kad.handle_packet(
+                Packet {
+                    id: node.id(),
+                    ..answer(query.seq_num, Payload::Nodes(Vec::new()))
+                },
+                to,
+            );
+        }
+        assert_eq!(kad.known_peers.len(), 4);
+    }
+
+    #[test]
+    fn transient_contacts() {
+        let config = Config {
+            maintain_routing_table: false,
+            ..Config::default()
+        };
+        let (mut kad, sent, clock) = timed_kad(config);
+        let seed = Contact::new(rand::random(), ([127, 0, 0, 1], 1).into());
+        kad.handle_command(Command::Bootstrap(vec![seed.addr()]));
+        let (ping, _) = sent.try_recv().unwrap();
+        let pong = pong(seed.id(), ping.seq_num);
+        kad.handle_packet(pong, seed.addr());
+        assert_eq!(kad.known_peers.contacts(), vec![seed]);
+
+        // Anyone else we hear from is answered but not remembered
+        let stranger = Contact::new(rand::random(), ([127, 0, 0, 1], 2).into());
+        let ping = packet(stranger.id(), 7, Payload::Ping);
+        kad.handle_packet(ping, stranger.addr());
+        assert!(matches!(
+            sent.try_recv().unwrap().0.payload,
+            Payload::Pong | Payload::PongExt(_)
+        ));
+        assert_eq!(kad.known_peers.len(), 1);
+
+        // A get still goes as far as it needs to, through nodes only the lookup knows about
+        let (reply, result) = channel::bounded(1);
+        let key = rand::random();
+        kad.handle_command(Command::Get {
+            key,
+            deadline: clock.now() + Duration::from_secs(10),
+            reply,
+        });
+        for (node, payload) in [
+            (seed, Payload::Nodes(vec![stranger])),
+            (stranger, Payload::Value(b"value".to_vec())),
+        ] {
+            let (query, peer) = sent.try_recv().unwrap();
+            assert_eq!(peer, node.addr());
+            let response = packet(node.id(), query.seq_num, payload);
+            kad.handle_packet(response, peer);
+        }
+        let (found, _) = result.try_recv().unwrap().unwrap();
+        assert_eq!(found.unwrap().0, b"value");
+        assert_eq!(kad.known_peers.contacts(), vec![seed]);
+    }
+
+    #[test]
+    fn seq_num_wraparound() {
+        let (mut kad, sent, clock) = timed_kad(Config::default());
+        let peer = ([127, 0, 0, 1], 1).into();
+        let deadline = clock.now() + Duration::from_secs(10);
+        let mut replies = Vec::new();
+        let mut ping = |kad: &mut Kad| {
+            let (reply, result) = channel::bounded(1);
+            kad.handle_command(Command::PingWait {
+                peer,
+                deadline,
+                reply,
+            });
+            replies.push(result);
+            sent.try_recv().unwrap().0.seq_num
+        };
+
+        let first = ping(&mut kad);
+        assert_eq!(first, 1);
+        kad.next_seq = u64::MAX - 1;
+        let seq_nums: Vec<u64> = (0..5).map(|_| ping(&mut kad)).collect();
+        // 0 is skipped, and so is the first ping, which is still waiting
+        assert_eq!(seq_nums, vec![u64::MAX - 1, u64::MAX, 2, 3, 4]);
+        assert_eq!(kad.pending.len(), 6);
+        assert!(!kad.pending.contains_key(&0));
+    }
+
+    #[test]
+    fn sender_gone() {
+        let (mut kad, sent) = new_kad();
+        let (watcher, anomalies) = channel::unbounded();
+        kad.handle_command(Command::WatchAnomalies(watcher));
+        drop(sent);
+
+        // Neither send takes the worker down, and only the first is reported
+        let peer = ([127, 0, 0, 1], 1).into();
+        kad.send_packet(peer, 0, Payload::Ping);
+        kad.send_packet(peer, 0, Payload::Ping);
+        assert_eq!(
+            anomalies.try_iter().collect::<Vec<_>>(),
+            vec![Anomaly::SenderGone]
+        );
+
+        let failed = Anomaly::SendFailed(io::ErrorKind::PermissionDenied);
+        assert!(kad.handle_command(Command::Report(failed.clone())));
+        assert_eq!(anomalies.try_recv(), Ok(failed));
+    }
+
+    #[test]
+    fn stale_contacts() {
+        let (mut kad, _sent, clock) = timed_kad(Config::default());
+        let told = Contact::new(rand::random(), ([127, 0, 0, 1], 1).into());
+        kad.known_peers.insert(kad.id, told).unwrap();
+
+        let old: NodeID = rand::random();
+        let recent: NodeID = rand::random();
+        kad.handle_packet(ping(old), ([127, 0, 0, 1], 2).into());
+        clock.advance(Duration::from_secs(60));
+        kad.handle_packet(ping(recent), ([127, 0, 0, 1], 3).into());
+        clock.advance(Duration::from_secs(30));
+
+        let ids = |contacts: Vec<Contact>| {
+            let mut ids: Vec<NodeID> = contacts.iter().map(|c| c.id()).collect();
+            ids.sort();
+            ids
+        };
+        let mut expected = vec![told.id(), old];
+        expected.sort();
+        let window = Duration::from_secs(45);
+        assert_eq!(
+            ids(kad.known_peers.stale_contacts(clock.now(), window)),
+            expected
+        );
+
+        // Hearing from it again makes it fresh
+        kad.handle_packet(ping(old), ([127, 0, 0, 1], 2).into());
+        let stale = kad.known_peers.stale_contacts(clock.now(), window);
+        assert_eq!(ids(stale), vec![told.id()]);
+
+        // Everyone we've heard from at all is fresh enough for a long enough window
+        clock.advance(Duration::from_secs(600));
+        let (reply, stale) = channel::bounded(1);
+        kad.handle_command(Command::StaleContacts(Duration::from_secs(3600), reply));
+        assert_eq!(ids(stale.recv().unwrap()), vec![told.id()]);
+    }
+
+    #[test]
+    fn keepalive_idle_contacts() {
+        let (mut kad, sent, clock) = timed_kad(Config::default());
+        let interval = kad.config.keepalive_interval.unwrap();
+
+        let idle: SocketAddr = ([127, 0, 0, 1], 1).into();
+        let busy: SocketAddr = ([127, 0, 0, 1], 2).into();
+        for (seq_num, peer) in [idle, busy].iter().enumerate() {
+            let ping = packet(
+                rand::random(),
+                seq_num as u64,
+                Payload::PingExt(Extensions::new(Capabilities::ALL, None)),
+            );
+            kad.handle_packet(ping, *peer);
+        }
+        assert_eq!(sent.try_iter().count(), 2);
+
+        // Only busy hears from us again before the interval runs out
+        clock.advance(interval + Duration::from_secs(1));
+        kad.known_peers.sent_to(busy, clock.now());
+        kad.handle_tick();
+
+        let pings: Vec<(Packet, SocketAddr)> = sent.try_iter().collect();
+        assert_eq!(pings.len(), 1);
+        assert!(matches!(
+            pings[0].0.payload,
+            Payload::Ping | Payload::PingExt(_)
+        ));
+        assert_eq!(pings[0].1, idle);
+    }
+
+    // A node whose table is full of nodes closer to key than itself
+    fn far_from(key: NodeID) -> (Kad, channel::Receiver<(Packet, SocketAddr)>) {
+        let (mut kad, sent) = new_kad();
+        kad.id = key ^ NodeID::max();
+        for i in 0..K {
+            let mut id = key;
+            id.bytes[KEY_BYTES - 1] ^= i as u8 + 1;
+            let contact = Contact::new(id, ([127, 0, 0, 1], i as u16 + 1).into());
+            kad.known_peers.insert(kad.id, contact).unwrap();
+        }
+        (kad, sent)
+    }
+
+    #[test]
+    fn store_not_responsible() {
+        let key: NodeID = rand::random();
+        let (mut kad, sent) = far_from(key);
+
+        // Near us, where there's room, so nobody has to be challenged to make way for it
+        let mut stranger = kad.id;
+        stranger.bytes[KEY_BYTES - 1] ^= 1;
+        let store = packet(stranger, 1, Payload::Store(key, b"value".to_vec(), 0));
+        kad.handle_packet(store, ([127, 0, 0, 2], 1).into());
+
+        match sent.try_recv().unwrap().0.payload {
+            Payload::StoreAck(ack) => assert_eq!(ack, Err(Rejection::NotResponsible)),
+            p => panic!("expected StoreAck, got {:?}", p),
+        }
+        assert!(kad.values.get(key).is_none());
+
+        // A node that is responsible takes it
+        let (mut kad, sent) = new_kad();
+        let store = packet(rand::random(), 1, Payload::Store(key, b"value".to_vec(), 0));
+        kad.handle_packet(store, ([127, 0, 0, 2], 1).into());
+        assert!(matches!(
+            sent.try_recv().unwrap().0.payload,
+            Payload::StoreAck(Ok(()))
+        ));
+        assert_eq!(kad.values.get(key), Some(b"value".to_vec()));
+    }
+
+    #[test]
+    fn store_forwarding() {
+        let key: NodeID = rand::random();
+        let (mut kad, sent) = far_from(key);
+        kad.config.forward_stores = true;
+
+        // Near us, where there's room, so nobody has to be challenged to make way for it
+        let mut stranger = kad.id;
+        stranger.bytes[KEY_BYTES - 1] ^= 1;
+        let store = packet(stranger, 1, Payload::Store(key, b"value".to_vec(), 0));
+        kad.handle_packet(store, ([127, 0, 0, 2], 1).into());
+
+        let (forwarded, to) = sent.try_recv().unwrap();
+        assert!(matches!(forwarded.payload, Payload::Store(k, ..) if k == key));
+        assert_eq!(to, kad.known_peers.closest(key, 1)[0].addr());
+        assert!(matches!(
+            sent.try_recv().unwrap().0.payload,
+            Payload::StoreAck(Err(Rejection::Forwarded))
+        ));
+        assert!(kad.values.get(key).is_none());
+    }
+
+    fn store_signed(
+        kad: &mut Kad,
+        sent: &channel::Receiver<(Packet, SocketAddr)>,
+        value: SignedValue,
+    ) -> Result<(), Rejection> {
+        let store = packet(rand::random(), 1, Payload::StoreSigned(value));
+        kad.handle_packet(store, ([127, 0, 0, 2], 1).into());
+        match sent.try_recv().unwrap().0.payload {
+            Payload::StoreAck(ack) => ack,
+            p => panic!("expected StoreAck, got {:?}", p),
+        }
+    }
+
+    // Sends kad a store for key from a stranger, and returns the ack
+    fn store_versioned(
+        kad: &mut Kad,
+        sent: &channel::Receiver<(Packet, SocketAddr)>,
+        key: NodeID,
+        value: &[u8],
+        version: u64,
+    ) -> Result<(), Rejection> {
+        let store = packet(
+            rand::random(),
+            1,
+            Payload::Store(key, value.to_vec(), version),
+        );
+        kad.handle_packet(store, ([127, 0, 0, 2], 1).into());
+        match sent.try_recv().unwrap().0.payload {
+            Payload::StoreAck(ack) => ack,
+            p => panic!("expected StoreAck, got {:?}", p),
+        }
+    }
+
+    #[test]
+    fn stale_version() {
+        let (mut kad, sent) = new_kad();
+        let key: NodeID = rand::random();
+
+        assert_eq!(
+            store_versioned(&mut kad, &sent, key, b"unversioned", 0),
+            Ok(())
+        );
+        assert_eq!(store_versioned(&mut kad, &sent, key, b"second", 2), Ok(()));
+        assert_eq!(
+            store_versioned(&mut kad, &sent, key, b"first", 1),
+            Err(Rejection::StaleVersion)
+        );
+        assert_eq!(
+            store_versioned(&mut kad, &sent, key, b"unversioned", 0),
+            Err(Rejection::StaleVersion)
+        );
+        match find_value(&mut kad, &sent, key) {
+            Payload::Value(value) => assert_eq!(value, b"second"),
+            p => panic!("expected Value, got {:?}", p),
+        }
+
+        // The same version again is a rewrite, so the last one wins
+        assert_eq!(store_versioned(&mut kad, &sent, key, b"again", 2), Ok(()));
+        assert_eq!(kad.values.get_versioned(key), Some((b"again".to_vec(), 2)));
+    }
+
+    fn find_value(
+        kad: &mut Kad,
+        sent: &channel::Receiver<(Packet, SocketAddr)>,
+        key: NodeID,
+    ) -> Payload {
+        let find = packet(rand::random(), 2, Payload::FindValue(key));
+        kad.handle_packet(find, ([127, 0, 0, 2], 1).into());
+        sent.try_recv().unwrap().0.payload
+    }
+
+    #[test]
+    fn signed_update() {
+        let (mut kad, sent) = new_kad();
+        let signing_key = SigningKey::from_bytes(&rand::random());
+
+        let first = SignedValue::sign(&signing_key, 1, b"first".to_vec());
+        assert_eq!(store_signed(&mut kad, &sent, first), Ok(()));
+
+        let second = SignedValue::sign(&signing_key, 2, b"second".to_vec());
+        assert_eq!(store_signed(&mut kad, &sent, second.clone()), Ok(()));
+
+        match find_value(&mut kad, &sent, second.key()) {
+            Payload::Signed(value) => assert_eq!(value, second),
+            p => panic!("expected Signed, got {:?}", p),
+        }
+    }
+
+    #[test]
+    fn signed_key_taken() {
+        let (mut kad, sent) = new_kad();
+        let signing_key = SigningKey::from_bytes(&rand::random());
+        let value = SignedValue::sign(&signing_key, 1, b"signed".to_vec());
+        let key = value.key();
+
+        // Something already there doesn't hide the signed value, or get to replace it after
+        assert_eq!(store_versioned(&mut kad, &sent, key, b"plain", 0), Ok(()));
+        assert_eq!(store_signed(&mut kad, &sent, value.clone()), Ok(()));
+        assert_eq!(
+            store_versioned(&mut kad, &sent, key, b"plain", 7),
+            Err(Rejection::SignedKey)
+        );
+        match find_value(&mut kad, &sent, key) {
+            Payload::Signed(found) => assert_eq!(found, value),
+            p => panic!("expected Signed, got {:?}", p),
+        }
+    }
+
+    #[test]
+    fn get_signed_past_plain() {
+        let (mut kad, sent, clock) = timed_kad(Config::default());
+        let holders: Vec<Contact> = (1..=2)
+            .map(|port| Contact::new(rand::random(), ([127, 0, 0, 1], port).into()))
+            .collect();
+        for holder in holders.iter() {
+            kad.known_peers.insert(kad.id, *holder).unwrap();
+        }
+        let value = SignedValue::sign(&SigningKey::from_bytes(&rand::random()), 1, Vec::new());
+        let (reply, result) = channel::bounded(1);
+        kad.handle_command(Command::GetSigned {
+            key: value.key(),
+            deadline: clock.now() + Duration::from_secs(10),
+            reply,
+        });
+        let queries: Vec<(Packet, SocketAddr)> = sent.try_iter().collect();
+        assert_eq!(queries.len(), holders.len());
+
+        // Someone's plain value under the key is no answer, so the lookup waits on the other
+        let answers = [
+            Payload::Value(b"plain".to_vec()),
+            Payload::Signed(value.clone()),
+        ];
+        for ((query, to), answer) in queries.into_iter().zip(answers) {
+            assert!(result.try_recv().is_err());
+            let holder = holders.iter().find(|h| h.addr() == to).unwrap();
+            kad.handle_packet(packet(holder.id(), query.seq_num, answer), to);
+        }
+        assert_eq!(result.try_recv().unwrap().unwrap(), Some(value));
+    }
+
+    #[test]
+    fn signed_bad_signature() {
+        let (mut kad, sent) = new_kad();
+        let signing_key = SigningKey::from_bytes(&rand::random());
+        let value = SignedValue::sign(&signing_key, 1, b"value".to_vec());
+        let key = value.key();
+
+        // The signature goes last on the wire
+        let mut bytes = bincode::serialize(&value).unwrap();
+        *bytes.last_mut().unwrap() ^= 1;
+        let tampered: SignedValue = bincode::deserialize(&bytes).unwrap();
+
+        assert_eq!(
+            store_signed(&mut kad, &sent, tampered),
+            Err(Rejection::BadSignature)
+        );
+        assert!(kad.signed.get(key).is_none());
+    }
+
+    #[test]
+    fn signed_stale_seq() {
+        let (mut kad, sent) = new_kad();
+        let signing_key = SigningKey::from_bytes(&rand::random());
+
+        let current = SignedValue::sign(&signing_key, 5, b"current".to_vec());
+        assert_eq!(store_signed(&mut kad, &sent, current.clone()), Ok(()));
+
+        for seq in [4, 5] {
+            let stale = SignedValue::sign(&signing_key, seq, b"stale".to_vec());
+            assert_eq!(
+                store_signed(&mut kad, &sent, stale),
+                Err(Rejection::StaleSequence)
+            );
+        }
+        // The same value again is no conflict
+        assert_eq!(store_signed(&mut kad, &sent, current.clone()), Ok(()));
+        assert_eq!(kad.signed.get(current.key()), Some(&current));
+    }
+
+    #[test]
+    fn republish_to_new_node() {
+        let interval = Duration::from_secs(60);
+        let config = Config {
+            republish_interval: Some(interval),
+            keepalive_interval: None,
+            ..Config::default()
+        };
+        let (mut kad, sent, clock) = timed_kad(config);
+        let key: NodeID = rand::random();
+        kad.values
+            .put(key, b"value".to_vec(), 0, clock.now())
+            .unwrap();
+
+        let holder = Contact::new(rand::random(), ([127, 0, 0, 1], 1).into());
+        kad.known_peers.insert(kad.id, holder).unwrap();
+
+        // Answers the queries sent since the last call, of which there must be some: the holder with
+        // the value and anyone else with no nodes. Returns whatever we sent after that.
+        let answer = |kad: &mut Kad| {
+            let queries: Vec<(Packet, SocketAddr)> = sent.try_iter().collect();
+            assert!(!queries.is_empty());
+            for (query, to) in queries {
+                assert!(matches!(query.payload, Payload::FindValue(k) if k == key));
+                let (id, payload) = if to == holder.addr() {
+                    (holder.id(), Payload::Value(b"value".to_vec()))
+                } else {
+                    (key, Payload::Nodes(Vec::new()))
+                };
+                let response = packet(id, query.seq_num, payload);
+                kad.handle_packet(response, to);
+            }
+            sent.try_iter().collect::<Vec<(Packet, SocketAddr)>>()
+        };
+
+        // Nothing happens before the interval is up
+        kad.handle_tick();
+        assert_eq!(sent.try_iter().count(), 0);
+
+        // The only node we know already holds the value
+        clock.advance(interval + Duration::from_secs(1));
+        kad.handle_tick();
+        assert!(answer(&mut kad).is_empty());
+
+        // A closer node joins and gets the value on the next round, but the holder doesn't
+        let newcomer = Contact::new(key, ([127, 0, 0, 1], 2).into());
+        kad.known_peers.insert(kad.id, newcomer).unwrap();
+        clock.advance(interval);
+        kad.handle_tick();
+        let stores = answer(&mut kad);
+        assert_eq!(stores.len(), 1);
+        assert!(matches!(&stores[0].0.payload, Payload::Store(k, ..) if *k == key));
+        assert_eq!(stores[0].1, newcomer.addr());
+    }
 }
@@ -0,0 +1,290 @@
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+
+use crossbeam::channel;
+use rand::prelude::*;
+use rand::rngs::StdRng;
+
+use super::*;
+
+// Which queued packet a Network delivers next
+#[derive(Copy, Clone, Debug)]
+pub enum Order {
+    // Oldest first
+    Fifo,
+    // A random one, picked with an RNG seeded from this
+    Shuffled(u64),
+}
+
+struct Node {
+    kad: Kad,
+    addr: SocketAddr,
+    sent: channel::Receiver<(Packet, SocketAddr)>,
+    // How long everything it sends takes to arrive
+    delay: Duration,
+}
+
+// Nodes talking over queues instead of sockets, all on the test's thread and sharing one clock.
+// Nothing happens until the test steps it, one delivery at a time, in an order that only depends
+// on the seed, so the same test plays out the same way on every run. A packet can't be delivered
+// before its sender's delay is up, and when nothing else can be, the clock moves on to the next
+// one that can.
+pub struct Network {
+    nodes: Vec<Node>,
+    order: Order,
+    rng: StdRng,
+    pub clock: ManualClock,
+    // From, to, packet, and when it can be delivered
+    queue: VecDeque<(SocketAddr, SocketAddr, Packet, Instant)>,
+    // From, to and sequence number of everything delivered so far, in order
+    pub delivered: Vec<(SocketAddr, SocketAddr, u64)>,
+}
+
+impl Network {
+    // Node i listens on 127.0.0.1, port i + 1. Their IDs come from seed, so are the same every run.
+    pub fn new(size: usize, seed: u64, order: Order) -> Network {
+        Network::with_config(size, seed, order, Config::default())
+    }
+
+    // Every node gets its own copy of config
+    pub fn with_config(size: usize, seed: u64, order: Order, config: Config) -> Network {
+        let clock = ManualClock::new();
+        let mut ids = StdRng::seed_from_u64(seed);
+        let nodes = (0..size)
+            .map(|i| {
+                let (send, sent) = channel::unbounded();
+                let mut kad = Kad::with_clock(
+                    send,
+                    config.clone(),
+                    Box::new(clock.clone()),
+                    MemoryStore::default(),
+                );
+                kad.id = NodeID::random(&mut ids);
+                let addr = ([127, 0, 0, 1], i as u16 + 1).into();
+                kad.set_own_addrs(vec![addr]);
+                Node {
+                    kad,
+                    addr,
+                    sent,
+                    delay: Duration::from_secs(0),
+                }
+            })
+            .collect();
+        let rng = match order {
+            Order::Fifo => StdRng::seed_from_u64(0),
+            Order::Shuffled(seed) => StdRng::seed_from_u64(seed),
+        };
+        Network {
+            nodes,
+            order,
+            rng,
+            clock,
+            queue: VecDeque::new(),
+            delivered: Vec::new(),
+        }
+    }
+
+    pub fn node(&mut self, i: usize) -> &mut Kad {
+        &mut self.nodes[i].kad
+    }
+
+    pub fn contact(&self, i: usize) -> Contact {
+        Contact::new(self.nodes[i].kad.id, self.nodes[i].addr)
+    }
+
+    // Holds back whatever node i sends from now on by delay
+    pub fn set_delay(&mut self, i: usize, delay: Duration) {
+        self.nodes[i].delay = delay;
+    }
+
+    // Queues whatever the nodes have sent since we last looked, node by node.
+    fn collect(&mut self) {
+        let now = self.clock.now();
+        for node in self.nodes.iter() {
+            for (packet, to) in node.sent.try_iter() {
+                self.queue
+                    .push_back((node.addr, to, packet, now + node.delay));
+            }
+        }
+    }
+
+    // Delivers one queued packet, returning whether there was one. Packets to addresses no node
+    // has are lost, and count as delivered.
+    pub fn step(&mut self) -> bool {
+        self.collect();
+        let due = match self.queue.iter().map(|(.., due)| *due).min() {
+            Some(due) => due,
+            None => return false,
+        };
+        let now = self.clock.now();
+        if due > now {
+            self.clock.advance(due - now);
+        }
+        let ready: Vec<usize> = (0..self.queue.len())
+            .filter(|&i| self.queue[i].3 <= due.max(now))
+            .collect();
+        let i = match self.order {
+            Order::Fifo => ready[0],
+            Order::Shuffled(_) => ready[self.rng.gen_range(0, ready.len())],
+        };
+        let (from, to, packet, _) = self.queue.remove(i).unwrap();
+        self.delivered.push((from, to, packet.seq_num));
+        if let Some(node) = self.nodes.iter_mut().find(|n| n.addr == to) {
+            node.kad.handle_packet(packet, from);
+        }
+        true
+    }
+
+    // Steps until nothing is left to deliver, returning how many steps that took.
+    pub fn run(&mut self) -> usize {
+        let mut steps = 0;
+        while self.step() {
+            steps += 1;
+        }
+        steps
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // A lookup across a ring where each node only knows the next few, returning what it found and
+    // the order everything was delivered in
+    fn ring_lookup(order: Order) -> (LookupStatus, Vec<(SocketAddr, SocketAddr, u64)>) {
+        let size = 40;
+        let mut network = Network::new(size, 185, order);
+        for i in 0..size {
+            for j in 1..=3 {
+                let contact = network.contact((i + j) % size);
+                let kad = network.node(i);
+                kad.known_peers.insert(kad.id, contact).unwrap();
+            }
+        }
+
+        let target = NodeID::random(&mut StdRng::seed_from_u64(1850));
+        let (handle, waiter) = LookupHandle::new();
+        network
+            .node(0)
+            .handle_command(Command::FindNode(target, waiter));
+        assert!(network.run() > 0);
+        (handle.wait(), network.delivered)
+    }
+
+    #[test]
+    fn deterministic_lookup() {
+        for order in [Order::Fifo, Order::Shuffled(7)] {
+            let (status, delivered) = ring_lookup(order);
+            match &status {
+                LookupStatus::Completed(closest) => assert!(!closest.is_empty()),
+                status => panic!("{:?}", status),
+            }
+            assert_eq!(ring_lookup(order), (status, delivered));
+        }
+
+        // A different order plays out differently
+        let (_, fifo) = ring_lookup(Order::Fifo);
+        let (_, shuffled) = ring_lookup(Order::Shuffled(7));
+        assert_ne!(fifo, shuffled);
+    }
+
+    // What node 0, which knows everyone else, answers when asked who's closest to target. With
+    // static tables the target's bucket already holds every closer node, so spillover can't save
+    // hops there; what it guarantees is a full answer.
+    fn answer(closest_nodes: ClosestNodes, target: NodeID) -> Vec<Contact> {
+        let size = 60;
+        let config = Config {
+            closest_nodes,
+            ..Config::default()
+        };
+        let mut network = Network::with_config(size, 192, Order::Fifo, config);
+        for i in 1..size {
+            let contact = network.contact(i);
+            let kad = network.node(0);
+            kad.known_peers.insert(kad.id, contact).ok();
+        }
+        network.node(0).closest_nodes(target)
+    }
+
+    #[test]
+    fn spillover_fills_answers() {
+        let mut keys = StdRng::seed_from_u64(1920);
+        let mut short = 0;
+        for _ in 0..30 {
+            let target = NodeID::random(&mut keys);
+            let spillover = answer(ClosestNodes::Spillover, target);
+            let bucket = answer(ClosestNodes::Bucket, target);
+            assert_eq!(spillover.len(), K);
+            // The bucket's contacts are the nearest there are, so spillover leads with them
+            assert_eq!(spillover[..bucket.len()], bucket[..]);
+            if bucket.len() < K {
+                short += 1;
+            }
+        }
+        // Most targets fall into the half of the keyspace our biggest bucket is for, which still
+        // holds K, but the rest don't
+        assert!(short > 0);
+    }
+
+    #[test]
+    fn injected_rtts() {
+        let mut network = Network::new(11, 1, Order::Fifo);
+        let delays: Vec<Duration> = (1..=10).map(|i| Duration::from_millis(10 * i)).collect();
+        let deadline = network.clock.now() + Duration::from_secs(1);
+        let results: Vec<_> = delays
+            .iter()
+            .enumerate()
+            .map(|(i, &delay)| {
+                network.set_delay(i + 1, delay);
+                let peer = network.contact(i + 1).addr();
+                let (reply, result) = channel::bounded(1);
+                network.node(0).handle_command(Command::PingWait {
+                    peer,
+                    deadline,
+                    reply,
+                });
+                result
+            })
+            .collect();
+        network.run();
+
+        // Each pong comes back after its sender's delay, however the deliveries interleave
+        let rtts = results
+            .iter()
+            .map(|r| r.try_recv().unwrap().unwrap())
+            .collect();
+        let stats = crate::RttStats::from_samples(rtts, 0);
+        assert_eq!(stats.answered, delays.len());
+        assert_eq!(stats.min, delays[0]);
+        assert_eq!(stats.median, delays[4]);
+        assert_eq!(stats.p95, delays[9]);
+        assert_eq!(stats.max, delays[9]);
+    }
+
+    #[test]
+    fn step_until_quiet() {
+        let mut network = Network::new(2, 1, Order::Fifo);
+        assert!(!network.step());
+
+        let contact = network.contact(1);
+        let (reply, result) = channel::bounded(1);
+        let deadline = network.clock.now() + Duration::from_secs(1);
+        network.node(0).handle_command(Command::PingWait {
+            peer: contact.addr(),
+            deadline,
+            reply,
+        });
+        // The ping, then its pong, timed by the shared clock
+        assert!(network.step());
+        assert!(result.try_recv().is_err());
+        network.clock.advance(Duration::from_millis(30));
+        assert!(network.step());
+        assert_eq!(
+            result.try_recv().unwrap().unwrap(),
+            Duration::from_millis(30)
+        );
+        // Then each asks the other what it supports, having heard a plain ping or pong from it
+        assert_eq!(network.run(), 4);
+        assert_eq!(network.run(), 0);
+    }
+}
@@ -0,0 +1,789 @@
+use std::convert::TryInto;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use bincode::{DefaultOptions, Options};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+
+use super::kbucket::{Contact, NodeID};
+use super::lz;
+use super::signed::SignedValue;
+use super::token::Token;
+
+// Each variant goes on the wire as its position in this list, a little-endian u32, followed by its
+// fields. New variants go at the end; the wire_tags test catches reordering.
+#[derive(Serialize, Deserialize, Debug)]
+pub enum Payload {
+    Ping,
+    Pong,
+    FindNode(NodeID),
+    Nodes(Vec<Contact>),
+    // Registers the sender's IP, at the given port, as a peer for the key. The token must be one
+    // the node gave the sender's IP in Peers. Answered with StoreAck.
+    Announce(NodeID, u16, Token),
+    GetPeers(NodeID),
+    // The peers announced under the key, or the closest nodes to it if nobody has announced, and
+    // a token for announcing to the sender
+    Peers(Vec<SocketAddr>, Vec<Contact>, Token),
+    // The value and its version. Nodes holding a later version of the key's value refuse it.
+    // Unversioned values are version 0, so any versioned store replaces them.
+    Store(NodeID, Vec<u8>, u64),
+    StoreAck(Result<(), Rejection>),
+    // Answered with Value if the node has it and Nodes otherwise
+    FindValue(NodeID),
+    Value(Vec<u8>),
+    StoreSigned(SignedValue),
+    // Answers FindValue for a key holding a signed value
+    Signed(SignedValue),
+    // The sender is about to go away. Purely advisory, and never answered.
+    Leaving,
+    // Ping and Pong, carrying what the sender supports and whatever it has to say about itself.
+    // Older nodes can't read them, so other nodes only get a PingExt once, asking whether they can.
+    PingExt(Extensions),
+    PongExt(Extensions),
+    // Adds the value to the set under the key, which Store would replace. Answered with
+    // StoreAck.
+    Add(NodeID, Vec<u8>),
+    // Answered with Values
+    FindValues(NodeID),
+    // Members of the set under the key, or the closest nodes to it if the set is empty
+    Values(Vec<Vec<u8>>, Vec<Contact>),
+    // A tag from a newer version of the protocol than ours. Never sent, only decoded from a
+    // packet whose payload we can't read, so its fields (and any signature after them) are lost.
+    #[serde(skip)]
+    Unknown(u32),
+}
+
+// One past the last tag we know. Anything from here on decodes as Payload::Unknown.
+const KNOWN_TAGS: u32 = 19;
+
+// Entry tags in Extensions
+const CAPABILITIES: u8 = 0;
+const VERSION: u8 = 1;
+const UPTIME: u8 = 2;
+
+// What a node says about itself in a PingExt or PongExt, as a list of tagged entries. Entries
+// with tags a node doesn't know are skipped, so new ones can be added without breaking it.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct Extensions(Vec<(u8, Vec<u8>)>);
+
+impl Extensions {
+    pub fn new(capabilities: Capabilities, metadata: Option<Metadata>) -> Extensions {
+        let mut entries = vec![(CAPABILITIES, capabilities.0.to_le_bytes().to_vec())];
+        if let Some(metadata) = metadata {
+            entries.push((VERSION, metadata.version().as_bytes().to_vec()));
+            entries.push((UPTIME, metadata.uptime.to_le_bytes().to_vec()));
+        }
+        Extensions(entries)
+    }
+
+    // Everything, if the sender didn't say
+    pub fn capabilities(&self) -> Capabilities {
+        self.entry(CAPABILITIES)
+            .and_then(|bytes| bytes.try_into().ok())
+            .map_or(Capabilities::ALL, |bytes| {
+                Capabilities(u32::from_le_bytes(bytes))
+            })
+    }
+
+    // Only there if the sender gave its version. Anything too long is cut short.
+    pub fn metadata(&self) -> Option<Metadata> {
+        let version = self.entry(VERSION)?;
+        let version = &version[..version.len().min(MAX_VERSION_LEN)];
+        let version = match std::str::from_utf8(version) {
+            Ok(version) => version,
+            Err(e) => std::str::from_utf8(&version[..e.valid_up_to()]).unwrap(),
+        };
+        let uptime = self
+            .entry(UPTIME)
+            .and_then(|bytes| bytes.try_into().ok())
+            .map_or(0, u64::from_le_bytes);
+        Some(Metadata::new(version, Duration::from_secs(uptime)))
+    }
+
+    fn entry(&self, tag: u8) -> Option<&[u8]> {
+        self.0
+            .iter()
+            .find(|(t, _)| *t == tag)
+            .map(|(_, bytes)| &bytes[..])
+    }
+}
+
+// What a node supports beyond pinging and finding nodes, as it says in its pings and pongs. Peers
+// that haven't told us are taken to support everything.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Capabilities(pub u32);
+
+impl Capabilities {
+    // Store and FindValue
+    pub const STORE: Capabilities = Capabilities(1);
+    // StoreSigned, and answering FindValue with Signed
+    pub const SIGNED: Capabilities = Capabilities(1 << 1);
+    // Announce and GetPeers
+    pub const PEERS: Capabilities = Capabilities(1 << 2);
+    // Add and FindValues
+    pub const SETS: Capabilities = Capabilities(1 << 3);
+    // Everything this version of the protocol has
+    pub const ALL: Capabilities = Capabilities(0b1111);
+
+    pub fn contains(self, other: Capabilities) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl std::ops::BitOr for Capabilities {
+    type Output = Capabilities;
+
+    fn bitor(self, other: Capabilities) -> Capabilities {
+        Capabilities(self.0 | other.0)
+    }
+}
+
+// Longest software version a ping or pong can carry, in bytes
+pub const MAX_VERSION_LEN: usize = 32;
+
+// What a node says about itself in its pings and pongs: the software it's running and how long
+// it's been up. Each goes in an entry of its own, with the version cut to MAX_VERSION_LEN, so it
+// can't grow a packet by more than a few dozen bytes.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Metadata {
+    version: [u8; MAX_VERSION_LEN],
+    version_len: u8,
+    // In seconds
+    uptime: u64,
+}
+
+impl Metadata {
+    // Cuts version down to MAX_VERSION_LEN bytes, short of any character that would go over.
+    pub fn new(version: &str, uptime: Duration) -> Metadata {
+        let mut len = version.len().min(MAX_VERSION_LEN);
+        while !version.is_char_boundary(len) {
+            len -= 1;
+        }
+        let mut bytes = [0; MAX_VERSION_LEN];
+        bytes[..len].copy_from_slice(&version.as_bytes()[..len]);
+        Metadata {
+            version: bytes,
+            version_len: len as u8,
+            uptime: uptime.as_secs(),
+        }
+    }
+
+    // Empty if the peer sent something that isn't UTF-8
+    pub fn version(&self) -> &str {
+        let len = usize::from(self.version_len).min(MAX_VERSION_LEN);
+        std::str::from_utf8(&self.version[..len]).unwrap_or("")
+    }
+
+    pub fn uptime(&self) -> Duration {
+        Duration::from_secs(self.uptime)
+    }
+}
+
+// Why a node refused to store a value
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub enum Rejection {
+    // The node knows of K others closer to the key than itself
+    NotResponsible,
+    BadSignature,
+    // We hold a signed value for the key with a later sequence number, or a different one with
+    // the same sequence number
+    StaleSequence,
+    // We hold a later version of the key's value
+    StaleVersion,
+    // An announce whose token we didn't give out, or gave out too long ago
+    BadToken,
+    // A set member longer than MAX_MEMBER_LEN
+    TooLarge,
+    // The sender's IP already has as many announcements or set members as we keep for one IP
+    TooMany,
+    // Our Store couldn't keep it, e.g. because it's full or its disk failed
+    StoreFailed,
+    // The node isn't responsible, so passed it on towards one that is (see
+    // Config::forward_stores). Nothing says whether it got there, so it doesn't count as stored.
+    Forwarded,
+    // A plain value for a key that holds a signed one, which only the key's holder can replace
+    SignedKey,
+}
+
+impl Payload {
+    pub(crate) fn is_response(&self) -> bool {
+        matches!(
+            self,
+            Payload::Pong
+                | Payload::PongExt(_)
+                | Payload::Nodes(_)
+                | Payload::Peers(..)
+                | Payload::StoreAck(_)
+                | Payload::Value(_)
+                | Payload::Signed(_)
+                | Payload::Values(..)
+        )
+    }
+}
+
+// On the wire, with every integer little-endian:
+//
+//   network_id  4 bytes
+//   id          32 bytes
+//   seq_num     8 bytes
+//   payload     4 byte tag (see Payload), then its fields
+//   signature   1 byte, 0 for none or 1 followed by the signature
+//
+// Variable-length fields (vectors, byte strings) are an 8 byte length followed by their items.
+// If the tag has its COMPRESSED bit set, the payload's fields are instead an 8 byte length and
+// that many bytes of them compressed (see lz.rs).
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Packet {
+    // Which network the sender belongs to. Nodes drop packets from any network but their own.
+    pub network_id: u32,
+    pub id: NodeID,
+    pub seq_num: u64,
+    pub payload: Payload,
+    // Only sent by nodes with Config::sign_packets on, which drop any packet without a valid one
+    pub signature: Option<PacketSignature>,
+}
+
+// Proves a packet came from the node whose ID it carries, and wasn't changed on the way. A node
+// that signs takes the hash of its public key as its ID, so nobody else can sign as it.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PacketSignature {
+    pub public_key: VerifyingKey,
+    pub signature: Signature,
+}
+
+// What a signature adds to a packet, past the byte that says whether there is one: the key, with
+// its 8 byte length, then the signature
+pub const SIGNATURE_LEN: usize = 8 + 32 + 64;
+
+// The largest payload a UDP datagram can carry over IPv4
+pub const MAX_PACKET_SIZE: u64 = 65_507;
+
+// Set in a payload's tag when its fields are compressed
+const COMPRESSED: u32 = 1 << 31;
+
+// Where the payload's tag is in an encoded packet, after network_id, id and seq_num
+const TAG_AT: usize = 44;
+const FIELDS_AT: usize = TAG_AT + 4;
+
+// Payloads whose fields take fewer bytes than this are never compressed, as they wouldn't get much
+// smaller
+pub const COMPRESS_THRESHOLD: usize = 512;
+
+// The largest value an unsigned Store packet can carry within MAX_PACKET_SIZE. The rest of the
+// packet takes 97 bytes: 44 of header, the payload's tag, key, value length and version, and the
+// byte saying there's no signature. Anything else on the datagram, like a MAC, comes out of this
+// (see Config::max_value_size).
+pub const MAX_VALUE_SIZE: usize = MAX_PACKET_SIZE as usize - 97;
+
+// The wire encoding: bincode's classic fixed-width little-endian layout, but strict about trailing
+// bytes so a packet with anything appended to it is treated as malformed. Nothing bigger than fits
+// in a datagram is encoded or decoded, so a length field claiming more is refused before anything
+// is allocated for it. Each option is spelled out rather than left to bincode's defaults, so the
+// layout can't change under us.
+fn codec() -> impl Options {
+    codec_within(MAX_PACKET_SIZE)
+}
+
+fn codec_within(limit: u64) -> impl Options {
+    DefaultOptions::new()
+        .with_little_endian()
+        .with_fixint_encoding()
+        .reject_trailing_bytes()
+        .with_limit(limit)
+}
+
+impl Packet {
+    // A packet whose payload is newer than we know still keeps its header, so we can at least learn
+    // the sender from it.
+    pub fn decode(buf: &[u8]) -> bincode::Result<Packet> {
+        if let Some(tag) = buf.get(TAG_AT..FIELDS_AT) {
+            let tag = u32::from_le_bytes(tag.try_into().unwrap());
+            if tag & COMPRESSED != 0 {
+                return Packet::decode(&Packet::decompress(buf, tag)?);
+            }
+        }
+        codec().deserialize(buf).or_else(|err| {
+            let header: (u32, NodeID, u64, u32) =
+                codec().allow_trailing_bytes().deserialize(buf)?;
+            match header {
+                (network_id, id, seq_num, tag) if tag >= KNOWN_TAGS => Ok(Packet {
+                    network_id,
+                    id,
+                    seq_num,
+                    payload: Payload::Unknown(tag),
+                    signature: None,
+                }),
+                _ => Err(err),
+            }
+        })
+    }
+
+    pub fn encode_into(&self, buf: &mut Vec<u8>) -> bincode::Result<()> {
+        self.encode_within(buf, MAX_PACKET_SIZE)
+    }
+
+    // Like encode_into, but fails if the packet comes to more than limit bytes, e.g. to leave room
+    // for a MAC after it.
+    pub fn encode_within(&self, buf: &mut Vec<u8>, limit: u64) -> bincode::Result<()> {
+        codec_within(limit).serialize_into(buf, self)
+    }
+
+    // Like encode_within, but compresses a Nodes or Value payload of at least COMPRESS_THRESHOLD
+    // bytes, so long as that makes it smaller. Only nodes that know to decompress it can read it.
+    pub fn encode_compressed_within(&self, buf: &mut Vec<u8>, limit: u64) -> bincode::Result<()> {
+        let start = buf.len();
+        self.encode_within(buf, limit)?;
+        if !matches!(self.payload, Payload::Nodes(_) | Payload::Value(_)) {
+            return Ok(());
+        }
+        let fields_end = buf.len() - codec().serialized_size(&self.signature)? as usize;
+        let fields = &buf[start + FIELDS_AT..fields_end];
+        if fields.len() < COMPRESS_THRESHOLD {
+            return Ok(());
+        }
+        let packed = lz::compress(fields);
+        if packed.len() + 8 >= fields.len() {
+            return Ok(());
+        }
+
+        let signature = buf[fields_end..].to_vec();
+        buf.truncate(start + FIELDS_AT);
+        let tag = &mut buf[start + TAG_AT..];
+        tag[3] |= (COMPRESSED >> 24) as u8;
+        buf.extend_from_slice(&(packed.len() as u64).to_le_bytes());
+        buf.extend_from_slice(&packed);
+        buf.extend_from_slice(&signature);
+        Ok(())
+    }
+
+    // The packet in buf as it would have been without compression. Nothing that would come out
+    // bigger than a datagram is decompressed.
+    fn decompress(buf: &[u8], tag: u32) -> bincode::Result<Vec<u8>> {
+        let malformed = || Box::new(bincode::ErrorKind::Custom("bad compressed payload".into()));
+        let len = buf
+            .get(FIELDS_AT..FIELDS_AT + 8)
+            .map(|len| u64::from_le_bytes(len.try_into().unwrap()) as usize)
+            .ok_or_else(malformed)?;
+        let packed_end = (FIELDS_AT + 8).checked_add(len).ok_or_else(malformed)?;
+        let packed = buf.get(FIELDS_AT + 8..packed_end).ok_or_else(malformed)?;
+        let fields =
+            lz::decompress(packed, MAX_PACKET_SIZE as usize - FIELDS_AT).ok_or_else(malformed)?;
+
+        let mut plain = buf[..TAG_AT].to_vec();
+        plain.extend_from_slice(&(tag & !COMPRESSED).to_le_bytes());
+        plain.extend_from_slice(&fields);
+        plain.extend_from_slice(&buf[packed_end..]);
+        Ok(plain)
+    }
+
+    // What a signature covers: every field but the signature itself
+    fn signed_bytes(&self) -> bincode::Result<Vec<u8>> {
+        codec().serialize(&(self.network_id, self.id, self.seq_num, &self.payload))
+    }
+
+    pub fn sign(&mut self, signing_key: &SigningKey) -> bincode::Result<()> {
+        let signature = signing_key.sign(&self.signed_bytes()?);
+        self.signature = Some(PacketSignature {
+            public_key: signing_key.verifying_key(),
+            signature,
+        });
+        Ok(())
+    }
+
+    // Whether the packet carries a signature by the key its ID is the hash of, and it matches the
+    // rest of the packet.
+    pub fn verify(&self) -> bool {
+        match (&self.signature, self.signed_bytes()) {
+            (Some(s), Ok(bytes)) => {
+                NodeID::from_key(s.public_key.as_bytes()) == self.id
+                    && s.public_key.verify(&bytes, &s.signature).is_ok()
+            }
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn reject_trailing_bytes() {
+        let pack = Packet {
+            network_id: 0,
+            id: rand::random(),
+            seq_num: 7,
+            payload: Payload::Ping,
+            signature: None,
+        };
+        let mut buf = Vec::new();
+        pack.encode_into(&mut buf).unwrap();
+        // Same bytes as plain bincode, only decoding got stricter
+        assert_eq!(buf, bincode::serialize(&pack).unwrap());
+
+        let decoded = Packet::decode(&buf).unwrap();
+        assert_eq!(decoded.id, pack.id);
+        assert_eq!(decoded.seq_num, 7);
+
+        buf.extend_from_slice(&[0xDE, 0xAD]);
+        assert!(Packet::decode(&buf).is_err());
+    }
+
+    #[test]
+    fn wire_format() {
+        let mut id = NodeID::zero();
+        id.bytes[0] = 0xAB;
+        id.bytes[31] = 0xCD;
+        let encode = |payload| {
+            let pack = Packet {
+                network_id: 0x0102_0304,
+                id,
+                seq_num: 0x1122_3344_5566_7788,
+                payload,
+                signature: None,
+            };
+            let mut buf = Vec::new();
+            pack.encode_into(&mut buf).unwrap();
+            buf
+        };
+
+        let mut ping = vec![0x04, 0x03, 0x02, 0x01, 0xAB];
+        ping.extend_from_slice(&[0; 30]);
+        ping.push(0xCD);
+        ping.extend_from_slice(&[0x88, 0x77, 0x66, 0x55, 0x44, 0x33, 0x22, 0x11]);
+        ping.extend_from_slice(&[0, 0, 0, 0]);
+        // No signature
+        ping.push(0);
+        assert_eq!(encode(Payload::Ping), ping);
+
+        let mut pong = ping.clone();
+        pong[44] = 1;
+        assert_eq!(encode(Payload::Pong), pong);
+
+        let mut ext = ping[..44].to_vec();
+        ext.extend_from_slice(&[14, 0, 0, 0]);
+        // One entry: tag, length, then the capabilities
+        ext.extend_from_slice(&[1, 0, 0, 0, 0, 0, 0, 0]);
+        ext.push(0);
+        ext.extend_from_slice(&[4, 0, 0, 0, 0, 0, 0, 0]);
+        ext.extend_from_slice(&[0b1111, 0, 0, 0]);
+        ext.push(0);
+        assert_eq!(
+            encode(Payload::PingExt(Extensions::new(Capabilities::ALL, None))),
+            ext
+        );
+    }
+
+    #[test]
+    fn baseline_ping() {
+        // A ping from a node that predates extensions: the header, tag 0, and no signature
+        let mut buf = vec![0; 44];
+        buf.extend_from_slice(&[0, 0, 0, 0]);
+        buf.push(0);
+        assert!(matches!(
+            Packet::decode(&buf).unwrap().payload,
+            Payload::Ping
+        ));
+    }
+
+    #[test]
+    fn unknown_extensions() {
+        let mut extensions = Extensions::new(Capabilities::PEERS, None);
+        extensions.0.insert(0, (0xFF, vec![1, 2, 3]));
+        let pack = Packet {
+            network_id: 0,
+            id: rand::random(),
+            seq_num: 1,
+            payload: Payload::PongExt(extensions),
+            signature: None,
+        };
+        let mut buf = Vec::new();
+        pack.encode_into(&mut buf).unwrap();
+        match Packet::decode(&buf).unwrap().payload {
+            Payload::PongExt(decoded) => {
+                assert_eq!(decoded.capabilities(), Capabilities::PEERS);
+                assert_eq!(decoded.metadata(), None);
+            }
+            payload => panic!("{:?}", payload),
+        }
+        // Anyone who doesn't say is taken to support everything
+        assert_eq!(Extensions::default().capabilities(), Capabilities::ALL);
+    }
+
+    #[test]
+    fn metadata() {
+        let metadata = Metadata::new("dht/0.1", Duration::from_millis(90_500));
+        assert_eq!(metadata.version(), "dht/0.1");
+        assert_eq!(metadata.uptime(), Duration::from_secs(90));
+
+        // Cut short of the character that would go over
+        let long = format!("{}é", "a".repeat(MAX_VERSION_LEN - 1));
+        let metadata = Metadata::new(&long, Duration::ZERO);
+        assert_eq!(metadata.version(), &long[..MAX_VERSION_LEN - 1]);
+
+        let pack = Packet {
+            network_id: 0,
+            id: rand::random(),
+            seq_num: 1,
+            payload: Payload::PongExt(Extensions::new(Capabilities::ALL, Some(metadata))),
+            signature: None,
+        };
+        let mut buf = Vec::new();
+        pack.encode_into(&mut buf).unwrap();
+        match Packet::decode(&buf).unwrap().payload {
+            Payload::PongExt(decoded) => assert_eq!(decoded.metadata(), Some(metadata)),
+            payload => panic!("{:?}", payload),
+        }
+
+        // Each part is an entry of its own, so either can go missing, or come too long, without
+        // losing the other
+        let mut version = long.into_bytes();
+        version.push(b'!');
+        let extensions = Extensions(vec![(VERSION, version)]);
+        let decoded = extensions.metadata().unwrap();
+        assert_eq!(decoded.version(), &"a".repeat(MAX_VERSION_LEN - 1));
+        assert_eq!(decoded.uptime(), Duration::ZERO);
+        let extensions = Extensions(vec![(UPTIME, 5u64.to_le_bytes().to_vec())]);
+        assert_eq!(extensions.metadata(), None);
+        assert_eq!(extensions.capabilities(), Capabilities::ALL);
+    }
+
+    #[test]
+    fn compressed_nodes() {
+        let contacts: Vec<Contact> = (0..20)
+            .map(|i| Contact::new(rand::random(), ([127, 0, 0, 1], 7000 + i).into()))
+            .collect();
+        let key = SigningKey::from_bytes(&rand::random());
+        let mut pack = Packet {
+            network_id: 3,
+            id: NodeID::from_key(key.verifying_key().as_bytes()),
+            seq_num: 5,
+            payload: Payload::Nodes(contacts.clone()),
+            signature: None,
+        };
+        pack.sign(&key).unwrap();
+
+        let (mut plain, mut compressed) = (Vec::new(), Vec::new());
+        pack.encode_into(&mut plain).unwrap();
+        pack.encode_compressed_within(&mut compressed, MAX_PACKET_SIZE)
+            .unwrap();
+        assert!(compressed.len() < plain.len());
+        assert_ne!(compressed[TAG_AT + 3] & 0x80, 0);
+
+        let decoded = Packet::decode(&compressed).unwrap();
+        assert!(decoded.verify());
+        match decoded.payload {
+            Payload::Nodes(decoded) => {
+                assert_eq!(decoded, contacts);
+                assert!(decoded
+                    .iter()
+                    .zip(&contacts)
+                    .all(|(a, b)| a.addr() == b.addr()));
+            }
+            payload => panic!("{:?}", payload),
+        }
+
+        // A length running past the end leaves it malformed
+        for len in [u64::MAX, compressed.len() as u64] {
+            let mut bad = compressed.clone();
+            bad[FIELDS_AT..FIELDS_AT + 8].copy_from_slice(&len.to_le_bytes());
+            assert!(Packet::decode(&bad).is_err());
+        }
+
+        // Small payloads, and ones other than Nodes and Value, go as they are
+        for payload in [
+            Payload::Nodes(contacts[..2].to_vec()),
+            Payload::Store(rand::random(), vec![0; 4 * COMPRESS_THRESHOLD], 0),
+        ] {
+            let pack = Packet {
+                payload,
+                signature: pack.signature.clone(),
+                ..pack
+            };
+            let (mut plain, mut compressed) = (Vec::new(), Vec::new());
+            pack.encode_into(&mut plain).unwrap();
+            pack.encode_compressed_within(&mut compressed, MAX_PACKET_SIZE)
+                .unwrap();
+            assert_eq!(plain, compressed);
+        }
+    }
+
+    #[test]
+    fn unknown_payload() {
+        let pack = Packet {
+            network_id: 5,
+            id: rand::random(),
+            seq_num: 9,
+            payload: Payload::FindNode(rand::random()),
+            signature: None,
+        };
+        let mut buf = Vec::new();
+        pack.encode_into(&mut buf).unwrap();
+
+        // A newer node's payload, with fields we couldn't make sense of
+        buf[44..48].copy_from_slice(&u32::to_le_bytes(KNOWN_TAGS + 3));
+        buf.extend_from_slice(&[0xDE, 0xAD]);
+        let decoded = Packet::decode(&buf).unwrap();
+        assert_eq!(decoded.network_id, 5);
+        assert_eq!(decoded.id, pack.id);
+        assert_eq!(decoded.seq_num, 9);
+        assert!(matches!(decoded.payload, Payload::Unknown(tag) if tag == KNOWN_TAGS + 3));
+
+        // A tag we know with fields that don't fit it is still malformed
+        buf[44..48].copy_from_slice(&u32::to_le_bytes(2));
+        assert!(Packet::decode(&buf).is_err());
+        assert!(Packet::decode(&buf[..40]).is_err());
+    }
+
+    #[test]
+    fn oversized_lengths() {
+        let pack = Packet {
+            network_id: 0,
+            id: NodeID::zero(),
+            seq_num: 0,
+            payload: Payload::Value(vec![0xEE; 3]),
+            signature: None,
+        };
+        let mut buf = Vec::new();
+        pack.encode_into(&mut buf).unwrap();
+        let mut expected = vec![0; 44];
+        expected.extend_from_slice(&[10, 0, 0, 0]);
+        expected.extend_from_slice(&[3, 0, 0, 0, 0, 0, 0, 0]);
+        expected.extend_from_slice(&[0xEE, 0xEE, 0xEE, 0]);
+        assert_eq!(buf, expected);
+
+        // Claim more bytes than any datagram could hold, or more than the whole address space
+        for len in [MAX_PACKET_SIZE, u64::MAX].iter() {
+            buf[48..56].copy_from_slice(&len.to_le_bytes());
+            assert!(Packet::decode(&buf).is_err());
+        }
+    }
+
+    #[test]
+    fn max_value_size() {
+        let store = |len| Packet {
+            network_id: 0,
+            id: rand::random(),
+            seq_num: 0,
+            payload: Payload::Store(rand::random(), vec![0; len], 0),
+            signature: None,
+        };
+        let mut buf = Vec::new();
+        store(MAX_VALUE_SIZE).encode_into(&mut buf).unwrap();
+        assert_eq!(buf.len() as u64, MAX_PACKET_SIZE);
+        assert!(store(MAX_VALUE_SIZE + 1)
+            .encode_into(&mut Vec::new())
+            .is_err());
+    }
+
+    #[test]
+    fn wire_tags() {
+        let key = NodeID::zero();
+        let signed = SignedValue::sign(&SigningKey::from_bytes(&[7; 32]), 0, Vec::new());
+        let payloads = vec![
+            (Payload::Ping, 0),
+            (Payload::Pong, 1),
+            (Payload::FindNode(key), 2),
+            (Payload::Nodes(Vec::new()), 3),
+            (Payload::Announce(key, 0, [0; 8]), 4),
+            (Payload::GetPeers(key), 5),
+            (Payload::Peers(Vec::new(), Vec::new(), [0; 8]), 6),
+            (Payload::Store(key, Vec::new(), 0), 7),
+            (Payload::StoreAck(Ok(())), 8),
+            (Payload::FindValue(key), 9),
+            (Payload::Value(Vec::new()), 10),
+            (Payload::StoreSigned(signed.clone()), 11),
+            (Payload::Signed(signed), 12),
+            (Payload::Leaving, 13),
+            (Payload::PingExt(Extensions::default()), 14),
+            (Payload::PongExt(Extensions::default()), 15),
+            (Payload::Add(key, Vec::new()), 16),
+            (Payload::FindValues(key), 17),
+            (Payload::Values(Vec::new(), Vec::new()), 18),
+        ];
+        assert_eq!(payloads.len() as u32, KNOWN_TAGS);
+        for (payload, tag) in payloads {
+            let buf = codec().serialize(&payload).unwrap();
+            assert_eq!(buf[..4], u32::to_le_bytes(tag), "{:?}", payload);
+        }
+    }
+
+    #[test]
+    fn signed_packet() {
+        let signing_key = SigningKey::from_bytes(&rand::random());
+        let mut pack = Packet {
+            network_id: 0,
+            id: NodeID::from_key(signing_key.verifying_key().as_bytes()),
+            seq_num: 3,
+            payload: Payload::Value(b"value".to_vec()),
+            signature: None,
+        };
+        assert!(!pack.verify());
+        pack.sign(&signing_key).unwrap();
+        assert!(pack.verify());
+
+        let mut buf = Vec::new();
+        pack.encode_into(&mut buf).unwrap();
+        assert_eq!(
+            buf.len(),
+            codec().serialized_size(&pack.payload).unwrap() as usize + 45 + SIGNATURE_LEN
+        );
+        let decoded = Packet::decode(&buf).unwrap();
+        assert!(decoded.verify());
+        assert_eq!(
+            decoded.signature.unwrap().public_key,
+            signing_key.verifying_key()
+        );
+
+        // Change the value on the way
+        let at = buf.windows(5).position(|w| w == b"value").unwrap();
+        buf[at] ^= 1;
+        let tampered = Packet::decode(&buf).unwrap();
+        assert!(matches!(&tampered.payload, Payload::Value(v) if v != b"value"));
+        assert!(!tampered.verify());
+
+        // Or sign it again with someone else's key, but keep the original key
+        let mut forged = Packet::decode(&buf).unwrap();
+        forged
+            .sign(&SigningKey::from_bytes(&rand::random()))
+            .unwrap();
+        forged.signature.as_mut().unwrap().public_key = signing_key.verifying_key();
+        assert!(!forged.verify());
+
+        // Or sign as someone else entirely, under a made up ID
+        let mut impostor = Packet::decode(&buf).unwrap();
+        impostor.payload = Payload::Value(b"value".to_vec());
+        impostor.id = rand::random();
+        impostor
+            .sign(&SigningKey::from_bytes(&rand::random()))
+            .unwrap();
+        assert!(!impostor.verify());
+    }
+
+    #[test]
+    fn round_trip() {
+        let key: NodeID = rand::random();
+        let pack = Packet {
+            network_id: 0,
+            id: rand::random(),
+            seq_num: 3,
+            payload: Payload::Store(key, b"value".to_vec(), 9),
+            signature: None,
+        };
+        let mut buf = Vec::new();
+        pack.encode_into(&mut buf).unwrap();
+
+        let decoded = Packet::decode(&buf).unwrap();
+        assert_eq!(decoded.id, pack.id);
+        assert_eq!(decoded.seq_num, pack.seq_num);
+        match decoded.payload {
+            Payload::Store(k, value, version) => {
+                assert_eq!(k, key);
+                assert_eq!(value, b"value");
+                assert_eq!(version, 9);
+            }
+            p => panic!("expected Store, got {:?}", p),
+        }
+    }
+}
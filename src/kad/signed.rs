@@ -0,0 +1,57 @@
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+
+use super::kbucket::NodeID;
+
+// A mutable value published by the holder of a key pair. It's stored under the hash of the public
+// key, and only ever replaced by a value with a higher sequence number signed by the same key.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct SignedValue {
+    public_key: VerifyingKey,
+    seq: u64,
+    value: Vec<u8>,
+    signature: Signature,
+}
+
+// What actually gets signed: the sequence number, so it can't be replayed with an older value,
+// followed by the value itself.
+fn message(seq: u64, value: &[u8]) -> Vec<u8> {
+    let mut msg = Vec::with_capacity(8 + value.len());
+    msg.extend_from_slice(&seq.to_be_bytes());
+    msg.extend_from_slice(value);
+    msg
+}
+
+impl SignedValue {
+    pub fn sign(signing_key: &SigningKey, seq: u64, value: Vec<u8>) -> SignedValue {
+        let signature = signing_key.sign(&message(seq, &value));
+        SignedValue {
+            public_key: signing_key.verifying_key(),
+            seq,
+            value,
+            signature,
+        }
+    }
+
+    // The key this value is stored under
+    pub fn key(&self) -> NodeID {
+        NodeID::from_key(self.public_key.as_bytes())
+    }
+
+    pub fn public_key(&self) -> VerifyingKey {
+        self.public_key
+    }
+
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    pub fn value(&self) -> &[u8] {
+        &self.value
+    }
+
+    pub fn verify(&self) -> bool {
+        self.public_key
+            .verify(&message(self.seq, &self.value), &self.signature)
+            .is_ok()
+    }
+}
@@ -0,0 +1,436 @@
+use std::collections::{BTreeMap, HashMap};
+use std::error;
+use std::fmt;
+use std::hash::Hash;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Instant;
+
+use super::kbucket::NodeID;
+use super::signed::SignedValue;
+
+// Most peers returned for one key, so a response still fits comfortably in a datagram.
+pub const MAX_PEERS: usize = 64;
+
+// A plain value as a `Store` holds it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StoredValue {
+    pub value: Vec<u8>,
+    pub version: u64,
+    // When it was last stored, for Config::value_ttl
+    pub stored: Instant,
+}
+
+// Why a `Store` couldn't keep a value.
+#[derive(Debug)]
+pub enum StoreError {
+    // It has no room for more
+    Full,
+    // Its backing storage failed
+    Io(io::Error),
+}
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StoreError::Full => write!(f, "store is full"),
+            StoreError::Io(e) => write!(f, "store I/O error: {}", e),
+        }
+    }
+}
+
+impl error::Error for StoreError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            StoreError::Io(e) => Some(e),
+            StoreError::Full => None,
+        }
+    }
+}
+
+impl From<io::Error> for StoreError {
+    fn from(e: io::Error) -> StoreError {
+        StoreError::Io(e)
+    }
+}
+
+// Where a node keeps the plain values it's responsible for, so they can live on disk or in a
+// size-bounded cache instead of in memory. Versioning and expiry are worked out on top, so a
+// backend only has to hold what it's given.
+pub trait Store {
+    // Replaces whatever was held under key. A failure is passed on to whoever sent the value, as
+    // Rejection::StoreFailed.
+    fn put(&mut self, key: NodeID, value: StoredValue) -> Result<(), StoreError>;
+    fn get(&self, key: NodeID) -> Option<StoredValue>;
+    fn remove(&mut self, key: NodeID) -> Option<StoredValue>;
+    // In no particular order
+    fn iter(&self) -> Box<dyn Iterator<Item = (NodeID, StoredValue)> + '_>;
+    // Like iter, but without reading the values
+    fn keys(&self) -> Box<dyn Iterator<Item = NodeID> + '_>;
+    // Drops every value keep turns down
+    fn retain(&mut self, keep: &mut dyn FnMut(NodeID, &StoredValue) -> bool);
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+// The default `Store`, which keeps everything in a `HashMap`.
+#[derive(Default)]
+pub struct MemoryStore {
+    values: HashMap<NodeID, StoredValue>,
+}
+
+impl Store for MemoryStore {
+    fn put(&mut self, key: NodeID, value: StoredValue) -> Result<(), StoreError> {
+        self.values.insert(key, value);
+        Ok(())
+    }
+
+    fn get(&self, key: NodeID) -> Option<StoredValue> {
+        self.values.get(&key).cloned()
+    }
+
+    fn remove(&mut self, key: NodeID) -> Option<StoredValue> {
+        self.values.remove(&key)
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = (NodeID, StoredValue)> + '_> {
+        Box::new(self.values.iter().map(|(key, value)| (*key, value.clone())))
+    }
+
+    fn keys(&self) -> Box<dyn Iterator<Item = NodeID> + '_> {
+        Box::new(self.values.keys().copied())
+    }
+
+    fn retain(&mut self, keep: &mut dyn FnMut(NodeID, &StoredValue) -> bool) {
+        self.values.retain(|key, value| keep(*key, value));
+    }
+
+    fn len(&self) -> usize {
+        self.values.len()
+    }
+}
+
+// Opaque values stored under a key, along with their version and when they were last stored.
+pub struct ValueStore<S: Store = MemoryStore> {
+    backend: S,
+}
+
+impl<S: Store> ValueStore<S> {
+    pub fn new(backend: S) -> ValueStore<S> {
+        ValueStore { backend }
+    }
+
+    // Keeps value unless we already hold a later version, returning whether it did. The same
+    // version replaces what we have, so the last write wins among unversioned values.
+    pub fn put(
+        &mut self,
+        key: NodeID,
+        value: Vec<u8>,
+        version: u64,
+        now: Instant,
+    ) -> Result<bool, StoreError> {
+        match self.backend.get(key) {
+            Some(held) if held.version > version => Ok(false),
+            _ => {
+                let value = StoredValue {
+                    value,
+                    version,
+                    stored: now,
+                };
+                self.backend.put(key, value)?;
+                Ok(true)
+            }
+        }
+    }
+
+    pub fn get(&self, key: NodeID) -> Option<Vec<u8>> {
+        self.get_versioned(key).map(|(value, _)| value)
+    }
+
+    pub fn get_versioned(&self, key: NodeID) -> Option<(Vec<u8>, u64)> {
+        self.backend
+            .get(key)
+            .map(|stored| (stored.value, stored.version))
+    }
+
+    // Drops every value last stored before the given time.
+    pub fn expire(&mut self, before: Instant) {
+        self.backend.retain(&mut |_, value| value.stored >= before);
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (NodeID, Vec<u8>)> + '_ {
+        self.backend.iter().map(|(key, stored)| (key, stored.value))
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = NodeID> + '_ {
+        self.backend.keys()
+    }
+
+    pub fn len(&self) -> usize {
+        self.backend.len()
+    }
+}
+
+// Signed values, keyed by the hash of their public key, along with when they were last stored.
+pub struct SignedStore {
+    values: HashMap<NodeID, (SignedValue, Instant)>,
+}
+
+impl SignedStore {
+    pub fn new() -> SignedStore {
+        SignedStore {
+            values: HashMap::new(),
+        }
+    }
+
+    // Keeps value unless we already hold one with a later sequence number, or a different one with
+    // the same sequence number. Storing what we hold again is fine. The signature must already
+    // have been checked.
+    pub fn put(&mut self, value: SignedValue, now: Instant) -> bool {
+        match self.values.get(&value.key()) {
+            Some((old, _)) if old.seq() > value.seq() => false,
+            Some((old, _)) if old.seq() == value.seq() && *old != value => false,
+            _ => {
+                self.values.insert(value.key(), (value, now));
+                true
+            }
+        }
+    }
+
+    pub fn get(&self, key: NodeID) -> Option<&SignedValue> {
+        self.values.get(&key).map(|(value, _)| value)
+    }
+
+    pub fn expire(&mut self, before: Instant) {
+        self.values.retain(|_, (_, stored)| *stored >= before);
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = NodeID> + '_ {
+        self.values.keys().cloned()
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+}
+
+// Each set, how much any one IP has added across all of them, and how many entries there are in
+// all are capped, so no one can fill our memory by announcing or adding over and over. Past a
+// set's cap or the total its oldest entry goes, but an IP at its cap has to wait for its own
+// entries to expire before it can add more.
+pub const MAX_PEERS_PER_KEY: usize = 1024;
+pub const MAX_ANNOUNCED_PER_IP: usize = 1024;
+pub const MAX_ANNOUNCED: usize = 64 * 1024;
+pub const MAX_MEMBERS_PER_KEY: usize = 256;
+pub const MAX_MEMBERS_PER_IP: usize = 256;
+pub const MAX_SET_ENTRIES: usize = 16 * 1024;
+
+// Most members returned for one key, and the longest a member can be, so a response still fits in
+// a datagram.
+pub const MAX_MEMBERS: usize = 48;
+pub const MAX_MEMBER_LEN: usize = 1024;
+
+// One item in a set: the set's key, the IP that last added it and when
+struct Entry<T> {
+    key: NodeID,
+    item: T,
+    owner: IpAddr,
+    added: Instant,
+}
+
+// Sets of items stored under a key, each with when it was last added, added to one at a time and
+// read back whole. Entries are indexed by item within their set, and kept in the order they were
+// last added across all of them, so adding and evicting never scan.
+pub struct SetStore<T> {
+    sets: HashMap<NodeID, HashMap<T, u64>>,
+    // Every entry by when it was last added, oldest first. Times never go backwards, so counting
+    // up keeps them in order.
+    order: BTreeMap<u64, Entry<T>>,
+    // How many entries each IP last added
+    owners: HashMap<IpAddr, usize>,
+    next: u64,
+    per_key: usize,
+    per_owner: usize,
+    total: usize,
+}
+
+impl<T: Clone + Eq + Hash> SetStore<T> {
+    pub fn new(per_key: usize, per_owner: usize, total: usize) -> SetStore<T> {
+        SetStore {
+            sets: HashMap::new(),
+            order: BTreeMap::new(),
+            owners: HashMap::new(),
+            next: 0,
+            per_key,
+            per_owner,
+            total,
+        }
+    }
+
+    // Adds item to key's set on behalf of owner, or refreshes it if it's there already. False if
+    // that would take owner past its cap.
+    pub fn add(&mut self, key: NodeID, item: T, owner: IpAddr, now: Instant) -> bool {
+        let old = self.sets.get(&key).and_then(|set| set.get(&item)).copied();
+        let refresh = old.is_some_and(|n| self.order[&n].owner == owner);
+        if !refresh && self.owners.get(&owner).copied().unwrap_or(0) >= self.per_owner {
+            return false;
+        }
+
+        match old {
+            Some(n) => self.evict(n),
+            None => {
+                let set = self.sets.get(&key);
+                if set.is_some_and(|set| set.len() >= self.per_key) {
+                    let oldest = set.and_then(|set| set.values().min()).copied();
+                    if let Some(oldest) = oldest {
+                        self.evict(oldest);
+                    }
+                }
+            }
+        }
+        if self.order.len() >= self.total {
+            let oldest = self.order.keys().next().copied();
+            if let Some(oldest) = oldest {
+                self.evict(oldest);
+            }
+        }
+
+        let n = self.next;
+        self.next += 1;
+        self.sets.entry(key).or_default().insert(item.clone(), n);
+        *self.owners.entry(owner).or_default() += 1;
+        let entry = Entry {
+            key,
+            item,
+            owner,
+            added: now,
+        };
+        self.order.insert(n, entry);
+        true
+    }
+
+    fn evict(&mut self, n: u64) {
+        let entry = match self.order.remove(&n) {
+            Some(entry) => entry,
+            None => return,
+        };
+        if let Some(set) = self.sets.get_mut(&entry.key) {
+            set.remove(&entry.item);
+            if set.is_empty() {
+                self.sets.remove(&entry.key);
+            }
+        }
+        if let Some(count) = self.owners.get_mut(&entry.owner) {
+            *count -= 1;
+            if *count == 0 {
+                self.owners.remove(&entry.owner);
+            }
+        }
+    }
+
+    // Returns up to max of the items in key's set, most recently added first.
+    pub fn get(&self, key: NodeID, max: usize) -> Vec<T> {
+        let mut items: Vec<(&T, u64)> = match self.sets.get(&key) {
+            Some(set) => set.iter().map(|(item, &n)| (item, n)).collect(),
+            None => return Vec::new(),
+        };
+        items.sort_by_key(|&(_, n)| std::cmp::Reverse(n));
+        items
+            .into_iter()
+            .take(max)
+            .map(|(item, _)| item.clone())
+            .collect()
+    }
+
+    // Forgets every item last added before the given time.
+    pub fn expire(&mut self, before: Instant) {
+        while let Some((&n, entry)) = self.order.iter().next() {
+            if entry.added >= before {
+                break;
+            }
+            self.evict(n);
+        }
+    }
+}
+
+// Sets of peer endpoints announced under a key (e.g. a torrent infohash)
+pub type PeerStore = SetStore<SocketAddr>;
+
+impl PeerStore {
+    pub fn peers() -> PeerStore {
+        SetStore::new(MAX_PEERS_PER_KEY, MAX_ANNOUNCED_PER_IP, MAX_ANNOUNCED)
+    }
+
+    // Each peer counts against its own IP's cap
+    pub fn announce(&mut self, key: NodeID, peer: SocketAddr, now: Instant) -> bool {
+        self.add(key, peer, peer.ip(), now)
+    }
+}
+
+// Sets of opaque values added under a key, for keys that stand for a group of things rather than
+// one blob
+pub type ValueSets = SetStore<Vec<u8>>;
+
+impl ValueSets {
+    pub fn members() -> ValueSets {
+        SetStore::new(MAX_MEMBERS_PER_KEY, MAX_MEMBERS_PER_IP, MAX_SET_ENTRIES)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn set_caps() {
+        let start = Instant::now();
+        let mut sets = SetStore::new(3, 10, 5);
+        let ip: IpAddr = [10, 0, 0, 1].into();
+        let (a, b) = (rand::random(), rand::random());
+        for i in 0..4 {
+            assert!(sets.add(a, i, ip, start + Duration::from_secs(i)));
+        }
+        // The oldest in the set made way
+        assert_eq!(sets.get(a, 10), vec![3, 2, 1]);
+
+        // Refreshing moves an item to the back of the line
+        sets.add(a, 1, ip, start + Duration::from_secs(4));
+        assert_eq!(sets.get(a, 10), vec![1, 3, 2]);
+        for i in 10..13 {
+            sets.add(b, i, ip, start + Duration::from_secs(i));
+        }
+        // And over the total cap, the oldest anywhere goes
+        assert_eq!(sets.order.len(), 5);
+        assert_eq!(sets.get(a, 10), vec![1, 3]);
+        assert_eq!(sets.get(b, 2), vec![12, 11]);
+
+        sets.expire(start + Duration::from_secs(11));
+        assert_eq!(sets.get(a, 10), Vec::<u64>::new());
+        assert_eq!(sets.get(b, 10), vec![12, 11]);
+        assert_eq!(sets.order.len(), 2);
+    }
+
+    #[test]
+    fn owner_cap() {
+        let now = Instant::now();
+        let mut sets = SetStore::new(10, 2, 10);
+        let (ip, other): (IpAddr, IpAddr) = ([10, 0, 0, 1].into(), [10, 0, 0, 2].into());
+        let key = rand::random();
+        assert!(sets.add(key, 1, ip, now));
+        assert!(sets.add(rand::random(), 2, ip, now));
+        // Whatever the key, an IP at its cap can only refresh what it has
+        assert!(!sets.add(key, 3, ip, now));
+        assert!(sets.add(key, 1, ip, now));
+        assert!(sets.add(key, 3, other, now));
+
+        // Taking over another IP's entry counts against the new owner
+        assert!(!sets.add(key, 3, ip, now));
+        sets.expire(now + Duration::from_secs(1));
+        assert!(sets.owners.is_empty());
+        assert!(sets.add(key, 3, ip, now));
+    }
+}
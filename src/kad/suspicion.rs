@@ -0,0 +1,111 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+// Unsolicited responses from one IP it takes to get it banned. Late answers to requests we've
+// given up on count too, so this leaves room for an honest node on a slow link.
+pub const SUSPICION_LIMIT: u32 = 16;
+
+// How long an IP's score lasts after its latest unsolicited response
+pub const SUSPICION_WINDOW: Duration = Duration::from_secs(60);
+
+// How long a ban lasts. Everything from a banned IP is dropped until then.
+pub const BAN_DURATION: Duration = Duration::from_secs(10 * 60);
+
+// Most IPs scored at once. Past this, the one that's gone longest without offending is forgotten.
+pub const MAX_SCORED: usize = 4096;
+
+// Scores IPs by the unsolicited responses they send, and bans the ones that send too many. Used
+// with Config::strict_responses.
+#[derive(Default)]
+pub struct Suspicion {
+    // How many, and when the latest came in
+    scores: HashMap<IpAddr, (u32, Instant)>,
+    // When each ban ends
+    banned: HashMap<IpAddr, Instant>,
+}
+
+impl Suspicion {
+    // Counts an unsolicited response from ip, returning whether that got it banned.
+    pub fn offend(&mut self, ip: IpAddr, now: Instant) -> bool {
+        let score = match self.scores.get(&ip) {
+            Some(&(score, last)) if now.saturating_duration_since(last) < SUSPICION_WINDOW => {
+                score + 1
+            }
+            _ => 1,
+        };
+        if score < SUSPICION_LIMIT {
+            if self.scores.len() >= MAX_SCORED && !self.scores.contains_key(&ip) {
+                self.expire(now);
+                let oldest = self.scores.iter().min_by_key(|(_, &(_, last))| last);
+                if let Some((&oldest, _)) = oldest.filter(|_| self.scores.len() >= MAX_SCORED) {
+                    self.scores.remove(&oldest);
+                }
+            }
+            self.scores.insert(ip, (score, now));
+            return false;
+        }
+        self.scores.remove(&ip);
+        self.banned.insert(ip, now + BAN_DURATION);
+        true
+    }
+
+    pub fn is_banned(&self, ip: IpAddr, now: Instant) -> bool {
+        self.banned.get(&ip).is_some_and(|&until| now < until)
+    }
+
+    // Forgets scores and bans that have run out.
+    pub fn expire(&mut self, now: Instant) {
+        self.scores
+            .retain(|_, &mut (_, last)| now.saturating_duration_since(last) < SUSPICION_WINDOW);
+        self.banned.retain(|_, &mut until| now < until);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn scores_lapse() {
+        let start = Instant::now();
+        let mut suspicion = Suspicion::default();
+        let ip: IpAddr = [10, 0, 0, 1].into();
+        for _ in 1..SUSPICION_LIMIT {
+            assert!(!suspicion.offend(ip, start));
+        }
+
+        // Waiting out the window starts it over
+        let later = start + SUSPICION_WINDOW;
+        for _ in 1..SUSPICION_LIMIT {
+            assert!(!suspicion.offend(ip, later));
+        }
+        assert!(!suspicion.is_banned(ip, later));
+        assert!(suspicion.offend(ip, later));
+        assert!(suspicion.is_banned(ip, later));
+        assert!(!suspicion.is_banned([10, 0, 0, 2].into(), later));
+
+        suspicion.expire(later + BAN_DURATION);
+        assert!(!suspicion.is_banned(ip, later));
+    }
+
+    #[test]
+    fn bounded_scores() {
+        let start = Instant::now();
+        let mut suspicion = Suspicion::default();
+        let ip = |i: u32| IpAddr::from(u32::to_be_bytes(i));
+        for i in 0..MAX_SCORED as u32 {
+            suspicion.offend(ip(i), start + Duration::from_millis(u64::from(i)));
+        }
+        suspicion.offend(ip(0), start + SUSPICION_WINDOW / 2);
+
+        // The quietest goes to make room, and decayed ones before anyone
+        let later = start + SUSPICION_WINDOW / 2;
+        suspicion.offend(ip(u32::MAX), later);
+        assert_eq!(suspicion.scores.len(), MAX_SCORED);
+        assert!(!suspicion.scores.contains_key(&ip(1)));
+        assert!(suspicion.scores.contains_key(&ip(0)));
+        suspicion.offend(ip(u32::MAX - 1), later + SUSPICION_WINDOW);
+        assert_eq!(suspicion.scores.len(), 1);
+    }
+}
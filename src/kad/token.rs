@@ -0,0 +1,88 @@
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use sha2::{Digest, Sha256};
+
+use super::kbucket::NodeID;
+
+// How often the secret behind our tokens changes. Tokens made with the one before still work, so
+// a token is good for between one and two of these.
+pub const TOKEN_ROTATION: Duration = Duration::from_secs(5 * 60);
+
+// What a node hands out with its answer to `GetPeers`, and wants back with an `Announce` for the
+// same key. It proves the announcer can receive at the IP it's announcing, as in BitTorrent.
+pub type Token = [u8; 8];
+
+// Makes and checks tokens as a hash of the asker's IP, the key it asked about and a secret only we
+// know.
+pub struct Tokens {
+    secret: [u8; 32],
+    previous: [u8; 32],
+    rotated: Instant,
+}
+
+impl Tokens {
+    pub fn new(now: Instant) -> Tokens {
+        Tokens {
+            secret: rand::random(),
+            previous: rand::random(),
+            rotated: now,
+        }
+    }
+
+    fn make(secret: &[u8; 32], ip: IpAddr, key: NodeID) -> Token {
+        let mut hasher = Sha256::new();
+        hasher.update(secret);
+        match ip {
+            IpAddr::V4(ip) => hasher.update(ip.octets()),
+            IpAddr::V6(ip) => hasher.update(ip.octets()),
+        }
+        hasher.update(key.bytes);
+        let mut token = [0; 8];
+        token.copy_from_slice(&hasher.finalize()[..8]);
+        token
+    }
+
+    pub fn issue(&self, ip: IpAddr, key: NodeID) -> Token {
+        Tokens::make(&self.secret, ip, key)
+    }
+
+    pub fn check(&self, ip: IpAddr, key: NodeID, token: Token) -> bool {
+        token == Tokens::make(&self.secret, ip, key)
+            || token == Tokens::make(&self.previous, ip, key)
+    }
+
+    // Moves on to a new secret once the current one has been in use for TOKEN_ROTATION.
+    pub fn rotate(&mut self, now: Instant) {
+        if now.saturating_duration_since(self.rotated) >= TOKEN_ROTATION {
+            self.previous = self.secret;
+            self.secret = rand::random();
+            self.rotated = now;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn rotation() {
+        let start = Instant::now();
+        let mut tokens = Tokens::new(start);
+        let ip: IpAddr = [10, 0, 0, 1].into();
+        let key = rand::random();
+        let token = tokens.issue(ip, key);
+        assert!(tokens.check(ip, key, token));
+        assert!(!tokens.check([10, 0, 0, 2].into(), key, token));
+        // Nor is it good for any other key
+        assert!(!tokens.check(ip, rand::random(), token));
+
+        // Still good for one rotation, but not two
+        tokens.rotate(start + TOKEN_ROTATION);
+        assert!(tokens.check(ip, key, token));
+        assert_ne!(tokens.issue(ip, key), token);
+        tokens.rotate(start + TOKEN_ROTATION * 2);
+        assert!(!tokens.check(ip, key, token));
+    }
+}
@@ -4,107 +4,2304 @@ extern crate crossbeam;
 extern crate serde;
 
 use crossbeam::channel;
+use ed25519_dalek::VerifyingKey;
+use rand::seq::SliceRandom;
 
-use bincode::{deserialize, serialize_into};
-
+use std::collections::VecDeque;
+use std::fs;
 use std::io;
 use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::path::Path;
+use std::sync::Arc;
 use std::thread;
 use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+mod bandwidth;
+use bandwidth::{SendMeter, TokenBucket};
+
+mod config;
+pub use config::{ClosestNodes, Config, EvictionPolicy, LookupOverflow};
+
+mod error;
+pub use error::Error;
+
+mod recv;
+pub use recv::DropReason;
+use recv::{Drops, RecvBuf};
+
+mod chunked;
+
+mod mac;
+
+mod shutdown;
+pub use shutdown::ShutdownHandle;
+
+mod rtt;
+pub use rtt::RttStats;
+
+mod health;
+pub use health::HealthReport;
 
 mod kad;
 use kad::*;
+pub use kad::{
+    Anomaly, BucketView, Contact, LookupEvent, LookupHandle, LookupStats, LookupStatus,
+    MemoryStore, NodeID, ParseIdError, Responsibility, SignedValue, Store, StoreError, StoredValue,
+};
+pub use kad::{
+    Capabilities, Extensions, Metadata, Packet, PacketSignature, Payload, Rejection, Token,
+    COMPRESS_THRESHOLD, MAX_MEMBER_LEN, MAX_PACKET_SIZE, MAX_VALUE_SIZE, MAX_VERSION_LEN,
+    SIGNATURE_LEN,
+};
+
+// Commands waiting for the worker. Past this, callers block until it catches up.
+const COMMAND_QUEUE: usize = 1024;
+
+// How long dropping a Dht waits for its threads to finish before leaving them to it
+const SHUTDOWN_WAIT: Duration = Duration::from_secs(1);
+
+// Contacts pinged by rtt_histogram, and how many of those pings are out at once
+const RTT_SAMPLE: usize = 32;
+const RTT_FANOUT: usize = 8;
+
+// How the OS says nothing is listening at an address, once an ICMP port unreachable comes back.
+// Where these show up, and whether they come with the address, varies from platform to platform.
+fn is_unreachable(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::ConnectionRefused | io::ErrorKind::ConnectionReset
+    )
+}
+
+// Errors that say the socket itself is done for, rather than anything about one datagram or where
+// it was going. Nothing after one of these would get through either.
+fn is_fatal(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::NotConnected | io::ErrorKind::BrokenPipe | io::ErrorKind::OutOfMemory
+    )
+}
+
+fn thread_builder(name: &str, config: &Config) -> thread::Builder {
+    let builder = thread::Builder::new().name(name.to_string());
+    match config.stack_size {
+        Some(size) => builder.stack_size(size),
+        None => builder,
+    }
+}
 
 pub struct Dht {
     addr: SocketAddr,
+    id: NodeID,
+    timeout: Duration,
+    // See Config::max_large_value
+    max_large_value: u64,
     command: channel::Sender<Command>,
 
-    worker: JoinHandle<()>,
-    sender: JoinHandle<io::Result<()>>,
-    //recver: JoinHandle<Result<()>>,
+    stop: ShutdownHandle,
+    // Share the sockets with the threads, so options set through them apply to the threads too
+    sockets: Vec<(SocketAddr, UdpSocket)>,
+    drops: Arc<Drops>,
+    sent: Arc<SendMeter>,
+    // Disconnects once every thread has exited
+    alive: channel::Receiver<()>,
+
+    worker: Option<JoinHandle<()>>,
+    sender: Option<JoinHandle<io::Result<()>>>,
+    // One per socket
+    recvers: Vec<JoinHandle<io::Result<()>>>,
+    // The receivers' end of their queue to the worker, for tests to see how much is waiting
+    #[cfg(test)]
+    queued: channel::Sender<(Packet, SocketAddr)>,
 }
 
 impl Dht {
-    pub fn start<A: ToSocketAddrs>(socket: A) -> io::Result<Dht> {
-        let send_sock = UdpSocket::bind(socket)?;
-        let recv_sock = send_sock.try_clone()?;
+    pub fn start<A: ToSocketAddrs>(socket: A) -> Result<Dht, Error> {
+        Dht::start_with_config(socket, Config::default())
+    }
 
-        let socket = send_sock.local_addr().unwrap();
+    pub fn start_with_config<A: ToSocketAddrs>(socket: A, config: Config) -> Result<Dht, Error> {
+        Dht::start_with_store(socket, config, MemoryStore::default())
+    }
 
-        let (cmd_tx, cmd_rx) = channel::unbounded();
-        let (send_tx, send_rx) = channel::unbounded();
+    // Keeps plain values in store, e.g. one backed by disk, rather than in memory
+    pub fn start_with_store<A: ToSocketAddrs, S: Store + Send + 'static>(
+        socket: A,
+        config: Config,
+        store: S,
+    ) -> Result<Dht, Error> {
+        let socket = UdpSocket::bind(socket).map_err(Error::Bind)?;
+        Dht::serve(vec![socket], config, store)
+    }
 
-        let mut kad = Kad::new(send_tx);
+    // Binds each address separately and serves them all as the one node, e.g. one IPv4 and one
+    // IPv6 socket where the OS won't do both on one. Packets go out over the first socket of the
+    // peer's family. Peers of a family with no socket are taken as unreachable. local_addr is the
+    // first address.
+    pub fn start_multi(addrs: &[SocketAddr], config: Config) -> Result<Dht, Error> {
+        Dht::start_multi_with_store(addrs, config, MemoryStore::default())
+    }
 
-        // This channel is bounded so a huge inrush of packets doesn't consume unbounded memory
-        // Right now it's a zero-capacity channel so it's effectively giving us the ability to
-        // select on the socket and the command channel at the same time.
-        let (recv_tx, recv_rx) = channel::bounded(0);
+    // Like start_multi, keeping plain values in store
+    pub fn start_multi_with_store<S: Store + Send + 'static>(
+        addrs: &[SocketAddr],
+        config: Config,
+        store: S,
+    ) -> Result<Dht, Error> {
+        let sockets = addrs
+            .iter()
+            .map(|addr| UdpSocket::bind(addr).map_err(Error::Bind))
+            .collect::<Result<Vec<UdpSocket>, Error>>()?;
+        if sockets.is_empty() {
+            return Err(Error::Bind(io::ErrorKind::InvalidInput.into()));
+        }
+        Dht::serve(sockets, config, store)
+    }
 
-        let sender: JoinHandle<io::Result<()>> = thread::Builder::new().spawn(move || {
-            let mut buf = Vec::new();
-            while let Ok((pack, peer)) = send_rx.recv() {
-                buf.clear();
-                serialize_into(&mut buf, &pack).unwrap();
-                let _ = send_sock.send_to(&buf, peer)?;
-                eprintln!("Sent {:?} to {}", pack, peer);
+    fn serve<S: Store + Send + 'static>(
+        sockets: Vec<UdpSocket>,
+        config: Config,
+        store: S,
+    ) -> Result<Dht, Error> {
+        let worker_thread = thread_builder("dht-worker", &config);
+        let (mut dht, worker) = Dht::prepare(sockets, config, store)?;
+        dht.worker = Some(worker_thread.spawn(worker)?);
+        Ok(dht)
+    }
+
+    // Starts every thread but the worker, which is left to the caller to run.
+    fn prepare<S: Store + Send + 'static>(
+        sockets: Vec<UdpSocket>,
+        config: Config,
+        store: S,
+    ) -> Result<(Dht, impl FnOnce() + Send + 'static), Error> {
+        config.validate()?;
+        // Every thread gets its own handle on each socket. Clones share options, so setting one
+        // through any of them applies to all.
+        let clones = |sockets: &[UdpSocket]| -> Result<Vec<(SocketAddr, UdpSocket)>, Error> {
+            let mut clones = Vec::with_capacity(sockets.len());
+            for s in sockets {
+                clones.push((s.local_addr()?, s.try_clone()?));
             }
-            Ok(())
-        })?;
+            Ok(clones)
+        };
+        let send_socks = clones(&sockets)?;
+        let wake = clones(&sockets)?;
+        let options = clones(&sockets)?;
+
+        let max_datagram_size = config.max_datagram_size;
+        let network_id = config.network_id;
+        let require_signed = config.sign_packets;
+        let accept_unknown = config.accept_unknown_payloads;
+        let compress = config.compress_payloads;
+        let psk = config.psk;
+        let max_packet_size = config.max_packet_size();
+        let recv_queue = config.recv_queue;
+        let max_send_rate = config.max_send_rate;
+        let timeout = config.timeout;
+        let max_large_value = config.max_large_value;
+        let tick = channel::tick(config.tick_interval);
+        let sender_thread = thread_builder("dht-sender", &config);
+        let recv_threads: Vec<thread::Builder> = sockets
+            .iter()
+            .map(|_| thread_builder("dht-recv", &config))
+            .collect();
+
+        let (cmd_tx, cmd_rx) = channel::bounded(COMMAND_QUEUE);
+        let (send_tx, send_rx) = channel::unbounded();
+
+        let mut kad = Kad::with_store(send_tx, config, store);
+        kad.set_own_addrs(options.iter().map(|(addr, _)| *addr).collect());
+        let id = kad.id();
 
-        let _recver: JoinHandle<io::Result<()>> = thread::Builder::new().spawn(move || {
-            let mut buf = vec![0; 1 << 16]; // Maximum size of a UDP datagram
+        // This channel is bounded so a huge inrush of packets doesn't consume unbounded memory.
+        // Once it's full the receivers wait for the worker, and the socket's own buffer takes up
+        // the slack.
+        let (recv_tx, recv_rx) = channel::bounded(recv_queue);
+
+        let addr = options[0].0;
+        let stop = ShutdownHandle::new(cmd_tx.clone(), wake);
+        let (alive_tx, alive) = channel::bounded(0);
+
+        let sender_alive = alive_tx.clone();
+        let sender_cmd = cmd_tx.clone();
+        let sent = Arc::new(SendMeter::default());
+        let sender_sent = sent.clone();
+        let sender: JoinHandle<io::Result<()>> = sender_thread.spawn(move || {
+            let _alive = sender_alive;
+            let mut buf = Vec::new();
+            let mut limit = max_send_rate.map(|rate| TokenBucket::new(rate, Instant::now()));
+            // Waiting to be sent. Answers to others go ahead of our own requests, which only
+            // makes a difference once the limit holds us up enough for a backlog to build.
+            let mut responses = VecDeque::new();
+            let mut requests = VecDeque::new();
             loop {
-                let (size, peer) = recv_sock.recv_from(&mut buf)?;
-                if let Ok(pack) = deserialize(&buf[..size]) {
-                    eprintln!("Received {:?} from {}", pack, peer);
+                let idle = responses.is_empty() && requests.is_empty();
+                let mut queue = |send: (Packet, SocketAddr)| {
+                    if send.0.payload.is_response() {
+                        responses.push_back(send);
+                    } else {
+                        requests.push_back(send);
+                    }
+                };
+                if idle {
+                    match send_rx.recv() {
+                        Ok(send) => queue(send),
+                        Err(_) => return Ok(()),
+                    }
+                }
+                send_rx.try_iter().for_each(&mut queue);
+                let (pack, peer) = match responses.pop_front() {
+                    Some(send) => send,
+                    None => requests.pop_front().unwrap(),
+                };
+                let send_sock = match send_socks
+                    .iter()
+                    .find(|(addr, _)| addr.is_ipv4() == peer.is_ipv4())
+                {
+                    Some((_, sock)) => sock,
+                    // Nothing of its family to send from, so there's no reaching it
+                    None => {
+                        sender_cmd.try_send(Command::Unreachable(peer)).ok();
+                        continue;
+                    }
+                };
 
-                    if recv_tx.send((pack, peer)).is_err() {
-                        return Ok(());
+                buf.clear();
+                let encoded = if compress {
+                    pack.encode_compressed_within(&mut buf, max_packet_size)
+                } else {
+                    pack.encode_within(&mut buf, max_packet_size)
+                };
+                if let Err(e) = encoded {
+                    // Losing one packet beats losing every packet after it
+                    eprintln!("Couldn't encode packet for {}: {}", peer, e);
+                    continue;
+                }
+                if let Some(psk) = &psk {
+                    mac::seal(psk, &mut buf);
+                }
+                if let Some(limit) = &mut limit {
+                    thread::sleep(limit.take(Instant::now(), buf.len()));
+                }
+                match send_sock.send_to(&buf, peer) {
+                    Ok(len) => {
+                        sender_sent.record(Instant::now(), len);
+                        eprintln!("Sent {:?} to {}", pack, peer);
+                    }
+                    Err(e) if is_fatal(&e) => {
+                        sender_cmd
+                            .try_send(Command::Report(Anomaly::SendFailed(e.kind())))
+                            .ok();
+                        return Err(e);
+                    }
+                    // Anything else is about this one peer, e.g. an address we can't reach from
+                    // here, so we might as well stop waiting on it. Here at least we know who it
+                    // was. If the queue is full, the timeouts will do.
+                    Err(e) => {
+                        if !is_unreachable(&e) {
+                            sender_cmd
+                                .try_send(Command::Report(Anomaly::SendFailed(e.kind())))
+                                .ok();
+                        }
+                        sender_cmd.try_send(Command::Unreachable(peer)).ok();
                     }
                 }
             }
         })?;
 
-        let worker = thread::Builder::new().spawn(move || loop {
-            select! {
-                recv(cmd_rx) -> cmd => {
-                    if kad.handle_command(cmd.unwrap()) {
-                        break
+        let drops = Arc::new(Drops::default());
+        let mut recvers = Vec::with_capacity(sockets.len());
+        for (recv_sock, recv_thread) in sockets.into_iter().zip(recv_threads) {
+            let recver_alive = alive_tx.clone();
+            let recver_stop = stop.clone();
+            let recver_drops = drops.clone();
+            let recv_tx = recv_tx.clone();
+            let recver: JoinHandle<io::Result<()>> = recv_thread.spawn(move || {
+                let _alive = recver_alive;
+                let mut buf = RecvBuf::new(max_datagram_size);
+                loop {
+                    let (datagram, peer) = match buf.recv_from(&recv_sock) {
+                        Ok(received) => received,
+                        // Not about the datagram we were waiting for, and without saying whose it
+                        // was, so all we can do is carry on
+                        Err(e) if is_unreachable(&e) => continue,
+                        Err(e) => return Err(e),
+                    };
+                    if recver_stop.is_stopping() {
+                        return Ok(());
+                    }
+                    if datagram.is_empty() {
+                        recver_drops.count(DropReason::Empty);
+                        continue;
+                    }
+                    let datagram = match &psk {
+                        Some(psk) => match mac::open(psk, datagram) {
+                            Some(packet) => packet,
+                            None => {
+                                recver_drops.count(DropReason::BadMac);
+                                continue;
+                            }
+                        },
+                        None => datagram,
+                    };
+                    // Anything that doesn't decode exactly is dropped as malformed
+                    match Packet::decode(datagram) {
+                        Ok(pack) if pack.network_id != network_id => {
+                            recver_drops.count(DropReason::ForeignNetwork)
+                        }
+                        Ok(pack) if pack.signature.is_none() && require_signed => {
+                            recver_drops.count(DropReason::Unsigned)
+                        }
+                        Ok(pack) if pack.signature.is_some() && !pack.verify() => {
+                            recver_drops.count(DropReason::BadSignature)
+                        }
+                        Ok(Packet {
+                            payload: Payload::Unknown(_),
+                            ..
+                        }) if !accept_unknown => recver_drops.count(DropReason::Malformed),
+                        Ok(pack) => {
+                            eprintln!("Received {:?} from {}", pack, peer);
+
+                            if recv_tx.send((pack, peer)).is_err() {
+                                return Ok(());
+                            }
+                        }
+                        Err(_) => recver_drops.count(DropReason::Malformed),
                     }
                 }
-                recv(recv_rx) -> packet => {
-                    let (packet, peer) = packet.unwrap();
-                    kad.handle_packet(packet, peer);
+            })?;
+            recvers.push(recver);
+        }
+        #[cfg(test)]
+        let queued = recv_tx.clone();
+        drop(recv_tx);
+
+        let worker = move || {
+            let _alive = alive_tx;
+            let held = channel::never();
+            loop {
+                // While paused, packets back up in recv_rx, then the socket's buffer
+                let packets = if kad.is_paused() { &held } else { &recv_rx };
+                // select! picks at random among ready arms, and the tick channel holds at most one
+                // tick, so maintenance can't crowd out packets or commands. On top of that, each
+                // command is followed by a waiting packet and each packet by a waiting command, so
+                // a backlog of one can't hold the other up for long even with bad luck.
+                select! {
+                    recv(cmd_rx) -> cmd => {
+                        if !kad.handle_command(cmd.unwrap()) {
+                            break
+                        }
+                        if kad.is_paused() {
+                            continue;
+                        }
+                        if let Ok((packet, peer)) = recv_rx.try_recv() {
+                            kad.handle_packet(packet, peer);
+                        }
+                    }
+                    recv(packets) -> packet => {
+                        let (packet, peer) = packet.unwrap();
+                        kad.handle_packet(packet, peer);
+                        if let Ok(cmd) = cmd_rx.try_recv() {
+                            if !kad.handle_command(cmd) {
+                                break
+                            }
+                        }
+                    }
+                    recv(tick) -> _ => kad.handle_tick(),
                 }
             }
-        })?;
+        };
 
-        Ok(Dht {
-            addr: socket,
+        let dht = Dht {
+            addr,
+            id,
+            timeout,
+            max_large_value,
             command: cmd_tx,
 
-            worker: worker,
-            sender: sender,
-            //recver: recver,
+            stop,
+            sockets: options,
+            drops,
+            sent,
+            alive,
+
+            worker: None,
+            sender: Some(sender),
+            recvers,
+            #[cfg(test)]
+            queued,
+        };
+        Ok((dht, worker))
+    }
+
+    // Runs the node on the calling thread until it's shut down, for a program that is nothing but
+    // the node. Everything else still gets its own thread. Once the node is up, ready is called
+    // with its address and a handle to stop it with, e.g. from a signal handler.
+    pub fn run_blocking<A: ToSocketAddrs>(
+        addr: A,
+        config: Config,
+        ready: impl FnOnce(SocketAddr, ShutdownHandle),
+    ) -> Result<(), Error> {
+        Dht::run_blocking_with_store(addr, config, MemoryStore::default(), ready)
+    }
+
+    // Like run_blocking, keeping plain values in store
+    pub fn run_blocking_with_store<A: ToSocketAddrs, S: Store + Send + 'static>(
+        addr: A,
+        config: Config,
+        store: S,
+        ready: impl FnOnce(SocketAddr, ShutdownHandle),
+    ) -> Result<(), Error> {
+        let socket = UdpSocket::bind(addr).map_err(Error::Bind)?;
+        let (dht, worker) = Dht::prepare(vec![socket], config, store)?;
+        ready(dht.local_addr(), dht.shutdown_handle());
+        worker();
+        Ok(())
+    }
+
+    // Pings each of peers, so they show up in the routing table once they answer. Calling it again
+    // before that replaces the earlier seeds, which are no longer waited on. Fails, leaving any
+    // earlier bootstrap alone, if peers can't be resolved.
+    pub fn bootstrap<A: ToSocketAddrs>(&mut self, peers: A) -> io::Result<()> {
+        let seeds = peers.to_socket_addrs()?.collect();
+        self.command.send(Command::Bootstrap(seeds)).ok();
+        Ok(())
+    }
+
+    // Forgets every contact and bootstraps again from peers, for a routing table gone stale or
+    // filled with bad contacts. Our ID and stored values stay. Lookups, gets, puts and pings still
+    // running fail with Error::Reset. Once a seed answers we look up our own ID, as a new node
+    // would, to fill the table back up. Fails, without resetting anything, if peers can't be
+    // resolved.
+    pub fn reset_and_bootstrap<A: ToSocketAddrs>(&mut self, peers: A) -> io::Result<()> {
+        let seeds = peers.to_socket_addrs()?.collect();
+        self.command.send(Command::Reset(seeds)).ok();
+        Ok(())
+    }
+
+    // The seeds of the latest bootstrap that haven't answered yet, or been given up on.
+    pub fn bootstrap_seeds(&self) -> Vec<SocketAddr> {
+        let (reply, seeds) = channel::bounded(1);
+        self.command.send(Command::BootstrapSeeds(reply)).ok();
+        seeds.recv().unwrap_or_default()
+    }
+
+    // Seeds the routing table from a file of hexid@addr lines, e.g. a list of nodes saved from an
+    // earlier run, and pings each so they're verified once they answer. Blank lines and lines
+    // starting with # are skipped. Returns how many contacts were read.
+    pub fn bootstrap_from_file(&mut self, path: &Path) -> Result<usize, Error> {
+        let mut contacts = Vec::new();
+        for (i, line) in fs::read_to_string(path)?.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (id, addr) = line.split_once('@').ok_or(Error::BadDescriptor(i + 1))?;
+            let id = id.parse().map_err(|_| Error::BadDescriptor(i + 1))?;
+            let addr = addr.parse().map_err(|_| Error::BadDescriptor(i + 1))?;
+            let contact = Contact::new(id, addr);
+            if !contact.has_usable_addr() {
+                return Err(Error::BadDescriptor(i + 1));
+            }
+            contacts.push(contact);
+        }
+
+        let count = contacts.len();
+        self.command
+            .send(Command::Seed(contacts))
+            .map_err(|_| Error::Shutdown)?;
+        Ok(count)
+    }
+
+    // Sends a command carrying a reply channel and waits for the worker to answer on it.
+    fn request<T>(
+        &self,
+        command: impl FnOnce(channel::Sender<Result<T, Error>>) -> Command,
+    ) -> Result<T, Error> {
+        let (reply, result) = channel::bounded(1);
+        self.command
+            .send(command(reply))
+            .map_err(|_| Error::Shutdown)?;
+        result.recv().unwrap_or(Err(Error::Shutdown))
+    }
+
+    pub fn ping(&self, peer: SocketAddr) -> Result<(), Error> {
+        self.ping_timeout(peer, self.timeout)
+    }
+
+    pub fn ping_timeout(&self, peer: SocketAddr, timeout: Duration) -> Result<(), Error> {
+        self.ping_rtt(peer, timeout).map(|_| ())
+    }
+
+    // Pings peer, and returns how long the answer took by the worker's clock
+    fn ping_rtt(&self, peer: SocketAddr, timeout: Duration) -> Result<Duration, Error> {
+        let deadline = Instant::now() + timeout;
+        self.request(|reply| Command::PingWait {
+            peer,
+            deadline,
+            reply,
         })
     }
 
-    pub fn bootstrap<A: ToSocketAddrs>(&mut self, peers: A) {
-        for peer in peers.to_socket_addrs().unwrap() {
-            self.command.send(Command::Ping(peer)).ok();
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        self.get_timeout(key, self.timeout)
+    }
+
+    pub fn get_timeout(&self, key: &[u8], timeout: Duration) -> Result<Option<Vec<u8>>, Error> {
+        let (found, _) = self.find_value(key, timeout)?;
+        Ok(found.map(|(value, _)| value))
+    }
+
+    // Like get, but also says how the lookup went. A value we hold ourselves takes no lookup, so
+    // comes with all zero stats.
+    pub fn get_with_stats(&self, key: &[u8]) -> Result<(Option<Vec<u8>>, LookupStats), Error> {
+        let (found, stats) = self.find_value(key, self.timeout)?;
+        Ok((found.map(|(value, _)| value), stats))
+    }
+
+    // Like get, but also says which node the value came from. That's us if we hold it ourselves.
+    pub fn get_with_source(&self, key: &[u8]) -> Result<Option<(Vec<u8>, Contact)>, Error> {
+        let (found, _) = self.find_value(key, self.timeout)?;
+        let me = Contact::new(self.id, self.addr);
+        Ok(found.map(|(value, source)| (value, source.unwrap_or(me))))
+    }
+
+    fn find_value(
+        &self,
+        key: &[u8],
+        timeout: Duration,
+    ) -> Result<(Option<Found>, LookupStats), Error> {
+        let deadline = Instant::now() + timeout;
+        self.request(|reply| Command::Get {
+            key: NodeID::from_key(key),
+            deadline,
+            reply,
+        })
+    }
+
+    // Stores value on the nodes closest to key, returning how many accepted it.
+    pub fn put(&self, key: &[u8], value: Vec<u8>) -> Result<usize, Error> {
+        self.put_timeout(key, value, self.timeout)
+    }
+
+    pub fn put_timeout(
+        &self,
+        key: &[u8],
+        value: Vec<u8>,
+        timeout: Duration,
+    ) -> Result<usize, Error> {
+        let (stored, _) = self.store(key, value, 0, timeout)?;
+        Ok(stored)
+    }
+
+    // Like put, but also says how the lookup for the nodes to store on went. Its elapsed time
+    // includes waiting for their acks.
+    pub fn put_with_stats(
+        &self,
+        key: &[u8],
+        value: Vec<u8>,
+    ) -> Result<(usize, LookupStats), Error> {
+        self.store(key, value, 0, self.timeout)
+    }
+
+    // Like put, but nodes already holding a later version of the value keep theirs, and aren't
+    // counted. A value put without a version is version 0.
+    pub fn put_versioned(&self, key: &[u8], value: Vec<u8>, version: u64) -> Result<usize, Error> {
+        let (stored, _) = self.store(key, value, version, self.timeout)?;
+        Ok(stored)
+    }
+
+    fn store(
+        &self,
+        key: &[u8],
+        value: Vec<u8>,
+        version: u64,
+        timeout: Duration,
+    ) -> Result<(usize, LookupStats), Error> {
+        let deadline = Instant::now() + timeout;
+        self.request(|reply| Command::Put {
+            key: NodeID::from_key(key),
+            value,
+            version,
+            deadline,
+            reply,
+        })
+    }
+
+    // Fetches the latest value signed by public_key that we can find.
+    pub fn get_signed(&self, public_key: &VerifyingKey) -> Result<Option<SignedValue>, Error> {
+        let deadline = Instant::now() + self.timeout;
+        self.request(|reply| Command::GetSigned {
+            key: NodeID::from_key(public_key.as_bytes()),
+            deadline,
+            reply,
+        })
+    }
+
+    // Stores a signed value under the hash of its public key. Nodes holding a value with a later
+    // sequence number, or a different value with the same one, refuse it, and aren't counted.
+    pub fn put_signed(&self, value: SignedValue) -> Result<usize, Error> {
+        let deadline = Instant::now() + self.timeout;
+        let (stored, _) = self.request(|reply| Command::PutSigned {
+            value: Box::new(value),
+            deadline,
+            reply,
+        })?;
+        Ok(stored)
+    }
+
+    // Announces us as a member of the swarm for infohash, reachable at our IP (as the nodes closest
+    // to it see it) and port. Returns how many of them took it. Announcements expire after
+    // Config::peer_ttl, so need repeating.
+    pub fn announce(&self, infohash: &[u8], port: u16) -> Result<usize, Error> {
+        let deadline = Instant::now() + self.timeout;
+        self.request(|reply| Command::Announce {
+            key: NodeID::from_key(infohash),
+            port,
+            deadline,
+            reply,
+        })
+    }
+
+    // Every member of the swarm for infohash that the nodes closest to it know of.
+    pub fn get_peers(&self, infohash: &[u8]) -> Result<Vec<SocketAddr>, Error> {
+        let deadline = Instant::now() + self.timeout;
+        self.request(|reply| Command::GetPeers {
+            key: NodeID::from_key(infohash),
+            deadline,
+            reply,
+        })
+    }
+
+    // Adds value to the set under key on the nodes closest to it, returning how many took it.
+    // Unlike put, whatever else is in the set stays. Members expire after Config::peer_ttl, so need
+    // adding again, and can be at most MAX_MEMBER_LEN bytes.
+    pub fn add(&self, key: &[u8], value: Vec<u8>) -> Result<usize, Error> {
+        let deadline = Instant::now() + self.timeout;
+        let (stored, _) = self.request(|reply| Command::Add {
+            key: NodeID::from_key(key),
+            value,
+            deadline,
+            reply,
+        })?;
+        Ok(stored)
+    }
+
+    // Every member of the set under key that the nodes closest to it hold.
+    pub fn get_all(&self, key: &[u8]) -> Result<Vec<Vec<u8>>, Error> {
+        let deadline = Instant::now() + self.timeout;
+        self.request(|reply| Command::GetAll {
+            key: NodeID::from_key(key),
+            deadline,
+            reply,
+        })
+    }
+
+    // Starts an iterative lookup for the nodes closest to target.
+    pub fn find_node(&self, target: NodeID) -> LookupHandle {
+        let (handle, waiter) = LookupHandle::new();
+        self.command.send(Command::FindNode(target, waiter)).ok();
+        handle
+    }
+
+    // Sends a hand-built packet to peer, for testing and protocol experiments. Advanced: nothing
+    // checks the packet makes sense, and any response is handled like one to a request we never
+    // made.
+    pub fn send_raw(&self, packet: Packet, peer: SocketAddr) {
+        self.command
+            .send(Command::SendRaw(Box::new(packet), peer))
+            .ok();
+    }
+
+    // Reports the progress of every lookup from now on, each event tagged with the lookup's target.
+    pub fn watch_lookups(&self) -> channel::Receiver<(NodeID, LookupEvent)> {
+        let (watcher, events) = channel::unbounded();
+        self.command.send(Command::WatchLookups(watcher)).ok();
+        events
+    }
+
+    // How many lookups are running, and how many are waiting for one of them to finish (see
+    // Config::max_lookups).
+    pub fn lookups(&self) -> (usize, usize) {
+        let (reply, counts) = channel::bounded(1);
+        self.command.send(Command::Lookups(reply)).ok();
+        counts.recv().unwrap_or_default()
+    }
+
+    // Reports whatever goes wrong on the node's threads that they don't stop for, or that stops the
+    // sender, from now on.
+    pub fn watch_anomalies(&self) -> channel::Receiver<Anomaly> {
+        let (watcher, anomalies) = channel::unbounded();
+        self.command.send(Command::WatchAnomalies(watcher)).ok();
+        anomalies
+    }
+
+    // Pings one contact from every non-empty bucket, refreshing liveness and NAT mappings.
+    pub fn warm_up(&self) {
+        self.command.send(Command::WarmUp).ok();
+    }
+
+    // Every plain value this node holds, by key, e.g. to back them up. Signed values aren't
+    // included.
+    pub fn export_store(&self) -> Vec<(NodeID, Vec<u8>)> {
+        let (reply, pairs) = channel::bounded(1);
+        self.command.send(Command::ExportStore(reply)).ok();
+        pairs.recv().unwrap_or_default()
+    }
+
+    // Holds each pair as if it had been stored here, e.g. from another node's export_store.
+    // Values over Config::max_value_size are skipped, as are keys we already hold a later version of.
+    // Returns how many were taken.
+    pub fn import_store(&self, pairs: Vec<(NodeID, Vec<u8>)>) -> usize {
+        let (reply, imported) = channel::bounded(1);
+        self.command.send(Command::ImportStore(pairs, reply)).ok();
+        imported.recv().unwrap_or_default()
+    }
+
+    // Looks up a random ID in the bucket of IDs sharing `bucket` leading bits with ours, which
+    // turns up contacts for that bucket along the way. Buckets past the end, or without any
+    // contacts to start from, are left alone.
+    pub fn refresh_bucket(&self, bucket: usize) {
+        self.command.send(Command::RefreshBucket(bucket)).ok();
+    }
+
+    // Stops handling packets until resume, leaving them to queue up. Commands still go through, so
+    // anything that needs an answer from another node will time out. Returns once no more packets
+    // will be handled.
+    pub fn pause(&self) {
+        let (reply, paused) = channel::bounded(1);
+        self.command.send(Command::Pause(reply)).ok();
+        paused.recv().ok();
+    }
+
+    pub fn resume(&self) {
+        self.command.send(Command::Resume).ok();
+    }
+
+    // Refreshes every bucket with contacts in it.
+    pub fn refresh_all(&self) {
+        self.command.send(Command::RefreshAll).ok();
+    }
+
+    // Inserts contact into the routing table without checking it's really there.
+    #[cfg(test)]
+    pub(crate) fn debug_insert(&self, contact: Contact) {
+        self.command.send(Command::DebugInsert(contact)).ok();
+    }
+
+    // A snapshot of every contact in the routing table, ordered by ID.
+    pub fn known_peers(&self) -> Vec<Contact> {
+        let (reply, peers) = channel::bounded(1);
+        self.command.send(Command::KnownPeers(reply)).ok();
+        peers.recv().unwrap_or_default()
+    }
+
+    // Like known_peers, but only the contacts whose pings or pongs told us their software version
+    // and uptime, along with what they said.
+    pub fn peer_metadata(&self) -> Vec<(Contact, Metadata)> {
+        let (reply, peers) = channel::bounded(1);
+        self.command.send(Command::PeerMetadata(reply)).ok();
+        peers.recv().unwrap_or_default()
+    }
+
+    // A copy of each k-bucket, furthest from us first, for deciding what maintenance to do
+    // ourselves.
+    pub fn buckets(&self) -> Vec<BucketView> {
+        let (reply, buckets) = channel::bounded(1);
+        self.command.send(Command::Buckets(reply)).ok();
+        buckets.recv().unwrap_or_default()
+    }
+
+    // Blocks until the routing table holds at least min contacts, returning how many it holds,
+    // or until timeout passes.
+    pub fn wait_for_peers(&self, min: usize, timeout: Duration) -> Result<usize, Error> {
+        let deadline = Instant::now() + timeout;
+        self.request(|reply| Command::WaitForPeers {
+            min,
+            deadline,
+            reply,
+        })
+    }
+
+    // Pings a random sample of up to RTT_SAMPLE contacts, at most RTT_FANOUT at a time, and sums up
+    // how long they took to answer. The worker times each from sending the ping to handling its
+    // pong, so the times run a little over the network's.
+    pub fn rtt_histogram(&self) -> RttStats {
+        let mut peers = self.known_peers();
+        peers.shuffle(&mut rand::thread_rng());
+        peers.truncate(RTT_SAMPLE);
+
+        let rtts: Vec<Option<Duration>> = thread::scope(|s| {
+            let peers = &peers;
+            let pingers: Vec<_> = (0..RTT_FANOUT.min(peers.len()))
+                .map(|first| {
+                    s.spawn(move || {
+                        let pings = peers.iter().skip(first).step_by(RTT_FANOUT);
+                        pings
+                            .map(|peer| self.ping_rtt(peer.addr(), self.timeout).ok())
+                            .collect::<Vec<_>>()
+                    })
+                })
+                .collect();
+            pingers
+                .into_iter()
+                .flat_map(|pinger| pinger.join().unwrap())
+                .collect()
+        });
+
+        let lost = rtts.iter().filter(|rtt| rtt.is_none()).count();
+        RttStats::from_samples(rtts.into_iter().flatten().collect(), lost)
+    }
+
+    // The routing table's entry for id, with its current address, if there is one.
+    pub fn contains(&self, id: NodeID) -> Option<Contact> {
+        let (reply, contact) = channel::bounded(1);
+        self.command.send(Command::Contains(id, reply)).ok();
+        contact.recv().unwrap_or_default()
+    }
+
+    // Contacts we haven't heard from in older_than, or ever, e.g. to see how healthy the routing
+    // table is.
+    pub fn stale_contacts(&self, older_than: Duration) -> Vec<Contact> {
+        let (reply, contacts) = channel::bounded(1);
+        self.command
+            .send(Command::StaleContacts(older_than, reply))
+            .ok();
+        contacts.recv().unwrap_or_default()
+    }
+
+    // The closest nodes to us, as of the last time we looked. Empty until the first lookup for our
+    // own ID finishes, Config::neighborhood_interval after starting.
+    pub fn neighborhood(&self) -> Vec<Contact> {
+        let (reply, peers) = channel::bounded(1);
+        self.command.send(Command::Neighborhood(reply)).ok();
+        peers.recv().unwrap_or_default()
+    }
+
+    // Where we rank among the nodes we know of for key, for deciding whether a value stored under
+    // it is ours to keep.
+    pub fn responsibility(&self, key: &[u8]) -> Result<Responsibility, Error> {
+        let (reply, responsibility) = channel::bounded(1);
+        self.command
+            .send(Command::Responsibility(NodeID::from_key(key), reply))
+            .map_err(|_| Error::Shutdown)?;
+        responsibility.recv().map_err(|_| Error::Shutdown)
+    }
+
+    // A guess at how many nodes the whole network has, from how densely our nearest contacts are
+    // packed around us. It gets better as the routing table fills in. None until we know someone.
+    pub fn estimated_network_size(&self) -> Option<u64> {
+        let (reply, estimate) = channel::bounded(1);
+        self.command.send(Command::EstimateSize(reply)).ok();
+        estimate.recv().unwrap_or_default()
+    }
+
+    // Stops the node. This is the same as dropping it.
+    pub fn shutdown(self) {}
+
+    // Shuts down considerately: every value we hold goes to the nodes closest to its key, and our
+    // neighbors hear we're going so they can look for someone to take our place. Returns how many
+    // values were handed off. They go out a batch per tick, so the more we hold the longer this
+    // takes. Nothing waits for them to arrive, and once this returns every other request fails
+    // with Error::Shutdown.
+    pub fn leave(&self) -> Result<usize, Error> {
+        let (reply, handed_off) = channel::bounded(1);
+        self.command
+            .send(Command::Leave(reply))
+            .map_err(|_| Error::Shutdown)?;
+        let handed_off = handed_off.recv().map_err(|_| Error::Shutdown)?;
+        self.stop.shutdown();
+
+        // A request queued behind the shutdown would never be answered, so wait for the threads
+        // to go before letting anyone make one
+        self.alive.recv_timeout(SHUTDOWN_WAIT).ok();
+        Ok(handed_off)
+    }
+
+    // A handle that can stop the node from elsewhere. Once it has, every request fails with
+    // Error::Shutdown.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.stop.clone()
+    }
+
+    // The IP time-to-live (hop limit) of everything we send, so operators can keep datagrams from
+    // getting beyond the local network.
+    pub fn set_ttl(&self, ttl: u32) -> Result<(), Error> {
+        for (_, socket) in self.sockets.iter() {
+            socket.set_ttl(ttl)?;
         }
+        Ok(())
     }
 
-    pub fn shutdown(self) {
-        self.command.send(Command::Shutdown).unwrap();
-        self.worker.join().unwrap();
-        self.sender.join().unwrap().unwrap();
-        // Don't wait on recver, since it will never die until it gets a packet and discovers the broken channel
-        //self.recver.join().unwrap().unwrap();
+    pub fn ttl(&self) -> Result<u32, Error> {
+        Ok(self.sockets[0].1.ttl()?)
+    }
+
+    // The time-to-live of multicast datagrams. Only IPv4 sockets have one.
+    pub fn set_multicast_ttl(&self, ttl: u32) -> Result<(), Error> {
+        let mut v4 = self
+            .sockets
+            .iter()
+            .filter(|(addr, _)| addr.is_ipv4())
+            .peekable();
+        if v4.peek().is_none() {
+            return Err(Error::Io(io::ErrorKind::Unsupported.into()));
+        }
+        for (_, socket) in v4 {
+            socket.set_multicast_ttl_v4(ttl)?;
+        }
+        Ok(())
+    }
+
+    pub fn multicast_ttl(&self) -> Result<u32, Error> {
+        match self.sockets.iter().find(|(addr, _)| addr.is_ipv4()) {
+            Some((_, socket)) => Ok(socket.multicast_ttl_v4()?),
+            None => Err(Error::Io(io::ErrorKind::Unsupported.into())),
+        }
+    }
+
+    // Whether multicast datagrams we send are looped back to this host.
+    pub fn set_multicast_loop(&self, on: bool) -> Result<(), Error> {
+        for (addr, socket) in self.sockets.iter() {
+            match addr {
+                SocketAddr::V4(_) => socket.set_multicast_loop_v4(on)?,
+                SocketAddr::V6(_) => socket.set_multicast_loop_v6(on)?,
+            }
+        }
+        Ok(())
+    }
+
+    pub fn multicast_loop(&self) -> Result<bool, Error> {
+        let (addr, socket) = &self.sockets[0];
+        Ok(match addr {
+            SocketAddr::V4(_) => socket.multicast_loop_v4()?,
+            SocketAddr::V6(_) => socket.multicast_loop_v6()?,
+        })
+    }
+
+    // How many bytes a second we've sent, over the last second
+    pub fn send_rate(&self) -> f64 {
+        self.sent.rate(Instant::now())
+    }
+
+    // How many datagrams we've thrown away for the given reason
+    pub fn dropped(&self, reason: DropReason) -> u64 {
+        self.drops.get(reason)
+    }
+
+    // The routing table, stored values, lookups and dropped datagrams, summed up for a status
+    // page or a health check. Everything but the drops comes from the worker at one moment.
+    pub fn health(&self) -> HealthReport {
+        let (reply, report) = channel::bounded(1);
+        self.command.send(Command::Health(reply)).ok();
+        HealthReport {
+            drops: self.drops.all(),
+            ..report.recv().unwrap_or_default()
+        }
     }
 
     pub fn local_addr(&self) -> SocketAddr {
         self.addr
     }
+
+    // Every address we're serving, in the order they were given to start_multi.
+    pub fn local_addrs(&self) -> Vec<SocketAddr> {
+        self.sockets.iter().map(|(addr, _)| *addr).collect()
+    }
+
+    pub fn id(&self) -> NodeID {
+        self.id
+    }
+
+    // How far key is from us, by the same XOR metric the routing table uses.
+    pub fn distance_to(&self, key: &[u8]) -> NodeID {
+        self.id ^ NodeID::from_key(key)
+    }
+}
+
+impl Drop for Dht {
+    fn drop(&mut self) {
+        self.stop.shutdown();
+
+        // Only join once they're all done, so a stuck thread can't hang whoever dropped us
+        if let Err(channel::RecvTimeoutError::Disconnected) = self.alive.recv_timeout(SHUTDOWN_WAIT)
+        {
+            self.worker.take().map(JoinHandle::join);
+            self.sender.take().map(JoinHandle::join);
+            for recver in self.recvers.drain(..) {
+                recver.join().ok();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn reset_and_bootstrap() {
+        let mut dht = Dht::start("127.0.0.1:0").unwrap();
+        let stale: Vec<Dht> = (0..2).map(|_| Dht::start("127.0.0.1:0").unwrap()).collect();
+        for other in stale.iter() {
+            dht.ping(other.local_addr()).unwrap();
+        }
+        // Each side asks the other what it supports after the first ping, and an answer landing
+        // after the reset would put a stale node back in the table. Once we know, pinging again
+        // comes back behind everything the asks sent.
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !dht.known_peers().iter().all(|c| c.has_extensions()) {
+            assert!(Instant::now() < deadline);
+            thread::sleep(Duration::from_millis(10));
+        }
+        for other in stale.iter() {
+            dht.ping(other.local_addr()).unwrap();
+        }
+        let seed = Dht::start("127.0.0.1:0").unwrap();
+        let behind = Dht::start("127.0.0.1:0").unwrap();
+        seed.ping(behind.local_addr()).unwrap();
+
+        // Nothing is reset for seeds that can't be resolved
+        assert!(dht.reset_and_bootstrap("no port").is_err());
+        assert_eq!(dht.known_peers().len(), 2);
+        dht.reset_and_bootstrap(seed.local_addr()).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let mut expected = vec![seed.id(), behind.id()];
+        expected.sort();
+        loop {
+            let known: Vec<NodeID> = dht.known_peers().iter().map(|c| c.id()).collect();
+            if known == expected {
+                break;
+            }
+            assert!(known.iter().all(|id| expected.contains(id)), "{:?}", known);
+            assert!(Instant::now() < deadline);
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn bad_config() {
+        for config in [
+            Config {
+                lookup_alpha: 0,
+                ..Config::default()
+            },
+            Config {
+                refresh_alpha: 0,
+                ..Config::default()
+            },
+            Config {
+                max_requests_per_peer: 0,
+                ..Config::default()
+            },
+        ] {
+            let started = Dht::start_with_config("127.0.0.1:0", config.clone());
+            assert!(matches!(started, Err(Error::BadConfig(_))));
+            let ran = Dht::run_blocking("127.0.0.1:0", config, |_, _| panic!("started"));
+            assert!(matches!(ran, Err(Error::BadConfig(_))));
+        }
+    }
+
+    #[test]
+    fn known_peers_after_pings() {
+        let dht = Dht::start("127.0.0.1:0").unwrap();
+        assert_eq!(dht.estimated_network_size(), None);
+        let mut others: Vec<Dht> = (0..3).map(|_| Dht::start("127.0.0.1:0").unwrap()).collect();
+        for other in others.iter_mut() {
+            other.bootstrap(dht.local_addr()).unwrap();
+        }
+
+        let mut expected: Vec<SocketAddr> = others.iter().map(|d| d.local_addr()).collect();
+        expected.sort();
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let mut known = Vec::new();
+        while Instant::now() < deadline {
+            known = dht.known_peers().iter().map(|c| c.addr()).collect();
+            known.sort();
+            if known.len() >= expected.len() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(known, expected);
+
+        for other in others.iter() {
+            let contact = dht.contains(other.id()).unwrap();
+            assert_eq!(contact.addr(), other.local_addr());
+        }
+        assert!(dht.contains(rand::random()).is_none());
+        assert!(dht.contains(dht.id()).is_none());
+        // Never fewer than the nodes we know of
+        assert!(dht.estimated_network_size().unwrap() >= 3);
+
+        for other in others {
+            other.shutdown();
+        }
+        dht.shutdown();
+    }
+
+    #[test]
+    fn bootstrap_file() {
+        let mut dht = Dht::start("127.0.0.1:0").unwrap();
+        let other = Dht::start("127.0.0.1:0").unwrap();
+        let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+        silent
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let silent_id: NodeID = rand::random();
+
+        let path = std::env::temp_dir().join(format!("dht-bootstrap-{}", dht.id()));
+        let descriptors = format!(
+            "# saved nodes\n{}@{}\n\n{}@{}\n",
+            other.id(),
+            other.local_addr(),
+            silent_id,
+            silent.local_addr().unwrap()
+        );
+        fs::write(&path, descriptors).unwrap();
+        let count = dht.bootstrap_from_file(&path);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(count.unwrap(), 2);
+
+        // Both are pinged, and the one that answers ends up verified
+        let mut buf = [0; 512];
+        let (_, from) = silent.recv_from(&mut buf).unwrap();
+        assert_eq!(from, dht.local_addr());
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !dht.contains(other.id()).unwrap().is_verified() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(dht.contains(other.id()).unwrap().is_verified());
+        assert!(!dht.contains(silent_id).unwrap().is_verified());
+    }
+
+    #[test]
+    fn bad_bootstrap_file() {
+        let mut dht = Dht::start("127.0.0.1:0").unwrap();
+        let path = std::env::temp_dir().join(format!("dht-bootstrap-{}", dht.id()));
+        fs::write(&path, format!("{}@127.0.0.1:1\nnot a node\n", dht.id())).unwrap();
+        let result = dht.bootstrap_from_file(&path);
+        fs::remove_file(&path).unwrap();
+        assert!(matches!(result, Err(Error::BadDescriptor(2))));
+        assert!(dht.known_peers().is_empty());
+
+        // Parses, but could never be reached
+        for addr in ["0.0.0.0:4000", "127.0.0.1:0", "[::]:4000"] {
+            fs::write(&path, format!("{}@{}\n", dht.id(), addr)).unwrap();
+            let result = dht.bootstrap_from_file(&path);
+            assert!(matches!(result, Err(Error::BadDescriptor(1))), "{}", addr);
+        }
+        fs::remove_file(&path).unwrap();
+
+        assert!(matches!(
+            dht.bootstrap_from_file(&path),
+            Err(Error::Io(ref e)) if e.kind() == io::ErrorKind::NotFound
+        ));
+    }
+
+    #[test]
+    fn wait_for_peers() {
+        let dht = Dht::start("127.0.0.1:0").unwrap();
+        assert_eq!(dht.wait_for_peers(0, Duration::from_secs(5)).unwrap(), 0);
+
+        let mut others: Vec<Dht> = (0..3).map(|_| Dht::start("127.0.0.1:0").unwrap()).collect();
+        thread::scope(|s| {
+            let waiting = s.spawn(|| dht.wait_for_peers(3, Duration::from_secs(5)));
+            thread::sleep(Duration::from_millis(50));
+            for other in others.iter_mut() {
+                other.bootstrap(dht.local_addr()).unwrap();
+            }
+            assert_eq!(waiting.join().unwrap().unwrap(), 3);
+        });
+
+        let start = Instant::now();
+        let result = dht.wait_for_peers(4, Duration::from_millis(100));
+        assert!(matches!(result, Err(Error::Timeout)));
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn neighborhood_converges() {
+        let hub = Dht::start("127.0.0.1:0").unwrap();
+        let others: Vec<Dht> = (0..8).map(|_| Dht::start("127.0.0.1:0").unwrap()).collect();
+        for other in others.iter() {
+            other.ping(hub.local_addr()).unwrap();
+        }
+
+        let config = Config {
+            neighborhood_interval: Some(Duration::from_millis(100)),
+            ..Config::default()
+        };
+        let dht = Dht::start_with_config("127.0.0.1:0", config).unwrap();
+        dht.ping(hub.local_addr()).unwrap();
+
+        // Everyone fits in one neighborhood, so it should end up holding all of them, closest first
+        let mut expected: Vec<NodeID> = others.iter().map(Dht::id).collect();
+        expected.push(hub.id());
+        expected.sort_by_key(|&id| id ^ dht.id());
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            let found: Vec<NodeID> = dht.neighborhood().iter().map(Contact::id).collect();
+            if found == expected {
+                break;
+            }
+            assert!(Instant::now() < deadline, "neighborhood is {:?}", found);
+            thread::sleep(Duration::from_millis(50));
+        }
+    }
+
+    #[test]
+    fn raw_ping() {
+        let dht = Dht::start("127.0.0.1:0").unwrap();
+        let peer = Dht::start("127.0.0.1:0").unwrap();
+
+        let id: NodeID = rand::random();
+        let ping = Packet {
+            network_id: 0,
+            id,
+            seq_num: 7,
+            payload: Payload::PingExt(Extensions::default()),
+            signature: None,
+        };
+        dht.send_raw(ping, peer.local_addr());
+
+        // The peer took the ping to be from id, and we learned the peer from its pong
+        let deadline = Instant::now() + Duration::from_secs(1);
+        while !dht.known_peers().iter().any(|c| c.id() == peer.id()) {
+            assert!(Instant::now() < deadline);
+            thread::sleep(Duration::from_millis(10));
+        }
+        let learned = peer.known_peers();
+        assert_eq!(learned.len(), 1);
+        assert_eq!(learned[0].id(), id);
+        assert_eq!(learned[0].addr(), dht.local_addr());
+    }
+
+    #[test]
+    fn pause() {
+        let dht = Dht::start("127.0.0.1:0").unwrap();
+        let peer = Dht::start("127.0.0.1:0").unwrap();
+
+        peer.pause();
+        let short = Duration::from_millis(300);
+        assert!(matches!(
+            dht.ping_timeout(peer.local_addr(), short),
+            Err(Error::Timeout)
+        ));
+        assert!(peer.known_peers().is_empty());
+
+        // The ping was waiting all along
+        peer.resume();
+        let deadline = Instant::now() + Duration::from_secs(1);
+        while !peer.known_peers().iter().any(|c| c.id() == dht.id()) {
+            assert!(Instant::now() < deadline);
+            thread::sleep(Duration::from_millis(10));
+        }
+        dht.ping(peer.local_addr()).unwrap();
+    }
+
+    #[test]
+    fn leave() {
+        let leaver = Dht::start("127.0.0.1:0").unwrap();
+        // Alone, so it holds these itself
+        for key in ["a", "b", "c"].iter() {
+            leaver.put(key.as_bytes(), key.as_bytes().to_vec()).unwrap();
+        }
+        let neighbors: Vec<Dht> = (0..2).map(|_| Dht::start("127.0.0.1:0").unwrap()).collect();
+        for neighbor in neighbors.iter() {
+            neighbor.ping(leaver.local_addr()).unwrap();
+        }
+
+        assert_eq!(leaver.leave().unwrap(), 3);
+        assert!(matches!(
+            leaver.ping(neighbors[0].local_addr()),
+            Err(Error::Shutdown)
+        ));
+
+        for neighbor in neighbors.iter() {
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while neighbor.export_store().len() < 3 && Instant::now() < deadline {
+                thread::sleep(Duration::from_millis(10));
+            }
+            for key in ["a", "b", "c"].iter() {
+                assert_eq!(
+                    neighbor.get(key.as_bytes()).unwrap().unwrap(),
+                    key.as_bytes()
+                );
+            }
+            assert!(neighbor.contains(leaver.id()).is_none());
+        }
+    }
+
+    #[test]
+    fn debug_insert() {
+        let dht = Dht::start("127.0.0.1:0").unwrap();
+        let target: NodeID = rand::random();
+        // Enough to split a few buckets, nobody of whom is ever sent anything
+        for port in 1..=60 {
+            dht.debug_insert(Contact::new(rand::random(), ([127, 0, 0, 1], port).into()));
+        }
+        let mut expected = dht.known_peers();
+        expected.sort_by_key(|c| c.id() ^ target);
+
+        // Asked from as far from the target as can be, so the asker doesn't make the cut itself
+        let mut far = target;
+        for b in far.bytes.iter_mut() {
+            *b = !*b;
+        }
+        let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+        sock.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let query = Packet {
+            network_id: 0,
+            id: far,
+            seq_num: 1,
+            payload: Payload::FindNode(target),
+            signature: None,
+        };
+        let mut buf = Vec::new();
+        query.encode_into(&mut buf).unwrap();
+        sock.send_to(&buf, dht.local_addr()).unwrap();
+
+        let mut buf = [0; 4096];
+        let len = sock.recv(&mut buf).unwrap();
+        match Packet::decode(&buf[..len]).unwrap().payload {
+            Payload::Nodes(closest) => {
+                // Only the nearest K of them
+                assert!(!closest.is_empty() && closest.len() < expected.len());
+                assert_eq!(closest[..], expected[..closest.len()]);
+            }
+            payload => panic!("expected Nodes, got {:?}", payload),
+        }
+    }
+
+    #[test]
+    fn send_rate_limit() {
+        let rate = 50_000;
+        let dht = Dht::start_with_config(
+            "127.0.0.1:0",
+            Config {
+                max_send_rate: Some(rate),
+                ..Config::default()
+            },
+        )
+        .unwrap();
+        let sink = UdpSocket::bind("127.0.0.1:0").unwrap();
+        sink.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+        let count = 50;
+        let mut bytes = 0;
+        for seq_num in 0..count {
+            let packet = Packet {
+                network_id: 0,
+                id: dht.id(),
+                seq_num,
+                payload: Payload::Value(vec![0; 1000]),
+                signature: None,
+            };
+            let mut buf = Vec::new();
+            packet.encode_into(&mut buf).unwrap();
+            bytes += buf.len();
+            dht.send_raw(packet, sink.local_addr().unwrap());
+        }
+
+        let mut buf = [0; 2048];
+        sink.recv(&mut buf).unwrap();
+        let first = Instant::now();
+        for _ in 1..count {
+            sink.recv(&mut buf).unwrap();
+        }
+        let took = first.elapsed().as_secs_f64();
+        // Everything past the first burst is paced
+        let expected = (bytes as f64 - rate as f64 / 10.0) / rate as f64;
+        assert!(
+            took > expected * 0.7 && took < expected * 1.5,
+            "{}s to send {} bytes, expected about {}s",
+            took,
+            bytes,
+            expected
+        );
+
+        let measured = dht.send_rate();
+        assert!(
+            measured > rate as f64 * 0.6 && measured < rate as f64 * 1.5,
+            "measured {} bytes/s",
+            measured
+        );
+    }
+
+    #[test]
+    fn run_blocking() {
+        let (ready_tx, ready) = channel::bounded(1);
+        thread::scope(|s| {
+            let node = s.spawn(move || {
+                Dht::run_blocking("127.0.0.1:0", Config::default(), |addr, stop| {
+                    ready_tx.send((addr, stop)).unwrap();
+                })
+            });
+            let (addr, stop) = ready.recv().unwrap();
+
+            let dht = Dht::start("127.0.0.1:0").unwrap();
+            let pinged = dht.ping(addr);
+            stop.shutdown();
+            pinged.unwrap();
+            node.join().unwrap().unwrap();
+        });
+    }
+
+    #[test]
+    fn export_import() {
+        let dht = Dht::start("127.0.0.1:0").unwrap();
+        let keys: Vec<String> = (0..5).map(|i| format!("key {}", i)).collect();
+        for key in keys.iter() {
+            dht.put(key.as_bytes(), key.as_bytes().to_vec()).unwrap();
+        }
+        let mut pairs = dht.export_store();
+        assert_eq!(pairs.len(), keys.len());
+
+        pairs.push((rand::random(), vec![0; MAX_VALUE_SIZE + 1]));
+        let fresh = Dht::start("127.0.0.1:0").unwrap();
+        assert_eq!(fresh.import_store(pairs), keys.len());
+        for key in keys.iter() {
+            assert_eq!(fresh.get(key.as_bytes()).unwrap().unwrap(), key.as_bytes());
+        }
+        assert_eq!(fresh.export_store(), dht.export_store());
+    }
+
+    #[test]
+    fn oversized_packet() {
+        let dht = Dht::start("127.0.0.1:0").unwrap();
+        let peer = Dht::start("127.0.0.1:0").unwrap();
+
+        let huge = Packet {
+            network_id: 0,
+            id: dht.id(),
+            seq_num: 0,
+            payload: Payload::Value(vec![0; MAX_PACKET_SIZE as usize]),
+            signature: None,
+        };
+        dht.send_raw(huge, peer.local_addr());
+
+        // The sender dropped it and carried on
+        dht.ping(peer.local_addr()).unwrap();
+        assert!(!dht.sender.as_ref().unwrap().is_finished());
+    }
+
+    #[test]
+    fn unsendable_contacts() {
+        let dht = Dht::start("127.0.0.1:0").unwrap();
+        let peer = Dht::start("127.0.0.1:0").unwrap();
+        let anomalies = dht.watch_anomalies();
+        let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+        sock.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let liar = Contact::new(rand::random(), sock.local_addr().unwrap());
+        dht.debug_insert(liar);
+
+        // Answer the lookup with contacts we have no way to send to
+        let lookup = dht.find_node(rand::random());
+        let mut buf = [0; 4096];
+        let len = sock.recv(&mut buf).unwrap();
+        let query = Packet::decode(&buf[..len]).unwrap();
+        let nodes = vec![
+            Contact::new(rand::random(), "[2001:db8::1]:4000".parse().unwrap()),
+            Contact::new(rand::random(), ([255, 255, 255, 255], 4000).into()),
+            Contact::new(rand::random(), ([224, 0, 0, 1], 4000).into()),
+        ];
+        let reply = Packet {
+            network_id: 0,
+            id: liar.id(),
+            seq_num: query.seq_num,
+            payload: Payload::Nodes(nodes),
+            signature: None,
+        };
+        let mut out = Vec::new();
+        reply.encode_into(&mut out).unwrap();
+        sock.send_to(&out, dht.local_addr()).unwrap();
+        lookup.wait();
+
+        // None of them were tried, and the node carries on as before
+        peer.ping(dht.local_addr()).unwrap();
+        dht.ping(peer.local_addr()).unwrap();
+        assert!(!dht.sender.as_ref().unwrap().is_finished());
+        assert!(!anomalies
+            .try_iter()
+            .any(|a| matches!(a, Anomaly::SendFailed(_) | Anomaly::SenderGone)));
+        assert_eq!(dht.known_peers().len(), 2);
+    }
+
+    #[test]
+    fn no_socket_for_family() {
+        let dht = Dht::start("127.0.0.1:0").unwrap();
+        let peer = Dht::start("127.0.0.1:0").unwrap();
+
+        let start = Instant::now();
+        assert!(dht.ping("[::1]:4000".parse().unwrap()).is_err());
+        assert!(start.elapsed() < dht.timeout / 2);
+        dht.ping(peer.local_addr()).unwrap();
+    }
+
+    #[test]
+    fn failed_send() {
+        let dht = Dht::start("127.0.0.1:0").unwrap();
+        let peer = Dht::start("127.0.0.1:0").unwrap();
+        let anomalies = dht.watch_anomalies();
+
+        // The OS won't send from a loopback socket to anywhere off it
+        let ping = Packet {
+            network_id: 0,
+            id: dht.id(),
+            seq_num: 0,
+            payload: Payload::Ping,
+            signature: None,
+        };
+        dht.send_raw(ping, ([192, 0, 2, 1], 4000).into());
+        assert!(matches!(
+            anomalies.recv_timeout(Duration::from_secs(5)),
+            Ok(Anomaly::SendFailed(_))
+        ));
+
+        // Only that one datagram was lost
+        dht.ping(peer.local_addr()).unwrap();
+        assert!(!dht.sender.as_ref().unwrap().is_finished());
+
+        // And a ping that can't go out fails without waiting out the timeout
+        let start = Instant::now();
+        assert!(dht.ping(([192, 0, 2, 1], 4000).into()).is_err());
+        assert!(start.elapsed() < dht.timeout / 2);
+    }
+
+    #[test]
+    fn lookup_events() {
+        let hub = Dht::start("127.0.0.1:0").unwrap();
+        let others: Vec<Dht> = (0..4).map(|_| Dht::start("127.0.0.1:0").unwrap()).collect();
+        for other in others.iter() {
+            other.ping(hub.local_addr()).unwrap();
+        }
+        let dht = Dht::start("127.0.0.1:0").unwrap();
+        dht.ping(hub.local_addr()).unwrap();
+
+        let events = dht.watch_lookups();
+        let target: NodeID = rand::random();
+        let closest = match dht.find_node(target).wait() {
+            LookupStatus::Completed(closest) => closest,
+            status => panic!("lookup ended with {:?}", status),
+        };
+
+        let events: Vec<LookupEvent> = events
+            .try_iter()
+            .filter(|(t, _)| *t == target)
+            .map(|(_, e)| e)
+            .collect();
+        assert_eq!(events.first(), Some(&LookupEvent::Started));
+        assert_eq!(events.last(), Some(&LookupEvent::Completed(closest)));
+
+        // Everyone answers only after being asked, and the hub tells us about everyone else
+        let mut queried = Vec::new();
+        for event in events.iter() {
+            match event {
+                LookupEvent::Queried(c) => queried.push(*c),
+                LookupEvent::Responded(c, added) => {
+                    assert!(queried.contains(c));
+                    if c.id() == hub.id() {
+                        assert_eq!(*added, others.len());
+                    }
+                }
+                _ => (),
+            }
+        }
+        assert_eq!(queried.len(), others.len() + 1);
+    }
+
+    #[test]
+    fn drop_bad_datagrams() {
+        let dht = Dht::start("127.0.0.1:0").unwrap();
+        let peer = Dht::start("127.0.0.1:0").unwrap();
+        let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+        sock.send_to(&[], dht.local_addr()).unwrap();
+        sock.send_to(&[0xFF; 3], dht.local_addr()).unwrap();
+
+        // Still working, and by the time the ping is answered both have been looked at
+        dht.ping(peer.local_addr()).unwrap();
+        assert_eq!(dht.dropped(DropReason::Empty), 1);
+        assert_eq!(dht.dropped(DropReason::Malformed), 1);
+    }
+
+    #[test]
+    fn health() {
+        let dht = Dht::start("127.0.0.1:0").unwrap();
+        assert_eq!(dht.health(), HealthReport::default());
+
+        let others: Vec<Dht> = (0..3).map(|_| Dht::start("127.0.0.1:0").unwrap()).collect();
+        for other in others.iter() {
+            dht.ping(other.local_addr()).unwrap();
+        }
+        let pairs = vec![
+            (NodeID::from_key(b"a"), b"1".to_vec()),
+            (NodeID::from_key(b"b"), b"2".to_vec()),
+        ];
+        assert_eq!(dht.import_store(pairs), 2);
+        let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+        sock.send_to(&[], dht.local_addr()).unwrap();
+        sock.send_to(&[], dht.local_addr()).unwrap();
+        dht.ping(others[0].local_addr()).unwrap();
+
+        let report = dht.health();
+        assert_eq!(report.peers, 3);
+        let mut buckets: Vec<(usize, usize)> = Vec::new();
+        for contact in dht.known_peers() {
+            let bucket = dht.id().common_prefix_len(contact.id());
+            match buckets.iter_mut().find(|(b, _)| *b == bucket) {
+                Some((_, n)) => *n += 1,
+                None => buckets.push((bucket, 1)),
+            }
+        }
+        buckets.sort();
+        assert_eq!(report.buckets, buckets);
+        assert_eq!(report.values, 2);
+        assert_eq!(report.signed_values, 0);
+        assert_eq!((report.lookups, report.queued_lookups), (0, 0));
+        assert_eq!(report.drops, vec![(DropReason::Empty, 2)]);
+        assert_eq!(report.estimated_size, dht.estimated_network_size());
+        assert!(report.estimated_size.unwrap() >= 3);
+    }
+
+    #[test]
+    fn separate_networks() {
+        let private = |network_id| Config {
+            network_id,
+            ..Config::default()
+        };
+        let dht = Dht::start_with_config("127.0.0.1:0", private(7)).unwrap();
+        let same = Dht::start_with_config("127.0.0.1:0", private(7)).unwrap();
+        let other = Dht::start_with_config("127.0.0.1:0", private(8)).unwrap();
+
+        dht.ping(same.local_addr()).unwrap();
+
+        let timeout = Duration::from_millis(200);
+        assert!(matches!(
+            dht.ping_timeout(other.local_addr(), timeout),
+            Err(Error::Timeout)
+        ));
+        assert!(matches!(
+            other.ping_timeout(dht.local_addr(), timeout),
+            Err(Error::Timeout)
+        ));
+        assert_eq!(other.dropped(DropReason::ForeignNetwork), 1);
+        assert_eq!(dht.dropped(DropReason::ForeignNetwork), 1);
+        assert!(other.known_peers().is_empty());
+        assert_eq!(dht.known_peers().len(), 1);
+    }
+
+    #[test]
+    fn pre_shared_key() {
+        let private = |psk| Config {
+            psk: Some(psk),
+            ..Config::default()
+        };
+        let dht = Dht::start_with_config("127.0.0.1:0", private([1; 32])).unwrap();
+        let same = Dht::start_with_config("127.0.0.1:0", private([1; 32])).unwrap();
+        let other = Dht::start_with_config("127.0.0.1:0", private([2; 32])).unwrap();
+        let open = Dht::start("127.0.0.1:0").unwrap();
+
+        dht.ping(same.local_addr()).unwrap();
+
+        let timeout = Duration::from_millis(200);
+        assert!(matches!(
+            other.ping_timeout(dht.local_addr(), timeout),
+            Err(Error::Timeout)
+        ));
+        assert!(matches!(
+            open.ping_timeout(dht.local_addr(), timeout),
+            Err(Error::Timeout)
+        ));
+        assert_eq!(dht.dropped(DropReason::BadMac), 2);
+        assert_eq!(dht.known_peers().len(), 1);
+    }
+
+    #[test]
+    fn max_value_size() {
+        let sizes = [
+            (Some([1; 32]), false, MAX_VALUE_SIZE - mac::MAC_LEN),
+            (None, true, MAX_VALUE_SIZE - SIGNATURE_LEN),
+            (
+                Some([1; 32]),
+                true,
+                MAX_VALUE_SIZE - mac::MAC_LEN - SIGNATURE_LEN,
+            ),
+        ];
+        for (psk, sign_packets, max) in sizes {
+            let config = Config {
+                psk,
+                sign_packets,
+                ..Config::default()
+            };
+            assert_eq!(config.max_value_size(), max);
+            let dht = Dht::start_with_config("127.0.0.1:0", config.clone()).unwrap();
+            let peer = Dht::start_with_config("127.0.0.1:0", config).unwrap();
+            dht.ping(peer.local_addr()).unwrap();
+
+            // The biggest value still makes it over, MAC, signature and all
+            assert_eq!(dht.put(b"key", vec![7; max]).unwrap(), 1);
+            assert_eq!(peer.get(b"key").unwrap().unwrap().len(), max);
+            assert_eq!(dht.dropped(DropReason::Malformed), 0);
+            assert!(matches!(
+                dht.put(b"key", vec![7; max + 1]),
+                Err(Error::TooLarge)
+            ));
+        }
+    }
+
+    #[test]
+    fn signed_packets() {
+        let signing = || Config {
+            sign_packets: true,
+            ..Config::default()
+        };
+        let dht = Dht::start_with_config("127.0.0.1:0", signing()).unwrap();
+        let other = Dht::start_with_config("127.0.0.1:0", signing()).unwrap();
+        let unsigned = Dht::start("127.0.0.1:0").unwrap();
+
+        dht.ping(other.local_addr()).unwrap();
+
+        let timeout = Duration::from_millis(200);
+        assert!(matches!(
+            unsigned.ping_timeout(dht.local_addr(), timeout),
+            Err(Error::Timeout)
+        ));
+        assert_eq!(dht.dropped(DropReason::Unsigned), 1);
+
+        // Nodes that don't insist on signatures still take signed packets, but their reply is dropped
+        assert!(matches!(
+            dht.ping_timeout(unsigned.local_addr(), timeout),
+            Err(Error::Timeout)
+        ));
+        assert!(unsigned.contains(dht.id()).is_some());
+        // Its pong, and then its asking what we support
+        assert_eq!(dht.dropped(DropReason::Unsigned), 3);
+        assert_eq!(unsigned.dropped(DropReason::BadSignature), 0);
+    }
+
+    #[test]
+    fn short_deadline_times_out() {
+        let dht = Dht::start("127.0.0.1:0").unwrap();
+        // Bound, so the ping isn't refused, but nobody ever answers
+        let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+
+        let start = Instant::now();
+        let result = dht.ping_timeout(silent.local_addr().unwrap(), Duration::from_millis(50));
+        assert!(matches!(result, Err(Error::Timeout)));
+        assert!(start.elapsed() < Duration::from_secs(1));
+
+        dht.shutdown();
+    }
+
+    #[test]
+    fn tick_cadence() {
+        let config = Config {
+            tick_interval: Duration::from_millis(10),
+            ..Config::default()
+        };
+        let dht = Dht::start_with_config("127.0.0.1:0", config).unwrap();
+        let peer = Dht::start("127.0.0.1:0").unwrap();
+        let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let silent = silent.local_addr().unwrap();
+        let timeout = Duration::from_millis(300);
+
+        thread::scope(|s| {
+            let start = Instant::now();
+            let timed_out = s.spawn(|| dht.ping_timeout(silent, timeout));
+
+            // Packets keep flowing while the worker ticks
+            for _ in 0..10 {
+                dht.ping(peer.local_addr()).unwrap();
+            }
+            assert!(start.elapsed() < timeout);
+
+            // The timeout is noticed on the first tick after it passes
+            assert!(matches!(timed_out.join().unwrap(), Err(Error::Timeout)));
+            let elapsed = start.elapsed();
+            assert!(timeout <= elapsed && elapsed < timeout + Duration::from_millis(150));
+        });
+    }
+
+    #[test]
+    fn put_then_get() {
+        let storer = Dht::start("127.0.0.1:0").unwrap();
+        let writer = Dht::start("127.0.0.1:0").unwrap();
+        let reader = Dht::start("127.0.0.1:0").unwrap();
+        writer.ping(storer.local_addr()).unwrap();
+        assert_eq!(writer.put(b"key", b"value".to_vec()).unwrap(), 1);
+
+        reader.ping(storer.local_addr()).unwrap();
+        assert_eq!(reader.get(b"key").unwrap(), Some(b"value".to_vec()));
+        assert_eq!(reader.get(b"missing").unwrap(), None);
+
+        reader.shutdown();
+        writer.shutdown();
+        storer.shutdown();
+    }
+
+    #[test]
+    fn swarm() {
+        let tracker = Dht::start("127.0.0.1:0").unwrap();
+        let members: Vec<Dht> = (0..3).map(|_| Dht::start("127.0.0.1:0").unwrap()).collect();
+        for (i, member) in members.iter().enumerate() {
+            member.ping(tracker.local_addr()).unwrap();
+            // The tracker, and whichever members it has told this one about
+            assert!(member.announce(b"infohash", 6881 + i as u16).unwrap() >= 1);
+        }
+
+        let leecher = Dht::start("127.0.0.1:0").unwrap();
+        leecher.ping(tracker.local_addr()).unwrap();
+        let mut peers = leecher.get_peers(b"infohash").unwrap();
+        peers.sort();
+        let expected: Vec<SocketAddr> = (0..3).map(|i| ([127, 0, 0, 1], 6881 + i).into()).collect();
+        assert_eq!(peers, expected);
+        assert!(leecher.get_peers(b"other").unwrap().is_empty());
+    }
+
+    #[test]
+    fn value_set() {
+        let storer = Dht::start("127.0.0.1:0").unwrap();
+        let adders: Vec<Dht> = (0..3).map(|_| Dht::start("127.0.0.1:0").unwrap()).collect();
+        for (i, adder) in adders.iter().enumerate() {
+            adder.ping(storer.local_addr()).unwrap();
+            assert!(adder.add(b"set", vec![i as u8]).unwrap() >= 1);
+        }
+        // Adding a member again doesn't make two of it
+        assert!(adders[0].add(b"set", vec![0]).unwrap() >= 1);
+        let too_long = vec![0; MAX_MEMBER_LEN + 1];
+        assert!(matches!(
+            adders[0].add(b"set", too_long),
+            Err(Error::TooLarge)
+        ));
+
+        let reader = Dht::start("127.0.0.1:0").unwrap();
+        reader.ping(storer.local_addr()).unwrap();
+        let mut members = reader.get_all(b"set").unwrap();
+        members.sort();
+        assert_eq!(members, vec![vec![0], vec![1], vec![2]]);
+        assert!(reader.get_all(b"other").unwrap().is_empty());
+    }
+
+    #[test]
+    fn get_with_source() {
+        let storer = Dht::start("127.0.0.1:0").unwrap();
+        let writer = Dht::start("127.0.0.1:0").unwrap();
+        let reader = Dht::start("127.0.0.1:0").unwrap();
+        writer.ping(storer.local_addr()).unwrap();
+        assert_eq!(writer.put(b"key", b"value".to_vec()).unwrap(), 1);
+
+        // The writer knows the reader too, but only the storer holds the value
+        reader.ping(writer.local_addr()).unwrap();
+        reader.ping(storer.local_addr()).unwrap();
+        let (value, source) = reader.get_with_source(b"key").unwrap().unwrap();
+        assert_eq!(value, b"value");
+        assert_eq!(source.id(), storer.id());
+        assert_eq!(source.addr(), storer.local_addr());
+
+        let (_, source) = storer.get_with_source(b"key").unwrap().unwrap();
+        assert_eq!(source.id(), storer.id());
+        assert!(reader.get_with_source(b"missing").unwrap().is_none());
+    }
+
+    #[test]
+    fn concurrent_puts() {
+        let storer = Dht::start("127.0.0.1:0").unwrap();
+        let writer = Dht::start("127.0.0.1:0").unwrap();
+        writer.ping(storer.local_addr()).unwrap();
+
+        let writer = &writer;
+        let stored: Vec<usize> = thread::scope(|s| {
+            let puts: Vec<_> = (0..16)
+                .map(|i| s.spawn(move || writer.put(&[i], vec![i]).unwrap()))
+                .collect();
+            puts.into_iter().map(|p| p.join().unwrap()).collect()
+        });
+        assert!(stored.iter().all(|&n| n == 1));
+
+        for i in 0..16 {
+            assert_eq!(storer.get(&[i]).unwrap(), Some(vec![i]));
+        }
+    }
+
+    #[test]
+    fn put_then_get_signed() {
+        let storer = Dht::start("127.0.0.1:0").unwrap();
+        let writer = Dht::start("127.0.0.1:0").unwrap();
+        let reader = Dht::start("127.0.0.1:0").unwrap();
+        let signing_key = ed25519_dalek::SigningKey::from_bytes(&rand::random());
+
+        writer.ping(storer.local_addr()).unwrap();
+        let first = SignedValue::sign(&signing_key, 1, b"first".to_vec());
+        assert_eq!(writer.put_signed(first.clone()).unwrap(), 1);
+        assert_eq!(writer.put_signed(first.clone()).unwrap(), 1);
+        let second = SignedValue::sign(&signing_key, 2, b"second".to_vec());
+        assert_eq!(writer.put_signed(second.clone()).unwrap(), 1);
+        assert_eq!(writer.put_signed(first).unwrap(), 0);
+
+        reader.ping(storer.local_addr()).unwrap();
+        let got = reader.get_signed(&signing_key.verifying_key()).unwrap();
+        assert_eq!(got, Some(second));
+
+        reader.shutdown();
+        writer.shutdown();
+        storer.shutdown();
+    }
+
+    #[test]
+    fn socket_options() {
+        let dht = Dht::start("127.0.0.1:0").unwrap();
+        let peer = Dht::start("127.0.0.1:0").unwrap();
+
+        dht.set_ttl(7).unwrap();
+        assert_eq!(dht.ttl().unwrap(), 7);
+        dht.set_multicast_ttl(2).unwrap();
+        assert_eq!(dht.multicast_ttl().unwrap(), 2);
+        dht.set_multicast_loop(false).unwrap();
+        assert!(!dht.multicast_loop().unwrap());
+
+        // Loopback is a single hop, so the node still works
+        dht.ping(peer.local_addr()).unwrap();
+    }
+
+    #[test]
+    fn command_backlog() {
+        let dht = Dht::start("127.0.0.1:0").unwrap();
+        let peers: Vec<Dht> = (0..3).map(|_| Dht::start("127.0.0.1:0").unwrap()).collect();
+        for peer in peers.iter() {
+            dht.ping(peer.local_addr()).unwrap();
+        }
+
+        let flooding = AtomicBool::new(true);
+        thread::scope(|s| {
+            // Keeps the command queue full, without waiting on any of the answers
+            let command = dht.command.clone();
+            let flooding = &flooding;
+            s.spawn(move || {
+                while flooding.load(Ordering::Relaxed) {
+                    let (reply, _) = channel::bounded(1);
+                    command.send(Command::KnownPeers(reply)).unwrap();
+                }
+            });
+            thread::sleep(Duration::from_millis(50));
+
+            // The ping waits behind a full queue, then its answer has to get past the rest
+            let pings: Vec<_> = peers
+                .iter()
+                .map(|peer| {
+                    let start = Instant::now();
+                    dht.ping(peer.local_addr()).map(|_| start.elapsed())
+                })
+                .collect();
+            // Stop before checking anything, so a failure can't leave the flood going forever
+            flooding.store(false, Ordering::Relaxed);
+            for ping in pings {
+                assert!(ping.unwrap() < Duration::from_secs(1));
+            }
+        });
+    }
+
+    // Answers every ping after the given delay, once it has introduced itself to dht
+    fn slow_peer(dht: SocketAddr, delay: Duration, running: &AtomicBool) {
+        let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+        sock.set_read_timeout(Some(Duration::from_millis(20)))
+            .unwrap();
+        let id = rand::random();
+        let send = |seq_num, payload| {
+            let packet = Packet {
+                network_id: 0,
+                id,
+                seq_num,
+                payload,
+                signature: None,
+            };
+            let mut buf = Vec::new();
+            packet.encode_into(&mut buf).unwrap();
+            sock.send_to(&buf, dht).unwrap();
+        };
+        send(0, Payload::Ping);
+
+        let mut buf = [0; 512];
+        while running.load(Ordering::Relaxed) {
+            let len = match sock.recv_from(&mut buf) {
+                Ok((len, _)) => len,
+                Err(_) => continue,
+            };
+            let packet = Packet::decode(&buf[..len]).unwrap();
+            if let Payload::Ping = packet.payload {
+                thread::sleep(delay);
+                send(packet.seq_num, Payload::Pong);
+            }
+        }
+    }
+
+    // Fires count pings at a paused node, then resumes it once its receivers have queued all they
+    // can. Returns how many they had taken off the socket by then, and how many were answered.
+    fn stalled_burst(recv_queue: usize, count: u64) -> (usize, usize) {
+        let dht = Dht::start_with_config(
+            "127.0.0.1:0",
+            Config {
+                recv_queue,
+                ..Config::default()
+            },
+        )
+        .unwrap();
+        let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+        sock.set_read_timeout(Some(Duration::from_millis(500)))
+            .unwrap();
+        let id = rand::random();
+
+        dht.pause();
+        let mut buf = Vec::new();
+        for seq_num in 0..count {
+            let packet = Packet {
+                network_id: 0,
+                id,
+                seq_num,
+                payload: Payload::Ping,
+                signature: None,
+            };
+            buf.clear();
+            packet.encode_into(&mut buf).unwrap();
+            sock.send_to(&buf, dht.local_addr()).unwrap();
+        }
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while dht.queued.len() < recv_queue.min(count as usize) {
+            assert!(Instant::now() < deadline);
+            thread::sleep(Duration::from_millis(10));
+        }
+        let taken = dht.queued.len();
+        dht.resume();
+
+        let mut buf = [0; 512];
+        let mut pongs = 0;
+        while let Ok(len) = sock.recv(&mut buf) {
+            if let Ok(Packet {
+                payload: Payload::Pong,
+                ..
+            }) = Packet::decode(&buf[..len])
+            {
+                pongs += 1;
+            }
+        }
+        (taken, pongs)
+    }
+
+    #[test]
+    fn bursty_input() {
+        // While the worker is stalled, the queue takes its share of a burst off the socket, so the
+        // socket's own buffer only has to hold the rest. Small enough that it can, so every ping
+        // is answered either way.
+        let count = 100;
+        let queue = 64;
+        assert_eq!(stalled_burst(0, count), (0, count as usize));
+        assert_eq!(stalled_burst(queue, count), (queue, count as usize));
+    }
+
+    #[test]
+    fn rtt_histogram() {
+        let dht = Dht::start("127.0.0.1:0").unwrap();
+        assert_eq!(dht.rtt_histogram(), RttStats::default());
+
+        let delays: Vec<Duration> = (1..=10).map(|i| Duration::from_millis(20 * i)).collect();
+        let running = AtomicBool::new(true);
+        let stats = thread::scope(|s| {
+            for delay in delays.iter() {
+                let running = &running;
+                let addr = dht.local_addr();
+                s.spawn(move || slow_peer(addr, *delay, running));
+            }
+            let joined = dht.wait_for_peers(delays.len(), Duration::from_secs(5));
+            let stats = dht.rtt_histogram();
+            running.store(false, Ordering::Relaxed);
+            joined.unwrap();
+            stats
+        });
+
+        // How much loopback and a busy machine add on top of each peer's delay is anyone's guess,
+        // so only check nothing came back sooner than it could have. How the worker times a ping
+        // is checked against a manual clock in kad::network.
+        assert_eq!(stats.answered, delays.len());
+        assert_eq!(stats.lost, 0);
+        assert!(stats.min >= delays[0], "{:?}", stats);
+        assert!(stats.median >= delays[4], "{:?}", stats);
+        assert!(stats.p95 >= delays[9], "{:?}", stats);
+        assert!(stats.max >= stats.p95, "{:?}", stats);
+    }
+
+    #[test]
+    fn multi_with_store() {
+        let mut store = MemoryStore::default();
+        let value = StoredValue {
+            value: b"value".to_vec(),
+            version: 0,
+            stored: Instant::now(),
+        };
+        store.put(NodeID::from_key(b"key"), value).unwrap();
+        let addrs = ["127.0.0.1:0".parse().unwrap()];
+        let dht = Dht::start_multi_with_store(&addrs, Config::default(), store).unwrap();
+        assert_eq!(dht.get(b"key").unwrap(), Some(b"value".to_vec()));
+    }
+
+    #[test]
+    fn dual_stack() {
+        let addrs = ["127.0.0.1:0".parse().unwrap(), "[::1]:0".parse().unwrap()];
+        let dht = Dht::start_multi(&addrs, Config::default()).unwrap();
+        let served = dht.local_addrs();
+        assert_eq!(served.len(), 2);
+        assert_eq!(dht.local_addr(), served[0]);
+
+        let v4 = Dht::start("127.0.0.1:0").unwrap();
+        let v6 = Dht::start("[::1]:0").unwrap();
+        dht.ping(v4.local_addr()).unwrap();
+        dht.ping(v6.local_addr()).unwrap();
+
+        // Each family is answered from the socket it came in on
+        for addr in served.iter() {
+            let sock = UdpSocket::bind((addr.ip(), 0)).unwrap();
+            sock.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+            let ping = Packet {
+                network_id: 0,
+                id: rand::random(),
+                seq_num: 3,
+                payload: Payload::Ping,
+                signature: None,
+            };
+            let mut buf = Vec::new();
+            ping.encode_into(&mut buf).unwrap();
+            sock.send_to(&buf, addr).unwrap();
+
+            let mut buf = [0; 512];
+            let (len, from) = sock.recv_from(&mut buf).unwrap();
+            assert_eq!(from, *addr);
+            let pong = Packet::decode(&buf[..len]).unwrap();
+            assert!(matches!(pong.payload, Payload::Pong));
+            assert_eq!(pong.id, dht.id());
+        }
+
+        let alive = dht.alive.clone();
+        drop(dht);
+        assert_eq!(
+            alive.recv_timeout(Duration::from_millis(0)),
+            Err(channel::RecvTimeoutError::Disconnected)
+        );
+    }
+
+    #[test]
+    fn bind_in_use() {
+        let taken = UdpSocket::bind("127.0.0.1:0").unwrap();
+        match Dht::start(taken.local_addr().unwrap()) {
+            Err(Error::Bind(e)) => assert_eq!(e.kind(), io::ErrorKind::AddrInUse),
+            Err(e) => panic!("expected a bind error, got {}", e),
+            Ok(_) => panic!("bound an address that was already in use"),
+        }
+    }
+
+    #[test]
+    fn drop_stops_threads() {
+        let dht = Dht::start("127.0.0.1:0").unwrap();
+        let addr = dht.local_addr();
+        let alive = dht.alive.clone();
+        drop(dht);
+
+        // Every thread has exited, and with them every handle on the socket
+        assert_eq!(
+            alive.recv_timeout(Duration::from_millis(0)),
+            Err(channel::RecvTimeoutError::Disconnected)
+        );
+        UdpSocket::bind(addr).unwrap();
+    }
+
+    #[test]
+    fn shutdown_from_handle() {
+        let dht = Dht::start("127.0.0.1:0").unwrap();
+        let addr = dht.local_addr();
+        let alive = dht.alive.clone();
+
+        let handle = dht.shutdown_handle();
+        let other = handle.clone();
+        thread::spawn(move || other.shutdown()).join().unwrap();
+
+        // The threads stop even though the Dht is still around
+        assert_eq!(
+            alive.recv_timeout(SHUTDOWN_WAIT),
+            Err(channel::RecvTimeoutError::Disconnected)
+        );
+        assert!(matches!(dht.ping(addr), Err(Error::Shutdown)));
+
+        // Stopping twice, or dropping afterwards, is fine
+        handle.shutdown();
+        drop(dht);
+    }
+
+    #[test]
+    fn ping_metadata() {
+        let config = Config {
+            software_version: Some("test/1.0".to_string()),
+            ..Config::default()
+        };
+        let dht = Dht::start_with_config("127.0.0.1:0", config).unwrap();
+        let peer = Dht::start("127.0.0.1:0").unwrap();
+        dht.ping(peer.local_addr()).unwrap();
+
+        // A plain ping says nothing, but the peer asks, and hears it in the answer
+        let deadline = Instant::now() + Duration::from_secs(1);
+        while peer.peer_metadata().is_empty() {
+            assert!(Instant::now() < deadline);
+            thread::sleep(Duration::from_millis(10));
+        }
+        let described = peer.peer_metadata();
+        assert_eq!(described.len(), 1);
+        let (contact, metadata) = described[0];
+        assert_eq!(contact.id(), dht.id());
+        assert_eq!(metadata.version(), "test/1.0");
+        assert!(metadata.uptime() < Duration::from_secs(60));
+
+        // The peer had nothing to say about itself, though it's still known
+        assert!(dht.contains(peer.id()).is_some());
+        assert!(dht.peer_metadata().is_empty());
+    }
+
+    #[test]
+    fn distance_to() {
+        let dht = Dht::start("127.0.0.1:0").unwrap();
+        let key = NodeID::from_key(b"key");
+        let mut expected = NodeID::zero();
+        for i in 0..expected.bytes.len() {
+            expected.bytes[i] = dht.id().bytes[i] ^ key.bytes[i];
+        }
+        assert_eq!(dht.distance_to(b"key"), expected);
+        assert_ne!(dht.distance_to(b"other"), expected);
+    }
+
+    #[test]
+    fn thread_names() {
+        let config = Config {
+            stack_size: Some(256 * 1024),
+            ..Config::default()
+        };
+        let name = thread_builder("dht-worker", &config)
+            .spawn(|| thread::current().name().map(String::from))
+            .unwrap()
+            .join()
+            .unwrap();
+        assert_eq!(name.as_deref(), Some("dht-worker"));
+
+        let dht = Dht::start_with_config("127.0.0.1:0", config).unwrap();
+        assert_eq!(
+            dht.worker.as_ref().unwrap().thread().name(),
+            Some("dht-worker")
+        );
+        assert_eq!(
+            dht.sender.as_ref().unwrap().thread().name(),
+            Some("dht-sender")
+        );
+        dht.shutdown();
+    }
 }
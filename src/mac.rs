@@ -0,0 +1,63 @@
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+// Bytes of MAC on the end of every datagram when Config::psk is set
+pub const MAC_LEN: usize = 32;
+
+fn hmac(psk: &[u8; 32], data: &[u8]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(psk).unwrap();
+    mac.update(data);
+    mac
+}
+
+// Appends the HMAC-SHA256 of everything already in buf.
+pub fn seal(psk: &[u8; 32], buf: &mut Vec<u8>) {
+    let mac = hmac(psk, buf).finalize().into_bytes();
+    buf.extend_from_slice(&mac);
+}
+
+// The datagram without its MAC, if it ends in the right one for psk. The comparison takes as long
+// however much of a forged MAC was right.
+pub fn open<'a>(psk: &[u8; 32], datagram: &'a [u8]) -> Option<&'a [u8]> {
+    if datagram.len() < MAC_LEN {
+        return None;
+    }
+    let (body, mac) = datagram.split_at(datagram.len() - MAC_LEN);
+    hmac(psk, body).verify_slice(mac).ok().map(|_| body)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn rfc_4231() {
+        // Test case 2. HMAC pads the key out with zeros anyway, so padding it to 32 bytes first
+        // leaves it the same key.
+        let mut key = [0; 32];
+        key[..4].copy_from_slice(b"Jefe");
+        let mac = hmac(&key, b"what do ya want for nothing?")
+            .finalize()
+            .into_bytes();
+        let expected = [
+            0x5b, 0xdc, 0xc1, 0x46, 0xbf, 0x60, 0x75, 0x4e, 0x6a, 0x04, 0x24, 0x26, 0x08, 0x95,
+            0x75, 0xc7, 0x5a, 0x00, 0x3f, 0x08, 0x9d, 0x27, 0x39, 0x83, 0x9d, 0xec, 0x58, 0xb9,
+            0x64, 0xec, 0x38, 0x43,
+        ];
+        assert_eq!(mac[..], expected);
+    }
+
+    #[test]
+    fn seal_and_open() {
+        let psk = [7; 32];
+        let mut buf = b"packet".to_vec();
+        seal(&psk, &mut buf);
+        assert_eq!(buf.len(), 6 + MAC_LEN);
+        assert_eq!(open(&psk, &buf), Some(&b"packet"[..]));
+
+        assert_eq!(open(&[8; 32], &buf), None);
+        buf[0] ^= 1;
+        assert_eq!(open(&psk, &buf), None);
+        assert_eq!(open(&psk, &buf[..MAC_LEN - 1]), None);
+    }
+}
@@ -0,0 +1,135 @@
+extern crate dht;
+
+use std::env;
+use std::net::SocketAddr;
+use std::process;
+use std::thread;
+
+use dht::{Dht, Error};
+
+const USAGE: &str = "usage: dht [--bind ADDR] [--peer ADDR]... COMMAND
+
+commands:
+    start            run a node until killed
+    id               print the ID of a new node
+    ping ADDR        ping the node at ADDR
+    get KEY          look up the value stored under KEY
+    put KEY VALUE    store VALUE under KEY";
+
+enum Command {
+    Start,
+    Id,
+    Ping(SocketAddr),
+    Get(String),
+    Put(String, String),
+}
+
+struct Options {
+    bind: String,
+    // Pinged before running the command, so the node has someone to ask
+    peers: Vec<SocketAddr>,
+    command: Command,
+}
+
+fn parse_addr(s: &str) -> Result<SocketAddr, String> {
+    s.parse().map_err(|_| format!("invalid address {:?}", s))
+}
+
+fn parse(mut args: impl Iterator<Item = String>) -> Result<Options, String> {
+    let mut bind = String::from("0.0.0.0:0");
+    let mut peers = Vec::new();
+
+    let command = loop {
+        match args.next() {
+            Some(ref flag) if flag == "--bind" => {
+                bind = args.next().ok_or("--bind needs an address")?;
+            }
+            Some(ref flag) if flag == "--peer" => {
+                let peer = args.next().ok_or("--peer needs an address")?;
+                peers.push(parse_addr(&peer)?);
+            }
+            Some(command) => break command,
+            None => return Err(String::from("no command given")),
+        }
+    };
+
+    let rest: Vec<String> = args.collect();
+    let command = match (command.as_str(), rest.as_slice()) {
+        ("start", []) => Command::Start,
+        ("id", []) => Command::Id,
+        ("ping", [addr]) => Command::Ping(parse_addr(addr)?),
+        ("get", [key]) => Command::Get(key.clone()),
+        ("put", [key, value]) => Command::Put(key.clone(), value.clone()),
+        ("start", _) | ("id", _) | ("ping", _) | ("get", _) | ("put", _) => {
+            return Err(format!("wrong number of arguments to {}", command))
+        }
+        _ => return Err(format!("unknown command {:?}", command)),
+    };
+
+    Ok(Options {
+        bind,
+        peers,
+        command,
+    })
+}
+
+fn run(dht: &Dht, options: Options) -> Result<bool, Error> {
+    for peer in options.peers {
+        dht.ping(peer)?;
+    }
+
+    match options.command {
+        Command::Start => {
+            println!("{} listening on {}", dht.id(), dht.local_addr());
+            loop {
+                thread::park();
+            }
+        }
+        Command::Id => println!("{}", dht.id()),
+        Command::Ping(peer) => {
+            dht.ping(peer)?;
+            println!("pong from {}", peer);
+        }
+        Command::Get(key) => match dht.get(key.as_bytes())? {
+            Some(value) => println!("{}", String::from_utf8_lossy(&value)),
+            None => {
+                eprintln!("{:?} not found", key);
+                return Ok(false);
+            }
+        },
+        Command::Put(key, value) => {
+            let stored = dht.put(key.as_bytes(), value.into_bytes())?;
+            println!("stored on {} nodes", stored);
+        }
+    }
+    Ok(true)
+}
+
+fn main() {
+    let options = match parse(env::args().skip(1)) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            process::exit(2);
+        }
+    };
+
+    let dht = match Dht::start(options.bind.as_str()) {
+        Ok(dht) => dht,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            process::exit(1);
+        }
+    };
+
+    let result = run(&dht, options);
+    dht.shutdown();
+    match result {
+        Ok(true) => (),
+        Ok(false) => process::exit(1),
+        Err(e) => {
+            eprintln!("error: {}", e);
+            process::exit(1);
+        }
+    }
+}
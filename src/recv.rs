@@ -0,0 +1,151 @@
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// Most datagrams are small, so start here and only grow when one doesn't fit.
+const MIN_BUF: usize = 512;
+
+// Why the receiver threw a datagram away.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum DropReason {
+    // Zero bytes long, so there's nothing to even try decoding
+    Empty,
+    // Didn't decode to exactly one packet
+    Malformed,
+    // Sent by a node on a different network (see Config::network_id)
+    ForeignNetwork,
+    // Not signed, while we require signatures (see Config::sign_packets)
+    Unsigned,
+    // Signed, but the signature doesn't match the packet
+    BadSignature,
+    // Didn't end in a MAC made with our pre-shared key (see Config::psk)
+    BadMac,
+}
+
+const REASONS: [DropReason; 6] = [
+    DropReason::Empty,
+    DropReason::Malformed,
+    DropReason::ForeignNetwork,
+    DropReason::Unsigned,
+    DropReason::BadSignature,
+    DropReason::BadMac,
+];
+
+// How many datagrams the receiver has dropped, for each reason
+#[derive(Default)]
+pub struct Drops {
+    empty: AtomicU64,
+    malformed: AtomicU64,
+    foreign_network: AtomicU64,
+    unsigned: AtomicU64,
+    bad_signature: AtomicU64,
+    bad_mac: AtomicU64,
+}
+
+impl Drops {
+    fn counter(&self, reason: DropReason) -> &AtomicU64 {
+        match reason {
+            DropReason::Empty => &self.empty,
+            DropReason::Malformed => &self.malformed,
+            DropReason::ForeignNetwork => &self.foreign_network,
+            DropReason::Unsigned => &self.unsigned,
+            DropReason::BadSignature => &self.bad_signature,
+            DropReason::BadMac => &self.bad_mac,
+        }
+    }
+
+    pub fn count(&self, reason: DropReason) {
+        self.counter(reason).fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn get(&self, reason: DropReason) -> u64 {
+        self.counter(reason).load(Ordering::Relaxed)
+    }
+
+    // Every reason anything has been dropped for, with how many
+    pub fn all(&self) -> Vec<(DropReason, u64)> {
+        REASONS
+            .iter()
+            .map(|&reason| (reason, self.get(reason)))
+            .filter(|&(_, count)| count > 0)
+            .collect()
+    }
+}
+
+// A receive buffer that grows to fit the datagrams actually arriving, up to a cap, and shrinks
+// back down once they get small again.
+pub struct RecvBuf {
+    buf: Vec<u8>,
+    max: usize,
+    last_size: usize,
+}
+
+impl RecvBuf {
+    pub fn new(max: usize) -> RecvBuf {
+        RecvBuf {
+            buf: vec![0; MIN_BUF.min(max)],
+            max,
+            last_size: 0,
+        }
+    }
+
+    // Peeks at the next datagram, doubling the buffer for as long as the datagram fills it.
+    // peek_from only reports as many bytes as fit, so a full buffer means it may have been cut short.
+    fn next_packet_len(&mut self, sock: &UdpSocket) -> io::Result<usize> {
+        loop {
+            let (size, _) = sock.peek_from(&mut self.buf)?;
+            if size < self.buf.len() || self.buf.len() >= self.max {
+                return Ok(size);
+            }
+            let len = (self.buf.len() * 2).min(self.max);
+            self.buf.resize(len, 0);
+        }
+    }
+
+    pub fn recv_from(&mut self, sock: &UdpSocket) -> io::Result<(&[u8], SocketAddr)> {
+        if self.buf.len() > MIN_BUF && self.last_size <= MIN_BUF {
+            self.buf.truncate(MIN_BUF);
+            self.buf.shrink_to_fit();
+        }
+
+        self.next_packet_len(sock)?;
+        let (size, peer) = sock.recv_from(&mut self.buf)?;
+        self.last_size = size;
+        Ok((&self.buf[..size], peer))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn mixed_datagram_sizes() {
+        let recv_sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let send_sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let max = 4096;
+
+        let sizes = [10, 3000, 10, 10, 600, 5000];
+        for (i, size) in sizes.iter().enumerate() {
+            send_sock
+                .send_to(&vec![i as u8; *size], recv_sock.local_addr().unwrap())
+                .unwrap();
+        }
+
+        let mut buf = RecvBuf::new(max);
+        let mut capacities = Vec::new();
+        for (i, size) in sizes.iter().enumerate() {
+            let (data, peer) = buf.recv_from(&recv_sock).unwrap();
+            assert_eq!(peer, send_sock.local_addr().unwrap());
+            // Too-long datagrams are cut off at the cap
+            assert_eq!(data.len(), (*size).min(max));
+            assert!(data.iter().all(|b| *b == i as u8));
+            capacities.push(buf.buf.len());
+        }
+
+        assert!(capacities.iter().all(|c| *c <= max));
+        // Grew for the 3000 byte datagram, then shrank back once they were small again
+        assert!(capacities[1] >= 3000);
+        assert_eq!(capacities[3], MIN_BUF);
+    }
+}
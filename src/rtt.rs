@@ -0,0 +1,65 @@
+use std::time::Duration;
+
+// How long contacts took to answer a ping, over everyone who answered.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct RttStats {
+    // How many contacts answered, and how many were pinged but didn't
+    pub answered: usize,
+    pub lost: usize,
+    pub min: Duration,
+    pub median: Duration,
+    pub p95: Duration,
+    pub max: Duration,
+}
+
+impl RttStats {
+    // All zero durations if nobody answered
+    pub fn from_samples(mut rtts: Vec<Duration>, lost: usize) -> RttStats {
+        rtts.sort();
+        if rtts.is_empty() {
+            return RttStats {
+                lost,
+                ..RttStats::default()
+            };
+        }
+
+        RttStats {
+            answered: rtts.len(),
+            lost,
+            min: rtts[0],
+            median: percentile(&rtts, 50),
+            p95: percentile(&rtts, 95),
+            max: rtts[rtts.len() - 1],
+        }
+    }
+}
+
+// The smallest sample at least p percent of the samples are no greater than (nearest rank).
+// sorted must be sorted and not empty.
+fn percentile(sorted: &[Duration], p: usize) -> Duration {
+    let rank = (sorted.len() * p).div_ceil(100);
+    sorted[rank.max(1) - 1]
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn percentiles() {
+        let rtts = (1..=100).rev().map(Duration::from_millis).collect();
+        let stats = RttStats::from_samples(rtts, 3);
+        assert_eq!(stats.answered, 100);
+        assert_eq!(stats.lost, 3);
+        assert_eq!(stats.min, Duration::from_millis(1));
+        assert_eq!(stats.median, Duration::from_millis(50));
+        assert_eq!(stats.p95, Duration::from_millis(95));
+        assert_eq!(stats.max, Duration::from_millis(100));
+
+        let one = RttStats::from_samples(vec![Duration::from_millis(7)], 0);
+        assert_eq!(one.median, Duration::from_millis(7));
+        assert_eq!(one.p95, Duration::from_millis(7));
+
+        assert_eq!(RttStats::from_samples(Vec::new(), 2).answered, 0);
+    }
+}
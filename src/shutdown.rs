@@ -0,0 +1,52 @@
+use crossbeam::channel;
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use crate::kad::Command;
+
+// Stops a Dht from anywhere, without needing the Dht itself (e.g. from a signal handler thread).
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    command: channel::Sender<Command>,
+    // Set once we're stopping, so each receiver stops once the datagram we send to wake it arrives
+    stopping: Arc<AtomicBool>,
+    // Every socket we serve, with its address
+    wake: Arc<Vec<(SocketAddr, UdpSocket)>>,
+}
+
+impl ShutdownHandle {
+    pub(crate) fn new(
+        command: channel::Sender<Command>,
+        wake: Vec<(SocketAddr, UdpSocket)>,
+    ) -> ShutdownHandle {
+        ShutdownHandle {
+            command,
+            stopping: Arc::new(AtomicBool::new(false)),
+            wake: Arc::new(wake),
+        }
+    }
+
+    pub(crate) fn is_stopping(&self) -> bool {
+        self.stopping.load(Ordering::Relaxed)
+    }
+
+    // Tells every thread to stop, without waiting for them. Doing it more than once is harmless.
+    pub fn shutdown(&self) {
+        self.command.send(Command::Shutdown).ok();
+
+        // The receivers only notice they should stop when a datagram arrives, so send each one
+        self.stopping.store(true, Ordering::Relaxed);
+        for (addr, socket) in self.wake.iter() {
+            let mut wake_addr = *addr;
+            if wake_addr.ip().is_unspecified() {
+                wake_addr.set_ip(match wake_addr {
+                    SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::LOCALHOST),
+                    SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::LOCALHOST),
+                });
+            }
+            socket.send_to(&[], wake_addr).ok();
+        }
+    }
+}
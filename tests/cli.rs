@@ -0,0 +1,34 @@
+extern crate dht;
+
+use std::process::Command;
+
+use dht::Dht;
+
+fn cli() -> Command {
+    Command::new(env!("CARGO_BIN_EXE_dht"))
+}
+
+#[test]
+fn ping() {
+    let node = Dht::start("127.0.0.1:0").unwrap();
+    let addr = node.local_addr();
+
+    let output = cli()
+        .args(["--bind", "127.0.0.1:0", "ping", &addr.to_string()])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        format!("pong from {}\n", addr)
+    );
+
+    node.shutdown();
+}
+
+#[test]
+fn usage() {
+    let output = cli().arg("frobnicate").output().unwrap();
+    assert_eq!(output.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("usage:"));
+}